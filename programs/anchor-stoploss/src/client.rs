@@ -0,0 +1,847 @@
+// Off-chain instruction builders, enabled with the `client` feature.
+//
+// Services building stoploss transactions only need the market address, its account data
+// and the user's wallet. Everything else, ie the stoploss PDA, the DEX vault signer, the
+// order's own vaults and the market queues, is derived here so account lists are never
+// hand rolled.
+
+use crate::{accounts, config_address, fee_vault_address, instruction, mint_decimals, open_orders_address, order_vault_address, LotSizes, OrderMode, OrderType, Side, StoplossState, Strategy, TimeInForce, TriggerType};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::sysvar;
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::dex::serum_dex::state::{gen_vault_signer_key, MarketState};
+use std::str::FromStr;
+
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+
+/// The shared `b"stoploss"` PDA. It owns every order vault and signs the DEX orders.
+pub fn stoploss_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"stoploss"], program_id)
+}
+
+/// The wallet's associated token account for `mint`, used as the default client wallet.
+pub fn associated_token_address(wallet: &Pubkey, mint: &Pubkey) -> Pubkey {
+    let program_id = Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap();
+    Pubkey::find_program_address(&[wallet.as_ref(), spl_token::ID.as_ref(), mint.as_ref()], &program_id).0
+}
+
+/// The DEX accounts of a market, read out of the market account.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketInfo {
+    pub market: Pubkey,
+    pub dex_program: Pubkey,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub vault_signer: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+}
+
+impl MarketInfo {
+    /// Parses the market account `data`, as returned by `getAccountInfo`, for the market
+    /// at `market` owned by `dex_program`.
+    pub fn from_account_data(market: Pubkey, dex_program: Pubkey, data: &[u8]) -> std::result::Result<Self, ProgramError> {
+        let mut lamports = 0;
+        let mut data = data.to_vec();
+        let info = AccountInfo::new(&market, false, false, &mut lamports, &mut data, &dex_program, false, 0);
+        let state = MarketState::load(&info, &dex_program).map_err(|_| ProgramError::InvalidAccountData)?;
+        let vault_signer = gen_vault_signer_key(state.vault_signer_nonce, &market, &dex_program)?;
+        Ok(MarketInfo {
+            market,
+            dex_program,
+            request_queue: pubkey_from_words(state.req_q),
+            event_queue: pubkey_from_words(state.event_q),
+            bids: pubkey_from_words(state.bids),
+            asks: pubkey_from_words(state.asks),
+            coin_vault: pubkey_from_words(state.coin_vault),
+            pc_vault: pubkey_from_words(state.pc_vault),
+            vault_signer,
+            coin_mint: pubkey_from_words(state.coin_mint),
+            pc_mint: pubkey_from_words(state.pc_mint),
+            coin_lot_size: state.coin_lot_size,
+            pc_lot_size: state.pc_lot_size,
+        })
+    }
+
+    /// The market's lot sizes along with its mints' decimals, read out of the mint accounts.
+    pub fn lot_sizes(&self, coin_mint_data: &[u8], pc_mint_data: &[u8]) -> std::result::Result<LotSizes, ProgramError> {
+        Ok(LotSizes {
+            coin_lot_size: self.coin_lot_size,
+            pc_lot_size: self.pc_lot_size,
+            coin_decimals: mint_decimals(coin_mint_data)?,
+            pc_decimals: mint_decimals(pc_mint_data)?,
+        })
+    }
+
+    fn accounts(&self, open_orders: Pubkey, order_payer: Pubkey, coin_wallet: Pubkey, pc_wallet: Pubkey) -> accounts::MarketAccounts {
+        accounts::MarketAccounts {
+            market: self.market,
+            open_orders,
+            request_queue: self.request_queue,
+            event_queue: self.event_queue,
+            bids: self.bids,
+            asks: self.asks,
+            order_payer_token_account: order_payer,
+            coin_vault: self.coin_vault,
+            pc_vault: self.pc_vault,
+            vault_signer: self.vault_signer,
+            coin_wallet,
+            pc_wallet,
+            coin_mint: self.coin_mint,
+            pc_mint: self.pc_mint,
+        }
+    }
+}
+
+// serum stores pubkeys as [u64; 4]
+fn pubkey_from_words(words: [u64; 4]) -> Pubkey {
+    let mut bytes = [0u8; 32];
+    for (i, w) in words.iter().enumerate() {
+        bytes[i * 8..(i + 1) * 8].copy_from_slice(&w.to_le_bytes());
+    }
+    Pubkey::new(&bytes)
+}
+
+/// The order's vaults, derived from the order address and mint.
+pub fn order_vaults(program_id: &Pubkey, order: &Pubkey, coin_mint: &Pubkey, pc_mint: &Pubkey) -> (Pubkey, Pubkey) {
+    (
+        order_vault_address(order, coin_mint, program_id).0,
+        order_vault_address(order, pc_mint, program_id).0,
+    )
+}
+
+// (paying, receiving) vaults of an existing order
+fn paying_and_receiving_vaults(order: &StoplossState) -> (Pubkey, Pubkey) {
+    match order.side {
+        Side::Bid => (order.stoploss_quote_vault, order.stoploss_base_vault),
+        Side::Ask => (order.stoploss_base_vault, order.stoploss_quote_vault),
+    }
+}
+
+/// Builds `new_order`. The caller signs with the wallet and the new `stoploss_state` keypair.
+///
+/// The client wallets default to the wallet's associated token accounts and the deposit is
+/// taken from the one for the paying mint.
+pub struct NewOrderBuilder {
+    program_id: Pubkey,
+    market: MarketInfo,
+    wallet: Pubkey,
+    stoploss_state: Pubkey,
+    open_orders: Pubkey,
+    signal_provider: Pubkey,
+    coin_wallet: Pubkey,
+    pc_wallet: Pubkey,
+    side: Side,
+    limit_price: u64,
+    client_order_id: u64,
+    trigger_price: u64,
+    max_coin_qty: u64,
+    max_pc_qty: u64,
+    should_create_open_orders: bool,
+    trigger_type: TriggerType,
+    trailing_offset: u64,
+    keeper_allowed: bool,
+    keeper_bounty: u64,
+    time_in_force: TimeInForce,
+    expiry_ts: i64,
+    order_mode: OrderMode,
+}
+
+impl NewOrderBuilder {
+    pub fn new(
+        program_id: Pubkey,
+        market: MarketInfo,
+        wallet: Pubkey,
+        stoploss_state: Pubkey,
+        signal_provider: Pubkey,
+        side: Side,
+    ) -> Self {
+        NewOrderBuilder {
+            program_id,
+            coin_wallet: associated_token_address(&wallet, &market.coin_mint),
+            pc_wallet: associated_token_address(&wallet, &market.pc_mint),
+            market,
+            wallet,
+            stoploss_state,
+            open_orders: open_orders_address(&stoploss_state, &program_id).0,
+            signal_provider,
+            side,
+            limit_price: 0,
+            client_order_id: 0,
+            trigger_price: 0,
+            max_coin_qty: 0,
+            max_pc_qty: 0,
+            should_create_open_orders: false,
+            trigger_type: TriggerType::Fixed,
+            trailing_offset: 0,
+            keeper_allowed: false,
+            keeper_bounty: 0,
+            time_in_force: TimeInForce::GoodTillCancel,
+            expiry_ts: 0,
+            order_mode: OrderMode::StopLimit,
+        }
+    }
+
+    /// Limit and trigger prices, in price lots.
+    pub fn prices(mut self, limit_price: u64, trigger_price: u64) -> Self {
+        self.limit_price = limit_price;
+        self.trigger_price = trigger_price;
+        self
+    }
+
+    /// Sizes in native units. The paying side is deposited, ie coin for sells and pc for buys.
+    pub fn quantity(mut self, max_coin_qty: u64, max_pc_qty: u64) -> Self {
+        self.max_coin_qty = max_coin_qty;
+        self.max_pc_qty = max_pc_qty;
+        self
+    }
+
+    pub fn client_order_id(mut self, client_order_id: u64) -> Self {
+        self.client_order_id = client_order_id;
+        self
+    }
+
+    pub fn trailing(mut self, trigger_type: TriggerType, trailing_offset: u64) -> Self {
+        self.trigger_type = trigger_type;
+        self.trailing_offset = trailing_offset;
+        self
+    }
+
+    /// Lets any keeper execute the order, paying `bounty` out of the deposit.
+    pub fn keeper(mut self, bounty: u64) -> Self {
+        self.keeper_allowed = true;
+        self.keeper_bounty = bounty;
+        self
+    }
+
+    /// Defaults to GoodTillCancel. `expiry_ts` is only used for GoodTillDate.
+    pub fn time_in_force(mut self, time_in_force: TimeInForce, expiry_ts: i64) -> Self {
+        self.time_in_force = time_in_force;
+        self.expiry_ts = expiry_ts;
+        self
+    }
+
+    /// Defaults to StopLimit.
+    pub fn order_mode(mut self, order_mode: OrderMode) -> Self {
+        self.order_mode = order_mode;
+        self
+    }
+
+    pub fn should_create_open_orders(mut self, should_create_open_orders: bool) -> Self {
+        self.should_create_open_orders = should_create_open_orders;
+        self
+    }
+
+    /// Overrides the associated token accounts used as client wallets.
+    pub fn wallets(mut self, coin_wallet: Pubkey, pc_wallet: Pubkey) -> Self {
+        self.coin_wallet = coin_wallet;
+        self.pc_wallet = pc_wallet;
+        self
+    }
+
+    pub fn instruction(&self) -> Instruction {
+        let order_payer = match self.side {
+            Side::Bid => self.pc_wallet,
+            Side::Ask => self.coin_wallet,
+        };
+        let (base_vault, quote_vault) = order_vaults(&self.program_id, &self.stoploss_state, &self.market.coin_mint, &self.market.pc_mint);
+        let accounts = accounts::NewOrder {
+            market: self.market.accounts(self.open_orders, order_payer, self.coin_wallet, self.pc_wallet),
+            stoploss_base_vault: base_vault,
+            stoploss_quote_vault: quote_vault,
+            stoploss_open_orders: self.open_orders,
+            signal_provider: self.signal_provider,
+            authority: self.wallet,
+            stoploss_state: self.stoploss_state,
+            stoploss_program: self.program_id,
+            dex_program: self.market.dex_program,
+            pda: stoploss_pda(&self.program_id).0,
+            token_program: spl_token::ID,
+            system_program: anchor_lang::solana_program::system_program::ID,
+            rent: sysvar::rent::ID,
+            config: config_address(&self.program_id).0,
+        };
+        let data = instruction::NewOrder {
+            side: self.side,
+            limit_price: self.limit_price,
+            client_order_id: self.client_order_id,
+            trigger_price: self.trigger_price,
+            max_coin_qty: self.max_coin_qty,
+            max_pc_qty: self.max_pc_qty,
+            should_create_open_orders: self.should_create_open_orders,
+            trigger_type: self.trigger_type,
+            trailing_offset: self.trailing_offset,
+            keeper_allowed: self.keeper_allowed,
+            keeper_bounty: self.keeper_bounty,
+            time_in_force: self.time_in_force,
+            expiry_ts: self.expiry_ts,
+            order_mode: self.order_mode,
+        };
+        Instruction {
+            program_id: self.program_id,
+            accounts: accounts.to_account_metas(None),
+            data: data.data(),
+        }
+    }
+}
+
+/// Builds `execute_order` for an existing order, signed by the signal provider or a keeper.
+/// The program ignores the size, limit, `reuse_unfilled` and order type a keeper passes.
+pub struct ExecuteOrderBuilder<'a> {
+    program_id: Pubkey,
+    market: MarketInfo,
+    order: &'a StoplossState,
+    authority: Pubkey,
+    keeper_bounty_account: Pubkey,
+    execute_qty: u64,
+    execute_limit: u64,
+    reuse_unfilled: bool,
+    order_type: OrderType,
+}
+
+impl<'a> ExecuteOrderBuilder<'a> {
+    /// `order` is the fetched stoploss state. The keeper bounty account defaults to the
+    /// authority's associated token account for the paying mint.
+    pub fn new(program_id: Pubkey, market: MarketInfo, order: &'a StoplossState, authority: Pubkey) -> Self {
+        let paying_mint = match order.side {
+            Side::Bid => order.pc_mint,
+            Side::Ask => order.coin_mint,
+        };
+        ExecuteOrderBuilder {
+            program_id,
+            market,
+            order,
+            authority,
+            keeper_bounty_account: associated_token_address(&authority, &paying_mint),
+            execute_qty: order.coin_leaves_qty,
+            execute_limit: match order.order_mode {
+                OrderMode::StopLimit => order.limit_price,
+                OrderMode::StopMarket => 0,
+            },
+            reuse_unfilled: false,
+            order_type: OrderType::ImmediateOrCancel,
+        }
+    }
+
+    /// Size of the child order in native base, defaults to all of the coin leaves. For a buy
+    /// that is as much as the quote leaves buy, up to its `max_coin_qty` cap.
+    pub fn execute_qty(mut self, execute_qty: u64) -> Self {
+        self.execute_qty = execute_qty;
+        self
+    }
+
+    /// Limit of the child order, defaults to the order's limit. Stop-market orders default to
+    /// zero, ie the slippage cap.
+    pub fn execute_limit(mut self, execute_limit: u64) -> Self {
+        self.execute_limit = execute_limit;
+        self
+    }
+
+    pub fn reuse_unfilled(mut self, reuse_unfilled: bool) -> Self {
+        self.reuse_unfilled = reuse_unfilled;
+        self
+    }
+
+    /// Defaults to ImmediateOrCancel. Limit and PostOnly children rest on the book.
+    pub fn order_type(mut self, order_type: OrderType) -> Self {
+        self.order_type = order_type;
+        self
+    }
+
+    pub fn keeper_bounty_account(mut self, keeper_bounty_account: Pubkey) -> Self {
+        self.keeper_bounty_account = keeper_bounty_account;
+        self
+    }
+
+    pub fn instruction(&self) -> Instruction {
+        let order = self.order;
+        // the protocol fee is taken in the mint the order receives
+        let receiving_mint = match order.side {
+            Side::Bid => order.coin_mint,
+            Side::Ask => order.pc_mint,
+        };
+        let accounts = accounts::ExecuteOrder {
+            market: self.market.accounts(order.stoploss_open_orders, order.payer, order.client_coin_wallet, order.client_pc_wallet),
+            stoploss_base_vault: order.stoploss_base_vault,
+            stoploss_quote_vault: order.stoploss_quote_vault,
+            authority: self.authority,
+            stoploss_state: order.own_address,
+            stoploss_open_orders: order.stoploss_open_orders,
+            amend_authority: order.amend_authority,
+            keeper_bounty_account: self.keeper_bounty_account,
+            stoploss_program: self.program_id,
+            dex_program: self.market.dex_program,
+            pda: stoploss_pda(&self.program_id).0,
+            token_program: spl_token::ID,
+            rent: sysvar::rent::ID,
+            config: config_address(&self.program_id).0,
+            fee_vault: fee_vault_address(&receiving_mint, &self.program_id).0,
+            oracle: order.oracle,
+        };
+        let data = instruction::ExecuteOrder {
+            execute_qty: self.execute_qty,
+            execute_limit: self.execute_limit,
+            reuse_unfilled: self.reuse_unfilled,
+            order_type: self.order_type,
+        };
+        Instruction {
+            program_id: self.program_id,
+            accounts: with_sibling(accounts.to_account_metas(None), order),
+            data: data.data(),
+        }
+    }
+}
+
+/// Builds `settle_order` for an order with a resting child. Anyone can sign.
+pub fn settle_order(program_id: Pubkey, market: MarketInfo, order: &StoplossState, authority: Pubkey) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: settle_order_accounts(program_id, market, order, authority),
+        data: instruction::SettleOrder {}.data(),
+    }
+}
+
+/// Builds `cancel_child_order`, signed by the order owner or the signal provider.
+pub fn cancel_child_order(program_id: Pubkey, market: MarketInfo, order: &StoplossState, authority: Pubkey) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: settle_order_accounts(program_id, market, order, authority),
+        data: instruction::CancelChildOrder {}.data(),
+    }
+}
+
+fn settle_order_accounts(program_id: Pubkey, market: MarketInfo, order: &StoplossState, authority: Pubkey) -> Vec<AccountMeta> {
+    let receiving_mint = match order.side {
+        Side::Bid => order.coin_mint,
+        Side::Ask => order.pc_mint,
+    };
+    let accounts = accounts::SettleOrder {
+        market: market.accounts(order.stoploss_open_orders, order.payer, order.client_coin_wallet, order.client_pc_wallet),
+        stoploss_base_vault: order.stoploss_base_vault,
+        stoploss_quote_vault: order.stoploss_quote_vault,
+        authority,
+        stoploss_state: order.own_address,
+        amend_authority: order.amend_authority,
+        dex_program: market.dex_program,
+        pda: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+        config: config_address(&program_id).0,
+        fee_vault: fee_vault_address(&receiving_mint, &program_id).0,
+    };
+    with_sibling(accounts.to_account_metas(None), order)
+}
+
+/// Builds `cancel_order`, signed by the order owner.
+pub fn cancel_order(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let (paying_vault, receiving_vault) = paying_and_receiving_vaults(order);
+    let accounts = accounts::CancelOrder {
+        stoploss_state: order.own_address,
+        authority: order.amend_authority,
+        coin_wallet: order.client_coin_wallet,
+        pc_wallet: order.client_pc_wallet,
+        stoploss_paying_vault: paying_vault,
+        stoploss_receiving_vault: receiving_vault,
+        vault_owner: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+    };
+    Instruction {
+        program_id,
+        accounts: with_sibling(accounts.to_account_metas(None), order),
+        data: instruction::CancelOrder {}.data(),
+    }
+}
+
+/// Builds `expire_order`. Permissionless, so anyone can pay for it once the order has expired.
+pub fn expire_order(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let accounts = accounts::ExpireOrder {
+        stoploss_state: order.own_address,
+        amend_authority: order.amend_authority,
+        coin_wallet: order.client_coin_wallet,
+        pc_wallet: order.client_pc_wallet,
+        stoploss_base_vault: order.stoploss_base_vault,
+        stoploss_quote_vault: order.stoploss_quote_vault,
+        vault_owner: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+        clock: sysvar::clock::ID,
+    };
+    Instruction {
+        program_id,
+        accounts: with_sibling(accounts.to_account_metas(None), order),
+        data: instruction::ExpireOrder {}.data(),
+    }
+}
+
+/// Builds `suspend_order`, signed by the order owner.
+pub fn suspend_order(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let accounts = accounts::SuspendOrder {
+        stoploss_state: order.own_address,
+        authority: order.amend_authority,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::SuspendOrder {}.data(),
+    }
+}
+
+/// Builds `resume_order`, signed by the order owner.
+pub fn resume_order(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let accounts = accounts::ResumeOrder {
+        stoploss_state: order.own_address,
+        authority: order.amend_authority,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::ResumeOrder {}.data(),
+    }
+}
+
+/// Builds `amend_order`, signed by the order owner. Prices in price lots and
+/// `new_quantity` in native units of the paying mint.
+pub fn amend_order(program_id: Pubkey, order: &StoplossState, limit_price: u64, new_quantity: u64, trigger_price: u64) -> Instruction {
+    let (paying_vault, _) = paying_and_receiving_vaults(order);
+    let accounts = accounts::AmendOrder {
+        stoploss_state: order.own_address,
+        authority: order.amend_authority,
+        coin_wallet: order.client_coin_wallet,
+        pc_wallet: order.client_pc_wallet,
+        stoploss_paying_vault: paying_vault,
+        vault_owner: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+    };
+    let data = instruction::AmendOrder {
+        limit_price,
+        _client_order_id: order.client_order_id,
+        new_quantity,
+        trigger_price,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Builds `initialise_open_orders`, signed by the signal provider which pays the rent. Both
+/// legs of a bracket move to New, so it only needs sending for one of them.
+pub fn initialise_open_orders(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let accounts = accounts::InitialiseOpenOrders {
+        authority: order.signal_provider,
+        stoploss_state: order.own_address,
+        stoploss_open_orders: order.stoploss_open_orders,
+        market: order.market,
+        dex_program: order.dex_program,
+        pda: stoploss_pda(&program_id).0,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        rent: sysvar::rent::ID,
+    };
+    Instruction {
+        program_id,
+        accounts: with_sibling(accounts.to_account_metas(None), order),
+        data: instruction::InitialiseOpenOrders {}.data(),
+    }
+}
+
+/// Builds `close_order`, signed by the order owner. The rent of the order and its vaults goes
+/// to `recipient`, and the rent of its open orders back to whoever paid it.
+pub fn close_order(program_id: Pubkey, order: &StoplossState, recipient: Pubkey) -> Instruction {
+    let accounts = accounts::CloseOrder {
+        stoploss_state: order.own_address,
+        authority: order.amend_authority,
+        recipient,
+        stoploss_base_vault: order.stoploss_base_vault,
+        stoploss_quote_vault: order.stoploss_quote_vault,
+        vault_owner: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+        stoploss_open_orders: order.stoploss_open_orders,
+        market: order.market,
+        dex_program: order.dex_program,
+        open_orders_payer: order.open_orders_payer,
+    };
+    Instruction {
+        program_id,
+        accounts: with_sibling(accounts.to_account_metas(None), order),
+        data: instruction::CloseOrder {}.data(),
+    }
+}
+
+/// Builds `new_bracket_leg`, adding `bracket_leg` as the other leg of `order`. The caller
+/// signs with the order owner and the new `bracket_leg` keypair. The new leg starts in the
+/// order's status, and a PendingInit pair moves to New together on `initialise_open_orders`.
+pub fn new_bracket_leg(program_id: Pubkey, order: &StoplossState, bracket_leg: Pubkey, limit_price: u64, client_order_id: u64, trigger_price: u64) -> Instruction {
+    let accounts = accounts::NewBracketLeg {
+        stoploss_state: order.own_address,
+        bracket_leg,
+        authority: order.amend_authority,
+        rent: sysvar::rent::ID,
+    };
+    let data = instruction::NewBracketLeg {
+        limit_price,
+        client_order_id,
+        trigger_price,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Builds `set_oracle`, signed by the order owner. Takes the order's address rather than its
+/// state so it can go in the same transaction as `new_order`, along with the order's `market`.
+pub fn set_oracle(program_id: Pubkey, order: Pubkey, market: &MarketInfo, authority: Pubkey, oracle: Pubkey, max_age: u64, max_confidence_bps: u16) -> Instruction {
+    let accounts = accounts::SetOracle {
+        stoploss_state: order,
+        authority,
+        oracle,
+        config: config_address(&program_id).0,
+        market: market.market,
+        coin_mint: market.coin_mint,
+        pc_mint: market.pc_mint,
+        dex_program: market.dex_program,
+    };
+    let data = instruction::SetOracle { max_age, max_confidence_bps };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Builds `set_strategy`, signed by the order owner. Like `set_oracle` it takes the order's
+/// address so it can go in the same transaction as `new_order`.
+pub struct SetStrategyBuilder {
+    program_id: Pubkey,
+    order: Pubkey,
+    authority: Pubkey,
+    strategy: Strategy,
+    start_ts: i64,
+    end_ts: i64,
+    slice_count: u16,
+    max_participation_bps: u16,
+    min_child_interval: u64,
+}
+
+impl SetStrategyBuilder {
+    pub fn new(program_id: Pubkey, order: Pubkey, authority: Pubkey, strategy: Strategy, start_ts: i64, end_ts: i64) -> Self {
+        SetStrategyBuilder {
+            program_id,
+            order,
+            authority,
+            strategy,
+            start_ts,
+            end_ts,
+            slice_count: 0,
+            max_participation_bps: 0,
+            min_child_interval: 0,
+        }
+    }
+
+    /// Number of Twap slices.
+    pub fn slice_count(mut self, slice_count: u16) -> Self {
+        self.slice_count = slice_count;
+        self
+    }
+
+    /// Largest Vwap child, in basis points of the size of the best opposite order, ie the top
+    /// of book rather than traded volume.
+    pub fn max_participation_bps(mut self, max_participation_bps: u16) -> Self {
+        self.max_participation_bps = max_participation_bps;
+        self
+    }
+
+    /// Shortest gap between children, in seconds. Defaults to zero.
+    pub fn min_child_interval(mut self, min_child_interval: u64) -> Self {
+        self.min_child_interval = min_child_interval;
+        self
+    }
+
+    pub fn instruction(&self) -> Instruction {
+        let accounts = accounts::SetStrategy {
+            stoploss_state: self.order,
+            authority: self.authority,
+        };
+        let data = instruction::SetStrategy {
+            strategy: self.strategy,
+            start_ts: self.start_ts,
+            end_ts: self.end_ts,
+            slice_count: self.slice_count,
+            max_participation_bps: self.max_participation_bps,
+            min_child_interval: self.min_child_interval,
+        };
+        Instruction {
+            program_id: self.program_id,
+            accounts: accounts.to_account_metas(None),
+            data: data.data(),
+        }
+    }
+}
+
+/// Builds `update_trailing_mark`. Permissionless, so anyone can pay for it.
+pub fn update_trailing_mark(program_id: Pubkey, order: &StoplossState) -> Instruction {
+    let accounts = accounts::UpdateTrailingMark {
+        stoploss_state: order.own_address,
+        market: order.market,
+        bids: order.bids,
+        asks: order.asks,
+        dex_program: order.dex_program,
+        coin_mint: order.coin_mint,
+        pc_mint: order.pc_mint,
+        oracle: order.oracle,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::UpdateTrailingMark {}.data(),
+    }
+}
+
+/// Builds `initialise_config`, making `admin` the config admin. `admin` signs and pays.
+pub fn initialise_config(program_id: Pubkey, admin: Pubkey, fee_recipient: Pubkey, protocol_fee_bps: u16, max_slippage_bps: u16) -> Instruction {
+    let accounts = accounts::InitialiseConfig {
+        admin,
+        config: config_address(&program_id).0,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        rent: sysvar::rent::ID,
+    };
+    let data = instruction::InitialiseConfig {
+        fee_recipient,
+        protocol_fee_bps,
+        max_slippage_bps,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Builds any of the admin config instructions, eg `instruction::SetHalted { halted: true }`,
+/// signed by the config admin.
+pub fn update_config(program_id: Pubkey, admin: Pubkey, data: impl InstructionData) -> Instruction {
+    let accounts = accounts::UpdateConfig {
+        config: config_address(&program_id).0,
+        admin,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Builds `initialise_fee_vault` for `mint`. `admin` signs and pays.
+pub fn initialise_fee_vault(program_id: Pubkey, admin: Pubkey, mint: Pubkey) -> Instruction {
+    let accounts = accounts::InitialiseFeeVault {
+        config: config_address(&program_id).0,
+        admin,
+        fee_vault: fee_vault_address(&mint, &program_id).0,
+        mint,
+        pda: stoploss_pda(&program_id).0,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        token_program: spl_token::ID,
+        rent: sysvar::rent::ID,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::InitialiseFeeVault {}.data(),
+    }
+}
+
+/// Builds `withdraw_fees`, paying the `mint` fee vault to `recipient`, a token account owned by
+/// the config's fee recipient. Signed by the config admin.
+pub fn withdraw_fees(program_id: Pubkey, admin: Pubkey, mint: Pubkey, recipient: Pubkey) -> Instruction {
+    let accounts = accounts::WithdrawFees {
+        config: config_address(&program_id).0,
+        admin,
+        fee_vault: fee_vault_address(&mint, &program_id).0,
+        recipient,
+        pda: stoploss_pda(&program_id).0,
+        token_program: spl_token::ID,
+    };
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: instruction::WithdrawFees {}.data(),
+    }
+}
+
+// bracket legs pass the other leg as the first remaining account, eg so initialise_open_orders
+// can move both legs and close_order can leave the shared accounts to a leg still working
+fn with_sibling(mut metas: Vec<AccountMeta>, order: &StoplossState) -> Vec<AccountMeta> {
+    if order.sibling != Pubkey::default() {
+        metas.push(AccountMeta::new(order.sibling, false));
+    }
+    metas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn market() -> MarketInfo {
+        MarketInfo {
+            market: Pubkey::new_unique(),
+            dex_program: Pubkey::new_unique(),
+            request_queue: Pubkey::new_unique(),
+            event_queue: Pubkey::new_unique(),
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            coin_vault: Pubkey::new_unique(),
+            pc_vault: Pubkey::new_unique(),
+            vault_signer: Pubkey::new_unique(),
+            coin_mint: Pubkey::new_unique(),
+            pc_mint: Pubkey::new_unique(),
+            coin_lot_size: 100_000,
+            pc_lot_size: 100,
+        }
+    }
+
+    #[test]
+    fn new_order_derives_vaults_and_payer() {
+        let program_id = Pubkey::new_unique();
+        let market = market();
+        let wallet = Pubkey::new_unique();
+        let order = Pubkey::new_unique();
+        let ix = NewOrderBuilder::new(program_id, market, wallet, order, Pubkey::new_unique(), Side::Ask)
+            .prices(600, 550)
+            .quantity(1_000_000, u64::MAX)
+            .instruction();
+
+        let keys: Vec<Pubkey> = ix.accounts.iter().map(|m| m.pubkey).collect();
+        let coin_wallet = associated_token_address(&wallet, &market.coin_mint);
+        // market accounts come first, the order payer is the coin wallet for a sell
+        assert_eq!(keys[0], market.market);
+        assert_eq!(keys[1], open_orders_address(&order, &program_id).0);
+        assert_eq!(keys[6], coin_wallet);
+        assert_eq!(keys[10], coin_wallet);
+        let (base_vault, quote_vault) = order_vaults(&program_id, &order, &market.coin_mint, &market.pc_mint);
+        assert_eq!(keys[14], base_vault);
+        assert_eq!(keys[15], quote_vault);
+        assert!(ix.accounts.iter().any(|m| m.pubkey == wallet && m.is_signer));
+        assert!(keys.contains(&stoploss_pda(&program_id).0));
+        assert_eq!(keys.last(), Some(&config_address(&program_id).0));
+    }
+
+    #[test]
+    fn pubkey_round_trips_through_words() {
+        let key = Pubkey::new_unique();
+        let bytes = key.to_bytes();
+        let mut words = [0u64; 4];
+        for (i, w) in words.iter_mut().enumerate() {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+            *w = u64::from_le_bytes(b);
+        }
+        assert_eq!(pubkey_from_words(words), key);
+    }
+}
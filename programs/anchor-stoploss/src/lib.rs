@@ -1,60 +1,95 @@
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::instruction::Instruction;
 use anchor_lang::solana_program::program;
-use anchor_spl::dex::serum_dex::state::MarketState;
-//use anchor_spl::dex::serum_dex::state::OpenOrders;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::system_instruction;
+use anchor_spl::dex::serum_dex::state::{AccountFlag, EventView, MarketState, OpenOrders, ToAlignedBytes};
 use anchor_spl::token::{self, TokenAccount, Transfer};
-use borsh::{BorshDeserialize, BorshSerialize};
+use serum_dex::fees::FeeTier;
 use serum_dex::instruction::{MarketInstruction, NewOrderInstructionV3};
 use serum_dex::matching::{OrderType as SerumOrderType, Side as SerumSide};
+use std::convert::{identity, TryFrom};
 use std::num::NonZeroU64;
 
-use base64;
-use bincode;
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "client")]
+pub mod client;
+
 #[program]
 pub mod anchor_stoploss {
 
     use super::*;
 
-    /// Called by the stoploss server when receiving an order for a market that it has not previously
-    /// seen. The Stoploss server has to create the open orders account
-    /// because it is the owner. ie it cannot be set from the UI because it needs to use the
-    /// private key.
+    /// Creates the order's open orders account on the DEX for orders created without
+    /// `should_create_open_orders`, and moves the order from PendingInit to New.
     ///
-    /// Arguments:
+    /// The account is at the address derived from the order and owned by the stoploss PDA, so
+    /// only this order ever trades through it. It is closed by `close_order`, which refunds
+    /// its rent to whoever paid it.
     ///
+    /// Bracket legs share the open orders account, so both legs move to New. The other leg
+    /// is passed as the first remaining account.
+    ///
+    /// Called by the signal provider, which pays the rent.
     pub fn initialise_open_orders<'info>(ctx: Context<'_, '_, '_, 'info, InitialiseOpenOrders<'info>>) -> Result<()> {
-        // TODO - not sure how best to check this.
         msg!("initialising open orders {:?}", &ctx.accounts.stoploss_open_orders.key);
-        // currently another transaction could modify the open orders account
-        // if ctx.accounts.stoploss_state.stoploss_open_orders == OrdStatus::Cancelled {
-        //     msg!("order already has open orders account");
-        //     return Err(ErrorCode::AttemptingToExecuteCancelledOrder.into());
-        // }
         if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.signal_provider {
             msg!("Incorrect Signal Provider account was provided. Should be algo server sending InitialiseOpenOrders instructions.");
             return Err(ErrorCode::IncorrectSignalProviderAccount.into());
         }
+        let stoploss = &ctx.accounts.stoploss_state;
+        if stoploss.ord_status != OrdStatus::PendingInit {
+            msg!("order already has open orders. status {:?}", stoploss.ord_status);
+            return Err(ErrorCode::OpenOrdersAlreadyInitialised.into());
+        }
+        check_account("stoploss open orders", ctx.accounts.stoploss_open_orders.key, &stoploss.stoploss_open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        check_account("market", ctx.accounts.market.key, &stoploss.market, ErrorCode::IncorrectMarketAccount)?;
+        check_account("dex program", ctx.accounts.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+        check_account("pda", ctx.accounts.pda.key, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+
+        // bracket legs share one open orders account, the other leg may have created it already
+        if ctx.accounts.stoploss_open_orders.lamports() == 0 {
+            // a bracket leg trades through the open orders of the order it was added to
+            let order = if stoploss.sibling != Pubkey::default() && open_orders_address(&stoploss.own_address, ctx.program_id).0 != stoploss.stoploss_open_orders {
+                stoploss.sibling
+            } else {
+                stoploss.own_address
+            };
+            create_open_orders(
+                ctx.accounts.authority.clone(),
+                ctx.accounts.stoploss_open_orders.clone(),
+                ctx.accounts.market.clone(),
+                ctx.accounts.pda.clone(),
+                ctx.accounts.dex_program.clone(),
+                ctx.accounts.system_program.clone(),
+                &ctx.accounts.rent,
+                &order,
+                ctx.program_id,
+            )?;
+        }
+
+        if stoploss.sibling != Pubkey::default() {
+            let mut sibling = load_sibling(stoploss, ctx.remaining_accounts)?;
+            if sibling.ord_status == OrdStatus::PendingInit {
+                sibling.ord_status = OrdStatus::New;
+                sibling.exit(ctx.program_id)?;
+                emit!(sibling.update_event());
+            }
+        }
 
         let stoploss = &mut ctx.accounts.stoploss_state;
-        stoploss.stoploss_open_orders = *ctx.accounts.stoploss_open_orders.key;
         stoploss.ord_status = OrdStatus::New;
 
-        let update = StoplossOrderUpdate {
-            own_address: *stoploss.to_account_info().key,
-        };
-
-        let encoded = bincode::serialize(&update).unwrap();
-        msg!("STOPLOSS_PARENT_UPDATE: {:?}", base64::encode(&encoded));
+        emit!(stoploss.update_event());
 
         Ok(())
     }
 
     /// Creates a stoploss order with trigger price and limit price etc.
-    /// Immediately transfers funds into the stoploss vaults so it can execute
-    /// asynchronously.
+    /// Creates the order's own base and quote vaults, derived from the order address
+    /// and mint, and immediately transfers funds into them so it can execute
+    /// asynchronously. The vaults are closed once the order reaches a terminal state.
     ///
     /// Stoploss is used when a user is long (short) and they want to sell (buy)
     /// to cover their exposure if the market moves against them.    ///
@@ -67,9 +102,33 @@ pub mod anchor_stoploss {
     /// * `trigger_price`   - The price at which the stoploss is activated. For
     /// example a trigger price of 10 when buying means the order will trigger when
     /// the market price goes *above* 10. Vice versa for sells.
-    /// * `max_coin_qty`    - max_coin_qty tradeable.
+    /// * `max_coin_qty`    - max_coin_qty tradeable. Buys are executed in base, so for a buy
+    /// this caps how much base the order buys with its quote and must be non zero. Buys used to
+    /// accept zero here and size in quote, they are now rejected with InvalidMaxCoinQty.
     /// * `max_pc_qty`      - max_pc_qty tradeable.
     /// * `signal_provider` - The acount that can trigger child order executions.
+    /// * `should_create_open_orders` - Creates the order's open orders account on the DEX
+    /// straight away, paid for by the owner. Otherwise the order starts PendingInit until the
+    /// signal provider calls `initialise_open_orders`.
+    /// * `trigger_type`    - Fixed uses `trigger_price` as is. The trailing types instead
+    /// derive the trigger from a high-water (sells) or low-water (buys) mark that starts at
+    /// the current market price and is ratcheted by `update_trailing_mark`. Setting an oracle
+    /// restarts the mark from the oracle price, see `set_oracle`.
+    /// * `trailing_offset` - Distance of the trigger from the mark for trailing orders, in
+    /// price lots for TrailingAbsolute and in basis points of the mark for TrailingBps, at
+    /// most 10000.
+    /// * `keeper_allowed`  - When true any keeper, not just the signal provider, can execute
+    /// the order once the on-chain trigger condition is met. Such orders cannot be given a
+    /// strategy, see `set_strategy`.
+    /// * `keeper_bounty`   - Paid to the first keeper whose child order fills anything, in the
+    /// paying mint and out of the deposit.
+    /// * `time_in_force`   - GoodTillCancel orders live until cancelled. GoodTillDate orders
+    /// expire at `expiry_ts` and Day orders at the end of the current UTC day, after which
+    /// anyone can call `expire_order` to refund them.
+    /// * `expiry_ts`       - Unix timestamp a GoodTillDate order expires at. Ignored otherwise.
+    /// * `order_mode`      - StopLimit children trade at the signal provider's `execute_limit`.
+    /// StopMarket children take the best available prices, down (sells) or up (buys) to the
+    /// config's `max_slippage_bps` from the trigger price. `limit_price` still bounds both.
     ///
     pub fn new_order<'info>(
         ctx: Context<'_, '_, '_, 'info, NewOrder<'info>>,
@@ -80,9 +139,92 @@ pub mod anchor_stoploss {
         max_coin_qty: u64,
         max_pc_qty: u64,
         should_create_open_orders: bool,
+        trigger_type: TriggerType,
+        trailing_offset: u64,
+        keeper_allowed: bool,
+        keeper_bounty: u64,
+        time_in_force: TimeInForce,
+        expiry_ts: i64,
+        order_mode: OrderMode,
     ) -> Result<()> {
-        let (_pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        let config = &ctx.accounts.config;
+        check_account("config", config.to_account_info().key, &config_address(ctx.program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        if config.halted {
+            msg!("program halted. cannot create orders");
+            return Err(ErrorCode::ProgramHalted.into());
+        }
+        if !config.is_signal_provider(ctx.accounts.signal_provider.key) {
+            msg!("signal provider {:?} is not approved", ctx.accounts.signal_provider.key);
+            return Err(ErrorCode::SignalProviderNotApproved.into());
+        }
+        let expiry_ts = order_expiry(time_in_force, expiry_ts, Clock::get()?.unix_timestamp)?;
+        let deposit = match side {
+            Side::Bid => max_pc_qty,
+            Side::Ask => max_coin_qty,
+        };
+        if side == Side::Bid && max_coin_qty == 0 {
+            msg!("buys are sized in base. max_coin_qty must be non zero");
+            return Err(ErrorCode::InvalidMaxCoinQty.into());
+        }
+        if keeper_bounty >= deposit {
+            msg!("keeper bounty {:?} must be less than the deposit {:?}", keeper_bounty, deposit);
+            return Err(ErrorCode::KeeperBountyTooLarge.into());
+        }
+        if trigger_type == TriggerType::TrailingBps && trailing_offset > 10_000 {
+            msg!("trailing offset {:?} bps is more than the whole mark", trailing_offset);
+            return Err(ErrorCode::InvalidBps.into());
+        }
+
+        let (pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
         let seeds = &[&b"stoploss"[..], &[bump_seed]];
+        // everything else is checked against what is recorded here, so these must be right
+        check_account("pda", ctx.accounts.pda.key, &pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("stoploss program", ctx.accounts.stoploss_program.key, ctx.program_id, ErrorCode::IncorrectStoplossProgram)?;
+        check_account("token program", ctx.accounts.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+
+        // each order holds its funds in its own vaults so balances are never shared between orders
+        let order = *ctx.accounts.stoploss_state.to_account_info().key;
+        create_order_vault(
+            ctx.accounts.authority.clone(),
+            ctx.accounts.stoploss_base_vault.clone(),
+            ctx.accounts.market.coin_mint.clone(),
+            ctx.accounts.pda.clone(),
+            ctx.accounts.system_program.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.rent,
+            &order,
+            ctx.program_id,
+        )?;
+        create_order_vault(
+            ctx.accounts.authority.clone(),
+            ctx.accounts.stoploss_quote_vault.clone(),
+            ctx.accounts.market.pc_mint.clone(),
+            ctx.accounts.pda.clone(),
+            ctx.accounts.system_program.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.rent,
+            &order,
+            ctx.program_id,
+        )?;
+
+        // the order trades through its own open orders account too, so nothing it has resting
+        // or unsettled on the DEX is ever mixed up with another order's
+        let (open_orders, _) = open_orders_address(&order, ctx.program_id);
+        check_account("stoploss open orders", ctx.accounts.stoploss_open_orders.key, &open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        check_account("open orders", ctx.accounts.market.open_orders.key, &open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        if should_create_open_orders {
+            create_open_orders(
+                ctx.accounts.authority.clone(),
+                ctx.accounts.stoploss_open_orders.clone(),
+                ctx.accounts.market.market.clone(),
+                ctx.accounts.pda.clone(),
+                ctx.accounts.dex_program.clone(),
+                ctx.accounts.system_program.clone(),
+                &ctx.accounts.rent,
+                &order,
+                ctx.program_id,
+            )?;
+        }
 
         if side == Side::Bid {
             msg!("buying transferring {:?} from pc", max_pc_qty);
@@ -102,7 +244,7 @@ pub mod anchor_stoploss {
         let stoploss = &mut ctx.accounts.stoploss_state;
         stoploss.own_address = *stoploss.to_account_info().key;
         stoploss.market = *ctx.accounts.market.market.key;
-        //stoploss.stoploss_open_orders = *ctx.accounts.stoploss_open_orders.key;
+        stoploss.stoploss_open_orders = *ctx.accounts.stoploss_open_orders.key;
         stoploss.request_queue = *ctx.accounts.market.request_queue.key;
         stoploss.event_queue = *ctx.accounts.market.event_queue.key;
         stoploss.bids = *ctx.accounts.market.bids.key;
@@ -126,6 +268,25 @@ pub mod anchor_stoploss {
         stoploss.limit_price = limit_price;
         stoploss.client_order_id = client_order_id;
         stoploss.trigger_price = trigger_price;
+        stoploss.trigger_type = trigger_type;
+        stoploss.trailing_offset = trailing_offset;
+        stoploss.order_mode = order_mode;
+        if trigger_type != TriggerType::Fixed {
+            let market_price = best_opposite_price(
+                &ctx.accounts.market.market,
+                &ctx.accounts.market.bids,
+                &ctx.accounts.market.asks,
+                side,
+                &ctx.accounts.dex_program.key,
+            )?;
+            match market_price {
+                None => {
+                    msg!("no resting orders on the opposite side of the book. cannot start the trailing mark");
+                    return Err(ErrorCode::MarketPriceUnavailable.into());
+                }
+                Some(px) => stoploss.trailing_mark = px,
+            }
+        }
         stoploss.max_coin_qty = max_coin_qty;
         stoploss.max_pc_qty = max_pc_qty;
 
@@ -139,27 +300,45 @@ pub mod anchor_stoploss {
         stoploss.coin_mint = *ctx.accounts.market.coin_mint.key;
         stoploss.pc_mint = *ctx.accounts.market.pc_mint.key;
         stoploss.should_create_open_orders = should_create_open_orders;
-        // always assume pending init for now - better versions in the future
-        // will check the open orders key properly and see if its populated
-        // TODO - only set pending init if required
-        stoploss.ord_status = OrdStatus::PendingInit;
-        msg!("checking status {:?}", ctx.accounts.market.open_orders.key.to_string());
-        if ctx.accounts.market.open_orders.key.to_string().eq("11111111111111111111111111111111") {
-            msg!("should set pending init");
-            stoploss.ord_status = OrdStatus::PendingInit;
-        }
+        stoploss.open_orders_payer = match should_create_open_orders {
+            true => *ctx.accounts.authority.key,
+            false => *ctx.accounts.signal_provider.key,
+        };
+        stoploss.keeper_allowed = keeper_allowed;
+        stoploss.keeper_bounty = keeper_bounty;
+        stoploss.time_in_force = time_in_force;
+        stoploss.expiry_ts = expiry_ts;
+        // without open orders the order waits for the signal provider to initialise them
+        stoploss.ord_status = match should_create_open_orders {
+            true => OrdStatus::New,
+            false => OrdStatus::PendingInit,
+        };
 
         match side {
             Side::Bid => stoploss.client_paying_account = *ctx.accounts.market.pc_wallet.to_account_info().key,
             Side::Ask => stoploss.client_paying_account = *ctx.accounts.market.coin_wallet.to_account_info().key,
         }
 
-        let update = StoplossOrderUpdate {
-            own_address: *stoploss.to_account_info().key,
-        };
-
-        let encoded = bincode::serialize(&update).unwrap();
-        msg!("STOPLOSS_PARENT_UPDATE: {:?}", base64::encode(&encoded));
+        emit!(StoplossOrderCreated {
+            own_address: stoploss.own_address,
+            market: stoploss.market,
+            client_order_id: stoploss.client_order_id,
+            side: stoploss.side,
+            order_kind: stoploss.order_kind,
+            trigger_type: stoploss.trigger_type,
+            order_mode: stoploss.order_mode,
+            limit_price: stoploss.limit_price,
+            trigger_price: stoploss.effective_trigger_price(),
+            max_coin_qty: stoploss.max_coin_qty,
+            max_pc_qty: stoploss.max_pc_qty,
+            amend_authority: stoploss.amend_authority,
+            signal_provider: stoploss.signal_provider,
+            keeper_allowed: stoploss.keeper_allowed,
+            keeper_bounty: stoploss.keeper_bounty,
+            time_in_force: stoploss.time_in_force,
+            expiry_ts: stoploss.expiry_ts,
+            ord_status: stoploss.ord_status.clone(),
+        });
 
         Ok(())
     }
@@ -169,18 +348,50 @@ pub mod anchor_stoploss {
     /// The order can be for a smaller amount than the parent order as this allows
     /// strategies to split a large order into smaller chunks.
     ///
-    /// Any portion that is filled is settled back to the original client account.
+    /// Any portion that is filled is settled back to the original client account, less the
+    /// protocol fee from the program config. The fee is taken from the proceeds, ie quote on
+    /// sells and base on buys, and paid into the program's fee vault for that mint.
     ///
     /// Any portion that remains unfilled is settled according to reuse_unfilled flag.
     ///
+    /// Orders created with `keeper_allowed` can be executed by anyone, not only the signal
+    /// provider. Keepers only choose when the order executes: their `execute_qty`,
+    /// `execute_limit`, `reuse_unfilled` and `order_type` are ignored and the child is all of
+    /// the leaves as an ImmediateOrCancel at the order's limit, with `reuse_unfilled` set.
+    /// The first keeper whose child fills anything is paid the order's bounty. Until then the
+    /// bounty is held back from every child, the signal provider's included, and the order is
+    /// Filled once only the bounty is left. It is refunded with the vault if no keeper was paid.
+    ///
+    /// For a bracket leg the sibling leg must be passed as the first remaining account. It is
+    /// cancelled as soon as this leg fills anything, or reaches a terminal state, and otherwise
+    /// takes on this leg's leaves and keeper bounty so the shared deposit is never counted twice.
+    ///
+    /// The trigger is checked on chain against the order book: sells only execute once the
+    /// best bid is at or below the trigger price, buys once the best ask is at or above it.
+    /// Orders with an oracle, see `set_oracle`, are checked against the oracle price instead,
+    /// which must be no older than `oracle_max_age` and within `oracle_max_confidence_bps`.
+    /// The trigger is only checked until the first child goes out, after that the order keeps
+    /// working its leaves even if the price moves back through the trigger.
+    ///
+    /// Orders with a strategy, see `set_strategy`, only accept children inside the strategy's
+    /// window, at least `min_child_interval` apart and no bigger than its schedule allows.
     ///
     /// Arguments:
     ///
-    /// * `execute_qty`        - The portion of the parent order to execute.
-    ///     The execute_qty is specified in base (ie the coin token) and the quote
-    ///     (ie the pc token) is automatically calculated using the execute_limit.
-    /// * `execute_limit`      - The limit price to use when executing
-    ///     against the DEX.
+    /// * `execute_qty`        - The portion of the parent order to execute, in native base
+    ///     (ie the coin token) on both sides and rounded down to whole base lots. Must be no
+    ///     more than the coin leaves, which on a buy is the `max_coin_qty` cap.
+    ///     For buys the quote (ie the pc token) is automatically calculated using the
+    ///     execute_limit, capped at the quote leaves.
+    /// * `execute_limit`      - The limit price the child order is placed at on the DEX, in
+    ///     price lots like the parent's `limit_price`, ie quote lots per base lot as set by the
+    ///     market's lot sizes. Must be no worse than the parent's limit, so strategies can work
+    ///     the order anywhere inside the parent's band.
+    ///     For StopMarket orders the child is instead placed at the slippage cap, ie
+    ///     `max_slippage_bps` from the trigger price, or the oracle price for orders with an
+    ///     oracle, or at `execute_limit` if that is tighter.
+    ///     Pass zero to use the cap. The child is rejected if the best price on the book is
+    ///     already past the cap.
     /// * `reuse_unfilled`     - The DEX may not fully fill an order due to limit price
     ///     constraints, or orderbook liquidity availabilty (this is normal). However
     ///     when settling the unfilled qty can be transferred back to the client according to this flag.
@@ -192,11 +403,20 @@ pub mod anchor_stoploss {
     ///     back to the client after one and only one execution attempt.
     ///     Put more simply - if you want an order like a TWAP to run for an hour say
     ///     you cant have the first child order you send to the market cancel the whole thing.
+    ///     `set_strategy` enforces the TWAP's schedule on chain.
+    ///     With reuse_unfilled the unfilled portion stays in the order's vault, the order moves to
+    ///     PartiallyFilled after the first fill and to Filled once the leaves reach zero.
+    /// * `order_type`         - ImmediateOrCancel children trade what they can straight away.
+    ///     Limit and PostOnly children rest on the book with whatever does not trade
+    ///     immediately, and the order always carries on as if `reuse_unfilled` were set. Their
+    ///     fills are booked by `settle_order`, and `cancel_child_order` takes them off the book.
+    ///     Only one child can rest at a time and bracket legs cannot rest children.
     pub fn execute_order<'info>(
         ctx: Context<'_, '_, '_, 'info, ExecuteOrder<'info>>,
         execute_qty: u64,
         execute_limit: u64,
         reuse_unfilled: bool,
+        order_type: OrderType,
     ) -> Result<()> {
         if ctx.accounts.stoploss_state.ord_status == OrdStatus::Cancelled {
             msg!("order already cancelled");
@@ -206,68 +426,220 @@ pub mod anchor_stoploss {
             msg!("order already filled");
             return Err(ErrorCode::AttemptingToExecuteFilledOrder.into());
         }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Expired {
+            msg!("order already expired");
+            return Err(ErrorCode::AttemptingToExecuteExpiredOrder.into());
+        }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::PendingInit {
+            msg!("open orders not initialised yet");
+            return Err(ErrorCode::OpenOrdersNotInitialised.into());
+        }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Suspended {
+            msg!("order suspended by its owner");
+            return Err(ErrorCode::OrderSuspended.into());
+        }
+        if ctx.accounts.stoploss_state.resting_qty > 0 {
+            msg!("child {:?} still resting with {:?}", ctx.accounts.stoploss_state.resting_client_order_id, ctx.accounts.stoploss_state.resting_qty);
+            return Err(ErrorCode::ChildOrderResting.into());
+        }
+        check_account("config", ctx.accounts.config.to_account_info().key, &config_address(ctx.program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        if ctx.accounts.config.halted {
+            msg!("program halted. cannot execute orders");
+            return Err(ErrorCode::ProgramHalted.into());
+        }
+        let receiving_mint = match ctx.accounts.stoploss_state.side {
+            Side::Bid => ctx.accounts.stoploss_state.coin_mint,
+            Side::Ask => ctx.accounts.stoploss_state.pc_mint,
+        };
+        check_account("fee vault", ctx.accounts.fee_vault.key, &fee_vault_address(&receiving_mint, ctx.program_id).0, ErrorCode::IncorrectFeeVault)?;
+        let now = Clock::get()?.unix_timestamp;
+        if ctx.accounts.stoploss_state.is_expired(now) {
+            msg!("order expired at {:?}, now {:?}", ctx.accounts.stoploss_state.expiry_ts, now);
+            return Err(ErrorCode::OrderExpired.into());
+        }
+        ctx.accounts.stoploss_state.check_strategy_timing(now)?;
         if !ctx.accounts.authority.is_signer {
             msg!("The signal provider's signature is required.");
             return Err(ErrorCode::MissingSignalProviderSignature.into());
         }
-        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.signal_provider {
+        // keepers can execute opt-in orders too. The trigger is checked on chain below so the
+        // signal provider is not trusted any more than a keeper.
+        let is_keeper = ctx.accounts.authority.key != &ctx.accounts.stoploss_state.signal_provider;
+        if is_keeper && !ctx.accounts.stoploss_state.keeper_allowed {
             msg!("Incorrect Signal Provider account was provided. Should be algo server sending execute instructions.");
             return Err(ErrorCode::IncorrectSignalProviderAccount.into());
         }
-        if (ctx.accounts.stoploss_state.side == Side::Bid && execute_limit > ctx.accounts.stoploss_state.limit_price)
-            || (ctx.accounts.stoploss_state.side == Side::Ask && execute_limit < ctx.accounts.stoploss_state.limit_price)
+        // the bounty is held back from every child, see reserved_bounty, and only paid once a
+        // keeper's child fills something
+        let reserved_bounty = ctx.accounts.stoploss_state.reserved_bounty();
+        let bounty_due = match is_keeper {
+            true => reserved_bounty,
+            false => 0,
+        };
+        if bounty_due > 0 {
+            let paying_mint = match ctx.accounts.stoploss_state.side {
+                Side::Bid => ctx.accounts.stoploss_state.pc_mint,
+                Side::Ask => ctx.accounts.stoploss_state.coin_mint,
+            };
+            if ctx.accounts.keeper_bounty_account.mint != paying_mint {
+                msg!("keeper bounty account must be for mint {:?}", paying_mint);
+                return Err(ErrorCode::IncorrectKeeperBountyAccount.into());
+            }
+        }
+        // keepers only choose when the order executes. Whatever they pass, their child is all of
+        // the leaves as an ImmediateOrCancel at the order's limit and the order carries on with
+        // anything that does not fill.
+        let (execute_qty, execute_limit, reuse_unfilled, order_type) = match is_keeper {
+            false => (execute_qty, execute_limit, reuse_unfilled, order_type),
+            true => {
+                let stoploss = &ctx.accounts.stoploss_state;
+                let keeper_qty = match stoploss.side {
+                    Side::Bid => stoploss.coin_leaves_qty,
+                    Side::Ask => stoploss.coin_leaves_qty.saturating_sub(reserved_bounty),
+                };
+                let keeper_limit = match stoploss.order_mode {
+                    OrderMode::StopLimit => stoploss.limit_price,
+                    OrderMode::StopMarket => 0,
+                };
+                (keeper_qty, keeper_limit, true, OrderType::ImmediateOrCancel)
+            }
+        };
+        if order_type != OrderType::ImmediateOrCancel && ctx.accounts.stoploss_state.sibling != Pubkey::default() {
+            msg!("bracket legs share a deposit so cannot rest child orders");
+            return Err(ErrorCode::RestingChildNotAllowed.into());
+        }
+        if order_type != OrderType::ImmediateOrCancel && ctx.accounts.stoploss_state.order_mode == OrderMode::StopMarket {
+            msg!("stop-market orders only send ImmediateOrCancel children");
+            return Err(ErrorCode::RestingChildNotAllowed.into());
+        }
+        let derive_limit = execute_limit == 0 && ctx.accounts.stoploss_state.order_mode == OrderMode::StopMarket;
+        if (execute_limit == 0 && !derive_limit)
+            || (ctx.accounts.stoploss_state.side == Side::Bid && execute_limit > ctx.accounts.stoploss_state.limit_price)
+            || (ctx.accounts.stoploss_state.side == Side::Ask && execute_limit < ctx.accounts.stoploss_state.limit_price && !derive_limit)
         {
             msg!("parent limit {:?} execute_limit {:?}", ctx.accounts.stoploss_state.limit_price, execute_limit);
             return Err(ErrorCode::AttemptingToExecuteOutsideParentLimit.into());
         }
-        // TODO: also validate that the vault accounts and and all other market accounts match the onchain accounts
-        // stored in the StoplossState account
+        ctx.accounts.validate_accounts()?;
+        // the trigger is enforced on chain against the opposite side of the book, ie the
+        // side the child order would trade against.
+        let best_order = best_opposite_order(
+            &ctx.accounts.market.market,
+            &ctx.accounts.market.bids,
+            &ctx.accounts.market.asks,
+            ctx.accounts.stoploss_state.side,
+            &ctx.accounts.dex_program.key,
+        )?;
+        let (market_price, best_order_qty) = match best_order {
+            None => {
+                msg!("no resting orders on the opposite side of the book. cannot check trigger");
+                return Err(ErrorCode::MarketPriceUnavailable.into());
+            }
+            Some(order) => order,
+        };
+        let lots = LotSizes::load(&ctx.accounts.market.market, &ctx.accounts.market.coin_mint, &ctx.accounts.market.pc_mint, ctx.accounts.dex_program.key)?;
+        let trigger_price = ctx.accounts.stoploss_state.effective_trigger_price();
+        // orders with an oracle trigger off it rather than the book
+        let oracle_price = match ctx.accounts.stoploss_state.oracle == Pubkey::default() {
+            true => None,
+            false => Some(ctx.accounts.stoploss_state.oracle_price_lots(&ctx.accounts.oracle, &lots, now)?),
+        };
+        let trigger_check_price = oracle_price.unwrap_or(market_price);
+        // once triggered the order keeps working its leaves even if the price comes back
+        if !ctx.accounts.stoploss_state.triggered && !is_triggered(ctx.accounts.stoploss_state.side, ctx.accounts.stoploss_state.order_kind, trigger_check_price, trigger_price) {
+            msg!(
+                "trigger not reached. side {:?} price {:?} trigger {:?}",
+                ctx.accounts.stoploss_state.side,
+                trigger_check_price,
+                trigger_price
+            );
+            return Err(ErrorCode::TriggerPriceNotReached.into());
+        }
+        // stop-market children sweep the book down (sells) or up (buys) to the slippage cap
+        let execute_limit = match ctx.accounts.stoploss_state.order_mode {
+            OrderMode::StopLimit => execute_limit,
+            OrderMode::StopMarket => {
+                let side = ctx.accounts.stoploss_state.side;
+                let reference_price = oracle_price.unwrap_or(trigger_price);
+                let cap = slippage_cap(side, reference_price, ctx.accounts.config.max_slippage_bps);
+                if !within_limit(side, market_price, cap) {
+                    msg!("best price {:?} is past the slippage cap {:?} from {:?}", market_price, cap, reference_price);
+                    return Err(ErrorCode::SlippageCapExceeded.into());
+                }
+                let limit = tighter_limit(side, cap, ctx.accounts.stoploss_state.limit_price);
+                match derive_limit {
+                    true => limit,
+                    false => tighter_limit(side, limit, execute_limit),
+                }
+            }
+        };
 
-        // NOTE - if using the market vaults of the dex it still allows a transaction, but it looks very wrong! (the signs are reversed)
-        // dont think it should be able to trade using the market vault accounts... ATTACK VECTOR ? TODO: confirm
+        // sells cannot dip into the bounty, buys are capped by their quote leaves below
+        let coin_leaves = match ctx.accounts.stoploss_state.side {
+            Side::Bid => ctx.accounts.stoploss_state.coin_leaves_qty,
+            Side::Ask => ctx.accounts.stoploss_state.coin_leaves_qty.saturating_sub(reserved_bounty),
+        };
+        if execute_qty == 0 || execute_qty > coin_leaves {
+            msg!("execute_qty {:?} must be non zero and within coin leaves {:?}", execute_qty, coin_leaves);
+            return Err(ErrorCode::ExecuteQtyExceedsLeaves.into());
+        }
+        let max_coin_qty = lots.coin_lots(execute_qty);
+        if max_coin_qty == 0 {
+            msg!("execute_qty {:?} is less than a base lot of {:?}", execute_qty, lots.coin_lot_size);
+            return Err(ErrorCode::ExecuteQtyBelowLotSize.into());
+        }
 
-        // Token balances before the trade.
-        let mut pos_changes: PositionChanges = Default::default();
-        let sl_coin = token::accessor::amount(&ctx.accounts.stoploss_base_vault).unwrap();
-        let sl_pc = token::accessor::amount(&ctx.accounts.stoploss_quote_vault).unwrap();
-        pos_changes.record_before(
-            ctx.accounts.stoploss_state.side,
-            ctx.accounts.market.coin_wallet.amount,
-            ctx.accounts.market.pc_wallet.amount,
-            sl_coin,
-            sl_pc,
-        );
+        // open orders balances before the trade, the fill is read off the open orders account
+        // rather than the client wallets, see ChildFill.
+        let oo_before = OpenOrdersBalances::load(&ctx.accounts.market.open_orders)?;
 
-        let (s, sl_paying_account, max_coin_qty, max_pc_qty) = match ctx.accounts.stoploss_state.side {
-            Side::Bid => (
-                SerumSide::Bid,
-                &ctx.accounts.stoploss_quote_vault,
-                u64::MAX, // doesnt matter what you set the coin qty to for buys (as long as its large I guess)
-                execute_qty,
-            ),
-            Side::Ask => (
-                SerumSide::Ask,
-                &ctx.accounts.stoploss_base_vault,
-                coin_lots(&ctx.accounts.market.market, execute_qty, &ctx.accounts.dex_program.key),
-                u64::MAX,
-            ),
+        // both sides are sized in base lots. Buys also lock the quote for that size at the
+        // child's limit plus the taker fee, no more than is left on the order.
+        let (s, sl_paying_account, max_pc_qty) = match ctx.accounts.stoploss_state.side {
+            Side::Bid => {
+                let max_pc_qty = lots.pc_qty(execute_qty, execute_limit).map_or(u64::MAX, |pc| pc.saturating_add(taker_fee(pc)));
+                (
+                    SerumSide::Bid,
+                    &ctx.accounts.stoploss_quote_vault,
+                    max_pc_qty.min(ctx.accounts.stoploss_state.pc_leaves_qty.saturating_sub(reserved_bounty)),
+                )
+            }
+            Side::Ask => (SerumSide::Ask, &ctx.accounts.stoploss_base_vault, u64::MAX),
         };
+        // what the child can take out of the deposit, checked against the strategy's schedule
+        let child_paying_qty = match ctx.accounts.stoploss_state.side {
+            Side::Bid => max_pc_qty,
+            Side::Ask => max_coin_qty.saturating_mul(lots.coin_lot_size),
+        };
+        ctx.accounts.stoploss_state.check_strategy_size(now, child_paying_qty, max_coin_qty, best_order_qty)?;
+        let paying_before = token::accessor::amount(sl_paying_account)?;
 
         let parent_child_composite_id = concat(&[ctx.accounts.stoploss_state.client_order_id, ctx.accounts.stoploss_state.child_order_count]);
 
         msg!(
-            "placing {:?} order with execute_coin size {:?}, execute_pc size {:?}",
+            "placing {:?} {:?} order at {:?} with execute_coin size {:?}, execute_pc size {:?}",
+            order_type,
             s,
+            execute_limit,
             max_coin_qty,
             max_pc_qty
         );
 
+        let limit_price = NonZeroU64::new(execute_limit).ok_or_else(|| {
+            msg!("child limit is zero");
+            ErrorCode::AttemptingToExecuteOutsideParentLimit
+        })?;
+        let max_native_pc_qty_including_fees = NonZeroU64::new(max_pc_qty).ok_or_else(|| {
+            msg!("no quote leaves left for the child");
+            ErrorCode::ExecuteQtyExceedsLeaves
+        })?;
         let new_order = NewOrderInstructionV3 {
             side: s,
-            limit_price: NonZeroU64::new(ctx.accounts.stoploss_state.limit_price).unwrap(),
-            max_coin_qty: NonZeroU64::new(max_coin_qty).unwrap(),
-            max_native_pc_qty_including_fees: NonZeroU64::new(max_pc_qty).unwrap(),
-            order_type: SerumOrderType::ImmediateOrCancel,
+            limit_price,
+            // checked against the lot size above
+            max_coin_qty: NonZeroU64::new(max_coin_qty).ok_or(ErrorCode::ExecuteQtyBelowLotSize)?,
+            max_native_pc_qty_including_fees,
+            order_type: order_type.into(),
             client_order_id: parent_child_composite_id,
             self_trade_behavior: serum_dex::instruction::SelfTradeBehavior::DecrementTake,
             limit: 65535,
@@ -313,212 +685,137 @@ pub mod anchor_stoploss {
             &[&[&b"stoploss"[..], &[nonce]]],
         )?;
 
-        // settle
-        let (settle_to_coin_wallet, settle_to_pc_wallet) = match reuse_unfilled {
-            true => (ctx.accounts.stoploss_base_vault.clone(), ctx.accounts.stoploss_quote_vault.clone()),
-            false => (
-                ctx.accounts.market.coin_wallet.to_account_info().clone(),
-                ctx.accounts.market.pc_wallet.to_account_info().clone(),
-            ),
-        };
-
-        let data = MarketInstruction::SettleFunds.pack();
-        let accounts: Vec<AccountMeta> = vec![
-            AccountMeta::new(*ctx.accounts.market.market.key, false),
-            AccountMeta::new(*ctx.accounts.stoploss_open_orders.key, false),
-            AccountMeta::new_readonly(*ctx.accounts.pda.key, true),
-            AccountMeta::new(*ctx.accounts.market.coin_vault.key, false),
-            AccountMeta::new(*ctx.accounts.market.pc_vault.key, false),
-            AccountMeta::new(*settle_to_coin_wallet.key, false),
-            AccountMeta::new(*settle_to_pc_wallet.key, false),
-            AccountMeta::new_readonly(*ctx.accounts.market.vault_signer.key, false),
-            AccountMeta::new_readonly(*ctx.accounts.token_program.key, false),
-        ];
-        let instruction = Instruction {
-            program_id: *ctx.accounts.dex_program.key,
-            data,
-            accounts,
-        };
-
-        program::invoke_signed(
-            &instruction,
-            &[
-                ctx.accounts.market.market.clone(),
-                ctx.accounts.stoploss_open_orders.clone(),
-                ctx.accounts.pda.clone(),
-                ctx.accounts.market.coin_vault.clone(),
-                ctx.accounts.market.pc_vault.clone(),
-                settle_to_coin_wallet.clone(),
-                settle_to_pc_wallet.clone(),
-                ctx.accounts.market.vault_signer.clone(),
-                ctx.accounts.token_program.clone(),
-            ],
-            &[&[&b"stoploss"[..], &[nonce]]],
+        // what the DEX took from the vault for this child, the rest of the fill comes from
+        // the open orders account before settling moves it out again.
+        let deposit = paying_before.saturating_sub(token::accessor::amount(sl_paying_account)?);
+        let oo_after = OpenOrdersBalances::load(&ctx.accounts.market.open_orders)?;
+        let fee = taker_fee_charged(&ctx.accounts.market.market, &ctx.accounts.market.event_queue, &ctx.accounts.market.open_orders, parent_child_composite_id, ctx.accounts.dex_program.key)?;
+        let fill = ChildFill::from_open_orders(ctx.accounts.stoploss_state.side, &oo_before, &oo_after, deposit, fee)?;
+
+        // settle into the order's vaults, the proceeds are passed on once the protocol fee
+        // has been taken and anything unfilled is either reused or refunded below.
+        settle_to_order_vaults(
+            &ctx.accounts.market,
+            &ctx.accounts.pda,
+            &ctx.accounts.stoploss_base_vault,
+            &ctx.accounts.stoploss_quote_vault,
+            &ctx.accounts.token_program,
+            &ctx.accounts.dex_program,
+            &[&b"stoploss"[..], &[nonce]],
         )?;
 
-        let sl_coin = token::accessor::amount(&ctx.accounts.stoploss_base_vault)?;
-        let sl_pc = token::accessor::amount(&ctx.accounts.stoploss_quote_vault)?;
-        pos_changes.record_after(
-            ctx.accounts.market.coin_wallet.reload()?.amount,
-            ctx.accounts.market.pc_wallet.reload()?.amount,
-            sl_coin,
-            sl_pc,
-        );
-
-        ctx.accounts.stoploss_state.child_order_count += 1;
-
-        // for buys the unfilled portion is in client_pc_delta and is given back to the client
-        // for sells the unfilled portion is in client_coin_delta and is given back to the client
-        // account for that here because it is not filled
-        let (mut pc_qty_filled, mut coin_qty_filled) = match s {
-            SerumSide::Bid => (pos_changes.sl_pc_delta() - pos_changes.client_pc_delta(), pos_changes.client_coin_delta()),
-            SerumSide::Ask => (pos_changes.client_pc_delta(), pos_changes.sl_coin_delta() - pos_changes.client_coin_delta()),
+        // a resting child keeps whatever did not trade straight away locked on the book, the
+        // rest of its fills are booked by settle_order
+        let resting_qty = match order_type {
+            OrderType::ImmediateOrCancel => 0,
+            _ => OpenOrdersBalances::load(&ctx.accounts.market.open_orders)?.paying_total(ctx.accounts.stoploss_state.side),
         };
-
-        if !reuse_unfilled {
-            if pc_qty_filled == 0 && coin_qty_filled == 0 {
-                msg!(
-                    "nothing filled apparently - setting to cancelled. cl_coin_del={:?} cl_pc_del={:?}",
-                    coin_qty_filled,
-                    pc_qty_filled
-                );
-                ctx.accounts.stoploss_state.pc_leaves_qty = 0;
-                ctx.accounts.stoploss_state.coin_leaves_qty = 0;
-                ctx.accounts.stoploss_state.pc_cum_qty = 0;
-                ctx.accounts.stoploss_state.coin_cum_qty = 0;
-                ctx.accounts.stoploss_state.ord_status = OrdStatus::Cancelled;
-            } else {
-                ctx.accounts.stoploss_state.pc_leaves_qty = ctx.accounts.stoploss_state.pc_leaves_qty.checked_sub(pc_qty_filled).unwrap();
-                ctx.accounts.stoploss_state.coin_leaves_qty = ctx.accounts.stoploss_state.coin_leaves_qty.checked_sub(coin_qty_filled).unwrap();
-
-                // if it doesnt fully fill then the client is returned some coins, depending on reuse_unfilled
-                ctx.accounts.stoploss_state.pc_cum_qty = ctx.accounts.stoploss_state.pc_cum_qty.checked_add(pc_qty_filled).unwrap();
-                ctx.accounts.stoploss_state.coin_cum_qty = ctx.accounts.stoploss_state.coin_cum_qty.checked_add(coin_qty_filled).unwrap();
-
-                ctx.accounts.stoploss_state.last_price =
-                    calculate_price_lots(pc_qty_filled, coin_qty_filled, &ctx.accounts.market.market, &ctx.accounts.dex_program.key);
-
-                ctx.accounts.stoploss_state.avg_price = calculate_price_lots(
-                    ctx.accounts.stoploss_state.pc_cum_qty,
-                    ctx.accounts.stoploss_state.coin_cum_qty,
-                    &ctx.accounts.market.market,
-                    &ctx.accounts.dex_program.key,
-                );
-
-                msg!(
-                    "coin leaves {:?} pc leaves {:?}",
-                    ctx.accounts.stoploss_state.coin_leaves_qty,
-                    ctx.accounts.stoploss_state.pc_leaves_qty
-                );
-
-                let sl_coin = token::accessor::amount(&ctx.accounts.stoploss_base_vault).unwrap();
-                let sl_pc = token::accessor::amount(&ctx.accounts.stoploss_quote_vault).unwrap();
-                msg!("vault remaining sl_coin {:?} sl_pc {:?}", sl_coin, sl_pc);
-
-                if (ctx.accounts.stoploss_state.side == Side::Bid && ctx.accounts.stoploss_state.pc_leaves_qty == 0)
-                    || (ctx.accounts.stoploss_state.side == Side::Ask && ctx.accounts.stoploss_state.coin_leaves_qty == 0)
-                {
-                    ctx.accounts.stoploss_state.ord_status = OrdStatus::Filled;
-                } else {
-                    ctx.accounts.stoploss_state.ord_status = OrdStatus::Cancelled;
-                }
-            }
+        if resting_qty > 0 {
+            let stoploss = &mut ctx.accounts.stoploss_state;
+            stoploss.resting_qty = resting_qty;
+            stoploss.resting_client_order_id = parent_child_composite_id;
+            msg!("child {:?} resting with {:?}", parent_child_composite_id, resting_qty);
         }
 
-        // TODO - fix this - broken after integration testing found some bugs in the more simple 
-        // path where reuse_unfilled = FALSE
-        // not required for demo purpose anyway
-        if reuse_unfilled {
-            // if no change then do nothing
-            if pc_qty_filled == 0 && coin_qty_filled == 0 {
-                msg!(
-                    "nothing filled, apparently. side {:?} limit {:?}",
-                    ctx.accounts.stoploss_state.side,
-                    execute_limit
-                );
-                return Ok(());
-            };
-
-            // in this path the coins are left in the stoploss vaults - and we want to transfer back to the client immediately
-            coin_qty_filled = coin_qty_filled + pos_changes.sl_coin_delta();
-            pc_qty_filled = pc_qty_filled + pos_changes.sl_coin_delta();
-
-            ctx.accounts.stoploss_state.pc_leaves_qty = ctx.accounts.stoploss_state.pc_leaves_qty.checked_sub(pc_qty_filled).unwrap();
-            ctx.accounts.stoploss_state.coin_leaves_qty = ctx.accounts.stoploss_state.coin_leaves_qty.checked_sub(coin_qty_filled).unwrap();
+        ctx.accounts.stoploss_state.child_order_count += 1;
+        ctx.accounts.stoploss_state.last_child_ts = now;
+        // an IOC child that found nothing to trade leaves the order untriggered, the price
+        // has to be checked again before the next child
+        if fill.pc_qty > 0 || fill.coin_qty > 0 || resting_qty > 0 {
+            ctx.accounts.stoploss_state.triggered = true;
+        }
 
-            // if it doesnt fully fill then the client is returned some coins, depending on reuse_unfilled
-            ctx.accounts.stoploss_state.pc_cum_qty = ctx.accounts.stoploss_state.pc_cum_qty.checked_add(pc_qty_filled).unwrap();
-            ctx.accounts.stoploss_state.coin_cum_qty = ctx.accounts.stoploss_state.coin_cum_qty.checked_add(coin_qty_filled).unwrap();
+        let pc_qty_filled = fill.pc_qty;
+        let coin_qty_filled = fill.coin_qty;
+        ctx.accounts.stoploss_state.apply_child_fill(&fill, reuse_unfilled || resting_qty > 0)?;
 
-            msg!(
-                "pc_cum_qty {:?} coin_cum_qty {:?}",
-                ctx.accounts.stoploss_state.pc_cum_qty,
-                ctx.accounts.stoploss_state.coin_cum_qty
-            );
+        msg!(
+            "filled coin {:?} pc {:?} fee {:?}. coin leaves {:?} pc leaves {:?} status {:?}",
+            coin_qty_filled,
+            pc_qty_filled,
+            fill.fee,
+            ctx.accounts.stoploss_state.coin_leaves_qty,
+            ctx.accounts.stoploss_state.pc_leaves_qty,
+            ctx.accounts.stoploss_state.ord_status
+        );
 
-            ctx.accounts.stoploss_state.last_price =
-                calculate_price_lots(pc_qty_filled, coin_qty_filled, &ctx.accounts.market.market, &ctx.accounts.dex_program.key);
+        // only the proceeds are passed on to the client. The unfilled portion stays in the
+        // order's vault for the next child, or is refunded when the vaults are closed.
+        let (proceeds, receiving_vault, client_wallet) = match ctx.accounts.stoploss_state.side {
+            Side::Bid => (coin_qty_filled, ctx.accounts.stoploss_base_vault.clone(), ctx.accounts.market.coin_wallet.to_account_info()),
+            Side::Ask => (fill.net_pc_qty(), ctx.accounts.stoploss_quote_vault.clone(), ctx.accounts.market.pc_wallet.to_account_info()),
+        };
+        let fee = pay_out_proceeds(
+            &mut ctx.accounts.stoploss_state,
+            proceeds,
+            ctx.accounts.config.protocol_fee_bps,
+            receiving_vault,
+            client_wallet,
+            ctx.accounts.fee_vault.clone(),
+            ctx.accounts.pda.clone(),
+            ctx.accounts.token_program.clone(),
+            &[&b"stoploss"[..], &[nonce]],
+        )?;
 
-            ctx.accounts.stoploss_state.avg_price = calculate_price_lots(
-                ctx.accounts.stoploss_state.pc_cum_qty,
-                ctx.accounts.stoploss_state.coin_cum_qty,
-                &ctx.accounts.market.market,
-                &ctx.accounts.dex_program.key,
-            );
+        let mut bounty_paid = 0;
+        if bounty_due > 0 && (pc_qty_filled > 0 || coin_qty_filled > 0) {
+            let paying_vault = match ctx.accounts.stoploss_state.side {
+                Side::Bid => ctx.accounts.stoploss_quote_vault.clone(),
+                Side::Ask => ctx.accounts.stoploss_base_vault.clone(),
+            };
+            transfer_tokens_signed(
+                paying_vault,
+                ctx.accounts.keeper_bounty_account.to_account_info(),
+                ctx.accounts.pda.clone(),
+                bounty_due,
+                ctx.accounts.token_program.clone(),
+                &[&b"stoploss"[..], &[nonce]],
+            )?;
 
-            match s {
-                SerumSide::Bid => transfer_tokens_signed(
-                    ctx.accounts.stoploss_base_vault.to_account_info(),
-                    ctx.accounts.market.coin_wallet.to_account_info(),
-                    ctx.accounts.pda.clone(),
-                    coin_qty_filled,
-                    ctx.accounts.token_program.clone(),
-                    &[&b"stoploss"[..], &[nonce]],
-                )?,
-                SerumSide::Ask => transfer_tokens_signed(
-                    ctx.accounts.stoploss_quote_vault.to_account_info(),
-                    ctx.accounts.market.pc_wallet.to_account_info(),
-                    ctx.accounts.pda.clone(),
-                    pc_qty_filled,
-                    ctx.accounts.token_program.clone(),
-                    &[&b"stoploss"[..], &[nonce]],
-                )?,
+            let stoploss = &mut ctx.accounts.stoploss_state;
+            match stoploss.side {
+                Side::Bid => stoploss.pc_leaves_qty = stoploss.pc_leaves_qty.checked_sub(bounty_due).ok_or(ErrorCode::KeeperBountyTooLarge)?,
+                Side::Ask => stoploss.coin_leaves_qty = stoploss.coin_leaves_qty.checked_sub(bounty_due).ok_or(ErrorCode::KeeperBountyTooLarge)?,
             }
-
-            if (ctx.accounts.stoploss_state.side == Side::Bid && ctx.accounts.stoploss_state.pc_leaves_qty == 0)
-                || (ctx.accounts.stoploss_state.side == Side::Ask && ctx.accounts.stoploss_state.coin_leaves_qty == 0)
-            {
-                ctx.accounts.stoploss_state.ord_status = OrdStatus::Filled;
-            } else {
-                // seems wrong ! should be in state partially filled here...
-                if ctx.accounts.stoploss_state.coin_cum_qty > 0 || ctx.accounts.stoploss_state.pc_cum_qty > 0 {
-                    ctx.accounts.stoploss_state.ord_status = OrdStatus::Cancelled;
-                }
+            stoploss.keeper_bounty_paid = true;
+            if stoploss.paying_leaves_qty() == 0 {
+                stoploss.ord_status = OrdStatus::Filled;
             }
-
-            ctx.accounts.stoploss_state.ord_status = OrdStatus::PartiallyFilled;
-            // TODO check that transferred matches
+            bounty_paid = bounty_due;
+            msg!("paid keeper {:?} bounty of {:?}", ctx.accounts.authority.key, bounty_paid);
         }
 
-        let update = StoplossOrderUpdate {
-            own_address: *ctx.accounts.stoploss_state.to_account_info().key,
-        };
+        let terminal = ctx.accounts.stoploss_state.ord_status == OrdStatus::Filled || ctx.accounts.stoploss_state.ord_status == OrdStatus::Cancelled;
+        sync_sibling(&ctx.accounts.stoploss_state, ctx.remaining_accounts, ctx.program_id, terminal || pc_qty_filled > 0 || coin_qty_filled > 0)?;
+        if terminal {
+            // consistent with FIX - leaves is zero in terminal state. Anything unfilled is
+            // refunded when the vaults are closed.
+            ctx.accounts.stoploss_state.coin_leaves_qty = 0;
+            ctx.accounts.stoploss_state.pc_leaves_qty = 0;
+            close_order_vaults(
+                &ctx.accounts.stoploss_state,
+                ctx.accounts.stoploss_base_vault.clone(),
+                ctx.accounts.stoploss_quote_vault.clone(),
+                ctx.accounts.market.coin_wallet.to_account_info(),
+                ctx.accounts.market.pc_wallet.to_account_info(),
+                ctx.accounts.amend_authority.clone(),
+                ctx.accounts.pda.clone(),
+                ctx.accounts.token_program.clone(),
+                &[&b"stoploss"[..], &[nonce]],
+            )?;
+        }
 
-        let encoded = bincode::serialize(&update).unwrap();
-        msg!("STOPLOSS_PARENT_UPDATE: {:?}", base64::encode(&encoded));
+        emit!(ctx.accounts.stoploss_state.update_event());
 
         let clock = Clock::get()?;
 
         let mut ord_status = OrdStatus::PartiallyFilled;
         if pc_qty_filled == 0 && coin_qty_filled == 0 {
-            ord_status = OrdStatus::Cancelled;
+            ord_status = if resting_qty > 0 { OrdStatus::New } else { OrdStatus::Cancelled };
+        } else if ctx.accounts.stoploss_state.ord_status == OrdStatus::Filled {
+            ord_status = OrdStatus::Filled;
         }
 
-        let size = match ctx.accounts.stoploss_state.side {
-            Side::Ask => ctx.accounts.stoploss_state.max_coin_qty,
-            Side::Bid => ctx.accounts.stoploss_state.max_pc_qty / ctx.accounts.stoploss_state.limit_price,
-        };
-
         let update = StoplossCreatedChildOrder {
             parent_address: ctx.accounts.stoploss_state.own_address,
             parent_order_id: ctx.accounts.stoploss_state.client_order_id,
@@ -526,23 +823,95 @@ pub mod anchor_stoploss {
             parent_child_composite_id: parent_child_composite_id,
             market: *ctx.accounts.market.market.key,
             side: ctx.accounts.stoploss_state.side,
-            requested_qty: size,
-            limit_price: ctx.accounts.stoploss_state.limit_price,
+            requested_qty: lots.round_coin(execute_qty),
+            limit_price: execute_limit,
             base_filled_qty: coin_qty_filled,
             quote_filled_qty: pc_qty_filled,
-            price: (coin_qty_filled as f64 / pc_qty_filled as f64).to_string(),
+            price: Price::from_fill(pc_qty_filled, coin_qty_filled),
+            fee_paid: fill.fee,
+            protocol_fee_paid: fee,
             filled_time: clock.unix_timestamp,
             ord_status: ord_status,
-            order_type: OrderType::ImmediateOrCancel,
+            order_type,
+            executed_by: *ctx.accounts.authority.key,
+            keeper_bounty_paid: bounty_paid,
         };
 
-        let encoded = bincode::serialize(&update).unwrap();
-        msg!("STOPLOSS_CHILD_UPDATE: {:?}", base64::encode(&encoded));
+        emit!(update);
 
         Ok(())
     }
 
+    /// Books the fills of a resting child order and sweeps them off the DEX. The proceeds go to
+    /// the client less the protocol fee, as for `execute_order`, and anything unlocked by a
+    /// cancel goes back into the order's vault for the next child.
+    ///
+    /// Fills only reach the open orders account once the DEX event queue has been cranked.
+    /// Permissionless, as funds only ever move to the accounts recorded on the order.
+    pub fn settle_order<'info>(ctx: Context<'_, '_, '_, 'info, SettleOrder<'info>>) -> Result<()> {
+        if ctx.accounts.stoploss_state.resting_qty == 0 {
+            msg!("order has no resting child order");
+            return Err(ErrorCode::NoRestingChildOrder.into());
+        }
+        ctx.accounts.validate_accounts(ctx.program_id)?;
+        ctx.accounts.settle_resting_child(ctx.remaining_accounts, ctx.program_id)
+    }
+
+    /// Takes a resting child order off the book and settles it, see `settle_order`. Anything
+    /// it had not filled goes back into the order's vault.
+    ///
+    /// The owner must call this before amending, cancelling or suspending an order with a
+    /// resting child.
+    /// Sending it in the same transaction as `amend_order` amends atomically. Any fills still
+    /// waiting in the DEX event queue are booked by a later `settle_order`.
+    pub fn cancel_child_order<'info>(ctx: Context<'_, '_, '_, 'info, SettleOrder<'info>>) -> Result<()> {
+        let stoploss = &ctx.accounts.stoploss_state;
+        if ctx.accounts.authority.key != &stoploss.signal_provider && ctx.accounts.authority.key != &stoploss.amend_authority {
+            msg!("child orders can only be cancelled by the owner or the signal provider, not {:?}", ctx.accounts.authority.key);
+            return Err(ErrorCode::IncorrectChildOrderAuthority.into());
+        }
+        if stoploss.resting_qty == 0 {
+            msg!("order has no resting child order");
+            return Err(ErrorCode::NoRestingChildOrder.into());
+        }
+        ctx.accounts.validate_accounts(ctx.program_id)?;
+
+        let (_pda, nonce) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        let instruction = Instruction {
+            program_id: *ctx.accounts.dex_program.key,
+            data: MarketInstruction::CancelOrderByClientIdV2(stoploss.resting_client_order_id).pack(),
+            accounts: vec![
+                AccountMeta::new(*ctx.accounts.market.market.key, false),
+                AccountMeta::new(*ctx.accounts.market.bids.key, false),
+                AccountMeta::new(*ctx.accounts.market.asks.key, false),
+                AccountMeta::new(*ctx.accounts.market.open_orders.key, false),
+                AccountMeta::new_readonly(*ctx.accounts.pda.key, true),
+                AccountMeta::new(*ctx.accounts.market.event_queue.key, false),
+            ],
+        };
+        msg!("cancelling child {:?}", stoploss.resting_client_order_id);
+        program::invoke_signed(
+            &instruction,
+            &[
+                ctx.accounts.market.market.clone(),
+                ctx.accounts.market.bids.clone(),
+                ctx.accounts.market.asks.clone(),
+                ctx.accounts.market.open_orders.clone(),
+                ctx.accounts.pda.clone(),
+                ctx.accounts.market.event_queue.clone(),
+                ctx.accounts.dex_program.clone(),
+            ],
+            &[&[&b"stoploss"[..], &[nonce]]],
+        )?;
+
+        ctx.accounts.settle_resting_child(ctx.remaining_accounts, ctx.program_id)
+    }
+
     /// Cancels the parent order and transfers any unfilled portion back to the client.
+    /// Both of the order's vaults are closed and their rent returned to the owner.
+    ///
+    /// For a bracket leg the sibling leg must be passed as the first remaining account
+    /// and is cancelled too, as the shared deposit has been refunded.
     ///
     ///
     /// Arguments:
@@ -564,24 +933,55 @@ pub mod anchor_stoploss {
             msg!("Order already cancelled. Cannot cancel.");
             return Err(ErrorCode::OrderAlreadyCancelled.into());
         }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Expired {
+            msg!("Order already expired. Cannot cancel.");
+            return Err(ErrorCode::OrderAlreadyExpired.into());
+        }
+        if ctx.accounts.stoploss_state.resting_qty > 0 {
+            msg!("child order still resting. cancel_child_order first");
+            return Err(ErrorCode::ChildOrderResting.into());
+        }
+        validate_refund_accounts(
+            &ctx.accounts.stoploss_state,
+            ctx.accounts.coin_wallet.to_account_info().key,
+            ctx.accounts.pc_wallet.to_account_info().key,
+            ctx.accounts.stoploss_paying_vault.to_account_info().key,
+            ctx.accounts.vault_owner.key,
+            ctx.accounts.token_program.key,
+        )?;
+        let receiving_vault = match ctx.accounts.stoploss_state.side {
+            Side::Bid => &ctx.accounts.stoploss_state.stoploss_base_vault,
+            Side::Ask => &ctx.accounts.stoploss_state.stoploss_quote_vault,
+        };
+        check_account(
+            "stoploss receiving vault",
+            ctx.accounts.stoploss_receiving_vault.key,
+            receiving_vault,
+            ErrorCode::IncorrectStoplossReceivingVault,
+        )?;
 
         let (_pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
         let seeds = &[&b"stoploss"[..], &[bump_seed]];
 
-        let (amount, receiving_wallet) = match ctx.accounts.stoploss_state.side {
-            Side::Ask => (ctx.accounts.stoploss_state.coin_leaves_qty, &ctx.accounts.coin_wallet),
-            Side::Bid => (ctx.accounts.stoploss_state.pc_leaves_qty, &ctx.accounts.pc_wallet),
+        // the vaults only ever hold this order's funds so everything left in them is refunded
+        let (base_vault, quote_vault) = match ctx.accounts.stoploss_state.side {
+            Side::Ask => (ctx.accounts.stoploss_paying_vault.to_account_info(), ctx.accounts.stoploss_receiving_vault.clone()),
+            Side::Bid => (ctx.accounts.stoploss_receiving_vault.clone(), ctx.accounts.stoploss_paying_vault.to_account_info()),
         };
-
-        transfer_tokens_signed(
-            ctx.accounts.stoploss_paying_vault.to_account_info(),
-            receiving_wallet.to_account_info(),
+        let (refunded_coin_qty, refunded_pc_qty) = close_order_vaults(
+            &ctx.accounts.stoploss_state,
+            base_vault,
+            quote_vault,
+            ctx.accounts.coin_wallet.to_account_info(),
+            ctx.accounts.pc_wallet.to_account_info(),
+            ctx.accounts.authority.clone(),
             ctx.accounts.vault_owner.clone(),
-            amount,
             ctx.accounts.token_program.clone(),
             seeds,
         )?;
 
+        cancel_sibling(&ctx.accounts.stoploss_state, ctx.remaining_accounts, ctx.program_id)?;
+
         let stoploss = &mut ctx.accounts.stoploss_state;
 
         // consistent with FIX - leaves is zero in terminal state
@@ -589,6 +989,150 @@ pub mod anchor_stoploss {
         stoploss.pc_leaves_qty = 0;
 
         stoploss.ord_status = OrdStatus::Cancelled;
+
+        emit!(StoplossOrderCancelled {
+            own_address: stoploss.own_address,
+            client_order_id: stoploss.client_order_id,
+            refunded_coin_qty,
+            refunded_pc_qty,
+            coin_cum_qty: stoploss.coin_cum_qty,
+            pc_cum_qty: stoploss.pc_cum_qty,
+            ord_status: stoploss.ord_status.clone(),
+        });
+        Ok(())
+    }
+
+    /// Expires an order whose time in force has run out, refunding whatever is left in its
+    /// vaults to the client. Both vaults are closed and their rent returned to the owner.
+    ///
+    /// Permissionless so stale orders can be cleaned up by anyone, eg the signal provider.
+    /// For a bracket leg the sibling leg must be passed as the first remaining account and is
+    /// cancelled too.
+    ///
+    pub fn expire_order(ctx: Context<ExpireOrder>) -> ProgramResult {
+        let stoploss = &ctx.accounts.stoploss_state;
+        if stoploss.ord_status == OrdStatus::Filled {
+            msg!("Order already filled. Cannot expire.");
+            return Err(ErrorCode::OrderAlreadyFilled.into());
+        }
+        if stoploss.ord_status == OrdStatus::Cancelled {
+            msg!("Order already cancelled. Cannot expire.");
+            return Err(ErrorCode::OrderAlreadyCancelled.into());
+        }
+        if stoploss.ord_status == OrdStatus::Expired {
+            msg!("Order already expired.");
+            return Err(ErrorCode::OrderAlreadyExpired.into());
+        }
+        let now = ctx.accounts.clock.unix_timestamp;
+        if !stoploss.is_expired(now) {
+            msg!("order {:?} expiry {:?} now {:?}", stoploss.time_in_force, stoploss.expiry_ts, now);
+            return Err(ErrorCode::OrderNotExpired.into());
+        }
+        if stoploss.resting_qty > 0 {
+            msg!("child order still resting. cancel_child_order first");
+            return Err(ErrorCode::ChildOrderResting.into());
+        }
+        check_account("client coin wallet", ctx.accounts.coin_wallet.to_account_info().key, &stoploss.client_coin_wallet, ErrorCode::IncorrectClientCoinWallet)?;
+        check_account("client pc wallet", ctx.accounts.pc_wallet.to_account_info().key, &stoploss.client_pc_wallet, ErrorCode::IncorrectClientPcWallet)?;
+        check_account("stoploss base vault", ctx.accounts.stoploss_base_vault.key, &stoploss.stoploss_base_vault, ErrorCode::IncorrectStoplossBaseVault)?;
+        check_account("stoploss quote vault", ctx.accounts.stoploss_quote_vault.key, &stoploss.stoploss_quote_vault, ErrorCode::IncorrectStoplossQuoteVault)?;
+        check_account("vault owner", ctx.accounts.vault_owner.key, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", ctx.accounts.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+
+        let (_pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        let seeds = &[&b"stoploss"[..], &[bump_seed]];
+
+        let (refunded_coin_qty, refunded_pc_qty) = close_order_vaults(
+            &ctx.accounts.stoploss_state,
+            ctx.accounts.stoploss_base_vault.clone(),
+            ctx.accounts.stoploss_quote_vault.clone(),
+            ctx.accounts.coin_wallet.to_account_info(),
+            ctx.accounts.pc_wallet.to_account_info(),
+            ctx.accounts.amend_authority.clone(),
+            ctx.accounts.vault_owner.clone(),
+            ctx.accounts.token_program.clone(),
+            seeds,
+        )?;
+
+        cancel_sibling(&ctx.accounts.stoploss_state, ctx.remaining_accounts, ctx.program_id)?;
+
+        let stoploss = &mut ctx.accounts.stoploss_state;
+
+        // consistent with FIX - leaves is zero in terminal state
+        stoploss.coin_leaves_qty = 0;
+        stoploss.pc_leaves_qty = 0;
+
+        stoploss.ord_status = OrdStatus::Expired;
+
+        emit!(StoplossOrderExpired {
+            own_address: stoploss.own_address,
+            client_order_id: stoploss.client_order_id,
+            expiry_ts: stoploss.expiry_ts,
+            refunded_coin_qty,
+            refunded_pc_qty,
+            coin_cum_qty: stoploss.coin_cum_qty,
+            pc_cum_qty: stoploss.pc_cum_qty,
+            ord_status: stoploss.ord_status.clone(),
+        });
+        Ok(())
+    }
+
+    /// Pauses an order so it cannot execute until resumed, eg around an exchange incident.
+    /// Funds stay in the order's vaults, and the order can still be amended, cancelled or expired.
+    /// A resting child must be cancelled with `cancel_child_order` first, and settles that
+    /// follow leave the order suspended.
+    ///
+    /// Bracket legs are suspended independently.
+    ///
+    pub fn suspend_order(ctx: Context<SuspendOrder>) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Suspend message not sent by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let stoploss = &mut ctx.accounts.stoploss_state;
+        if stoploss.ord_status == OrdStatus::Suspended {
+            msg!("Order already suspended.");
+            return Err(ErrorCode::OrderAlreadySuspended.into());
+        }
+        if stoploss.ord_status != OrdStatus::New && stoploss.ord_status != OrdStatus::PartiallyFilled {
+            msg!("only working orders can be suspended. status {:?}", stoploss.ord_status);
+            return Err(ErrorCode::OrderNotActive.into());
+        }
+        if stoploss.resting_qty > 0 {
+            msg!("child order still resting. cancel_child_order first");
+            return Err(ErrorCode::ChildOrderResting.into());
+        }
+
+        stoploss.ord_status = OrdStatus::Suspended;
+
+        emit!(stoploss.update_event());
+        Ok(())
+    }
+
+    /// Resumes a suspended order, returning it to New, or PartiallyFilled if it has filled before.
+    ///
+    pub fn resume_order(ctx: Context<ResumeOrder>) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Resume message not sent by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let stoploss = &mut ctx.accounts.stoploss_state;
+        if stoploss.ord_status != OrdStatus::Suspended {
+            msg!("order is not suspended. status {:?}", stoploss.ord_status);
+            return Err(ErrorCode::OrderNotSuspended.into());
+        }
+
+        stoploss.ord_status = stoploss.resumed_status();
+
+        emit!(stoploss.update_event());
         Ok(())
     }
 
@@ -604,8 +1148,10 @@ pub mod anchor_stoploss {
     ///     against the DEX.
     /// * `new_quantity`       - The new quantity to use on the order. Cannot amend to
     ///     a quantity that is less than already filled. Will reject the amend in this
-    ///     case.
-    /// * `trigger_price`      - The new trigger price to use.
+    ///     case. Bracket legs and orders with a strategy must pass their current quantity.
+    /// * `trigger_price`      - The new trigger price to use. Trailing orders trigger off
+    ///     their mark, and orders whose first child has filled or rested are no longer
+    ///     checked against it, so both must pass their current `trigger_price` unchanged.
     pub fn amend_order(ctx: Context<AmendOrder>, limit_price: u64, _client_order_id: u64, new_quantity: u64, trigger_price: u64) -> ProgramResult {
         if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
             msg!(
@@ -623,6 +1169,44 @@ pub mod anchor_stoploss {
             msg!("Order already cancelled. Cannot amend.");
             return Err(ErrorCode::OrderAlreadyCancelled.into());
         }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Expired {
+            msg!("Order already expired. Cannot amend.");
+            return Err(ErrorCode::OrderAlreadyExpired.into());
+        }
+        if ctx.accounts.stoploss_state.resting_qty > 0 {
+            msg!("child order still resting. cancel_child_order first");
+            return Err(ErrorCode::ChildOrderResting.into());
+        }
+        validate_refund_accounts(
+            &ctx.accounts.stoploss_state,
+            ctx.accounts.coin_wallet.to_account_info().key,
+            ctx.accounts.pc_wallet.to_account_info().key,
+            ctx.accounts.stoploss_paying_vault.to_account_info().key,
+            ctx.accounts.vault_owner.key,
+            ctx.accounts.token_program.key,
+        )?;
+
+        let current_quantity = match ctx.accounts.stoploss_state.side {
+            Side::Bid => ctx.accounts.stoploss_state.max_pc_qty,
+            Side::Ask => ctx.accounts.stoploss_state.max_coin_qty,
+        };
+        if ctx.accounts.stoploss_state.sibling != Pubkey::default() && new_quantity != current_quantity {
+            msg!("bracket legs share one deposit. cannot amend qty from {:?} to {:?}", current_quantity, new_quantity);
+            return Err(ErrorCode::CannotAmendBracketQuantity.into());
+        }
+        // the strategy's schedule and slices are measured against the deposit
+        if ctx.accounts.stoploss_state.strategy != Strategy::None && new_quantity != current_quantity {
+            msg!("{:?} strategy orders cannot amend qty from {:?} to {:?}", ctx.accounts.stoploss_state.strategy, current_quantity, new_quantity);
+            return Err(ErrorCode::CannotAmendStrategyQuantity.into());
+        }
+        if ctx.accounts.stoploss_state.trigger_type != TriggerType::Fixed && trigger_price != ctx.accounts.stoploss_state.trigger_price {
+            msg!("trailing orders trigger off their mark. cannot amend trigger to {:?}", trigger_price);
+            return Err(ErrorCode::CannotAmendTrailingTrigger.into());
+        }
+        if ctx.accounts.stoploss_state.triggered && trigger_price != ctx.accounts.stoploss_state.trigger_price {
+            msg!("order has triggered. cannot amend trigger to {:?}", trigger_price);
+            return Err(ErrorCode::CannotAmendTriggeredTrigger.into());
+        }
 
         let (_pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
         let seeds = &[&b"stoploss"[..], &[bump_seed]];
@@ -642,9 +1226,8 @@ pub mod anchor_stoploss {
                     return Err(ErrorCode::AlreadyFilledMoreThanRequestedAmendSize.into());
                 } else if stoploss.max_pc_qty > new_quantity {
                     // reducing size, transfer back to client
-                    // why cant I use max_pc_qty.checked_sub with on chain variables?
                     let delta = stoploss.max_pc_qty - new_quantity;
-                    stoploss.pc_leaves_qty -= delta;
+                    stoploss.pc_leaves_qty = stoploss.pc_leaves_qty.checked_sub(delta).ok_or(ErrorCode::AlreadyFilledMoreThanRequestedAmendSize)?;
                     stoploss.max_pc_qty = new_quantity;
                     transfer_tokens_signed(
                         ctx.accounts.stoploss_paying_vault.to_account_info(),
@@ -656,7 +1239,7 @@ pub mod anchor_stoploss {
                     )?;
                 } else if stoploss.max_pc_qty < new_quantity {
                     let delta = new_quantity - stoploss.max_pc_qty;
-                    stoploss.pc_leaves_qty += delta;
+                    stoploss.pc_leaves_qty = stoploss.pc_leaves_qty.checked_add(delta).ok_or(ErrorCode::NumericalOverflow)?;
                     stoploss.max_pc_qty = new_quantity;
                     transfer_tokens_signed(
                         ctx.accounts.pc_wallet.to_account_info(),
@@ -679,7 +1262,7 @@ pub mod anchor_stoploss {
                 } else if stoploss.max_coin_qty > new_quantity {
                     // reducing size, transfer back to client
                     let delta = stoploss.max_coin_qty - new_quantity;
-                    stoploss.coin_leaves_qty -= delta;
+                    stoploss.coin_leaves_qty = stoploss.coin_leaves_qty.checked_sub(delta).ok_or(ErrorCode::AlreadyFilledMoreThanRequestedAmendSize)?;
                     stoploss.max_coin_qty = new_quantity;
                     transfer_tokens_signed(
                         ctx.accounts.stoploss_paying_vault.to_account_info(),
@@ -691,7 +1274,7 @@ pub mod anchor_stoploss {
                     )?;
                 } else if stoploss.max_coin_qty < new_quantity {
                     let delta = new_quantity - stoploss.max_coin_qty;
-                    stoploss.coin_leaves_qty += delta;
+                    stoploss.coin_leaves_qty = stoploss.coin_leaves_qty.checked_add(delta).ok_or(ErrorCode::NumericalOverflow)?;
                     stoploss.max_coin_qty = new_quantity;
                     transfer_tokens_signed(
                         ctx.accounts.coin_wallet.to_account_info(),
@@ -705,15 +1288,580 @@ pub mod anchor_stoploss {
             }
         };
 
+        // the bounty must still be payable out of whatever is left to work
+        let reserved_bounty = stoploss.reserved_bounty();
+        if stoploss.paying_leaves_qty() > 0 && stoploss.paying_leaves_qty() <= reserved_bounty {
+            msg!("keeper bounty {:?} must be less than the leaves {:?}", reserved_bounty, stoploss.paying_leaves_qty());
+            return Err(ErrorCode::KeeperBountyTooLarge.into());
+        }
+
         stoploss.limit_price = limit_price;
         stoploss.trigger_price = trigger_price;
 
-        // TODO, think there is an edge case here where order wont have leaves==0
-        // but there is nothing more it can get due to some rounding wierdnesses
-        if stoploss.coin_leaves_qty == 0 && stoploss.pc_leaves_qty == 0 {
+        // amended down to what has already been spent. a buy's coin leaves are only a cap so
+        // clear those too, consistent with the other terminal states
+        if stoploss.paying_leaves_qty() == 0 {
             stoploss.ord_status = OrdStatus::Filled;
+            stoploss.coin_leaves_qty = 0;
+            stoploss.pc_leaves_qty = 0;
         }
 
+        emit!(StoplossOrderAmended {
+            own_address: stoploss.own_address,
+            client_order_id: stoploss.client_order_id,
+            limit_price: stoploss.limit_price,
+            trigger_price: stoploss.effective_trigger_price(),
+            max_coin_qty: stoploss.max_coin_qty,
+            max_pc_qty: stoploss.max_pc_qty,
+            coin_leaves_qty: stoploss.coin_leaves_qty,
+            pc_leaves_qty: stoploss.pc_leaves_qty,
+            ord_status: stoploss.ord_status.clone(),
+        });
+
+        Ok(())
+    }
+
+    /// Closes an order that is done with, returning its rent to `recipient`.
+    ///
+    /// The order must be Filled, Cancelled or Expired with nothing left to work and nothing left in
+    /// its vaults. Any vault that is still open (but empty) is closed too. The rent of its
+    /// open orders account goes back to `open_orders_payer`, the owner or the signal provider
+    /// depending on who created it.
+    ///
+    /// Bracket legs pass the other leg as the first remaining account. The vaults and open
+    /// orders they share are only closed once the other leg is no longer working.
+    ///
+    pub fn close_order(ctx: Context<CloseOrder>) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Close message not sent by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let stoploss = &ctx.accounts.stoploss_state;
+        if stoploss.ord_status != OrdStatus::Filled && stoploss.ord_status != OrdStatus::Cancelled && stoploss.ord_status != OrdStatus::Expired {
+            msg!("order is still active. status {:?}", stoploss.ord_status);
+            return Err(ErrorCode::OrderNotTerminal.into());
+        }
+        if stoploss.coin_leaves_qty != 0 || stoploss.pc_leaves_qty != 0 {
+            msg!("order still has leaves. coin {:?} pc {:?}", stoploss.coin_leaves_qty, stoploss.pc_leaves_qty);
+            return Err(ErrorCode::OrderNotTerminal.into());
+        }
+        check_account("stoploss base vault", ctx.accounts.stoploss_base_vault.key, &stoploss.stoploss_base_vault, ErrorCode::IncorrectStoplossBaseVault)?;
+        check_account("stoploss quote vault", ctx.accounts.stoploss_quote_vault.key, &stoploss.stoploss_quote_vault, ErrorCode::IncorrectStoplossQuoteVault)?;
+        check_account("vault owner", ctx.accounts.vault_owner.key, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", ctx.accounts.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+        check_account("stoploss open orders", ctx.accounts.stoploss_open_orders.key, &stoploss.stoploss_open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        check_account("market", ctx.accounts.market.key, &stoploss.market, ErrorCode::IncorrectMarketAccount)?;
+        check_account("dex program", ctx.accounts.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+
+        let (_pda, bump_seed) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        let seeds = &[&b"stoploss"[..], &[bump_seed]];
+
+        // bracket legs share their vaults and open orders, leave them to the other leg's
+        // close_order while it is still working
+        if sibling_is_live(stoploss, ctx.remaining_accounts)? {
+            msg!("bracket sibling {:?} is still working. leaving the shared accounts open", stoploss.sibling);
+            emit!(ctx.accounts.stoploss_state.update_event());
+            return Ok(());
+        }
+
+        for vault in [&ctx.accounts.stoploss_base_vault, &ctx.accounts.stoploss_quote_vault].iter() {
+            // already closed when the order reached its terminal state
+            if vault.lamports() == 0 {
+                continue;
+            }
+            let remaining = token::accessor::amount(vault)?;
+            if remaining != 0 {
+                msg!("vault {:?} still holds {:?}", vault.key, remaining);
+                return Err(ErrorCode::OrderVaultNotEmpty.into());
+            }
+            let close_instruction =
+                spl_token::instruction::close_account(&spl_token::ID, vault.key, ctx.accounts.recipient.key, ctx.accounts.vault_owner.key, &[])?;
+            program::invoke_signed(
+                &close_instruction,
+                &[(*vault).clone(), ctx.accounts.recipient.clone(), ctx.accounts.vault_owner.clone(), ctx.accounts.token_program.clone()],
+                &[&seeds[..]],
+            )?;
+        }
+
+        // never created for an order still PendingInit, and the other leg of a bracket may have
+        // closed it already
+        if ctx.accounts.stoploss_open_orders.lamports() != 0 {
+            check_account("open orders payer", ctx.accounts.open_orders_payer.key, &stoploss.open_orders_payer, ErrorCode::IncorrectOpenOrdersPayer)?;
+            close_open_orders(
+                ctx.accounts.stoploss_open_orders.clone(),
+                ctx.accounts.vault_owner.clone(),
+                ctx.accounts.open_orders_payer.clone(),
+                ctx.accounts.market.clone(),
+                ctx.accounts.dex_program.clone(),
+                seeds,
+            )?;
+        }
+
+        emit!(ctx.accounts.stoploss_state.update_event());
+
+        Ok(())
+    }
+
+    /// Adds the other leg to an order, turning the pair into a one-cancels-other bracket.
+    /// Adding a take-profit to a stoploss (or a stoploss to a take-profit) does not lock
+    /// any more funds - both legs work the deposit already held in the existing order's vaults.
+    /// Whichever leg fills first cancels the other.
+    ///
+    /// Only possible before the existing order has executed any child orders.
+    ///
+    /// Arguments:
+    ///
+    /// * `limit_price`     - The limit price of the new leg.
+    /// * `client_order_id` - Unique identifier for the new leg.
+    /// * `trigger_price`   - The trigger price of the new leg. A take-profit sell triggers
+    /// when the market rises to the trigger, a take-profit buy when it falls to it.
+    pub fn new_bracket_leg(ctx: Context<NewBracketLeg>, limit_price: u64, client_order_id: u64, trigger_price: u64) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Bracket leg not sent by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let existing = &ctx.accounts.stoploss_state;
+        if existing.sibling != Pubkey::default() {
+            msg!("order already has a bracket leg {:?}", existing.sibling);
+            return Err(ErrorCode::AlreadyBracketed.into());
+        }
+        if (existing.ord_status != OrdStatus::New && existing.ord_status != OrdStatus::PendingInit) || existing.child_order_count > 0 {
+            msg!("order has already executed. status {:?} child orders {:?}", existing.ord_status, existing.child_order_count);
+            return Err(ErrorCode::AlreadyBracketed.into());
+        }
+
+        let mut leg = (**existing).clone();
+        leg.own_address = *ctx.accounts.bracket_leg.to_account_info().key;
+        leg.order_kind = match existing.order_kind {
+            OrderKind::StopLoss => OrderKind::TakeProfit,
+            OrderKind::TakeProfit => OrderKind::StopLoss,
+        };
+        leg.limit_price = limit_price;
+        leg.client_order_id = client_order_id;
+        leg.trigger_price = trigger_price;
+        leg.trigger_type = TriggerType::Fixed;
+        leg.trailing_offset = 0;
+        leg.trailing_mark = 0;
+        leg.sibling = existing.own_address;
+        msg!("bracket leg {:?} {:?} for {:?}", leg.order_kind, leg.own_address, leg.sibling);
+
+        ctx.accounts.stoploss_state.sibling = leg.own_address;
+        *ctx.accounts.bracket_leg = leg;
+
+        for stoploss in [&ctx.accounts.stoploss_state, &ctx.accounts.bracket_leg].iter() {
+            emit!(stoploss.update_event());
+        }
+
+        Ok(())
+    }
+
+    /// Triggers the order off a Pyth price account rather than the order book, which is much
+    /// harder to push around on a thin market. Only the order's owner can set it, and only
+    /// before the first child order, eg in the same transaction as `new_order`. The oracle must
+    /// be owned by the config's `oracle_program`.
+    ///
+    /// Trailing orders also trail the oracle rather than the book, so their mark is restarted
+    /// from the current oracle price, which must pass `max_age` and `max_confidence_bps`.
+    ///
+    /// Arguments:
+    ///
+    /// * `max_age`            - Oldest oracle price, in seconds, `execute_order` accepts.
+    /// Must be non zero.
+    /// * `max_confidence_bps` - Widest oracle confidence interval, in basis points of the
+    /// price, `execute_order` accepts. Zero for no limit.
+    pub fn set_oracle(ctx: Context<SetOracle>, max_age: u64, max_confidence_bps: u16) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Oracle not set by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let stoploss = &ctx.accounts.stoploss_state;
+        if (stoploss.ord_status != OrdStatus::New && stoploss.ord_status != OrdStatus::PendingInit) || stoploss.child_order_count > 0 {
+            msg!("order has already executed. status {:?} child orders {:?}", stoploss.ord_status, stoploss.child_order_count);
+            return Err(ErrorCode::OracleLockedAfterExecution.into());
+        }
+        check_bps(max_confidence_bps)?;
+        if max_age == 0 {
+            msg!("oracle max age must be non zero");
+            return Err(ErrorCode::InvalidOracleMaxAge.into());
+        }
+        check_account("config", ctx.accounts.config.to_account_info().key, &config_address(ctx.program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        let oracle_program = ctx.accounts.config.oracle_program;
+        if oracle_program == Pubkey::default() || ctx.accounts.oracle.owner != &oracle_program {
+            msg!("oracle {:?} is owned by {:?}, not the oracle program {:?}", ctx.accounts.oracle.key, ctx.accounts.oracle.owner, oracle_program);
+            return Err(ErrorCode::IncorrectOracleProgram.into());
+        }
+        check_account("market", ctx.accounts.market.key, &stoploss.market, ErrorCode::IncorrectMarketAccount)?;
+        check_account("coin mint", ctx.accounts.coin_mint.key, &stoploss.coin_mint, ErrorCode::IncorrectCoinMint)?;
+        check_account("pc mint", ctx.accounts.pc_mint.key, &stoploss.pc_mint, ErrorCode::IncorrectPcMint)?;
+        check_account("dex program", ctx.accounts.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+        // only checks the layout, the price itself is checked when the order executes, or below
+        // for trailing orders
+        let price = OraclePrice::load(&ctx.accounts.oracle)?;
+        msg!("oracle {:?} price {:?} expo {:?}", ctx.accounts.oracle.key, price.price, price.expo);
+        let trailing_mark = match stoploss.trigger_type {
+            TriggerType::Fixed => None,
+            _ => {
+                let lots = LotSizes::load(&ctx.accounts.market, &ctx.accounts.coin_mint, &ctx.accounts.pc_mint, ctx.accounts.dex_program.key)?;
+                Some(price.checked_price_lots(&lots, Clock::get()?.unix_timestamp, max_age, max_confidence_bps)?)
+            }
+        };
+
+        let stoploss = &mut ctx.accounts.stoploss_state;
+        stoploss.oracle = *ctx.accounts.oracle.key;
+        stoploss.oracle_max_age = max_age;
+        stoploss.oracle_max_confidence_bps = max_confidence_bps;
+        if let Some(px) = trailing_mark {
+            msg!("trailing mark moved from {:?} to {:?}", stoploss.trailing_mark, px);
+            stoploss.trailing_mark = px;
+        }
+
+        emit!(stoploss.update_event());
+
+        Ok(())
+    }
+
+    /// Constrains how the signal provider can slice the order, so a compromised signal provider
+    /// cannot dump the whole order in one child. Only the order's owner can set it, and only
+    /// before the first child order, eg in the same transaction as `new_order`.
+    ///
+    /// Children are only accepted from `start_ts`, at least `min_child_interval` seconds apart.
+    /// Twap spreads the deposit evenly over `slice_count` slices between `start_ts` and
+    /// `end_ts`, so by the end of each slice the order can have used no more than its share.
+    /// Once `end_ts` has passed whatever is left can go. Vwap instead caps each child at
+    /// `max_participation_bps` of the size of the best order on the opposite side of the
+    /// book, ie of the top of book rather than of traded volume, and accepts no children once
+    /// `end_ts` has passed.
+    ///
+    /// Orders that allow keepers cannot have a strategy, as a keeper child takes all the leaves.
+    ///
+    /// Arguments:
+    ///
+    /// * `strategy`              - None, Twap or Vwap.
+    /// * `start_ts`              - Unix timestamp the first child can be sent at.
+    /// * `end_ts`                - Unix timestamp the schedule ends at. After `start_ts`.
+    /// * `slice_count`           - Number of Twap slices. Ignored for Vwap.
+    /// * `max_participation_bps` - Largest Vwap child, in basis points of the size of the best
+    /// opposite order. Ignored for Twap.
+    /// * `min_child_interval`    - Shortest gap between children, in seconds.
+    pub fn set_strategy(
+        ctx: Context<SetStrategy>,
+        strategy: Strategy,
+        start_ts: i64,
+        end_ts: i64,
+        slice_count: u16,
+        max_participation_bps: u16,
+        min_child_interval: u64,
+    ) -> ProgramResult {
+        if ctx.accounts.authority.key != &ctx.accounts.stoploss_state.amend_authority {
+            msg!(
+                "Strategy not set by owner. Owner {:?} sender {:?}",
+                ctx.accounts.stoploss_state.amend_authority,
+                ctx.accounts.authority
+            );
+            return Err(ErrorCode::IncorrectAmendAccount.into());
+        }
+        let stoploss = &ctx.accounts.stoploss_state;
+        if (stoploss.ord_status != OrdStatus::New && stoploss.ord_status != OrdStatus::PendingInit) || stoploss.child_order_count > 0 {
+            msg!("order has already executed. status {:?} child orders {:?}", stoploss.ord_status, stoploss.child_order_count);
+            return Err(ErrorCode::StrategyLockedAfterExecution.into());
+        }
+        check_bps(max_participation_bps)?;
+        let valid = match strategy {
+            Strategy::None => true,
+            Strategy::Twap => end_ts > start_ts && slice_count > 0,
+            Strategy::Vwap => end_ts > start_ts && max_participation_bps > 0,
+        };
+        if !valid {
+            msg!(
+                "invalid {:?} strategy. start {:?} end {:?} slices {:?} participation {:?}",
+                strategy,
+                start_ts,
+                end_ts,
+                slice_count,
+                max_participation_bps
+            );
+            return Err(ErrorCode::InvalidStrategy.into());
+        }
+        // keeper children always take all the leaves, which no schedule allows
+        if strategy != Strategy::None && stoploss.keeper_allowed {
+            msg!("keeper orders cannot have a {:?} strategy", strategy);
+            return Err(ErrorCode::KeeperNotAllowedWithStrategy.into());
+        }
+
+        let stoploss = &mut ctx.accounts.stoploss_state;
+        stoploss.strategy = strategy;
+        stoploss.strategy_start_ts = start_ts;
+        stoploss.strategy_end_ts = end_ts;
+        stoploss.slice_count = slice_count;
+        stoploss.max_participation_bps = max_participation_bps;
+        stoploss.min_child_interval = min_child_interval;
+
+        emit!(stoploss.update_event());
+
+        Ok(())
+    }
+
+    /// Ratchets the mark of a trailing stop from the current order book. Sells track the
+    /// highest best bid seen and buys the lowest best ask, so the derived trigger only ever
+    /// moves in the order's favour. Orders with an oracle track the oracle price instead,
+    /// which must pass the order's age and confidence checks.
+    ///
+    /// Permissionless - anyone can keep the mark up to date as it only reads the book or the
+    /// oracle.
+    ///
+    pub fn update_trailing_mark(ctx: Context<UpdateTrailingMark>) -> ProgramResult {
+        if ctx.accounts.stoploss_state.trigger_type == TriggerType::Fixed {
+            msg!("order does not have a trailing trigger");
+            return Err(ErrorCode::NotATrailingOrder.into());
+        }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Filled {
+            msg!("Order already filled. Cannot update trailing mark.");
+            return Err(ErrorCode::OrderAlreadyFilled.into());
+        }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Cancelled {
+            msg!("Order already cancelled. Cannot update trailing mark.");
+            return Err(ErrorCode::OrderAlreadyCancelled.into());
+        }
+        if ctx.accounts.stoploss_state.ord_status == OrdStatus::Expired {
+            msg!("Order already expired. Cannot update trailing mark.");
+            return Err(ErrorCode::OrderAlreadyExpired.into());
+        }
+        let stoploss = &ctx.accounts.stoploss_state;
+        check_account("market", ctx.accounts.market.key, &stoploss.market, ErrorCode::IncorrectMarketAccount)?;
+        check_account("bids", ctx.accounts.bids.key, &stoploss.bids, ErrorCode::IncorrectBidsAccount)?;
+        check_account("asks", ctx.accounts.asks.key, &stoploss.asks, ErrorCode::IncorrectAsksAccount)?;
+        check_account("dex program", ctx.accounts.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+
+        // orders with an oracle trail it rather than the book
+        let px = match stoploss.oracle == Pubkey::default() {
+            true => {
+                let market_price = best_opposite_price(
+                    &ctx.accounts.market,
+                    &ctx.accounts.bids,
+                    &ctx.accounts.asks,
+                    stoploss.side,
+                    ctx.accounts.dex_program.key,
+                )?;
+                match market_price {
+                    None => {
+                        msg!("no resting orders on the opposite side of the book. mark unchanged");
+                        return Err(ErrorCode::MarketPriceUnavailable.into());
+                    }
+                    Some(px) => px,
+                }
+            }
+            false => {
+                check_account("oracle", ctx.accounts.oracle.key, &stoploss.oracle, ErrorCode::IncorrectOracleAccount)?;
+                check_account("coin mint", ctx.accounts.coin_mint.key, &stoploss.coin_mint, ErrorCode::IncorrectCoinMint)?;
+                check_account("pc mint", ctx.accounts.pc_mint.key, &stoploss.pc_mint, ErrorCode::IncorrectPcMint)?;
+                let lots = LotSizes::load(&ctx.accounts.market, &ctx.accounts.coin_mint, &ctx.accounts.pc_mint, ctx.accounts.dex_program.key)?;
+                stoploss.oracle_price_lots(&ctx.accounts.oracle, &lots, Clock::get()?.unix_timestamp)?
+            }
+        };
+
+        let stoploss = &mut ctx.accounts.stoploss_state;
+        let ratchet = match stoploss.side {
+            Side::Ask => px > stoploss.trailing_mark,
+            Side::Bid => px < stoploss.trailing_mark,
+        };
+        if ratchet {
+            msg!("trailing mark moved from {:?} to {:?}", stoploss.trailing_mark, px);
+            stoploss.trailing_mark = px;
+
+            emit!(stoploss.update_event());
+        }
+
+        Ok(())
+    }
+
+    /// Creates the program config at the `b"config"` PDA, with the signer as its admin.
+    /// Can only be called once, and should be called as part of deploying the program.
+    ///
+    /// Arguments:
+    ///
+    /// * `fee_recipient`    - The account protocol fees are paid to.
+    /// * `protocol_fee_bps` - Fee charged on fills, in basis points.
+    /// * `max_slippage_bps` - How far a stop-market child order may trade from the trigger
+    /// price, in basis points. Zero for no limit.
+    pub fn initialise_config(ctx: Context<InitialiseConfig>, fee_recipient: Pubkey, protocol_fee_bps: u16, max_slippage_bps: u16) -> ProgramResult {
+        check_bps(protocol_fee_bps)?;
+        check_bps(max_slippage_bps)?;
+        let (config, bump) = config_address(ctx.program_id);
+        check_account("config", ctx.accounts.config.key, &config, ErrorCode::IncorrectConfigAccount)?;
+
+        let create_instruction = system_instruction::create_account(
+            ctx.accounts.admin.key,
+            ctx.accounts.config.key,
+            ctx.accounts.rent.minimum_balance(StoplossConfig::LEN),
+            StoplossConfig::LEN as u64,
+            ctx.program_id,
+        );
+        program::invoke_signed(
+            &create_instruction,
+            &[ctx.accounts.admin.clone(), ctx.accounts.config.clone(), ctx.accounts.system_program.clone()],
+            &[&[b"config", &[bump]]],
+        )?;
+
+        let config = StoplossConfig {
+            admin: *ctx.accounts.admin.key,
+            signal_providers: vec![],
+            protocol_fee_bps,
+            fee_recipient,
+            max_slippage_bps,
+            halted: false,
+            oracle_program: Pubkey::default(),
+        };
+        let mut data = ctx.accounts.config.try_borrow_mut_data()?;
+        let mut cursor: &mut [u8] = &mut data;
+        config.try_serialize(&mut cursor)?;
+
+        emit!(config.update_event());
+        Ok(())
+    }
+
+    /// Hands the config over to a new admin.
+    pub fn set_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        ctx.accounts.config.admin = new_admin;
+        emit!(ctx.accounts.config.update_event());
+        Ok(())
+    }
+
+    /// Approves a key to be named as the signal provider of new orders.
+    pub fn add_signal_provider(ctx: Context<UpdateConfig>, signal_provider: Pubkey) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        let config = &mut ctx.accounts.config;
+        if config.is_signal_provider(&signal_provider) {
+            msg!("signal provider {:?} already approved", signal_provider);
+            return Err(ErrorCode::SignalProviderAlreadyApproved.into());
+        }
+        if config.signal_providers.len() >= StoplossConfig::MAX_SIGNAL_PROVIDERS {
+            msg!("already {:?} signal providers", config.signal_providers.len());
+            return Err(ErrorCode::TooManySignalProviders.into());
+        }
+        config.signal_providers.push(signal_provider);
+        emit!(config.update_event());
+        Ok(())
+    }
+
+    /// Removes a signal provider from the allow-list. Orders already naming it are unaffected.
+    pub fn remove_signal_provider(ctx: Context<UpdateConfig>, signal_provider: Pubkey) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        let config = &mut ctx.accounts.config;
+        if !config.is_signal_provider(&signal_provider) {
+            msg!("signal provider {:?} is not approved", signal_provider);
+            return Err(ErrorCode::SignalProviderNotApproved.into());
+        }
+        config.signal_providers.retain(|sp| sp != &signal_provider);
+        emit!(config.update_event());
+        Ok(())
+    }
+
+    /// Sets the protocol fee and where it is paid.
+    pub fn set_protocol_fee(ctx: Context<UpdateConfig>, protocol_fee_bps: u16, fee_recipient: Pubkey) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        check_bps(protocol_fee_bps)?;
+        ctx.accounts.config.protocol_fee_bps = protocol_fee_bps;
+        ctx.accounts.config.fee_recipient = fee_recipient;
+        emit!(ctx.accounts.config.update_event());
+        Ok(())
+    }
+
+    /// Sets how far a child order may trade from the market price. Zero for no limit.
+    pub fn set_max_slippage(ctx: Context<UpdateConfig>, max_slippage_bps: u16) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        check_bps(max_slippage_bps)?;
+        ctx.accounts.config.max_slippage_bps = max_slippage_bps;
+        emit!(ctx.accounts.config.update_event());
+        Ok(())
+    }
+
+    /// Sets the program, ie Pyth, that owns the price accounts orders can trigger off. Until
+    /// it is set `set_oracle` refuses every account.
+    pub fn set_oracle_program(ctx: Context<UpdateConfig>, oracle_program: Pubkey) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        ctx.accounts.config.oracle_program = oracle_program;
+        emit!(ctx.accounts.config.update_event());
+        Ok(())
+    }
+
+    /// Creates the program's fee vault for a mint, owned by the stoploss PDA. Fees are taken
+    /// in whichever mint an order receives, so each market needs a vault for both of its mints
+    /// before a protocol fee is set.
+    pub fn initialise_fee_vault(ctx: Context<InitialiseFeeVault>) -> ProgramResult {
+        check_account("config", ctx.accounts.config.to_account_info().key, &config_address(ctx.program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        check_account("admin", ctx.accounts.admin.key, &ctx.accounts.config.admin, ErrorCode::IncorrectAdminAccount)?;
+        let (pda, _) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        check_account("pda", ctx.accounts.pda.key, &pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", ctx.accounts.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+        let (vault, bump) = fee_vault_address(ctx.accounts.mint.key, ctx.program_id);
+        check_account("fee vault", ctx.accounts.fee_vault.key, &vault, ErrorCode::IncorrectFeeVault)?;
+
+        create_token_vault(
+            ctx.accounts.admin.clone(),
+            ctx.accounts.fee_vault.clone(),
+            ctx.accounts.mint.clone(),
+            ctx.accounts.pda.clone(),
+            ctx.accounts.system_program.clone(),
+            ctx.accounts.token_program.clone(),
+            &ctx.accounts.rent,
+            &[b"fees", ctx.accounts.mint.key.as_ref(), &[bump]],
+        )
+    }
+
+    /// Pays everything in a fee vault to a token account owned by the config's fee recipient.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>) -> ProgramResult {
+        check_account("config", ctx.accounts.config.to_account_info().key, &config_address(ctx.program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        check_account("admin", ctx.accounts.admin.key, &ctx.accounts.config.admin, ErrorCode::IncorrectAdminAccount)?;
+        check_account("fee recipient", &ctx.accounts.recipient.owner, &ctx.accounts.config.fee_recipient, ErrorCode::IncorrectFeeRecipient)?;
+        check_account(
+            "fee vault",
+            ctx.accounts.fee_vault.to_account_info().key,
+            &fee_vault_address(&ctx.accounts.fee_vault.mint, ctx.program_id).0,
+            ErrorCode::IncorrectFeeVault,
+        )?;
+        let (pda, bump) = Pubkey::find_program_address(&[b"stoploss"], ctx.program_id);
+        check_account("pda", ctx.accounts.pda.key, &pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", ctx.accounts.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+
+        let amount = ctx.accounts.fee_vault.amount;
+        msg!("withdrawing {:?} fees to {:?}", amount, ctx.accounts.recipient.to_account_info().key);
+        transfer_tokens_signed(
+            ctx.accounts.fee_vault.to_account_info(),
+            ctx.accounts.recipient.to_account_info(),
+            ctx.accounts.pda.clone(),
+            amount,
+            ctx.accounts.token_program.clone(),
+            &[&b"stoploss"[..], &[bump]],
+        )?;
+
+        emit!(StoplossFeesWithdrawn {
+            mint: ctx.accounts.fee_vault.mint,
+            amount,
+            recipient: *ctx.accounts.recipient.to_account_info().key,
+        });
+        Ok(())
+    }
+
+    /// The kill switch. While halted no orders can be created or executed, but owners can
+    /// still cancel, amend and close theirs.
+    pub fn set_halted(ctx: Context<UpdateConfig>, halted: bool) -> ProgramResult {
+        ctx.accounts.check_admin(ctx.program_id)?;
+        msg!("halted {:?}", halted);
+        ctx.accounts.config.halted = halted;
+        emit!(ctx.accounts.config.update_event());
         Ok(())
     }
 }
@@ -749,34 +1897,550 @@ pub fn transfer_tokens<'info>(
     Ok(())
 }
 
+// Creates one of the order's vaults at the address derived from the order and the mint.
+// The vault is owned by the stoploss PDA as that is what signs orders on the DEX.
+fn create_order_vault<'info>(
+    payer: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    owner: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    rent: &Sysvar<'info, Rent>,
+    order: &Pubkey,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    let (vault_address, bump) = order_vault_address(order, mint.key, program_id);
+    check_account("order vault", vault.key, &vault_address, ErrorCode::IncorrectOrderVaultAddress)?;
+    create_token_vault(payer, vault, mint, owner, system_program, token_program, rent, &[order.as_ref(), mint.key.as_ref(), &[bump]])
+}
+
+// Creates a token account at a PDA of this program, signing with the PDA's `seeds`.
+fn create_token_vault<'info>(
+    payer: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    owner: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    rent: &Sysvar<'info, Rent>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let space = spl_token::state::Account::LEN;
+    let create_instruction =
+        system_instruction::create_account(payer.key, vault.key, rent.minimum_balance(space), space as u64, &spl_token::ID);
+    program::invoke_signed(&create_instruction, &[payer.clone(), vault.clone(), system_program.clone()], &[seeds])?;
+
+    let init_instruction = spl_token::instruction::initialize_account(&spl_token::ID, vault.key, mint.key, owner.key)?;
+    program::invoke(&init_instruction, &[vault.clone(), mint.clone(), owner.clone(), rent.to_account_info(), token_program.clone()])?;
+
+    Ok(())
+}
+
+/// Size of a DEX open orders account, including the DEX's padding.
+pub const OPEN_ORDERS_LEN: usize = std::mem::size_of::<OpenOrders>() + 12;
+
+/// The address of an order's open orders account on the DEX.
+pub fn open_orders_address(order: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"open_orders", order.as_ref()], program_id)
+}
+
+// Creates an order's open orders account at the address derived from the order, and
+// initialises it on the DEX with the stoploss PDA as its owner.
+fn create_open_orders<'info>(
+    payer: AccountInfo<'info>,
+    open_orders: AccountInfo<'info>,
+    market: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    rent: &Sysvar<'info, Rent>,
+    order: &Pubkey,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    let (address, bump) = open_orders_address(order, program_id);
+    check_account("open orders", open_orders.key, &address, ErrorCode::IncorrectOpenOrdersAccount)?;
+    let create_instruction = system_instruction::create_account(
+        payer.key,
+        open_orders.key,
+        rent.minimum_balance(OPEN_ORDERS_LEN),
+        OPEN_ORDERS_LEN as u64,
+        dex_program.key,
+    );
+    program::invoke_signed(
+        &create_instruction,
+        &[payer.clone(), open_orders.clone(), system_program.clone()],
+        &[&[&b"open_orders"[..], order.as_ref(), &[bump]]],
+    )?;
+
+    let (_pda, nonce) = Pubkey::find_program_address(&[b"stoploss"], program_id);
+    let instruction = Instruction {
+        program_id: *dex_program.key,
+        data: MarketInstruction::InitOpenOrders.pack(),
+        accounts: vec![
+            AccountMeta::new(*open_orders.key, false),
+            AccountMeta::new_readonly(*pda.key, true),
+            AccountMeta::new_readonly(*market.key, false),
+            AccountMeta::new_readonly(*rent.to_account_info().key, false),
+        ],
+    };
+    program::invoke_signed(
+        &instruction,
+        &[open_orders, pda, market, rent.to_account_info(), dex_program],
+        &[&[&b"stoploss"[..], &[nonce]]],
+    )?;
+    Ok(())
+}
+
+// Closes an order's open orders account on the DEX, returning its rent to `recipient`. The DEX
+// only allows this once nothing is resting and everything has been settled.
+fn close_open_orders<'info>(
+    open_orders: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    recipient: AccountInfo<'info>,
+    market: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *dex_program.key,
+        data: MarketInstruction::CloseOpenOrders.pack(),
+        accounts: vec![
+            AccountMeta::new(*open_orders.key, false),
+            AccountMeta::new_readonly(*pda.key, true),
+            AccountMeta::new(*recipient.key, false),
+            AccountMeta::new_readonly(*market.key, false),
+        ],
+    };
+    program::invoke_signed(&instruction, &[open_orders, pda, recipient, market, dex_program], &[seeds])?;
+    Ok(())
+}
+
+/// The address of the program's fee vault for the given mint.
+pub fn fee_vault_address(mint: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"fees", mint.as_ref()], program_id)
+}
+
+/// The protocol fee on `proceeds`, rounded down.
+pub fn protocol_fee(proceeds: u64, fee_bps: u16) -> u64 {
+    (proceeds as u128 * fee_bps as u128 / 10_000) as u64
+}
+
+/// The address of an order's vault for the given mint.
+pub fn order_vault_address(order: &Pubkey, mint: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[order.as_ref(), mint.as_ref()], program_id)
+}
+
+// Refunds anything left in the order's vaults back to the client and closes them, returning the
+// rent to the order owner. Only called once the order is in a terminal state.
+fn close_order_vaults<'info>(
+    stoploss: &StoplossState,
+    base_vault: AccountInfo<'info>,
+    quote_vault: AccountInfo<'info>,
+    coin_wallet: AccountInfo<'info>,
+    pc_wallet: AccountInfo<'info>,
+    rent_recipient: AccountInfo<'info>,
+    vault_owner: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    seeds: &[&[u8]],
+) -> Result<(u64, u64)> {
+    check_account("rent recipient", rent_recipient.key, &stoploss.amend_authority, ErrorCode::IncorrectAmendAccount)?;
+    let mut refunded = [0u64; 2];
+    for (i, (vault, wallet)) in [(base_vault, coin_wallet), (quote_vault, pc_wallet)].iter().enumerate() {
+        let remaining = token::accessor::amount(vault)?;
+        refunded[i] = remaining;
+        if remaining > 0 {
+            msg!("refunding {:?} from {:?} before closing", remaining, vault.key);
+            transfer_tokens_signed(vault.clone(), wallet.clone(), vault_owner.clone(), remaining, token_program.clone(), seeds)?;
+        }
+        let close_instruction = spl_token::instruction::close_account(&spl_token::ID, vault.key, rent_recipient.key, vault_owner.key, &[])?;
+        program::invoke_signed(
+            &close_instruction,
+            &[vault.clone(), rent_recipient.clone(), vault_owner.clone(), token_program.clone()],
+            &[&seeds[..]],
+        )?;
+    }
+    Ok((refunded[0], refunded[1]))
+}
+
+// Settles everything free on the order's open orders account into the order's own vaults.
+fn settle_to_order_vaults<'info>(
+    market: &MarketAccounts<'info>,
+    pda: &AccountInfo<'info>,
+    base_vault: &AccountInfo<'info>,
+    quote_vault: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    dex_program: &AccountInfo<'info>,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *dex_program.key,
+        data: MarketInstruction::SettleFunds.pack(),
+        accounts: vec![
+            AccountMeta::new(*market.market.key, false),
+            AccountMeta::new(*market.open_orders.key, false),
+            AccountMeta::new_readonly(*pda.key, true),
+            AccountMeta::new(*market.coin_vault.key, false),
+            AccountMeta::new(*market.pc_vault.key, false),
+            AccountMeta::new(*base_vault.key, false),
+            AccountMeta::new(*quote_vault.key, false),
+            AccountMeta::new_readonly(*market.vault_signer.key, false),
+            AccountMeta::new_readonly(*token_program.key, false),
+        ],
+    };
+    program::invoke_signed(
+        &instruction,
+        &[
+            market.market.clone(),
+            market.open_orders.clone(),
+            pda.clone(),
+            market.coin_vault.clone(),
+            market.pc_vault.clone(),
+            base_vault.clone(),
+            quote_vault.clone(),
+            market.vault_signer.clone(),
+            token_program.clone(),
+            dex_program.clone(),
+        ],
+        &[seeds],
+    )?;
+    Ok(())
+}
+
+// Passes the proceeds of a fill on from the order's receiving vault to the client, less the
+// protocol fee which goes to the fee vault. Returns the fee taken.
+fn pay_out_proceeds<'info>(
+    stoploss: &mut StoplossState,
+    proceeds: u64,
+    protocol_fee_bps: u16,
+    receiving_vault: AccountInfo<'info>,
+    client_wallet: AccountInfo<'info>,
+    fee_vault: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    seeds: &[&[u8]],
+) -> Result<u64> {
+    let fee = protocol_fee(proceeds, protocol_fee_bps);
+    if fee > 0 {
+        transfer_tokens_signed(receiving_vault.clone(), fee_vault, pda.clone(), fee, token_program.clone(), seeds)?;
+        stoploss.protocol_fees_paid = stoploss.protocol_fees_paid.checked_add(fee).ok_or(ErrorCode::NumericalOverflow)?;
+        msg!("protocol fee {:?}", fee);
+    }
+    if proceeds > fee {
+        transfer_tokens_signed(receiving_vault, client_wallet, pda, proceeds - fee, token_program, seeds)?;
+    }
+    Ok(fee)
+}
+
+// Cancels the other leg of a bracket, passed as the first remaining account. Both legs share
+// one deposit, so once one leg has traded or refunded it the other has nothing left to work.
+fn cancel_sibling<'info>(stoploss: &StoplossState, remaining_accounts: &[AccountInfo<'info>], program_id: &Pubkey) -> ProgramResult {
+    sync_sibling(stoploss, remaining_accounts, program_id, true)
+}
+
+// bracket legs work one deposit, so a leg that is still working keeps the other leg's leaves
+// and keeper bounty in step with its own. With `cancel` the other leg is cancelled instead.
+fn sync_sibling<'info>(stoploss: &StoplossState, remaining_accounts: &[AccountInfo<'info>], program_id: &Pubkey, cancel: bool) -> ProgramResult {
+    if stoploss.sibling == Pubkey::default() {
+        return Ok(());
+    }
+    let mut sibling = load_sibling(stoploss, remaining_accounts)?;
+    if sibling.ord_status == OrdStatus::Filled || sibling.ord_status == OrdStatus::Cancelled || sibling.ord_status == OrdStatus::Expired {
+        return Ok(());
+    }
+    if cancel {
+        msg!("cancelling bracket sibling {:?}", stoploss.sibling);
+        sibling.coin_leaves_qty = 0;
+        sibling.pc_leaves_qty = 0;
+        sibling.ord_status = OrdStatus::Cancelled;
+    } else {
+        sibling.coin_leaves_qty = stoploss.coin_leaves_qty;
+        sibling.pc_leaves_qty = stoploss.pc_leaves_qty;
+        sibling.keeper_bounty_paid = stoploss.keeper_bounty_paid;
+    }
+    sibling.exit(program_id)?;
+
+    emit!(sibling.update_event());
+    Ok(())
+}
+
+// whether the other leg of a bracket, passed as the first remaining account, is still working.
+// A leg that has been closed is not.
+fn sibling_is_live<'info>(stoploss: &StoplossState, remaining_accounts: &[AccountInfo<'info>]) -> Result<bool> {
+    if stoploss.sibling == Pubkey::default() {
+        return Ok(false);
+    }
+    let sibling_info = remaining_accounts.get(0).ok_or(ErrorCode::MissingSiblingAccount)?;
+    check_account("sibling", sibling_info.key, &stoploss.sibling, ErrorCode::IncorrectSiblingAccount)?;
+    if sibling_info.lamports() == 0 {
+        return Ok(false);
+    }
+    let sibling = load_sibling(stoploss, remaining_accounts)?;
+    Ok(sibling.ord_status != OrdStatus::Filled && sibling.ord_status != OrdStatus::Cancelled && sibling.ord_status != OrdStatus::Expired)
+}
+
+// the other leg of a bracket, passed as the first remaining account, which must link back
+fn load_sibling<'info>(stoploss: &StoplossState, remaining_accounts: &[AccountInfo<'info>]) -> Result<ProgramAccount<'info, StoplossState>> {
+    let sibling_info = remaining_accounts.get(0).ok_or(ErrorCode::MissingSiblingAccount)?;
+    check_account("sibling", sibling_info.key, &stoploss.sibling, ErrorCode::IncorrectSiblingAccount)?;
+    let sibling: ProgramAccount<StoplossState> = ProgramAccount::try_from(sibling_info)?;
+    if sibling.sibling != stoploss.own_address {
+        msg!("sibling {:?} is linked to {:?} not {:?}", sibling_info.key, sibling.sibling, stoploss.own_address);
+        return Err(ErrorCode::IncorrectSiblingAccount.into());
+    }
+    Ok(sibling)
+}
+
+fn check_account(name: &str, actual: &Pubkey, expected: &Pubkey, err: ErrorCode) -> ProgramResult {
+    if actual != expected {
+        msg!("Incorrect {} account. Expected {:?} got {:?}", name, expected, actual);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+// cancel and amend move funds between the stoploss vault and the client wallets, so all of them
+// must be the ones recorded when the order was created.
+fn validate_refund_accounts(
+    stoploss: &StoplossState,
+    coin_wallet: &Pubkey,
+    pc_wallet: &Pubkey,
+    stoploss_paying_vault: &Pubkey,
+    vault_owner: &Pubkey,
+    token_program: &Pubkey,
+) -> ProgramResult {
+    check_account("client coin wallet", coin_wallet, &stoploss.client_coin_wallet, ErrorCode::IncorrectClientCoinWallet)?;
+    check_account("client pc wallet", pc_wallet, &stoploss.client_pc_wallet, ErrorCode::IncorrectClientPcWallet)?;
+    let paying_vault = match stoploss.side {
+        Side::Bid => &stoploss.stoploss_quote_vault,
+        Side::Ask => &stoploss.stoploss_base_vault,
+    };
+    check_account("stoploss paying vault", stoploss_paying_vault, paying_vault, ErrorCode::IncorrectStoplossPayingVault)?;
+    check_account("vault owner", vault_owner, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+    check_account("token program", token_program, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+    Ok(())
+}
+
 fn concat(vec: &[u64]) -> u64 {
     let mut acc = 0;
     for elem in vec {
         acc *= 10;
         acc += elem;
     }
-    acc
-}
+    acc
+}
+
+/// Returns the best price in the book, in price lots, that a child order on `side` would
+/// trade against. Sells trade against the best bid and buys against the best ask.
+/// Returns None when that side of the book is empty.
+pub fn best_opposite_price(market: &AccountInfo, bids: &AccountInfo, asks: &AccountInfo, side: Side, dex_pid: &Pubkey) -> Result<Option<u64>> {
+    Ok(best_opposite_order(market, bids, asks, side, dex_pid)?.map(|(price, _)| price))
+}
+
+/// Like `best_opposite_price`, along with the size of the best order in base lots.
+pub fn best_opposite_order(market: &AccountInfo, bids: &AccountInfo, asks: &AccountInfo, side: Side, dex_pid: &Pubkey) -> Result<Option<(u64, u64)>> {
+    // The loaded market must be dropped before CPI.
+    let market = MarketState::load(market, dex_pid).map_err(|_| ErrorCode::MarketPriceUnavailable)?;
+    let order = match side {
+        Side::Ask => {
+            let bids = market.load_bids_mut(bids).map_err(|_| ErrorCode::MarketPriceUnavailable)?;
+            bids.find_max().and_then(|h| bids.get(h)).and_then(|n| n.as_leaf()).map(|l| (l.price().get(), l.quantity()))
+        }
+        Side::Bid => {
+            let asks = market.load_asks_mut(asks).map_err(|_| ErrorCode::MarketPriceUnavailable)?;
+            asks.find_min().and_then(|h| asks.get(h)).and_then(|n| n.as_leaf()).map(|l| (l.price().get(), l.quantity()))
+        }
+    };
+    Ok(order)
+}
+
+// The taker fee the DEX charged child `client_order_id`, off its taker fill event. The fee
+// tier depends on the market and on fee discounts the program never passes, so it is read
+// back rather than assumed. Zero when the child took nothing.
+fn taker_fee_charged(market: &AccountInfo, event_queue: &AccountInfo, open_orders: &AccountInfo, client_order_id: u64, dex_pid: &Pubkey) -> Result<u64> {
+    // The loaded market must be dropped before CPI.
+    let market = MarketState::load(market, dex_pid).map_err(|_| ErrorCode::IncorrectMarketAccount)?;
+    let events = market.load_event_queue_mut(event_queue).map_err(|_| ErrorCode::IncorrectMarketAccount)?;
+    let owner = open_orders.key.to_aligned_bytes();
+    let mut fee = 0u64;
+    for event in events.iter() {
+        if let Ok(EventView::Fill {
+            maker: false,
+            owner: event_owner,
+            client_order_id: Some(event_order_id),
+            native_fee_or_rebate,
+            ..
+        }) = event.as_view()
+        {
+            if event_owner == owner && event_order_id.get() == client_order_id {
+                fee = fee.checked_add(native_fee_or_rebate).ok_or(ErrorCode::NumericalOverflow)?;
+            }
+        }
+    }
+    Ok(fee)
+}
+
+/// A sell stop triggers when the market falls to or below the trigger price, a buy stop
+/// when it rises to or above it. Take-profits trigger in the opposite direction. Both
+/// prices are in price lots.
+pub fn is_triggered(side: Side, kind: OrderKind, market_price: u64, trigger_price: u64) -> bool {
+    match (side, kind) {
+        (Side::Ask, OrderKind::StopLoss) | (Side::Bid, OrderKind::TakeProfit) => market_price <= trigger_price,
+        (Side::Bid, OrderKind::StopLoss) | (Side::Ask, OrderKind::TakeProfit) => market_price >= trigger_price,
+    }
+}
+
+/// The worst price, in price lots, a stop-market child can trade at: `max_slippage_bps` below
+/// the reference price for sells and above it for buys, rounded towards the reference. Zero
+/// bps is no cap, ie the lowest or highest price the DEX accepts.
+pub fn slippage_cap(side: Side, reference_price: u64, max_slippage_bps: u16) -> u64 {
+    if max_slippage_bps == 0 {
+        return match side {
+            Side::Ask => 1,
+            Side::Bid => u64::MAX,
+        };
+    }
+    let reference_price = reference_price as u128;
+    let cap = match side {
+        Side::Ask => (reference_price * (10_000 - max_slippage_bps as u128) + 9_999) / 10_000,
+        Side::Bid => reference_price * (10_000 + max_slippage_bps as u128) / 10_000,
+    };
+    cap.max(1).min(u64::MAX as u128) as u64
+}
+
+/// Whether a sell (buy) trading at `price` is at or above (below) `limit`.
+pub fn within_limit(side: Side, price: u64, limit: u64) -> bool {
+    match side {
+        Side::Ask => price >= limit,
+        Side::Bid => price <= limit,
+    }
+}
+
+/// The tighter of two limit prices, ie the higher for sells and the lower for buys.
+pub fn tighter_limit(side: Side, a: u64, b: u64) -> u64 {
+    match side {
+        Side::Ask => a.max(b),
+        Side::Bid => a.min(b),
+    }
+}
+
+/// A market's lot sizes and mint decimals, for converting quantities and prices between
+/// native units, DEX lots and UI units. Prices in lots are quote lots per base lot, as on the
+/// order book.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LotSizes {
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub coin_decimals: u8,
+    pub pc_decimals: u8,
+}
+
+impl LotSizes {
+    pub fn load(market: &AccountInfo, coin_mint: &AccountInfo, pc_mint: &AccountInfo, dex_pid: &Pubkey) -> Result<Self> {
+        // The loaded market must be dropped before CPI.
+        let market = MarketState::load(market, dex_pid).map_err(|_| ErrorCode::IncorrectMarketAccount)?;
+        Ok(LotSizes {
+            coin_lot_size: market.coin_lot_size,
+            pc_lot_size: market.pc_lot_size,
+            coin_decimals: mint_decimals(&coin_mint.try_borrow_data()?)?,
+            pc_decimals: mint_decimals(&pc_mint.try_borrow_data()?)?,
+        })
+    }
+
+    /// Whole base lots in `coin_qty`, rounded down.
+    pub fn coin_lots(&self, coin_qty: u64) -> u64 {
+        coin_qty / self.coin_lot_size
+    }
+
+    /// `coin_qty` rounded down to whole base lots, ie what the DEX can trade of it.
+    pub fn round_coin(&self, coin_qty: u64) -> u64 {
+        self.coin_lots(coin_qty) * self.coin_lot_size
+    }
+
+    /// Price in lots of `pc_qty` quote for `coin_qty` base, rounded down. Zero without base.
+    pub fn price_lots(&self, pc_qty: u64, coin_qty: u64) -> u64 {
+        let lots = coin_qty as u128 * self.pc_lot_size as u128;
+        if lots == 0 {
+            return 0;
+        }
+        u64::try_from(pc_qty as u128 * self.coin_lot_size as u128 / lots).unwrap_or(u64::MAX)
+    }
+
+    /// Quote for the whole base lots in `coin_qty` at `price_lots`, before fees.
+    pub fn pc_qty(&self, coin_qty: u64, price_lots: u64) -> Option<u64> {
+        u64::try_from(self.coin_lots(coin_qty) as u128 * price_lots as u128 * self.pc_lot_size as u128).ok()
+    }
+
+    /// Most base, in whole lots, that `pc_qty` quote buys at `price_lots` once the taker fee
+    /// is paid on top.
+    pub fn coin_qty_for(&self, pc_qty: u64, price_lots: u64) -> u64 {
+        let lot_price = price_lots as u128 * self.pc_lot_size as u128;
+        if lot_price == 0 {
+            return 0;
+        }
+        let lots = gross_from_total_paid(pc_qty) as u128 / lot_price;
+        u64::try_from(lots * self.coin_lot_size as u128).unwrap_or(u64::MAX)
+    }
+
+    pub fn coin_ui(&self, coin_qty: u64) -> f64 {
+        coin_qty as f64 / 10f64.powi(self.coin_decimals as i32)
+    }
+
+    pub fn pc_ui(&self, pc_qty: u64) -> f64 {
+        pc_qty as f64 / 10f64.powi(self.pc_decimals as i32)
+    }
+
+    /// Quote per whole base, eg 6.004 for 6004 price lots on the test market.
+    pub fn price_ui(&self, price_lots: u64) -> f64 {
+        price_lots as f64 * self.pc_lot_size as f64 / self.coin_lot_size as f64 * 10f64.powi(self.coin_decimals as i32 - self.pc_decimals as i32)
+    }
+}
+
+/// A price in quote per base, both in native units, as a fixed-point number scaled by
+/// `Price::SCALE`. So 6.004 quote per base between mints with the same decimals is
+/// 6_004_000_000. Unlike price lots it does not truncate on fills smaller than a lot.
+#[derive(AnchorSerialize, AnchorDeserialize, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Price {
+    pub value: u64,
+}
+
+impl Price {
+    pub const SCALE: u64 = 1_000_000_000;
+
+    /// Price of `pc_qty` quote for `coin_qty` base, rounded down. Zero without base, and
+    /// saturates rather than overflowing.
+    pub fn from_fill(pc_qty: u64, coin_qty: u64) -> Price {
+        if coin_qty == 0 {
+            return Price::default();
+        }
+        Price::saturating(pc_qty as u128 * Self::SCALE as u128 / coin_qty as u128)
+    }
+
+    /// The price of `price_lots`, ie quote lots per base lot, rounded down.
+    pub fn from_lots(price_lots: u64, lots: &LotSizes) -> Price {
+        Price::saturating(price_lots as u128 * lots.pc_lot_size as u128 * Self::SCALE as u128 / lots.coin_lot_size as u128)
+    }
+
+    /// The price in price lots, rounded down.
+    pub fn to_lots(&self, lots: &LotSizes) -> u64 {
+        let lot_price = lots.pc_lot_size as u128 * Self::SCALE as u128;
+        u64::try_from(self.value as u128 * lots.coin_lot_size as u128 / lot_price).unwrap_or(u64::MAX)
+    }
 
-// just does the scaling really
-fn calculate_price_lots(pc_qty: u64, coin_qty: u64, market: &AccountInfo, dex_pid: &Pubkey) -> u64 {
-    msg!("calculate_price_lots pc_qty {:?} coin_qty {:?}", pc_qty, coin_qty);
-    if coin_qty == 0 {
-        return 0;
+    /// Quote per whole base, in UI units.
+    pub fn to_ui(&self, lots: &LotSizes) -> f64 {
+        self.value as f64 / Self::SCALE as f64 * 10f64.powi(lots.coin_decimals as i32 - lots.pc_decimals as i32)
+    }
+
+    fn saturating(value: u128) -> Price {
+        Price {
+            value: u64::try_from(value).unwrap_or(u64::MAX),
+        }
     }
-    // The loaded market must be dropped before CPI.
-    let market = MarketState::load(market, dex_pid).expect("error loading market");
-    msg!("market.pc_lot_size {:?} market.coin_lot_size {:?} ", market.pc_lot_size, market.coin_lot_size);
-    let scaled_pc_qty = pc_qty.checked_mul(market.coin_lot_size).unwrap();
-    let scaled_coin_qty = coin_qty; //.checked_mul(market.pc_lot_size).unwrap();
-    msg!("scaled_coin_qty {:?} scaled_pc_qty {:?} ", scaled_coin_qty, scaled_pc_qty);
-    scaled_pc_qty.checked_div(scaled_coin_qty).unwrap()
 }
 
-fn coin_lots(market: &AccountInfo, size: u64, dex_pid: &Pubkey) -> u64 {
-    // The loaded market must be dropped before CPI.
-    let market = MarketState::load(market, dex_pid).unwrap();
-    size.checked_div(market.coin_lot_size).unwrap()
+pub fn mint_decimals(data: &[u8]) -> Result<u8> {
+    Ok(spl_token::state::Mint::unpack(data)?.decimals)
 }
 
 // Market accounts are the accounts used to place orders against the dex minus
@@ -825,17 +2489,24 @@ pub struct MarketAccounts<'info> {
 
 #[derive(Accounts)]
 pub struct InitialiseOpenOrders<'info> {
-    #[account(signer)]
+    #[account(signer, mut)]
     authority: AccountInfo<'info>,
     #[account(mut)]
     stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(mut)]
     stoploss_open_orders: AccountInfo<'info>,
+    market: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
 pub struct NewOrder<'info> {
     market: MarketAccounts<'info>,
-    // stoploss program holds tokens
+    // the order's own vaults, created by new_order at the address derived from
+    // the stoploss_state address and the mint
     #[account(mut)]
     stoploss_base_vault: AccountInfo<'info>,
     #[account(mut)]
@@ -844,7 +2515,7 @@ pub struct NewOrder<'info> {
     stoploss_open_orders: AccountInfo<'info>,
     #[account(mut)]
     signal_provider: AccountInfo<'info>,
-    #[account(signer)]
+    #[account(signer, mut)]
     authority: AccountInfo<'info>,
     #[account(init)]
     stoploss_state: ProgramAccount<'info, StoplossState>,
@@ -852,7 +2523,9 @@ pub struct NewOrder<'info> {
     dex_program: AccountInfo<'info>,
     pda: AccountInfo<'info>,
     token_program: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
     rent: Sysvar<'info, Rent>,
+    config: ProgramAccount<'info, StoplossConfig>,
 }
 
 #[derive(Accounts)]
@@ -869,20 +2542,184 @@ pub struct ExecuteOrder<'info> {
     stoploss_state: ProgramAccount<'info, StoplossState>,
     #[account(mut)]
     stoploss_open_orders: AccountInfo<'info>,
+    // receives the rent of the order's vaults when they are closed
+    #[account(mut)]
+    amend_authority: AccountInfo<'info>,
+    // receives the bounty when a keeper executes the order. Any token account when the
+    // signal provider executes, as nothing is paid.
+    #[account(mut)]
+    keeper_bounty_account: CpiAccount<'info, TokenAccount>,
 
     stoploss_program: AccountInfo<'info>,
     dex_program: AccountInfo<'info>,
     pda: AccountInfo<'info>,
     token_program: AccountInfo<'info>,
     rent: Sysvar<'info, Rent>,
+    config: ProgramAccount<'info, StoplossConfig>,
+    // the program's fee vault for the mint the order receives. Only needs to exist while a
+    // protocol fee is set.
+    #[account(mut)]
+    fee_vault: AccountInfo<'info>,
+    // the order's oracle, see set_oracle. Any account when the order triggers off the book.
+    oracle: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CancelOrder<'info> {
+pub struct SettleOrder<'info> {
+    market: MarketAccounts<'info>,
+    #[account(mut)]
+    stoploss_base_vault: AccountInfo<'info>,
+    #[account(mut)]
+    stoploss_quote_vault: AccountInfo<'info>,
+    // anyone can settle, only the owner or the signal provider can cancel a child
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    // receives the rent of the order's vaults if the child fills the order
+    #[account(mut)]
+    amend_authority: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    config: ProgramAccount<'info, StoplossConfig>,
+    #[account(mut)]
+    fee_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitialiseConfig<'info> {
+    #[account(signer, mut)]
+    admin: AccountInfo<'info>,
+    #[account(mut)]
+    config: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut)]
+    config: ProgramAccount<'info, StoplossConfig>,
+    #[account(signer)]
+    admin: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitialiseFeeVault<'info> {
+    config: ProgramAccount<'info, StoplossConfig>,
+    #[account(signer, mut)]
+    admin: AccountInfo<'info>,
+    #[account(mut)]
+    fee_vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    pda: AccountInfo<'info>,
+    system_program: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    config: ProgramAccount<'info, StoplossConfig>,
+    #[account(signer)]
+    admin: AccountInfo<'info>,
+    #[account(mut)]
+    fee_vault: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    recipient: CpiAccount<'info, TokenAccount>,
+    pda: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+}
+
+impl<'info> UpdateConfig<'info> {
+    fn check_admin(&self, program_id: &Pubkey) -> ProgramResult {
+        check_account("config", self.config.to_account_info().key, &config_address(program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        check_account("admin", self.admin.key, &self.config.admin, ErrorCode::IncorrectAdminAccount)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseOrder<'info> {
+    #[account(mut, close = recipient)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    // receives the rent of the order account, and of any vault still open
+    #[account(mut)]
+    recipient: AccountInfo<'info>,
+    #[account(mut)]
+    stoploss_base_vault: AccountInfo<'info>,
+    #[account(mut)]
+    stoploss_quote_vault: AccountInfo<'info>,
+    vault_owner: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+    // closed on the DEX too, its rent goes back to open_orders_payer
+    #[account(mut)]
+    stoploss_open_orders: AccountInfo<'info>,
+    market: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    // whoever paid for the open orders account, see StoplossState::open_orders_payer. Any
+    // account if it was never created.
+    #[account(mut)]
+    open_orders_payer: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct NewBracketLeg<'info> {
+    // the existing order whose deposit the new leg shares
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(init)]
+    bracket_leg: ProgramAccount<'info, StoplossState>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct SetOracle<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    oracle: AccountInfo<'info>,
+    config: ProgramAccount<'info, StoplossConfig>,
+    // the order's market and mints, to price the oracle in lots for trailing orders
+    market: AccountInfo<'info>,
+    coin_mint: AccountInfo<'info>,
+    pc_mint: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetStrategy<'info> {
     #[account(mut)]
     stoploss_state: ProgramAccount<'info, StoplossState>,
     #[account(signer)]
     authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateTrailingMark<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    market: AccountInfo<'info>,
+    bids: AccountInfo<'info>,
+    asks: AccountInfo<'info>,
+    dex_program: AccountInfo<'info>,
+    coin_mint: AccountInfo<'info>,
+    pc_mint: AccountInfo<'info>,
+    // the order's oracle, see set_oracle. Any account when the order trails the book.
+    oracle: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(signer, mut)]
+    authority: AccountInfo<'info>,
     #[account(mut)]
     coin_wallet: CpiAccount<'info, TokenAccount>,
     #[account(mut)]
@@ -890,8 +2727,47 @@ pub struct CancelOrder<'info> {
     // the vault to refund the client from
     #[account(mut)]
     stoploss_paying_vault: CpiAccount<'info, TokenAccount>,
+    // the order's other vault, ie the one fills are received into. Closed along with the paying vault.
+    #[account(mut)]
+    stoploss_receiving_vault: AccountInfo<'info>,
+    vault_owner: AccountInfo<'info>,
+    token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireOrder<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    // receives the rent of the order's vaults
+    #[account(mut)]
+    amend_authority: AccountInfo<'info>,
+    #[account(mut)]
+    coin_wallet: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pc_wallet: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    stoploss_base_vault: AccountInfo<'info>,
+    #[account(mut)]
+    stoploss_quote_vault: AccountInfo<'info>,
     vault_owner: AccountInfo<'info>,
     token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct SuspendOrder<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResumeOrder<'info> {
+    #[account(mut)]
+    stoploss_state: ProgramAccount<'info, StoplossState>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
@@ -911,6 +2787,60 @@ pub struct AmendOrder<'info> {
     token_program: AccountInfo<'info>,
 }
 
+// program wide settings, one account at the config PDA
+#[account]
+#[derive(Debug)]
+pub struct StoplossConfig {
+    // the only key that can change the config
+    pub admin: Pubkey,
+    // keys new orders may name as their signal provider
+    pub signal_providers: Vec<Pubkey>,
+    pub protocol_fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    // how far a child order may trade from the market price. zero for no limit
+    pub max_slippage_bps: u16,
+    // kill switch for new_order and execute_order
+    pub halted: bool,
+    // owner of the price accounts orders can trigger off, ie pyth. Default until the admin sets
+    // it, which leaves set_oracle disabled
+    pub oracle_program: Pubkey,
+}
+
+impl StoplossConfig {
+    pub const MAX_SIGNAL_PROVIDERS: usize = 16;
+    /// Account size with room for every signal provider, including the discriminator.
+    pub const LEN: usize = 8 + 32 + 4 + 32 * Self::MAX_SIGNAL_PROVIDERS + 2 + 32 + 2 + 1 + 32;
+
+    pub fn is_signal_provider(&self, key: &Pubkey) -> bool {
+        self.signal_providers.contains(key)
+    }
+
+    pub fn update_event(&self) -> StoplossConfigUpdate {
+        StoplossConfigUpdate {
+            admin: self.admin,
+            signal_providers: self.signal_providers.clone(),
+            protocol_fee_bps: self.protocol_fee_bps,
+            fee_recipient: self.fee_recipient,
+            max_slippage_bps: self.max_slippage_bps,
+            halted: self.halted,
+            oracle_program: self.oracle_program,
+        }
+    }
+}
+
+/// The address of the program config.
+pub fn config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"config"], program_id)
+}
+
+fn check_bps(bps: u16) -> ProgramResult {
+    if bps > 10_000 {
+        msg!("{:?} bps is more than 100%", bps);
+        return Err(ErrorCode::InvalidBps.into());
+    }
+    Ok(())
+}
+
 // cant have enum in events last I checked, so have to pass a pointer to the order id and look it up
 // off chain
 #[account]
@@ -948,49 +2878,431 @@ pub struct StoplossState {
     pub max_coin_qty: u64,
     pub max_pc_qty: u64,
 
-    pub trigger_price: u64,
+    pub trigger_price: u64,
+    // trailing stops derive the trigger from the mark, see effective_trigger_price
+    pub trigger_type: TriggerType,
+    pub trailing_offset: u64,
+    // high-water mark for sells, low-water mark for buys. In price lots.
+    pub trailing_mark: u64,
+    // set by the first child order that fills or rests, the trigger is not checked again after that
+    pub triggered: bool,
+    pub order_kind: OrderKind,
+    // how execute_order prices the child, see new_order
+    pub order_mode: OrderMode,
+    // the other leg of a bracket order. Default pubkey when not part of a bracket.
+    pub sibling: Pubkey,
+
+    // fix like fields
+    pub side: Side,
+    pub limit_price: u64,
+    pub client_order_id: u64,
+    // remaining on the order
+    pub coin_leaves_qty: u64,
+    pub pc_leaves_qty: u64,
+    // filled on the order
+    pub coin_cum_qty: u64,
+    pub pc_cum_qty: u64,
+    // price of the last fill, excluding fees
+    pub last_price: Price,
+    // avg price of all fills, excluding fees
+    pub avg_price: Price,
+    pub ord_status: OrdStatus,
+
+    // signal provider is the authority used to execute child orders, eg an algo server
+    pub signal_provider: Pubkey,
+    pub child_order_count: u64,
+    pub client_paying_account: Pubkey,
+
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+
+    pub should_create_open_orders: bool,
+    // pays the open orders rent, and gets it back on close_order. The owner when created by
+    // new_order, otherwise the signal provider, which calls initialise_open_orders.
+    pub open_orders_payer: Pubkey,
+
+    // permissionless execution, see new_order
+    pub keeper_allowed: bool,
+    pub keeper_bounty: u64,
+    pub keeper_bounty_paid: bool,
+
+    // quote paid to the DEX in taker fees across all children. Not included in pc_cum_qty.
+    pub fees_paid: u64,
+    // paid to the program out of the proceeds, in quote for sells and base for buys
+    pub protocol_fees_paid: u64,
+
+    pub time_in_force: TimeInForce,
+    // unix timestamp the order can no longer execute from. Zero for GoodTillCancel.
+    pub expiry_ts: i64,
+
+    // a Limit or PostOnly child still on the book, in the paying mint. Counted in the leaves
+    // until settle_order books it.
+    pub resting_qty: u64,
+    pub resting_client_order_id: u64,
+
+    // pyth price account the trigger is checked against instead of the book. Default pubkey
+    // when the order triggers off the book, see set_oracle.
+    pub oracle: Pubkey,
+    // seconds
+    pub oracle_max_age: u64,
+    pub oracle_max_confidence_bps: u16,
+
+    // limits on how the signal provider slices the order, see set_strategy
+    pub strategy: Strategy,
+    pub strategy_start_ts: i64,
+    pub strategy_end_ts: i64,
+    pub slice_count: u16,
+    pub max_participation_bps: u16,
+    // seconds
+    pub min_child_interval: u64,
+    // unix timestamp of the last child order
+    pub last_child_ts: i64,
+}
+
+impl StoplossState {
+    /// The trigger price, in price lots, that execute_order checks the market against.
+    /// For trailing stops this sits `trailing_offset` below the mark for sells and above
+    /// it for buys.
+    pub fn effective_trigger_price(&self) -> u64 {
+        let offset = match self.trigger_type {
+            TriggerType::Fixed => return self.trigger_price,
+            TriggerType::TrailingAbsolute => self.trailing_offset,
+            TriggerType::TrailingBps => (self.trailing_mark as u128 * self.trailing_offset as u128 / 10_000) as u64,
+        };
+        match self.side {
+            Side::Ask => self.trailing_mark.saturating_sub(offset),
+            Side::Bid => self.trailing_mark.saturating_add(offset),
+        }
+    }
+
+    /// The status a suspended order goes back to. Only working orders can be suspended, and
+    /// an order that has filled anything and is still working must be reusing unfilled funds.
+    pub fn resumed_status(&self) -> OrdStatus {
+        if self.coin_cum_qty > 0 || self.pc_cum_qty > 0 {
+            OrdStatus::PartiallyFilled
+        } else {
+            OrdStatus::New
+        }
+    }
+
+    /// Whether the order's time in force has run out at `now`, a unix timestamp.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.time_in_force != TimeInForce::GoodTillCancel && now >= self.expiry_ts
+    }
+
+    /// Fails if the order's strategy does not allow a child at `now`, ie before the strategy
+    /// starts, after a Vwap strategy ends or within `min_child_interval` of the last child.
+    pub fn check_strategy_timing(&self, now: i64) -> Result<()> {
+        if self.strategy == Strategy::None {
+            return Ok(());
+        }
+        if now < self.strategy_start_ts {
+            msg!("{:?} strategy starts at {:?}, now {:?}", self.strategy, self.strategy_start_ts, now);
+            return Err(ErrorCode::StrategyNotStarted.into());
+        }
+        if self.strategy == Strategy::Vwap && now >= self.strategy_end_ts {
+            msg!("{:?} strategy ended at {:?}, now {:?}", self.strategy, self.strategy_end_ts, now);
+            return Err(ErrorCode::StrategyEnded.into());
+        }
+        let since_last = now.saturating_sub(self.last_child_ts).max(0) as u64;
+        if self.child_order_count > 0 && since_last < self.min_child_interval {
+            msg!("last child {:?}s ago, min interval {:?}s", since_last, self.min_child_interval);
+            return Err(ErrorCode::ChildOrderTooSoon.into());
+        }
+        Ok(())
+    }
+
+    /// The most of the paying mint a Twap order can have used by `now`: an even share of the
+    /// deposit for every slice that has started. u64::MAX for other strategies.
+    pub fn strategy_allowance(&self, now: i64) -> u64 {
+        if self.strategy != Strategy::Twap {
+            return u64::MAX;
+        }
+        let slices = self.slice_count as i128;
+        let duration = (self.strategy_end_ts as i128 - self.strategy_start_ts as i128).max(1);
+        let elapsed = (now as i128 - self.strategy_start_ts as i128).max(0);
+        let started = (elapsed * slices / duration + 1).min(slices);
+        let deposit = match self.side {
+            Side::Bid => self.max_pc_qty,
+            Side::Ask => self.max_coin_qty,
+        };
+        (deposit as i128 * started / slices.max(1)) as u64
+    }
+
+    /// How much of the paying mint the order has used so far, including fees and bounties.
+    pub fn paying_used(&self) -> u64 {
+        match self.side {
+            Side::Bid => self.max_pc_qty.saturating_sub(self.pc_leaves_qty),
+            Side::Ask => self.max_coin_qty.saturating_sub(self.coin_leaves_qty),
+        }
+    }
+
+    /// Fails if a child that can use `paying_qty` of the paying mint and trade `coin_lots`
+    /// base lots breaks the order's strategy at `now`. `best_order_lots` is the size of the
+    /// best order on the opposite side of the book.
+    pub fn check_strategy_size(&self, now: i64, paying_qty: u64, coin_lots: u64, best_order_lots: u64) -> Result<()> {
+        let allowance = self.strategy_allowance(now);
+        if self.paying_used().saturating_add(paying_qty) > allowance {
+            msg!("child of {:?} over the schedule. used {:?} allowed {:?}", paying_qty, self.paying_used(), allowance);
+            return Err(ErrorCode::ExceedsStrategySchedule.into());
+        }
+        if self.strategy == Strategy::Vwap && coin_lots as u128 * 10_000 > best_order_lots as u128 * self.max_participation_bps as u128 {
+            msg!(
+                "child of {:?} lots over {:?} bps of the best order's {:?} lots",
+                coin_lots,
+                self.max_participation_bps,
+                best_order_lots
+            );
+            return Err(ErrorCode::ExceedsMaxParticipation.into());
+        }
+        Ok(())
+    }
+
+    /// The order's oracle price in price lots at `now`, once it has passed the order's age and
+    /// confidence checks.
+    pub fn oracle_price_lots(&self, oracle: &AccountInfo, lots: &LotSizes, now: i64) -> Result<u64> {
+        OraclePrice::load(oracle)?.checked_price_lots(lots, now, self.oracle_max_age, self.oracle_max_confidence_bps)
+    }
+}
+
+/// The expiry timestamp to record for a new order created at `now`.
+///
+/// GoodTillDate orders must expire in the future, Day orders expire at the next UTC midnight
+/// and GoodTillCancel orders never do.
+pub fn order_expiry(time_in_force: TimeInForce, expiry_ts: i64, now: i64) -> Result<i64> {
+    const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+    match time_in_force {
+        TimeInForce::GoodTillCancel => Ok(0),
+        TimeInForce::GoodTillDate if expiry_ts <= now => {
+            msg!("expiry {:?} is not after the current time {:?}", expiry_ts, now);
+            Err(ErrorCode::InvalidExpiry.into())
+        }
+        TimeInForce::GoodTillDate => Ok(expiry_ts),
+        TimeInForce::Day => Ok((now.div_euclid(SECONDS_PER_DAY) + 1) * SECONDS_PER_DAY),
+    }
+}
+
+impl StoplossState {
+    /// Leaves on the side the order pays with, quote for buys and base for sells.
+    /// The order is filled when this reaches the reserved bounty.
+    pub fn paying_leaves_qty(&self) -> u64 {
+        match self.side {
+            Side::Bid => self.pc_leaves_qty,
+            Side::Ask => self.coin_leaves_qty,
+        }
+    }
+
+    /// The keeper bounty still to be paid, in the paying mint. It is held back out of the
+    /// paying leaves for every child, the signal provider's too, so a keeper can always be
+    /// paid. Refunded with the rest of the vault if the order fills without a keeper.
+    pub fn reserved_bounty(&self) -> u64 {
+        match self.keeper_allowed && !self.keeper_bounty_paid {
+            true => self.keeper_bounty,
+            false => 0,
+        }
+    }
+
+    /// Books one child order's fill against the order.
+    ///
+    /// With `reuse_unfilled` the order stays open until the paying leaves reach zero.
+    /// Without it the order runs one child only, so anything unfilled cancels the order.
+    pub fn apply_child_fill(&mut self, fill: &ChildFill, reuse_unfilled: bool) -> Result<()> {
+        let pc_qty_filled = fill.pc_qty;
+        let coin_qty_filled = fill.coin_qty;
+        if pc_qty_filled == 0 && coin_qty_filled == 0 {
+            if !reuse_unfilled {
+                self.ord_status = OrdStatus::Cancelled;
+            }
+            return Ok(());
+        }
+
+        // the receiving side leaves are only a cap, eg max_coin_qty on a buy, so saturate those.
+        // buys pay the fee on top out of the deposit.
+        match self.side {
+            Side::Bid => {
+                let pc_paid = pc_qty_filled.checked_add(fill.fee).ok_or(ErrorCode::NumericalOverflow)?;
+                self.pc_leaves_qty = self.pc_leaves_qty.checked_sub(pc_paid).ok_or(ErrorCode::FillExceedsLeaves)?;
+                self.coin_leaves_qty = self.coin_leaves_qty.saturating_sub(coin_qty_filled);
+            }
+            Side::Ask => {
+                self.coin_leaves_qty = self.coin_leaves_qty.checked_sub(coin_qty_filled).ok_or(ErrorCode::FillExceedsLeaves)?;
+                self.pc_leaves_qty = self.pc_leaves_qty.saturating_sub(pc_qty_filled);
+            }
+        }
+        self.pc_cum_qty = self.pc_cum_qty.checked_add(pc_qty_filled).ok_or(ErrorCode::NumericalOverflow)?;
+        self.coin_cum_qty = self.coin_cum_qty.checked_add(coin_qty_filled).ok_or(ErrorCode::NumericalOverflow)?;
+        self.fees_paid = self.fees_paid.checked_add(fill.fee).ok_or(ErrorCode::NumericalOverflow)?;
+
+        self.last_price = Price::from_fill(pc_qty_filled, coin_qty_filled);
+        self.avg_price = Price::from_fill(self.pc_cum_qty, self.coin_cum_qty);
+
+        // buys are sized in base, so they are also done once they reach their max_coin_qty cap.
+        // Any quote left over is refunded when the vaults are closed.
+        self.ord_status = if self.paying_leaves_qty() <= self.reserved_bounty() || self.coin_leaves_qty == 0 {
+            OrdStatus::Filled
+        } else if reuse_unfilled {
+            OrdStatus::PartiallyFilled
+        } else {
+            OrdStatus::Cancelled
+        };
+        Ok(())
+    }
+
+    /// Books what a resting child has filled since it was last settled. Settling does not
+    /// resume a suspended order, unless the fill completes it.
+    pub fn apply_resting_fill(&mut self, fill: &ChildFill) -> Result<()> {
+        let suspended = self.ord_status == OrdStatus::Suspended;
+        self.apply_child_fill(fill, true)?;
+        if suspended && self.ord_status != OrdStatus::Filled {
+            self.ord_status = OrdStatus::Suspended;
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the order for the status changed event.
+    pub fn update_event(&self) -> StoplossOrderUpdate {
+        StoplossOrderUpdate {
+            own_address: self.own_address,
+            market: self.market,
+            client_order_id: self.client_order_id,
+            side: self.side,
+            order_kind: self.order_kind,
+            limit_price: self.limit_price,
+            trigger_price: self.effective_trigger_price(),
+            max_coin_qty: self.max_coin_qty,
+            max_pc_qty: self.max_pc_qty,
+            coin_leaves_qty: self.coin_leaves_qty,
+            pc_leaves_qty: self.pc_leaves_qty,
+            coin_cum_qty: self.coin_cum_qty,
+            pc_cum_qty: self.pc_cum_qty,
+            last_price: self.last_price,
+            avg_price: self.avg_price,
+            fees_paid: self.fees_paid,
+            protocol_fees_paid: self.protocol_fees_paid,
+            child_order_count: self.child_order_count,
+            expiry_ts: self.expiry_ts,
+            ord_status: self.ord_status.clone(),
+        }
+    }
+}
+
+// events cant have structs as elements, so each event carries the order fields flattened out.
+// That lets indexers follow an order without a getAccountInfo for every update.
 
-    // fix like fields
+// Emitted whenever an order's status, fills or trigger change.
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossOrderUpdate {
+    pub own_address: Pubkey,
+    pub market: Pubkey,
+    pub client_order_id: u64,
     pub side: Side,
+    pub order_kind: OrderKind,
     pub limit_price: u64,
-    pub client_order_id: u64,
-    // remaining on the order
+    pub trigger_price: u64,
+    pub max_coin_qty: u64,
+    pub max_pc_qty: u64,
     pub coin_leaves_qty: u64,
     pub pc_leaves_qty: u64,
-    // filled on the order
     pub coin_cum_qty: u64,
     pub pc_cum_qty: u64,
-    // price of the last fill, including fees
-    pub last_price: u64,
-    // avg price of all fills, including fees
-    pub avg_price: u64,
+    pub last_price: Price,
+    pub avg_price: Price,
+    pub fees_paid: u64,
+    pub protocol_fees_paid: u64,
+    pub child_order_count: u64,
+    pub expiry_ts: i64,
     pub ord_status: OrdStatus,
+}
 
-    // signal provider is the authority used to execute child orders, eg an algo server
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossOrderCreated {
+    pub own_address: Pubkey,
+    pub market: Pubkey,
+    pub client_order_id: u64,
+    pub side: Side,
+    pub order_kind: OrderKind,
+    pub trigger_type: TriggerType,
+    pub order_mode: OrderMode,
+    pub limit_price: u64,
+    pub trigger_price: u64,
+    pub max_coin_qty: u64,
+    pub max_pc_qty: u64,
+    pub amend_authority: Pubkey,
     pub signal_provider: Pubkey,
-    pub child_order_count: u64,
-    pub client_paying_account: Pubkey,
-
-    pub coin_mint: Pubkey,
-    pub pc_mint: Pubkey,
+    pub keeper_allowed: bool,
+    pub keeper_bounty: u64,
+    pub time_in_force: TimeInForce,
+    pub expiry_ts: i64,
+    pub ord_status: OrdStatus,
+}
 
-    pub should_create_open_orders: bool,
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossOrderAmended {
+    pub own_address: Pubkey,
+    pub client_order_id: u64,
+    pub limit_price: u64,
+    pub trigger_price: u64,
+    pub max_coin_qty: u64,
+    pub max_pc_qty: u64,
+    pub coin_leaves_qty: u64,
+    pub pc_leaves_qty: u64,
+    pub ord_status: OrdStatus,
 }
 
-// dont seem to be able to emit events that have structs as elements, otherwise I would re-use stoplossstates
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossOrderCancelled {
+    pub own_address: Pubkey,
+    pub client_order_id: u64,
+    // what was left in the order's vaults and returned to the client
+    pub refunded_coin_qty: u64,
+    pub refunded_pc_qty: u64,
+    pub coin_cum_qty: u64,
+    pub pc_cum_qty: u64,
+    pub ord_status: OrdStatus,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct StoplossOrderUpdate {
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossOrderExpired {
     pub own_address: Pubkey,
+    pub client_order_id: u64,
+    pub expiry_ts: i64,
+    // what was left in the order's vaults and returned to the client
+    pub refunded_coin_qty: u64,
+    pub refunded_pc_qty: u64,
+    pub coin_cum_qty: u64,
+    pub pc_cum_qty: u64,
+    pub ord_status: OrdStatus,
 }
 
-impl From<StoplossState> for StoplossOrderUpdate {
-    fn from(s: StoplossState) -> Self {
-        StoplossOrderUpdate { own_address: s.own_address }
-    }
+// Emitted whenever the program config changes.
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossConfigUpdate {
+    pub admin: Pubkey,
+    pub signal_providers: Vec<Pubkey>,
+    pub protocol_fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    pub max_slippage_bps: u16,
+    pub halted: bool,
+    pub oracle_program: Pubkey,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, BorshSerialize, BorshDeserialize)]
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossFeesWithdrawn {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub recipient: Pubkey,
+}
+
+// Emitted for every child order executed against the DEX.
+#[event]
+#[derive(Debug, Clone)]
 pub struct StoplossCreatedChildOrder {
     pub parent_address: Pubkey,
     pub parent_order_id: u64,
@@ -998,14 +3310,39 @@ pub struct StoplossCreatedChildOrder {
     pub parent_child_composite_id: u64,
     pub market: Pubkey,
     pub side: Side,
+    // native base sent to the DEX, ie execute_qty rounded down to whole lots
     pub requested_qty: u64,
+    // the child's execute_limit, in price lots
     pub limit_price: u64,
     pub base_filled_qty: u64,
     pub quote_filled_qty: u64,
-    pub price: String,
+    // of this child's fill, zero if nothing filled
+    pub price: Price,
+    // quote fee charged by the DEX, not included in quote_filled_qty
+    pub fee_paid: u64,
+    // taken from the proceeds, ie quote for sells and base for buys
+    pub protocol_fee_paid: u64,
     pub filled_time: i64,
     pub ord_status: OrdStatus,
     pub order_type: OrderType,
+    // the signal provider, or the keeper for permissionless executions
+    pub executed_by: Pubkey,
+    pub keeper_bounty_paid: u64,
+}
+
+// Emitted when settle_order or cancel_child_order books the fills of a resting child.
+#[event]
+#[derive(Debug, Clone)]
+pub struct StoplossChildOrderSettled {
+    pub parent_address: Pubkey,
+    pub parent_child_composite_id: u64,
+    pub base_filled_qty: u64,
+    // maker fills pay no DEX fee, so this is what actually traded
+    pub quote_filled_qty: u64,
+    pub protocol_fee_paid: u64,
+    // still locked in the child on the book, in the paying mint
+    pub resting_qty: u64,
+    pub ord_status: OrdStatus,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -1017,6 +3354,7 @@ pub enum OrdStatus {
     Rejected = 7,
     Suspended = 9,
     PendingInit = 10,
+    Expired = 12,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
@@ -1034,7 +3372,45 @@ impl From<Side> for SerumSide {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, AnchorSerialize, AnchorDeserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
+pub enum OrderKind {
+    StopLoss,
+    TakeProfit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
+pub enum TriggerType {
+    Fixed,
+    TrailingAbsolute,
+    TrailingBps,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    GoodTillDate,
+    // expires at the end of the UTC day the order was created on
+    Day,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
+pub enum Strategy {
+    None,
+    // even slices over a time window
+    Twap,
+    // children capped at a share of the size of the best opposite order, until the window ends
+    Vwap,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, AnchorSerialize, AnchorDeserialize)]
+pub enum OrderMode {
+    // children trade at the signal provider's execute_limit
+    StopLimit,
+    // children take the best prices within the config's max slippage of the trigger
+    StopMarket,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, AnchorSerialize, AnchorDeserialize, Debug, PartialEq)]
 pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
@@ -1051,131 +3427,398 @@ impl From<OrderType> for SerumOrderType {
     }
 }
 
-// used to work out the price on a fill
-// should be easier than this tbh
-// ONLY use from context of execute method.
-pub struct PositionChanges {
-    pub side: Side,
-    pub client_coin_bal_before: u64,
-    pub client_pc_bal_before: u64,
-    pub sl_coin_bal_before: u64,
-    pub sl_pc_bal_before: u64,
-
-    pub client_coin_bal_after: u64,
-    pub client_pc_bal_after: u64,
-    pub sl_coin_bal_after: u64,
-    pub sl_pc_bal_after: u64,
+// the balances execute_order reads off the DEX open orders account, in native units.
+// totals include funds locked in resting orders, free is what SettleFunds would pay out.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OpenOrdersBalances {
+    pub coin_free: u64,
+    pub coin_total: u64,
+    pub pc_free: u64,
+    pub pc_total: u64,
 }
 
-impl Default for PositionChanges {
-    fn default() -> PositionChanges {
-        PositionChanges {
-            side: Side::Bid,
-            client_coin_bal_before: 0,
-            client_pc_bal_before: 0,
-            sl_coin_bal_before: 0,
-            sl_pc_bal_before: 0,
-            client_coin_bal_after: 0,
-            client_pc_bal_after: 0,
-            sl_coin_bal_after: 0,
-            sl_pc_bal_after: 0,
+impl OpenOrdersBalances {
+    // serum accounts are wrapped in b"serum" and b"padding". The open orders account flags,
+    // market and owner come before the balances.
+    const HEAD_PADDING: &'static [u8] = b"serum";
+    const TAIL_PADDING: &'static [u8] = b"padding";
+    const FLAGS_OFFSET: usize = 5;
+    const COIN_FREE_OFFSET: usize = Self::FLAGS_OFFSET + 8 + 32 + 32;
+
+    pub fn load(open_orders: &AccountInfo) -> Result<Self> {
+        Self::from_data(&open_orders.try_borrow_data()?)
+    }
+
+    pub fn from_data(data: &[u8]) -> Result<Self> {
+        if data.len() != OPEN_ORDERS_LEN || !data.starts_with(Self::HEAD_PADDING) || !data.ends_with(Self::TAIL_PADDING) {
+            msg!("not a DEX open orders account");
+            return Err(ErrorCode::IncorrectOpenOrdersAccount.into());
+        }
+        let read = |start: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[start..start + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let flags = read(Self::FLAGS_OFFSET);
+        if flags != (AccountFlag::Initialized | AccountFlag::OpenOrders).bits() {
+            msg!("open orders account flags {:?} are not initialised open orders", flags);
+            return Err(ErrorCode::IncorrectOpenOrdersAccount.into());
+        }
+        Ok(OpenOrdersBalances {
+            coin_free: read(Self::COIN_FREE_OFFSET),
+            coin_total: read(Self::COIN_FREE_OFFSET + 8),
+            pc_free: read(Self::COIN_FREE_OFFSET + 16),
+            pc_total: read(Self::COIN_FREE_OFFSET + 24),
+        })
+    }
+
+    /// Total on the side an order on `side` pays with, including anything locked in orders.
+    pub fn paying_total(&self, side: Side) -> u64 {
+        match side {
+            Side::Bid => self.pc_total,
+            Side::Ask => self.coin_total,
         }
     }
 }
 
-impl PositionChanges {
-    fn record_before(&mut self, side: Side, client_coin: u64, client_pc: u64, sl_coin: u64, sl_pc: u64) {
-        self.side = side;
-        self.client_coin_bal_before = client_coin;
-        self.client_pc_bal_before = client_pc;
-        self.sl_coin_bal_before = sl_coin;
-        self.sl_pc_bal_before = sl_pc;
+// the aggregate price out of a pyth price account. The price is `price * 10^expo` quote per
+// base in UI units, and `conf` is the confidence interval on the same scale.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OraclePrice {
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub status: u32,
+    // unix timestamp the aggregate price was published at
+    pub timestamp: i64,
+}
 
-        msg!(
-            "sl_coin_bal_before {:?} sl_coin_bal_after {:?}",
-            self.sl_coin_bal_before,
-            self.sl_coin_bal_after
-        );
-        msg!("sl_pc_bal_before {:?} sl_pc_bal_after {:?}", self.sl_pc_bal_before, self.sl_pc_bal_after);
-        msg!(
-            "client_coin_bal_before {:?} client_coin_bal_after {:?}",
-            self.client_coin_bal_before,
-            self.client_coin_bal_after
-        );
-        msg!(
-            "client_pc_bal_before {:?} client_pc_bal_after {:?}",
-            self.client_pc_bal_before,
-            self.client_pc_bal_after
-        );
+impl OraclePrice {
+    pub const MAGIC: u32 = 0xa1b2_c3d4;
+    pub const VERSION: u32 = 2;
+    pub const PRICE_ACCOUNT: u32 = 3;
+    pub const TRADING: u32 = 1;
+    // offsets into a pyth v2 price account
+    pub const EXPO_OFFSET: usize = 20;
+    pub const TIMESTAMP_OFFSET: usize = 96;
+    pub const AGG_PRICE_OFFSET: usize = 208;
+    pub const AGG_CONF_OFFSET: usize = 216;
+    pub const AGG_STATUS_OFFSET: usize = 224;
+
+    pub fn load(oracle: &AccountInfo) -> Result<Self> {
+        Self::from_data(&oracle.try_borrow_data()?)
     }
-    fn record_after(&mut self, client_coin: u64, client_pc: u64, sl_coin: u64, sl_pc: u64) {
-        self.client_coin_bal_after = client_coin;
-        self.client_pc_bal_after = client_pc;
-        self.sl_coin_bal_after = sl_coin;
-        self.sl_pc_bal_after = sl_pc;
-
-        msg!(
-            "sl_coin_bal_before {:?} sl_coin_bal_after {:?}",
-            self.sl_coin_bal_before,
-            self.sl_coin_bal_after
-        );
 
-        msg!("sl_pc_bal_before {:?} sl_pc_bal_after {:?}", self.sl_pc_bal_before, self.sl_pc_bal_after);
-        msg!(
-            "client_coin_bal_before {:?} client_coin_bal_after {:?}",
-            self.client_coin_bal_before,
-            self.client_coin_bal_after
-        );
-        msg!(
-            "client_pc_bal_before {:?} client_pc_bal_after {:?}",
-            self.client_pc_bal_before,
-            self.client_pc_bal_after
-        );
+    pub fn from_data(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::AGG_STATUS_OFFSET + 4 {
+            return Err(ErrorCode::InvalidOracleAccount.into());
+        }
+        let read = |start: usize, len: usize| {
+            let mut bytes = [0u8; 8];
+            bytes[..len].copy_from_slice(&data[start..start + len]);
+            u64::from_le_bytes(bytes)
+        };
+        if read(0, 4) as u32 != Self::MAGIC || read(4, 4) as u32 != Self::VERSION || read(8, 4) as u32 != Self::PRICE_ACCOUNT {
+            msg!("not a pyth v2 price account");
+            return Err(ErrorCode::InvalidOracleAccount.into());
+        }
+        Ok(OraclePrice {
+            price: read(Self::AGG_PRICE_OFFSET, 8) as i64,
+            conf: read(Self::AGG_CONF_OFFSET, 8),
+            expo: read(Self::EXPO_OFFSET, 4) as u32 as i32,
+            status: read(Self::AGG_STATUS_OFFSET, 4) as u32,
+            timestamp: read(Self::TIMESTAMP_OFFSET, 8) as i64,
+        })
     }
 
-    fn calc_fill_price(&self) -> u64 {
-        let sl_coin_delta = self.sl_coin_delta();
-        let sl_pc_delta = self.sl_pc_delta();
-        let client_coin_delta = self.client_coin_delta();
-        let client_pc_delta = self.client_pc_delta();
+    /// The price in price lots, rounded down. None when the price is not positive or does not
+    /// fit.
+    pub fn price_lots(&self, lots: &LotSizes) -> Option<u64> {
+        if self.price <= 0 {
+            return None;
+        }
+        // ui price * 10^pc_decimals / 10^coin_decimals is native quote per native base
+        let exponent = self.expo + lots.pc_decimals as i32 - lots.coin_decimals as i32;
+        let mut numerator = self.price as u128 * lots.coin_lot_size as u128;
+        let mut denominator = lots.pc_lot_size as u128;
+        if exponent >= 0 {
+            numerator = numerator.checked_mul(10u128.checked_pow(exponent as u32)?)?;
+        } else {
+            denominator = denominator.checked_mul(10u128.checked_pow((-exponent) as u32)?)?;
+        }
+        if denominator == 0 {
+            return None;
+        }
+        u64::try_from(numerator / denominator).ok()
+    }
 
-        let e = u64::pow(10, 6);
+    /// The price in price lots, if the oracle is trading, no more than `max_age` seconds old
+    /// at `now` and its confidence interval is within `max_confidence_bps` of the price. Zero
+    /// skips the confidence check.
+    pub fn checked_price_lots(&self, lots: &LotSizes, now: i64, max_age: u64, max_confidence_bps: u16) -> Result<u64> {
+        if self.status != Self::TRADING {
+            msg!("oracle status {:?} is not trading", self.status);
+            return Err(ErrorCode::OraclePriceUnavailable.into());
+        }
+        let age = now.saturating_sub(self.timestamp).max(0) as u64;
+        if age > max_age {
+            msg!("oracle price is {:?}s old, max {:?}s", age, max_age);
+            return Err(ErrorCode::OraclePriceStale.into());
+        }
+        if max_confidence_bps > 0 && self.conf as u128 * 10_000 > self.price.max(0) as u128 * max_confidence_bps as u128 {
+            msg!("oracle confidence {:?} too wide for price {:?}", self.conf, self.price);
+            return Err(ErrorCode::OracleConfidenceTooWide.into());
+        }
+        self.price_lots(lots).ok_or_else(|| {
+            msg!("oracle price {:?} expo {:?} not representable in price lots", self.price, self.expo);
+            ErrorCode::OraclePriceUnavailable.into()
+        })
+    }
+}
 
-        let coin_delta = client_coin_delta + sl_coin_delta;
+/// One child order's fill. `pc_qty` is the traded quote excluding the DEX fee, which
+/// is held separately in `fee`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChildFill {
+    pub coin_qty: u64,
+    pub pc_qty: u64,
+    pub fee: u64,
+}
 
-        if coin_delta == 0 {
-            return 0;
+impl ChildFill {
+    /// Works out the fill from the open orders balances either side of NewOrderV3.
+    ///
+    /// `deposit` is what the DEX took from the order's paying vault. Taker fills are
+    /// credited to the open orders account straight away, so for a sell the coin sold is
+    /// whatever was deposited but did not come back, and the quote received is the increase
+    /// in the quote total. Buys are the mirror image. The quote the DEX credits or debits
+    /// includes its taker fee, `fee`, as charged on the child's taker fill event.
+    pub fn from_open_orders(side: Side, before: &OpenOrdersBalances, after: &OpenOrdersBalances, deposit: u64, fee: u64) -> Result<Self> {
+        match side {
+            Side::Ask => {
+                let coin_qty = before
+                    .coin_total
+                    .checked_add(deposit)
+                    .and_then(|c| c.checked_sub(after.coin_total))
+                    .ok_or(ErrorCode::NumericalOverflow)?;
+                let net_pc = after.pc_total.checked_sub(before.pc_total).ok_or(ErrorCode::NumericalOverflow)?;
+                Ok(ChildFill {
+                    coin_qty,
+                    pc_qty: net_pc.checked_add(fee).ok_or(ErrorCode::NumericalOverflow)?,
+                    fee,
+                })
+            }
+            Side::Bid => {
+                let coin_qty = after.coin_total.checked_sub(before.coin_total).ok_or(ErrorCode::NumericalOverflow)?;
+                let pc_paid = before
+                    .pc_total
+                    .checked_add(deposit)
+                    .and_then(|c| c.checked_sub(after.pc_total))
+                    .ok_or(ErrorCode::NumericalOverflow)?;
+                Ok(ChildFill {
+                    coin_qty,
+                    pc_qty: pc_paid.checked_sub(fee).ok_or(ErrorCode::NumericalOverflow)?,
+                    fee,
+                })
+            }
         }
+    }
 
-        msg!("calc_fill_price sl_pc_delta {:?} client_pc_delta {:?}", sl_pc_delta, client_pc_delta);
-        let px = ((sl_pc_delta) - (client_pc_delta)) / coin_delta;
+    /// Works out what a resting child has filled from the open orders balances before settling.
+    ///
+    /// The order's open orders account only holds the resting child's funds by then, so for a
+    /// sell whatever of `resting_qty` is no longer on the account was sold, and all the quote
+    /// is proceeds. Buys are the mirror image. Resting orders fill as the maker so there is no
+    /// taker fee to split out, and any maker rebate simply counts towards the quote.
+    pub fn from_resting(side: Side, resting_qty: u64, balances: &OpenOrdersBalances) -> Result<Self> {
+        match side {
+            Side::Ask => Ok(ChildFill {
+                coin_qty: resting_qty.checked_sub(balances.coin_total).ok_or(ErrorCode::NumericalOverflow)?,
+                pc_qty: balances.pc_total,
+                fee: 0,
+            }),
+            Side::Bid => Ok(ChildFill {
+                coin_qty: balances.coin_total,
+                pc_qty: resting_qty.checked_sub(balances.pc_total).ok_or(ErrorCode::NumericalOverflow)?,
+                fee: 0,
+            }),
+        }
+    }
 
-        return px;
+    /// Quote the client actually receives for a sell.
+    pub fn net_pc_qty(&self) -> u64 {
+        self.pc_qty.saturating_sub(self.fee)
     }
+}
 
-    fn client_coin_delta(&self) -> u64 {
-        return self.delta(self.client_coin_bal_before, self.client_coin_bal_after);
+// the fee at the base tier, which is the dearest, so buys sized with it never lock too
+// little quote. Fills use the fee the DEX actually charged, see taker_fee_charged.
+fn taker_fee(pc_qty: u64) -> u64 {
+    FeeTier::Base.taker_fee(pc_qty)
+}
+
+// largest gross with gross + fee(gross) <= paid. The fee is rounded up by the DEX so
+// this cant be inverted directly, but it is monotonic so a binary search finds it.
+fn gross_from_total_paid(paid: u64) -> u64 {
+    let (mut lo, mut hi) = (0u64, paid);
+    while lo < hi {
+        let mid = hi - (hi - lo) / 2;
+        if mid.saturating_add(taker_fee(mid)) <= paid {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
     }
+    lo
+}
 
-    fn client_pc_delta(&self) -> u64 {
-        return self.delta(self.client_pc_bal_before, self.client_pc_bal_after);
+impl<'info> MarketAccounts<'info> {
+    // checks every market account against the ones recorded on the order at new_order time,
+    // and the DEX vaults against the ones recorded on the market itself.
+    fn validate_accounts(&self, stoploss: &StoplossState, dex_pid: &Pubkey) -> ProgramResult {
+        check_account("market", self.market.key, &stoploss.market, ErrorCode::IncorrectMarketAccount)?;
+        check_account("open orders", self.open_orders.key, &stoploss.stoploss_open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        check_account("request queue", self.request_queue.key, &stoploss.request_queue, ErrorCode::IncorrectRequestQueueAccount)?;
+        check_account("event queue", self.event_queue.key, &stoploss.event_queue, ErrorCode::IncorrectEventQueueAccount)?;
+        check_account("bids", self.bids.key, &stoploss.bids, ErrorCode::IncorrectBidsAccount)?;
+        check_account("asks", self.asks.key, &stoploss.asks, ErrorCode::IncorrectAsksAccount)?;
+        check_account("order payer", self.order_payer_token_account.key, &stoploss.payer, ErrorCode::IncorrectOrderPayerAccount)?;
+        check_account("vault signer", self.vault_signer.key, &stoploss.vault_signer, ErrorCode::IncorrectVaultSignerAccount)?;
+        check_account("client coin wallet", self.coin_wallet.to_account_info().key, &stoploss.client_coin_wallet, ErrorCode::IncorrectClientCoinWallet)?;
+        check_account("client pc wallet", self.pc_wallet.to_account_info().key, &stoploss.client_pc_wallet, ErrorCode::IncorrectClientPcWallet)?;
+        check_account("coin mint", self.coin_mint.key, &stoploss.coin_mint, ErrorCode::IncorrectCoinMint)?;
+        check_account("pc mint", self.pc_mint.key, &stoploss.pc_mint, ErrorCode::IncorrectPcMint)?;
+
+        // The loaded market must be dropped before CPI.
+        let market = MarketState::load(&self.market, dex_pid).map_err(|_| ErrorCode::IncorrectMarketAccount)?;
+        if identity(market.coin_vault) != self.coin_vault.key.to_aligned_bytes() || identity(market.pc_vault) != self.pc_vault.key.to_aligned_bytes() {
+            msg!("DEX vaults do not match the market. coin {:?} pc {:?}", self.coin_vault.key, self.pc_vault.key);
+            return Err(ErrorCode::IncorrectDexVaultAccount.into());
+        }
+        Ok(())
     }
+}
 
-    fn sl_coin_delta(&self) -> u64 {
-        return self.delta(self.sl_coin_bal_before, self.sl_coin_bal_after);
+impl<'info> ExecuteOrder<'info> {
+    // the vaults and programs are all recorded at new_order time. Swapping any of them, eg passing
+    // the DEX market vaults as the stoploss vaults, would let a child order trade with funds that
+    // do not belong to the order.
+    fn validate_accounts(&self) -> ProgramResult {
+        let stoploss = &self.stoploss_state;
+        check_account("dex program", self.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+        check_account("stoploss program", self.stoploss_program.key, &stoploss.stoploss_program, ErrorCode::IncorrectStoplossProgram)?;
+        check_account("pda", self.pda.key, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", self.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+        check_account("stoploss base vault", self.stoploss_base_vault.key, &stoploss.stoploss_base_vault, ErrorCode::IncorrectStoplossBaseVault)?;
+        check_account("stoploss quote vault", self.stoploss_quote_vault.key, &stoploss.stoploss_quote_vault, ErrorCode::IncorrectStoplossQuoteVault)?;
+        check_account("stoploss open orders", self.stoploss_open_orders.key, &stoploss.stoploss_open_orders, ErrorCode::IncorrectOpenOrdersAccount)?;
+        check_account("amend authority", self.amend_authority.key, &stoploss.amend_authority, ErrorCode::IncorrectAmendAccount)?;
+        if stoploss.oracle != Pubkey::default() {
+            check_account("oracle", self.oracle.key, &stoploss.oracle, ErrorCode::IncorrectOracleAccount)?;
+        }
+        self.market.validate_accounts(stoploss, self.dex_program.key)
     }
+}
 
-    fn sl_pc_delta(&self) -> u64 {
-        return self.delta(self.sl_pc_bal_before, self.sl_pc_bal_after);
+impl<'info> SettleOrder<'info> {
+    fn validate_accounts(&self, program_id: &Pubkey) -> ProgramResult {
+        let stoploss = &self.stoploss_state;
+        check_account("dex program", self.dex_program.key, &stoploss.dex_program, ErrorCode::IncorrectDexProgram)?;
+        check_account("pda", self.pda.key, &stoploss.pda, ErrorCode::IncorrectPdaAccount)?;
+        check_account("token program", self.token_program.key, &spl_token::ID, ErrorCode::IncorrectTokenProgram)?;
+        check_account("stoploss base vault", self.stoploss_base_vault.key, &stoploss.stoploss_base_vault, ErrorCode::IncorrectStoplossBaseVault)?;
+        check_account("stoploss quote vault", self.stoploss_quote_vault.key, &stoploss.stoploss_quote_vault, ErrorCode::IncorrectStoplossQuoteVault)?;
+        check_account("amend authority", self.amend_authority.key, &stoploss.amend_authority, ErrorCode::IncorrectAmendAccount)?;
+        check_account("config", self.config.to_account_info().key, &config_address(program_id).0, ErrorCode::IncorrectConfigAccount)?;
+        let receiving_mint = match stoploss.side {
+            Side::Bid => stoploss.coin_mint,
+            Side::Ask => stoploss.pc_mint,
+        };
+        check_account("fee vault", self.fee_vault.key, &fee_vault_address(&receiving_mint, program_id).0, ErrorCode::IncorrectFeeVault)?;
+        self.market.validate_accounts(stoploss, self.dex_program.key)
     }
 
-    fn delta(&self, before: u64, after: u64) -> u64 {
-        // narsty assumption that nothing has gone wrong and the sizes after are the wrong way...
-        if before >= after {
-            return before.checked_sub(after).unwrap();
-        } else {
-            return after.checked_sub(before).unwrap();
+    // Books whatever the resting child has filled since it was placed or last settled, settles
+    // the open orders account into the order's vaults and pays the proceeds out.
+    fn settle_resting_child(&mut self, remaining_accounts: &[AccountInfo<'info>], program_id: &Pubkey) -> Result<()> {
+        let (_pda, nonce) = Pubkey::find_program_address(&[b"stoploss"], program_id);
+        let seeds = &[&b"stoploss"[..], &[nonce]];
+        let side = self.stoploss_state.side;
+        let child_order_id = self.stoploss_state.resting_client_order_id;
+
+        let balances = OpenOrdersBalances::load(&self.market.open_orders)?;
+        let fill = ChildFill::from_resting(side, self.stoploss_state.resting_qty, &balances)?;
+        settle_to_order_vaults(
+            &self.market,
+            &self.pda,
+            &self.stoploss_base_vault,
+            &self.stoploss_quote_vault,
+            &self.token_program,
+            &self.dex_program,
+            seeds,
+        )?;
+        // only what is still locked in the child's order is left once settled
+        let resting_qty = OpenOrdersBalances::load(&self.market.open_orders)?.paying_total(side);
+
+        let stoploss = &mut self.stoploss_state;
+        stoploss.resting_qty = resting_qty;
+        if resting_qty == 0 {
+            stoploss.resting_client_order_id = 0;
+        }
+        stoploss.apply_resting_fill(&fill)?;
+        msg!(
+            "child {:?} filled coin {:?} pc {:?}. resting {:?} status {:?}",
+            child_order_id,
+            fill.coin_qty,
+            fill.pc_qty,
+            resting_qty,
+            stoploss.ord_status
+        );
+
+        let (proceeds, receiving_vault, client_wallet) = match side {
+            Side::Bid => (fill.coin_qty, self.stoploss_base_vault.clone(), self.market.coin_wallet.to_account_info()),
+            Side::Ask => (fill.net_pc_qty(), self.stoploss_quote_vault.clone(), self.market.pc_wallet.to_account_info()),
+        };
+        let fee = pay_out_proceeds(
+            &mut self.stoploss_state,
+            proceeds,
+            self.config.protocol_fee_bps,
+            receiving_vault,
+            client_wallet,
+            self.fee_vault.clone(),
+            self.pda.clone(),
+            self.token_program.clone(),
+            seeds,
+        )?;
+
+        if self.stoploss_state.ord_status == OrdStatus::Filled {
+            cancel_sibling(&self.stoploss_state, remaining_accounts, program_id)?;
+            self.stoploss_state.coin_leaves_qty = 0;
+            self.stoploss_state.pc_leaves_qty = 0;
+            close_order_vaults(
+                &self.stoploss_state,
+                self.stoploss_base_vault.clone(),
+                self.stoploss_quote_vault.clone(),
+                self.market.coin_wallet.to_account_info(),
+                self.market.pc_wallet.to_account_info(),
+                self.amend_authority.clone(),
+                self.pda.clone(),
+                self.token_program.clone(),
+                seeds,
+            )?;
         }
+
+        emit!(self.stoploss_state.update_event());
+        emit!(StoplossChildOrderSettled {
+            parent_address: self.stoploss_state.own_address,
+            parent_child_composite_id: child_order_id,
+            base_filled_qty: fill.coin_qty,
+            quote_filled_qty: fill.pc_qty,
+            protocol_fee_paid: fee,
+            resting_qty,
+            ord_status: self.stoploss_state.ord_status.clone(),
+        });
+        Ok(())
     }
 }
 
@@ -1226,4 +3869,736 @@ pub enum ErrorCode {
     #[msg("Unable to refund tokens back to sender account")]
     TransferFailed,
     AlreadyInitialised,
+    #[msg("The market has not crossed the trigger price. Rejecting Execute instruction")]
+    TriggerPriceNotReached,
+    #[msg("Unable to read a market price from the order book to check the trigger")]
+    MarketPriceUnavailable,
+    #[msg("Market account does not match the order")]
+    IncorrectMarketAccount,
+    #[msg("Open orders account does not match the order")]
+    IncorrectOpenOrdersAccount,
+    #[msg("Request queue account does not match the order")]
+    IncorrectRequestQueueAccount,
+    #[msg("Event queue account does not match the order")]
+    IncorrectEventQueueAccount,
+    #[msg("Bids account does not match the order")]
+    IncorrectBidsAccount,
+    #[msg("Asks account does not match the order")]
+    IncorrectAsksAccount,
+    #[msg("Order payer token account does not match the order")]
+    IncorrectOrderPayerAccount,
+    #[msg("DEX vault accounts do not match the market")]
+    IncorrectDexVaultAccount,
+    #[msg("DEX vault signer does not match the order")]
+    IncorrectVaultSignerAccount,
+    #[msg("Client coin wallet does not match the order")]
+    IncorrectClientCoinWallet,
+    #[msg("Client pc wallet does not match the order")]
+    IncorrectClientPcWallet,
+    #[msg("Coin mint does not match the order")]
+    IncorrectCoinMint,
+    #[msg("Pc mint does not match the order")]
+    IncorrectPcMint,
+    #[msg("Stoploss base vault does not match the order")]
+    IncorrectStoplossBaseVault,
+    #[msg("Stoploss quote vault does not match the order")]
+    IncorrectStoplossQuoteVault,
+    #[msg("Stoploss paying vault does not match the order")]
+    IncorrectStoplossPayingVault,
+    #[msg("DEX program does not match the order")]
+    IncorrectDexProgram,
+    #[msg("Stoploss program does not match the order")]
+    IncorrectStoplossProgram,
+    #[msg("Stoploss PDA does not match the order")]
+    IncorrectPdaAccount,
+    #[msg("Token program is not the SPL token program")]
+    IncorrectTokenProgram,
+    #[msg("Stoploss receiving vault does not match the order")]
+    IncorrectStoplossReceivingVault,
+    #[msg("Order vault is not at the address derived from the order and mint")]
+    IncorrectOrderVaultAddress,
+    #[msg("The order does not have a trailing trigger")]
+    NotATrailingOrder,
+    #[msg("The bracket sibling order must be passed as the first remaining account")]
+    MissingSiblingAccount,
+    #[msg("Sibling account is not the other leg of this bracket")]
+    IncorrectSiblingAccount,
+    #[msg("The order is already part of a bracket or has started executing")]
+    AlreadyBracketed,
+    #[msg("Bracket legs share one deposit. Cannot amend quantity")]
+    CannotAmendBracketQuantity,
+    #[msg("Keeper bounty must be less than the order deposit")]
+    KeeperBountyTooLarge,
+    #[msg("Keeper bounty account must be for the order's paying mint")]
+    IncorrectKeeperBountyAccount,
+    #[msg("The order must be filled or cancelled with no leaves before it can be closed")]
+    OrderNotTerminal,
+    #[msg("The order's vault still holds funds")]
+    OrderVaultNotEmpty,
+    #[msg("Execute quantity must be non zero and no more than the order's leaves")]
+    ExecuteQtyExceedsLeaves,
+    #[msg("Child order filled more than the order's leaves")]
+    FillExceedsLeaves,
+    #[msg("Numerical overflow")]
+    NumericalOverflow,
+    #[msg("Expiry must be in the future for good till date orders")]
+    InvalidExpiry,
+    #[msg("The order's time in force has expired. Cannot execute")]
+    OrderExpired,
+    #[msg("The order is in expired state. Cannot execute")]
+    AttemptingToExecuteExpiredOrder,
+    #[msg("The order has not reached its expiry")]
+    OrderNotExpired,
+    #[msg("Order already expired")]
+    OrderAlreadyExpired,
+    #[msg("The order is suspended. Cannot execute")]
+    OrderSuspended,
+    #[msg("Order already suspended")]
+    OrderAlreadySuspended,
+    #[msg("The order is not suspended")]
+    OrderNotSuspended,
+    #[msg("Only New or PartiallyFilled orders can be suspended")]
+    OrderNotActive,
+    #[msg("Incorrect config account")]
+    IncorrectConfigAccount,
+    #[msg("Config changes must be signed by the admin")]
+    IncorrectAdminAccount,
+    #[msg("The program is halted")]
+    ProgramHalted,
+    #[msg("The signal provider is not approved in the program config")]
+    SignalProviderNotApproved,
+    #[msg("The signal provider is already approved")]
+    SignalProviderAlreadyApproved,
+    #[msg("No room for more signal providers in the program config")]
+    TooManySignalProviders,
+    #[msg("Basis points must be no more than 10000")]
+    InvalidBps,
+    #[msg("Incorrect fee vault for the mint")]
+    IncorrectFeeVault,
+    #[msg("Fees can only be withdrawn to an account owned by the fee recipient")]
+    IncorrectFeeRecipient,
+    #[msg("Order already has its open orders account")]
+    OpenOrdersAlreadyInitialised,
+    #[msg("Order is waiting for its open orders account to be initialised")]
+    OpenOrdersNotInitialised,
+    #[msg("Order has a child resting on the book. Cancel it first")]
+    ChildOrderResting,
+    #[msg("Order has no child resting on the book")]
+    NoRestingChildOrder,
+    #[msg("Only the owner or the signal provider can cancel a child order")]
+    IncorrectChildOrderAuthority,
+    #[msg("Bracket legs cannot rest child orders")]
+    RestingChildNotAllowed,
+    #[msg("Execute quantity is less than one base lot")]
+    ExecuteQtyBelowLotSize,
+    #[msg("The best price on the book is past the order's slippage cap")]
+    SlippageCapExceeded,
+    #[msg("Incorrect oracle account for the order")]
+    IncorrectOracleAccount,
+    #[msg("The oracle account is not a Pyth price account")]
+    InvalidOracleAccount,
+    #[msg("The oracle is not publishing a usable price")]
+    OraclePriceUnavailable,
+    #[msg("The oracle price is too old")]
+    OraclePriceStale,
+    #[msg("The oracle price's confidence interval is too wide")]
+    OracleConfidenceTooWide,
+    #[msg("The oracle can only be set before the order executes")]
+    OracleLockedAfterExecution,
+    #[msg("The strategy can only be set before the order executes")]
+    StrategyLockedAfterExecution,
+    #[msg("Invalid strategy parameters")]
+    InvalidStrategy,
+    #[msg("The order's strategy has not started yet")]
+    StrategyNotStarted,
+    #[msg("Too soon after the last child order for the order's strategy")]
+    ChildOrderTooSoon,
+    #[msg("The child order is larger than the strategy's schedule allows")]
+    ExceedsStrategySchedule,
+    #[msg("The child order is larger than the strategy's max participation")]
+    ExceedsMaxParticipation,
+    #[msg("Trailing orders trigger off their mark, their trigger price cannot be amended")]
+    CannotAmendTrailingTrigger,
+    #[msg("The oracle account is not owned by the configured oracle program")]
+    IncorrectOracleProgram,
+    #[msg("The oracle max age must be non zero")]
+    InvalidOracleMaxAge,
+    #[msg("The open orders rent must go back to the account that paid it")]
+    IncorrectOpenOrdersPayer,
+    #[msg("The order's strategy has ended")]
+    StrategyEnded,
+    #[msg("Orders with a strategy cannot change their quantity")]
+    CannotAmendStrategyQuantity,
+    #[msg("A buy's max coin qty must be non zero")]
+    InvalidMaxCoinQty,
+    #[msg("Orders that allow keepers cannot have a strategy")]
+    KeeperNotAllowedWithStrategy,
+    #[msg("The order has triggered, its trigger price cannot be amended")]
+    CannotAmendTriggeredTrigger,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const LOTS: LotSizes = LotSizes {
+        coin_lot_size: 100_000,
+        pc_lot_size: 100,
+        coin_decimals: 6,
+        pc_decimals: 6,
+    };
+
+    fn order(side: Side, max_coin_qty: u64, max_pc_qty: u64) -> StoplossState {
+        StoplossState {
+            own_address: Pubkey::default(),
+            market: Pubkey::default(),
+            stoploss_open_orders: Pubkey::default(),
+            request_queue: Pubkey::default(),
+            event_queue: Pubkey::default(),
+            bids: Pubkey::default(),
+            asks: Pubkey::default(),
+            payer: Pubkey::default(),
+            client_coin_wallet: Pubkey::default(),
+            client_pc_wallet: Pubkey::default(),
+            stoploss_base_vault: Pubkey::default(),
+            stoploss_quote_vault: Pubkey::default(),
+            dex_program: Pubkey::default(),
+            pda: Pubkey::default(),
+            stoploss_program: Pubkey::default(),
+            vault_signer: Pubkey::default(),
+            token_program: Pubkey::default(),
+            amend_authority: Pubkey::default(),
+            max_coin_qty,
+            max_pc_qty,
+            trigger_price: 0,
+            trigger_type: TriggerType::Fixed,
+            trailing_offset: 0,
+            trailing_mark: 0,
+            triggered: false,
+            order_kind: OrderKind::StopLoss,
+            order_mode: OrderMode::StopLimit,
+            sibling: Pubkey::default(),
+            side,
+            limit_price: 0,
+            client_order_id: 0,
+            coin_leaves_qty: max_coin_qty,
+            pc_leaves_qty: max_pc_qty,
+            coin_cum_qty: 0,
+            pc_cum_qty: 0,
+            last_price: Price::default(),
+            avg_price: Price::default(),
+            ord_status: OrdStatus::New,
+            signal_provider: Pubkey::default(),
+            child_order_count: 0,
+            client_paying_account: Pubkey::default(),
+            coin_mint: Pubkey::default(),
+            pc_mint: Pubkey::default(),
+            should_create_open_orders: false,
+            open_orders_payer: Pubkey::default(),
+            keeper_allowed: false,
+            keeper_bounty: 0,
+            keeper_bounty_paid: false,
+            fees_paid: 0,
+            protocol_fees_paid: 0,
+            time_in_force: TimeInForce::GoodTillCancel,
+            expiry_ts: 0,
+            resting_qty: 0,
+            resting_client_order_id: 0,
+            oracle: Pubkey::default(),
+            oracle_max_age: 0,
+            oracle_max_confidence_bps: 0,
+            strategy: Strategy::None,
+            strategy_start_ts: 0,
+            strategy_end_ts: 0,
+            slice_count: 0,
+            max_participation_bps: 0,
+            min_child_interval: 0,
+            last_child_ts: 0,
+        }
+    }
+
+    fn price(value: u64) -> Price {
+        Price { value }
+    }
+
+    fn fill(pc_qty: u64, coin_qty: u64, fee: u64) -> ChildFill {
+        ChildFill { coin_qty, pc_qty, fee }
+    }
+
+    fn oo(coin_total: u64, pc_total: u64) -> OpenOrdersBalances {
+        OpenOrdersBalances {
+            coin_free: coin_total,
+            coin_total,
+            pc_free: pc_total,
+            pc_total,
+        }
+    }
+
+    #[test]
+    fn sell_fills_over_several_children() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+
+        sl.apply_child_fill(&fill(4_000_000, 400_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+        assert_eq!(sl.coin_leaves_qty, 600_000);
+        assert_eq!(sl.coin_cum_qty, 400_000);
+        assert_eq!(sl.last_price, price(10_000_000_000));
+
+        sl.apply_child_fill(&fill(3_600_000, 300_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+        assert_eq!(sl.coin_leaves_qty, 300_000);
+        assert_eq!(sl.last_price, price(12_000_000_000));
+        assert_eq!(sl.avg_price, price(10_857_142_857));
+
+        sl.apply_child_fill(&fill(3_000_000, 300_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+        assert_eq!(sl.coin_leaves_qty, 0);
+        assert_eq!(sl.coin_cum_qty, 1_000_000);
+        assert_eq!(sl.pc_cum_qty, 10_600_000);
+        assert_eq!(sl.avg_price, price(10_600_000_000));
+    }
+
+    #[test]
+    fn buy_fills_over_several_children() {
+        let mut sl = order(Side::Bid, u64::MAX, 10_000_000);
+
+        sl.apply_child_fill(&fill(6_000_000, 600_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+        assert_eq!(sl.pc_leaves_qty, 4_000_000);
+        // the coin leaves on a buy are only a cap
+        assert_eq!(sl.coin_leaves_qty, u64::MAX - 600_000);
+
+        sl.apply_child_fill(&fill(4_000_000, 200_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+        assert_eq!(sl.pc_leaves_qty, 0);
+        assert_eq!(sl.pc_cum_qty, 10_000_000);
+        assert_eq!(sl.coin_cum_qty, 800_000);
+        assert_eq!(sl.last_price, price(20_000_000_000));
+        assert_eq!(sl.avg_price, price(12_500_000_000));
+    }
+
+    #[test]
+    fn buy_is_filled_at_its_coin_cap() {
+        let mut sl = order(Side::Bid, 600_000, 10_000_000);
+        sl.apply_child_fill(&fill(6_000_000, 600_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+        assert_eq!(sl.pc_leaves_qty, 4_000_000);
+    }
+
+    #[test]
+    fn order_is_filled_down_to_the_reserved_bounty() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.keeper_allowed = true;
+        sl.keeper_bounty = 100_000;
+        sl.apply_child_fill(&fill(8_000_000, 800_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+        sl.apply_child_fill(&fill(1_000_000, 100_000, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+        assert_eq!(sl.coin_leaves_qty, 100_000);
+
+        // nothing is held back once the keeper has been paid
+        sl.keeper_bounty_paid = true;
+        assert_eq!(sl.reserved_bounty(), 0);
+    }
+
+    #[test]
+    fn empty_child_keeps_reusable_order_open() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(0, 0, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::New);
+
+        sl.apply_child_fill(&fill(2_000_000, 200_000, 0), true).unwrap();
+        sl.apply_child_fill(&fill(0, 0, 0), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+        assert_eq!(sl.coin_leaves_qty, 800_000);
+        assert_eq!(sl.coin_cum_qty, 200_000);
+        assert_eq!(sl.avg_price, price(10_000_000_000));
+    }
+
+    #[test]
+    fn one_shot_partial_fill_cancels() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(2_000_000, 200_000, 0), false).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Cancelled);
+        assert_eq!(sl.coin_cum_qty, 200_000);
+
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(0, 0, 0), false).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Cancelled);
+
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(10_000_000, 1_000_000, 0), false).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+    }
+
+    #[test]
+    fn one_shot_after_partial_fills_keeps_cum() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(2_000_000, 200_000, 0), true).unwrap();
+        sl.apply_child_fill(&fill(0, 0, 0), false).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Cancelled);
+        assert_eq!(sl.coin_cum_qty, 200_000);
+        assert_eq!(sl.pc_cum_qty, 2_000_000);
+    }
+
+    #[test]
+    fn overfill_is_rejected() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.apply_child_fill(&fill(9_000_000, 900_000, 0), true).unwrap();
+        assert!(sl.apply_child_fill(&fill(2_000_000, 200_000, 0), true).is_err());
+        assert_eq!(sl.coin_leaves_qty, 100_000);
+        assert_eq!(sl.ord_status, OrdStatus::PartiallyFilled);
+    }
+
+    #[test]
+    fn sell_fill_from_open_orders() {
+        // 500k coin deposited, 300k sold. the unfilled 200k is unlocked back to free
+        let net = 3_000_000 - taker_fee(3_000_000);
+        let f = ChildFill::from_open_orders(Side::Ask, &oo(0, 0), &oo(200_000, net), 500_000, taker_fee(3_000_000)).unwrap();
+        assert_eq!(f.coin_qty, 300_000);
+        assert_eq!(f.pc_qty, 3_000_000);
+        assert_eq!(f.fee, taker_fee(3_000_000));
+        assert_eq!(f.net_pc_qty(), net);
+    }
+
+    #[test]
+    fn buy_fill_from_open_orders() {
+        // 5m pc deposited, 4m traded for 400k coin plus the fee
+        let paid = 4_000_000 + taker_fee(4_000_000);
+        let f = ChildFill::from_open_orders(Side::Bid, &oo(0, 0), &oo(400_000, 5_000_000 - paid), 5_000_000, taker_fee(4_000_000)).unwrap();
+        assert_eq!(f.coin_qty, 400_000);
+        assert_eq!(f.pc_qty, 4_000_000);
+        assert_eq!(f.fee, taker_fee(4_000_000));
+    }
+
+    #[test]
+    fn fill_uses_the_fee_the_dex_charged() {
+        // eg a stable market charged below the base tier
+        let f = ChildFill::from_open_orders(Side::Bid, &oo(0, 0), &oo(400_000, 5_000_000 - 4_000_400), 5_000_000, 400).unwrap();
+        assert_eq!(f, fill(4_000_000, 400_000, 400));
+
+        let f = ChildFill::from_open_orders(Side::Ask, &oo(0, 0), &oo(200_000, 2_999_700), 500_000, 300).unwrap();
+        assert_eq!(f, fill(3_000_000, 300_000, 300));
+        assert_eq!(f.net_pc_qty(), 2_999_700);
+    }
+
+    #[test]
+    fn fill_ignores_funds_already_on_open_orders() {
+        // unsettled balances from an earlier child are not counted again
+        let net = 1_000_000 - taker_fee(1_000_000);
+        let f = ChildFill::from_open_orders(Side::Ask, &oo(50_000, 7_000), &oo(50_000, 7_000 + net), 100_000, taker_fee(1_000_000)).unwrap();
+        assert_eq!(f.coin_qty, 100_000);
+        assert_eq!(f.pc_qty, 1_000_000);
+
+        let f = ChildFill::from_open_orders(Side::Bid, &oo(10, 20), &oo(10, 20 + 5_000_000), 5_000_000, 0).unwrap();
+        assert_eq!(f, fill(0, 0, 0));
+    }
+
+    fn open_orders_data(flags: u64, balances: [u64; 4]) -> Vec<u8> {
+        let mut data = b"serum".to_vec();
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(&[0u8; 64]);
+        for v in balances.iter() {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data.resize(OPEN_ORDERS_LEN - 7, 0);
+        data.extend_from_slice(b"padding");
+        data
+    }
+
+    #[test]
+    fn open_orders_balances_need_an_open_orders_account() {
+        let open_orders = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
+        let balances = OpenOrdersBalances::from_data(&open_orders_data(open_orders, [1, 2, 3, 4])).unwrap();
+        assert_eq!(balances, OpenOrdersBalances { coin_free: 1, coin_total: 2, pc_free: 3, pc_total: 4 });
+
+        // a market, an uninitialised account, and the right flags without the padding
+        let market = (AccountFlag::Initialized | AccountFlag::Market).bits();
+        assert!(OpenOrdersBalances::from_data(&open_orders_data(market, [1, 2, 3, 4])).is_err());
+        assert!(OpenOrdersBalances::from_data(&open_orders_data(AccountFlag::OpenOrders.bits(), [1, 2, 3, 4])).is_err());
+        let data = open_orders_data(open_orders, [1, 2, 3, 4]);
+        assert!(OpenOrdersBalances::from_data(&data[..data.len() - 7]).is_err());
+    }
+
+    #[test]
+    fn resting_fill_is_what_left_the_book() {
+        // sold 300_000 of a 500_000 resting sell for 1_500_000 quote, no taker fee
+        let f = ChildFill::from_resting(Side::Ask, 500_000, &oo(200_000, 1_500_000)).unwrap();
+        assert_eq!(f, fill(1_500_000, 300_000, 0));
+        assert_eq!(oo(200_000, 1_500_000).paying_total(Side::Ask), 200_000);
+
+        let f = ChildFill::from_resting(Side::Bid, 5_000_000, &oo(400_000, 3_000_000)).unwrap();
+        assert_eq!(f, fill(2_000_000, 400_000, 0));
+
+        // cancelled before anything traded
+        assert_eq!(ChildFill::from_resting(Side::Bid, 5_000_000, &oo(0, 5_000_000)).unwrap(), fill(0, 0, 0));
+    }
+
+    #[test]
+    fn gross_round_trips_through_fee() {
+        for &gross in [0u64, 1, 99, 12_345, 1_000_000, 987_654_321].iter() {
+            assert_eq!(gross_from_total_paid(gross + taker_fee(gross)), gross);
+        }
+    }
+
+    #[test]
+    fn buy_fee_comes_out_of_leaves() {
+        let mut sl = order(Side::Bid, u64::MAX, 10_000_000);
+        sl.apply_child_fill(&fill(9_000_000, 900_000, 20_000), true).unwrap();
+        assert_eq!(sl.pc_leaves_qty, 980_000);
+        assert_eq!(sl.pc_cum_qty, 9_000_000);
+        assert_eq!(sl.fees_paid, 20_000);
+        assert_eq!(sl.avg_price, price(10_000_000_000));
+
+        sl.apply_child_fill(&fill(978_000, 97_800, 2_000), true).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+        assert_eq!(sl.fees_paid, 22_000);
+    }
+
+    #[test]
+    fn price_lots_handles_no_coin() {
+        assert_eq!(LOTS.price_lots(1_000, 0), 0);
+        assert_eq!(LOTS.price_lots(1_000, 3), 333_333);
+    }
+
+    #[test]
+    fn lot_conversions_round_trip() {
+        // 1.5 base at 6.004 on the test market
+        assert_eq!(LOTS.coin_lots(1_550_000), 15);
+        assert_eq!(LOTS.round_coin(1_550_000), 1_500_000);
+        assert_eq!(LOTS.pc_qty(1_550_000, 6004), Some(9_006_000));
+        assert_eq!(LOTS.price_lots(9_006_000, 1_500_000), 6004);
+        assert_eq!(LOTS.pc_qty(u64::MAX, u64::MAX), None);
+        assert!((LOTS.price_ui(6004) - 6.004).abs() < 1e-9);
+        assert!((LOTS.coin_ui(1_500_000) - 1.5).abs() < 1e-9);
+        assert!((LOTS.pc_ui(9_006_000) - 9.006).abs() < 1e-9);
+
+        // the fee is paid on top, so the exact quote for 15 lots only buys 14
+        let pc = LOTS.pc_qty(1_500_000, 6004).unwrap();
+        assert_eq!(LOTS.coin_qty_for(pc + taker_fee(pc), 6004), 1_500_000);
+        assert_eq!(LOTS.coin_qty_for(pc, 6004), 1_400_000);
+        assert_eq!(LOTS.coin_qty_for(pc, 0), 0);
+    }
+
+    #[test]
+    fn price_keeps_precision_below_a_lot() {
+        assert_eq!(Price::from_fill(1, 3), price(333_333_333));
+        assert_eq!(Price::from_fill(1_000, 0), Price::default());
+        assert_eq!(Price::from_fill(u64::MAX, 1), price(u64::MAX));
+        assert_eq!(Price::from_lots(6004, &LOTS), price(6_004_000_000));
+        assert!((price(6_004_000_000).to_ui(&LOTS) - 6.004).abs() < 1e-9);
+    }
+
+    proptest! {
+        #[test]
+        fn fill_price_is_the_exact_ratio_rounded_down(pc_qty in any::<u64>(), coin_qty in 1..u64::MAX) {
+            let p = Price::from_fill(pc_qty, coin_qty).value as u128;
+            let exact = pc_qty as u128 * Price::SCALE as u128;
+            if p < u64::MAX as u128 {
+                prop_assert!(p * coin_qty as u128 <= exact);
+                prop_assert!((p + 1) * coin_qty as u128 > exact);
+            } else {
+                prop_assert!(exact / coin_qty as u128 >= u64::MAX as u128);
+            }
+        }
+
+        #[test]
+        fn avg_price_lies_between_fill_prices(pc1 in 0..1u64 << 40, coin1 in 1..1u64 << 40, pc2 in 0..1u64 << 40, coin2 in 1..1u64 << 40) {
+            let (a, b) = (Price::from_fill(pc1, coin1), Price::from_fill(pc2, coin2));
+            let avg = Price::from_fill(pc1 + pc2, coin1 + coin2);
+            prop_assert!(a.min(b) <= avg && avg <= a.max(b));
+        }
+
+        #[test]
+        fn price_lots_round_trip(price_lots in 0..1u64 << 40) {
+            prop_assert_eq!(Price::from_lots(price_lots, &LOTS).to_lots(&LOTS), price_lots);
+        }
+
+        #[test]
+        fn ui_price_matches_reference(price_lots in 1..1u64 << 40, coin_decimals in 0..10u8, pc_decimals in 0..10u8) {
+            let lots = LotSizes { coin_decimals, pc_decimals, ..LOTS };
+            let ui = Price::from_lots(price_lots, &lots).to_ui(&lots);
+            let reference = price_lots as f64 * 100.0 / 100_000.0 * 10f64.powi(coin_decimals as i32 - pc_decimals as i32);
+            prop_assert!((ui - reference).abs() <= reference * 1e-12);
+        }
+    }
+
+    #[test]
+    fn price_ui_scales_by_decimals() {
+        // 9 decimal base against 6 decimal quote
+        let lots = LotSizes { coin_decimals: 9, ..LOTS };
+        assert!((lots.price_ui(6004) - 6_004.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn expiry_by_time_in_force() {
+        // 2021-09-01 13:00:00 UTC
+        let now = 1_630_501_200;
+        assert_eq!(order_expiry(TimeInForce::GoodTillCancel, now - 1, now).unwrap(), 0);
+        assert_eq!(order_expiry(TimeInForce::GoodTillDate, now + 60, now).unwrap(), now + 60);
+        assert!(order_expiry(TimeInForce::GoodTillDate, now, now).is_err());
+        assert_eq!(order_expiry(TimeInForce::Day, 0, now).unwrap(), 1_630_540_800);
+        // created exactly at midnight runs for the whole day
+        assert_eq!(order_expiry(TimeInForce::Day, 0, 1_630_540_800).unwrap(), 1_630_627_200);
+    }
+
+    #[test]
+    fn resumes_to_previous_working_status() {
+        let mut sl = order(Side::Bid, 0, 6_000_000);
+        sl.ord_status = OrdStatus::Suspended;
+        assert_eq!(sl.resumed_status(), OrdStatus::New);
+
+        sl.apply_child_fill(&fill(2_000_000, 300_000, 0), true).unwrap();
+        sl.ord_status = OrdStatus::Suspended;
+        assert_eq!(sl.resumed_status(), OrdStatus::PartiallyFilled);
+    }
+
+    #[test]
+    fn settling_keeps_an_order_suspended_until_filled() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        sl.ord_status = OrdStatus::Suspended;
+        sl.apply_resting_fill(&fill(0, 0, 0)).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Suspended);
+
+        sl.apply_resting_fill(&fill(4_000_000, 400_000, 0)).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Suspended);
+        assert_eq!(sl.coin_leaves_qty, 600_000);
+        assert_eq!(sl.resumed_status(), OrdStatus::PartiallyFilled);
+
+        sl.apply_resting_fill(&fill(6_000_000, 600_000, 0)).unwrap();
+        assert_eq!(sl.ord_status, OrdStatus::Filled);
+    }
+
+    #[test]
+    fn protocol_fee_rounds_down() {
+        assert_eq!(protocol_fee(13_208_800, 25), 33_022);
+        assert_eq!(protocol_fee(399, 25), 0);
+        assert_eq!(protocol_fee(1_000, 0), 0);
+        assert_eq!(protocol_fee(u64::MAX, 10_000), u64::MAX);
+    }
+
+    #[test]
+    fn slippage_cap_rounds_towards_reference() {
+        // 50 bps from a 6004 trigger is 5973.98 for sells and 6034.02 for buys
+        assert_eq!(slippage_cap(Side::Ask, 6004, 50), 5974);
+        assert_eq!(slippage_cap(Side::Bid, 6004, 50), 6034);
+        assert_eq!(slippage_cap(Side::Ask, 6004, 10_000), 1);
+        assert_eq!(slippage_cap(Side::Ask, 6004, 0), 1);
+        assert_eq!(slippage_cap(Side::Bid, 6004, 0), u64::MAX);
+        assert_eq!(slippage_cap(Side::Bid, u64::MAX, 10_000), u64::MAX);
+
+        assert!(within_limit(Side::Ask, 5974, 5974));
+        assert!(!within_limit(Side::Ask, 5973, 5974));
+        assert!(!within_limit(Side::Bid, 6035, 6034));
+        assert_eq!(tighter_limit(Side::Ask, 5974, 5990), 5990);
+        assert_eq!(tighter_limit(Side::Bid, 6034, 6010), 6010);
+    }
+
+    #[test]
+    fn oracle_price_in_lots() {
+        let mut data = vec![0u8; OraclePrice::AGG_STATUS_OFFSET + 4];
+        data[0..4].copy_from_slice(&OraclePrice::MAGIC.to_le_bytes());
+        data[4..8].copy_from_slice(&OraclePrice::VERSION.to_le_bytes());
+        data[8..12].copy_from_slice(&OraclePrice::PRICE_ACCOUNT.to_le_bytes());
+        data[OraclePrice::EXPO_OFFSET..OraclePrice::EXPO_OFFSET + 4].copy_from_slice(&(-8i32).to_le_bytes());
+        data[OraclePrice::TIMESTAMP_OFFSET..OraclePrice::TIMESTAMP_OFFSET + 8].copy_from_slice(&1_000i64.to_le_bytes());
+        data[OraclePrice::AGG_PRICE_OFFSET..OraclePrice::AGG_PRICE_OFFSET + 8].copy_from_slice(&600_400_000i64.to_le_bytes());
+        data[OraclePrice::AGG_CONF_OFFSET..OraclePrice::AGG_CONF_OFFSET + 8].copy_from_slice(&300_000u64.to_le_bytes());
+        data[OraclePrice::AGG_STATUS_OFFSET..].copy_from_slice(&OraclePrice::TRADING.to_le_bytes());
+        let oracle = OraclePrice::from_data(&data).unwrap();
+        assert_eq!(oracle.expo, -8);
+        assert_eq!(oracle.price_lots(&LOTS), Some(6004));
+
+        // 0.003 either side of 6.004 is 5 bps
+        assert_eq!(oracle.checked_price_lots(&LOTS, 1_060, 60, 5).unwrap(), 6004);
+        assert_eq!(oracle.checked_price_lots(&LOTS, 1_060, 60, 0).unwrap(), 6004);
+        assert!(oracle.checked_price_lots(&LOTS, 1_001, 0, 0).is_err());
+        assert!(oracle.checked_price_lots(&LOTS, 1_061, 60, 5).is_err());
+        assert!(oracle.checked_price_lots(&LOTS, 1_000, 60, 4).is_err());
+
+        let halted = OraclePrice { status: 0, ..oracle };
+        assert!(halted.checked_price_lots(&LOTS, 1_000, 60, 0).is_err());
+        let negative = OraclePrice { price: -1, ..oracle };
+        assert_eq!(negative.price_lots(&LOTS), None);
+
+        data[0] = 0;
+        assert!(OraclePrice::from_data(&data).is_err());
+    }
+
+    #[test]
+    fn twap_releases_a_slice_at_a_time() {
+        let mut sl = order(Side::Ask, 4_000_000, 0);
+        assert_eq!(sl.strategy_allowance(0), u64::MAX);
+
+        // 4 slices of 15 minutes over an hour
+        sl.strategy = Strategy::Twap;
+        sl.strategy_start_ts = 3_600;
+        sl.strategy_end_ts = 7_200;
+        sl.slice_count = 4;
+        sl.min_child_interval = 60;
+        assert!(sl.check_strategy_timing(3_599).is_err());
+        assert!(sl.check_strategy_timing(3_600).is_ok());
+        assert_eq!(sl.strategy_allowance(3_600), 1_000_000);
+        assert_eq!(sl.strategy_allowance(4_499), 1_000_000);
+        assert_eq!(sl.strategy_allowance(4_500), 2_000_000);
+        assert_eq!(sl.strategy_allowance(i64::MAX), 4_000_000);
+
+        assert!(sl.check_strategy_size(3_600, 1_000_000, 10, 0).is_ok());
+        assert!(sl.check_strategy_size(3_600, 1_000_001, 10, 0).is_err());
+        sl.apply_child_fill(&fill(6_004_000, 1_000_000, 0), true).unwrap();
+        sl.child_order_count = 1;
+        sl.last_child_ts = 3_600;
+        assert!(sl.check_strategy_size(4_499, 1, 0, 0).is_err());
+        assert!(sl.check_strategy_size(4_500, 1_000_000, 10, 0).is_ok());
+        assert!(sl.check_strategy_timing(3_659).is_err());
+        assert!(sl.check_strategy_timing(3_660).is_ok());
+    }
+
+    #[test]
+    fn vwap_caps_participation() {
+        let mut sl = order(Side::Bid, 0, 6_000_000);
+        sl.strategy = Strategy::Vwap;
+        sl.max_participation_bps = 2_500;
+        // a quarter of a 10 lot order
+        assert!(sl.check_strategy_size(0, 6_000_000, 2, 10).is_ok());
+        assert!(sl.check_strategy_size(0, 6_000_000, 3, 10).is_err());
+        assert!(sl.check_strategy_size(0, 6_000_000, 1, 0).is_err());
+
+        // unlike Twap nothing goes once the window has ended
+        sl.strategy_start_ts = 3_600;
+        sl.strategy_end_ts = 7_200;
+        assert!(sl.check_strategy_timing(7_199).is_ok());
+        assert!(sl.check_strategy_timing(7_200).is_err());
+        sl.strategy = Strategy::Twap;
+        sl.slice_count = 4;
+        assert!(sl.check_strategy_timing(7_200).is_ok());
+    }
+
+    #[test]
+    fn config_fits_every_signal_provider() {
+        let config = StoplossConfig {
+            admin: Pubkey::default(),
+            signal_providers: vec![Pubkey::default(); StoplossConfig::MAX_SIGNAL_PROVIDERS],
+            protocol_fee_bps: 0,
+            fee_recipient: Pubkey::default(),
+            max_slippage_bps: 0,
+            halted: false,
+            oracle_program: Pubkey::default(),
+        };
+        let mut data = vec![];
+        config.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), StoplossConfig::LEN);
+    }
+
+    #[test]
+    fn orders_expire_at_expiry_ts() {
+        let mut sl = order(Side::Ask, 1_000_000, 0);
+        assert!(!sl.is_expired(i64::MAX));
+
+        sl.time_in_force = TimeInForce::GoodTillDate;
+        sl.expiry_ts = 1_000;
+        assert!(!sl.is_expired(999));
+        assert!(sl.is_expired(1_000));
+    }
 }
@@ -0,0 +1,475 @@
+// Shared setup for the solana-program-test suite.
+//
+// The Serum DEX is loaded from the .so built out of the deps/dex submodule, or from
+// SERUM_DEX_SO when set, so nothing needs a running validator. A market is listed and
+// seeded with resting maker orders the same way tests/utils/index.js does for the mocha
+// tests: base and quote both have 6 decimals, the base lot is 0.1 and the quote lot
+// 0.0001, so a price in lots is the price * 1000.
+
+#![allow(dead_code)]
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::system_instruction;
+use anchor_lang::{AccountDeserialize, AccountSerialize, InstructionData};
+use anchor_spl::dex::serum_dex::instruction::{InitializeMarketInstruction, MarketInstruction, NewOrderInstructionV3, SelfTradeBehavior};
+use anchor_spl::dex::serum_dex::matching::{OrderType, Side as SerumSide};
+use anchor_spl::dex::serum_dex::state::gen_vault_signer_key;
+use anchor_stoploss::client::{self, ExecuteOrderBuilder, MarketInfo, NewOrderBuilder};
+use anchor_stoploss::{config_address, instruction, ErrorCode, OraclePrice, Side, StoplossConfig, StoplossState};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::account::Account;
+use solana_sdk::instruction::InstructionError;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::{Transaction, TransactionError};
+use solana_sdk::transport::TransportError;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+pub const DEX_PID: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+pub const STOPLOSS_PID: &str = "fFCaG7wcdoMUvtK2gaZ4E9WGcd1KQp1UYKp38A2E7K8";
+// the pyth mainnet program, set as the config's oracle program
+pub const PYTH_PID: &str = "FsJ3A3u2vn5cTVofAjvy6y5kwABJAqYWpe4975bi2epH";
+
+pub const COIN_LOT_SIZE: u64 = 100_000;
+pub const PC_LOT_SIZE: u64 = 100;
+const MARKET_LEN: u64 = 388;
+const REQUEST_QUEUE_LEN: u64 = 5120 + 12;
+const EVENT_QUEUE_LEN: u64 = 262144 + 12;
+const SLAB_LEN: u64 = 65536 + 12;
+const OPEN_ORDERS_LEN: u64 = 3228;
+
+// the book from tests/utils/index.js, as (price lots, size lots)
+pub const ASKS: &[(u64, u64)] = &[(6041, 78), (6051, 723), (6055, 54), (6067, 157), (6077, 3900)];
+pub const BIDS: &[(u64, u64)] = &[(6004, 85), (5995, 129), (5987, 62), (5978, 153), (5965, 828)];
+
+// size of a pyth v2 price account with all 32 publisher slots
+const PYTH_PRICE_LEN: usize = 3312;
+
+pub type TxResult = std::result::Result<(), TransportError>;
+
+pub struct Env {
+    pub ctx: ProgramTestContext,
+    pub program_id: Pubkey,
+    pub dex_program: Pubkey,
+    pub market: MarketInfo,
+    pub user: Keypair,
+    pub user_coin: Pubkey,
+    pub user_pc: Pubkey,
+    pub signal_provider: Keypair,
+    mint_authority: Keypair,
+}
+
+fn dex_so() -> Vec<u8> {
+    let path = std::env::var("SERUM_DEX_SO")
+        .unwrap_or_else(|_| format!("{}/../../deps/dex/target/deploy/serum_dex.so", env!("CARGO_MANIFEST_DIR")));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("unable to read the serum dex program at {}, build deps/dex first: {}", path, e))
+}
+
+impl Env {
+    /// A listed market with `bids` and `asks` resting, and a user holding 1000 of each token.
+    pub async fn new(bids: &[(u64, u64)], asks: &[(u64, u64)]) -> Env {
+        Self::with_accounts(bids, asks, &[]).await
+    }
+
+    /// Like `new`, with `accounts` loaded into the bank at genesis, eg oracle price accounts.
+    pub async fn with_accounts(bids: &[(u64, u64)], asks: &[(u64, u64)], accounts: &[(Pubkey, Account)]) -> Env {
+        let program_id = Pubkey::from_str(STOPLOSS_PID).unwrap();
+        let dex_program = Pubkey::from_str(DEX_PID).unwrap();
+
+        let mut program_test = ProgramTest::new("anchor_stoploss", program_id, processor!(anchor_stoploss::entry));
+        let dex = dex_so();
+        program_test.add_account(
+            dex_program,
+            Account {
+                lamports: Rent::default().minimum_balance(dex.len()),
+                data: dex,
+                owner: solana_sdk::bpf_loader::id(),
+                executable: true,
+                rent_epoch: 0,
+            },
+        );
+        for (address, account) in accounts {
+            program_test.add_account(*address, account.clone());
+        }
+        let ctx = program_test.start_with_context().await;
+
+        let mut env = Env {
+            ctx,
+            program_id,
+            dex_program,
+            market: MarketInfo {
+                market: Pubkey::default(),
+                dex_program,
+                request_queue: Pubkey::default(),
+                event_queue: Pubkey::default(),
+                bids: Pubkey::default(),
+                asks: Pubkey::default(),
+                coin_vault: Pubkey::default(),
+                pc_vault: Pubkey::default(),
+                vault_signer: Pubkey::default(),
+                coin_mint: Pubkey::default(),
+                pc_mint: Pubkey::default(),
+                coin_lot_size: COIN_LOT_SIZE,
+                pc_lot_size: PC_LOT_SIZE,
+            },
+            user: Keypair::new(),
+            user_coin: Pubkey::default(),
+            user_pc: Pubkey::default(),
+            signal_provider: Keypair::new(),
+            mint_authority: Keypair::new(),
+        };
+        env.list_market().await;
+
+        // the test payer is the config admin
+        let admin = env.ctx.payer.pubkey();
+        let signal_provider = env.signal_provider.pubkey();
+        let ix = client::initialise_config(program_id, admin, admin, 0, 0);
+        env.process(&[ix], &[]).await.unwrap();
+        env.update_config(instruction::AddSignalProvider { signal_provider }).await.unwrap();
+        let oracle_program = Pubkey::from_str(PYTH_PID).unwrap();
+        env.update_config(instruction::SetOracleProgram { oracle_program }).await.unwrap();
+
+        let user = env.user.pubkey();
+        env.fund(&user, 10_000_000_000).await;
+        env.fund(&signal_provider, 10_000_000_000).await;
+        env.user_coin = env.create_token_account(&env.market.coin_mint.clone(), &user).await;
+        env.user_pc = env.create_token_account(&env.market.pc_mint.clone(), &user).await;
+        env.mint_to(&env.user_coin.clone(), true, 1_000_000_000).await;
+        env.mint_to(&env.user_pc.clone(), false, 1_000_000_000).await;
+
+        env.seed_book(bids, asks).await;
+        env
+    }
+
+    pub async fn process(&mut self, instructions: &[Instruction], signers: &[&Keypair]) -> TxResult {
+        // a fresh blockhash each time so repeating an instruction is never a duplicate transaction
+        let blockhash = self.ctx.banks_client.get_new_blockhash(&self.ctx.last_blockhash).await.unwrap().0;
+        self.ctx.last_blockhash = blockhash;
+        let mut all_signers = vec![&self.ctx.payer];
+        all_signers.extend_from_slice(signers);
+        let tx = Transaction::new_signed_with_payer(instructions, Some(&self.ctx.payer.pubkey()), &all_signers, blockhash);
+        self.ctx.banks_client.process_transaction(tx).await
+    }
+
+    /// Sends one of the admin config instructions, signed by the admin.
+    pub async fn update_config(&mut self, data: impl InstructionData) -> TxResult {
+        let ix = client::update_config(self.program_id, self.ctx.payer.pubkey(), data);
+        self.process(&[ix], &[]).await
+    }
+
+    pub async fn config(&mut self) -> StoplossConfig {
+        let address = config_address(&self.program_id).0;
+        let account = self.ctx.banks_client.get_account(address).await.unwrap().expect("config account");
+        StoplossConfig::try_deserialize(&mut &account.data[..]).unwrap()
+    }
+
+    pub async fn fund(&mut self, to: &Pubkey, lamports: u64) {
+        let ix = system_instruction::transfer(&self.ctx.payer.pubkey(), to, lamports);
+        self.process(&[ix], &[]).await.unwrap();
+    }
+
+    pub async fn create_account(&mut self, space: u64, owner: &Pubkey) -> Keypair {
+        let account = Keypair::new();
+        let ix = system_instruction::create_account(
+            &self.ctx.payer.pubkey(),
+            &account.pubkey(),
+            Rent::default().minimum_balance(space as usize),
+            space,
+            owner,
+        );
+        self.process(&[ix], &[&account]).await.unwrap();
+        account
+    }
+
+    async fn create_mint(&mut self) -> Pubkey {
+        let mint = self.create_account(spl_token::state::Mint::LEN as u64, &spl_token::ID).await;
+        let ix = spl_token::instruction::initialize_mint(&spl_token::ID, &mint.pubkey(), &self.mint_authority.pubkey(), None, 6).unwrap();
+        self.process(&[ix], &[]).await.unwrap();
+        mint.pubkey()
+    }
+
+    pub async fn create_token_account(&mut self, mint: &Pubkey, owner: &Pubkey) -> Pubkey {
+        let account = self.create_account(spl_token::state::Account::LEN as u64, &spl_token::ID).await;
+        let ix = spl_token::instruction::initialize_account(&spl_token::ID, &account.pubkey(), mint, owner).unwrap();
+        self.process(&[ix], &[]).await.unwrap();
+        account.pubkey()
+    }
+
+    pub async fn mint_to(&mut self, account: &Pubkey, coin: bool, amount: u64) {
+        let mint = if coin { self.market.coin_mint } else { self.market.pc_mint };
+        let authority = Keypair::from_bytes(&self.mint_authority.to_bytes()).unwrap();
+        let ix = spl_token::instruction::mint_to(&spl_token::ID, &mint, account, &authority.pubkey(), &[], amount).unwrap();
+        self.process(&[ix], &[&authority]).await.unwrap();
+    }
+
+    pub async fn token_balance(&mut self, account: &Pubkey) -> u64 {
+        match self.ctx.banks_client.get_account(*account).await.unwrap() {
+            Some(a) => spl_token::state::Account::unpack(&a.data).unwrap().amount,
+            None => 0,
+        }
+    }
+
+    pub async fn account_exists(&mut self, account: &Pubkey) -> bool {
+        self.ctx.banks_client.get_account(*account).await.unwrap().is_some()
+    }
+
+    pub async fn lamports(&mut self, account: &Pubkey) -> u64 {
+        self.ctx.banks_client.get_account(*account).await.unwrap().map_or(0, |a| a.lamports)
+    }
+
+    pub async fn order(&mut self, address: &Pubkey) -> StoplossState {
+        let account = self.ctx.banks_client.get_account(*address).await.unwrap().expect("order account");
+        StoplossState::try_deserialize(&mut &account.data[..]).unwrap()
+    }
+
+    async fn list_market(&mut self) {
+        let coin_mint = self.create_mint().await;
+        let pc_mint = self.create_mint().await;
+
+        let market = Keypair::new();
+        let (vault_signer, nonce) = (0..u64::MAX)
+            .find_map(|nonce| gen_vault_signer_key(nonce, &market.pubkey(), &self.dex_program).ok().map(|k| (k, nonce)))
+            .unwrap();
+        let coin_vault = self.create_token_account(&coin_mint, &vault_signer).await;
+        let pc_vault = self.create_token_account(&pc_mint, &vault_signer).await;
+
+        let dex = self.dex_program;
+        let request_queue = self.create_account(REQUEST_QUEUE_LEN, &dex).await.pubkey();
+        let event_queue = self.create_account(EVENT_QUEUE_LEN, &dex).await.pubkey();
+        let bids = self.create_account(SLAB_LEN, &dex).await.pubkey();
+        let asks = self.create_account(SLAB_LEN, &dex).await.pubkey();
+
+        let create_market = system_instruction::create_account(
+            &self.ctx.payer.pubkey(),
+            &market.pubkey(),
+            Rent::default().minimum_balance(MARKET_LEN as usize),
+            MARKET_LEN,
+            &dex,
+        );
+        let data = MarketInstruction::InitializeMarket(InitializeMarketInstruction {
+            coin_lot_size: COIN_LOT_SIZE,
+            pc_lot_size: PC_LOT_SIZE,
+            fee_rate_bps: 0,
+            vault_signer_nonce: nonce,
+            pc_dust_threshold: 100,
+        })
+        .pack();
+        let init_market = Instruction {
+            program_id: dex,
+            data,
+            accounts: vec![
+                AccountMeta::new(market.pubkey(), false),
+                AccountMeta::new(request_queue, false),
+                AccountMeta::new(event_queue, false),
+                AccountMeta::new(bids, false),
+                AccountMeta::new(asks, false),
+                AccountMeta::new(coin_vault, false),
+                AccountMeta::new(pc_vault, false),
+                AccountMeta::new_readonly(coin_mint, false),
+                AccountMeta::new_readonly(pc_mint, false),
+                AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+            ],
+        };
+        self.process(&[create_market, init_market], &[&market]).await.unwrap();
+
+        let data = self.ctx.banks_client.get_account(market.pubkey()).await.unwrap().unwrap().data;
+        self.market = MarketInfo::from_account_data(market.pubkey(), dex, &data).unwrap();
+        assert_eq!(self.market.vault_signer, vault_signer);
+    }
+
+    /// Rests `bids` and `asks` on the book as post only maker orders from a separate market
+    /// maker, eg to move the price mid test.
+    pub async fn seed_book(&mut self, bids: &[(u64, u64)], asks: &[(u64, u64)]) {
+        let maker = Keypair::new();
+        self.fund(&maker.pubkey(), 1_000_000_000).await;
+        let maker_coin = self.create_token_account(&self.market.coin_mint.clone(), &maker.pubkey()).await;
+        let maker_pc = self.create_token_account(&self.market.pc_mint.clone(), &maker.pubkey()).await;
+        self.mint_to(&maker_coin, true, 1_000_000_000_000).await;
+        self.mint_to(&maker_pc, false, 1_000_000_000_000).await;
+        let open_orders = self.create_account(OPEN_ORDERS_LEN, &self.dex_program.clone()).await.pubkey();
+
+        let orders = bids.iter().map(|o| (SerumSide::Bid, *o)).chain(asks.iter().map(|o| (SerumSide::Ask, *o)));
+        for (side, (price, size)) in orders {
+            let payer = if side == SerumSide::Bid { maker_pc } else { maker_coin };
+            let data = MarketInstruction::NewOrderV3(NewOrderInstructionV3 {
+                side,
+                limit_price: NonZeroU64::new(price).unwrap(),
+                max_coin_qty: NonZeroU64::new(size).unwrap(),
+                max_native_pc_qty_including_fees: NonZeroU64::new(price * size * PC_LOT_SIZE).unwrap(),
+                order_type: OrderType::PostOnly,
+                client_order_id: 0,
+                self_trade_behavior: SelfTradeBehavior::AbortTransaction,
+                limit: 65535,
+            })
+            .pack();
+            let ix = Instruction {
+                program_id: self.dex_program,
+                data,
+                accounts: vec![
+                    AccountMeta::new(self.market.market, false),
+                    AccountMeta::new(open_orders, false),
+                    AccountMeta::new(self.market.request_queue, false),
+                    AccountMeta::new(self.market.event_queue, false),
+                    AccountMeta::new(self.market.bids, false),
+                    AccountMeta::new(self.market.asks, false),
+                    AccountMeta::new(payer, false),
+                    AccountMeta::new_readonly(maker.pubkey(), true),
+                    AccountMeta::new(self.market.coin_vault, false),
+                    AccountMeta::new(self.market.pc_vault, false),
+                    AccountMeta::new_readonly(spl_token::ID, false),
+                    AccountMeta::new_readonly(solana_sdk::sysvar::rent::ID, false),
+                ],
+            };
+            self.process(&[ix], &[&maker]).await.unwrap();
+        }
+    }
+
+    /// Builder for a new order from the user, with the user's token accounts as wallets.
+    pub fn new_order_builder(&self, side: Side, order: &Keypair) -> NewOrderBuilder {
+        NewOrderBuilder::new(
+            self.program_id,
+            self.market,
+            self.user.pubkey(),
+            order.pubkey(),
+            self.signal_provider.pubkey(),
+            side,
+        )
+        .wallets(self.user_coin, self.user_pc)
+    }
+
+    /// Sends a new order built by `build`, creating its state account.
+    pub async fn try_new_order(&mut self, side: Side, build: impl FnOnce(NewOrderBuilder) -> NewOrderBuilder) -> std::result::Result<Pubkey, TransportError> {
+        self.try_new_order_with_accounts(side, build, |_| {}).await
+    }
+
+    /// Like `try_new_order` with the accounts of the instruction edited by `edit`, eg to
+    /// substitute one.
+    pub async fn try_new_order_with_accounts(
+        &mut self,
+        side: Side,
+        build: impl FnOnce(NewOrderBuilder) -> NewOrderBuilder,
+        edit: impl FnOnce(&mut Vec<AccountMeta>),
+    ) -> std::result::Result<Pubkey, TransportError> {
+        let order = Keypair::new();
+        let space = 8 + std::mem::size_of::<StoplossState>();
+        let create = system_instruction::create_account(
+            &self.ctx.payer.pubkey(),
+            &order.pubkey(),
+            Rent::default().minimum_balance(space),
+            space as u64,
+            &self.program_id,
+        );
+        let mut ix = build(self.new_order_builder(side, &order)).instruction();
+        edit(&mut ix.accounts);
+        let user = Keypair::from_bytes(&self.user.to_bytes()).unwrap();
+        self.process(&[create, ix], &[&user, &order]).await?;
+        Ok(order.pubkey())
+    }
+
+    /// A new order that has had its open orders initialised by the signal provider.
+    pub async fn new_order(&mut self, side: Side, build: impl FnOnce(NewOrderBuilder) -> NewOrderBuilder) -> Pubkey {
+        let order = self.try_new_order(side, build).await.unwrap();
+        self.initialise_open_orders(&order).await.unwrap();
+        order
+    }
+
+    pub async fn initialise_open_orders(&mut self, order: &Pubkey) -> TxResult {
+        let state = self.order(order).await;
+        let ix = client::initialise_open_orders(self.program_id, &state);
+        let signal_provider = Keypair::from_bytes(&self.signal_provider.to_bytes()).unwrap();
+        self.process(&[ix], &[&signal_provider]).await
+    }
+
+    /// Builds an execute signed by the signal provider, for tweaking before `send_as`.
+    pub async fn execute_builder(&mut self, order: &Pubkey, execute_qty: u64, reuse_unfilled: bool) -> Instruction {
+        let state = self.order(order).await;
+        ExecuteOrderBuilder::new(self.program_id, self.market, &state, self.signal_provider.pubkey())
+            .execute_qty(execute_qty)
+            .reuse_unfilled(reuse_unfilled)
+            .keeper_bounty_account(state.client_paying_account)
+            .instruction()
+    }
+
+    pub async fn execute(&mut self, order: &Pubkey, execute_qty: u64, reuse_unfilled: bool) -> TxResult {
+        let ix = self.execute_builder(order, execute_qty, reuse_unfilled).await;
+        let signal_provider = Keypair::from_bytes(&self.signal_provider.to_bytes()).unwrap();
+        self.process(&[ix], &[&signal_provider]).await
+    }
+
+    /// Sends `ix` signed by the user, ie the order owner.
+    pub async fn send_as_user(&mut self, ix: Instruction) -> TxResult {
+        let user = Keypair::from_bytes(&self.user.to_bytes()).unwrap();
+        self.process(&[ix], &[&user]).await
+    }
+}
+
+/// A pyth v2 price account trading at `price * 10^expo` with confidence `conf`, published at
+/// the unix timestamp `timestamp`.
+pub fn pyth_price_account(price: i64, conf: u64, expo: i32, timestamp: i64) -> Account {
+    let mut data = vec![0u8; PYTH_PRICE_LEN];
+    let mut write = |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    write(0, &OraclePrice::MAGIC.to_le_bytes());
+    write(4, &OraclePrice::VERSION.to_le_bytes());
+    write(8, &OraclePrice::PRICE_ACCOUNT.to_le_bytes());
+    write(12, &(PYTH_PRICE_LEN as u32).to_le_bytes());
+    write(OraclePrice::EXPO_OFFSET, &expo.to_le_bytes());
+    write(OraclePrice::TIMESTAMP_OFFSET, &timestamp.to_le_bytes());
+    write(OraclePrice::AGG_PRICE_OFFSET, &price.to_le_bytes());
+    write(OraclePrice::AGG_CONF_OFFSET, &conf.to_le_bytes());
+    write(OraclePrice::AGG_STATUS_OFFSET, &OraclePrice::TRADING.to_le_bytes());
+    Account {
+        lamports: Rent::default().minimum_balance(PYTH_PRICE_LEN),
+        data,
+        owner: Pubkey::from_str(PYTH_PID).unwrap(),
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+/// `account` as an account of the stoploss program, padded to `len`, eg a look-alike of one
+/// of its accounts at some other address.
+pub fn stoploss_account(account: &impl AccountSerialize, len: usize) -> Account {
+    let mut data = vec![];
+    account.try_serialize(&mut data).unwrap();
+    data.resize(len, 0);
+    Account {
+        lamports: Rent::default().minimum_balance(len),
+        data,
+        owner: Pubkey::from_str(STOPLOSS_PID).unwrap(),
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+pub fn error_code(e: ErrorCode) -> u32 {
+    match ProgramError::from(e) {
+        ProgramError::Custom(code) => code,
+        other => panic!("not a custom error {:?}", other),
+    }
+}
+
+pub fn assert_error(result: TxResult, expected: ErrorCode) {
+    match result {
+        Err(TransportError::TransactionError(TransactionError::InstructionError(_, InstructionError::Custom(code)))) => {
+            assert_eq!(code, error_code(expected), "expected {:?}", expected)
+        }
+        other => panic!("expected {:?}, got {:?}", expected, other),
+    }
+}
+
+/// Like `assert_error` for errors anchor raises before the handler runs, eg a failed account
+/// constraint.
+pub fn assert_anchor_error(result: TxResult, expected: anchor_lang::__private::ErrorCode) {
+    let expected_code = match ProgramError::from(expected) {
+        ProgramError::Custom(code) => code,
+        other => panic!("not a custom error {:?}", other),
+    };
+    match result {
+        Err(TransportError::TransactionError(TransactionError::InstructionError(_, InstructionError::Custom(code)))) => {
+            assert_eq!(code, expected_code, "expected {:?}", expected)
+        }
+        other => panic!("expected {:?}, got {:?}", expected, other),
+    }
+}
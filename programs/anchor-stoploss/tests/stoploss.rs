@@ -0,0 +1,1236 @@
+// Runs every instruction against a real Serum DEX with solana-program-test.
+//
+//     cargo test-bpf --features test-bpf
+//
+// Order prices are in price lots, so 6004 is 6.004 quote per base, fill prices are fixed-point
+// so 6.004 is 6_004_000_000, and quantities are native.
+
+#![cfg(feature = "test-bpf")]
+
+mod common;
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::system_instruction;
+use anchor_stoploss::client::{self, ExecuteOrderBuilder, SetStrategyBuilder};
+use anchor_stoploss::{
+    instruction, ErrorCode, OraclePrice, OrdStatus, OrderMode, OrderType, Price, Side, StoplossConfig, StoplossState, Strategy, TimeInForce, TriggerType,
+};
+use common::{assert_anchor_error, assert_error, pyth_price_account, stoploss_account, Env, ASKS, BIDS};
+use solana_sdk::account::Account;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transport::TransportError;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ONE: u64 = 1_000_000;
+
+async fn env() -> Env {
+    Env::new(BIDS, ASKS).await
+}
+
+// sells 1 base once the best bid falls to 6.01, no lower than 5.99
+async fn sell(env: &mut Env) -> Pubkey {
+    env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0)).await
+}
+
+#[tokio::test]
+async fn sell_lifecycle() {
+    let mut env = env().await;
+    let order = env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PendingInit);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+    assert!(!env.account_exists(&state.stoploss_open_orders).await);
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::OpenOrdersNotInitialised);
+
+    env.initialise_open_orders(&order).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::New);
+    assert!(env.account_exists(&state.stoploss_open_orders).await);
+    assert_error(env.initialise_open_orders(&order).await, ErrorCode::OpenOrdersAlreadyInitialised);
+
+    let pc_before = env.token_balance(&env.user_pc.clone()).await;
+    env.execute(&order, ONE, false).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_cum_qty, ONE);
+    assert_eq!(state.coin_leaves_qty, 0);
+    assert_eq!(state.last_price, Price { value: 6_004_000_000 });
+    assert_eq!(state.child_order_count, 1);
+    let proceeds = env.token_balance(&env.user_pc.clone()).await - pc_before;
+    assert_eq!(proceeds, state.pc_cum_qty - state.fees_paid);
+    // the vaults are closed once the order is done
+    assert!(!env.account_exists(&state.stoploss_base_vault).await);
+
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::AttemptingToExecuteFilledOrder);
+
+    // the signal provider paid for the open orders, so their rent goes back to it
+    let (user, signal_provider) = (env.user.pubkey(), env.signal_provider.pubkey());
+    assert_eq!(state.open_orders_payer, signal_provider);
+    let mut ix = client::close_order(env.program_id, &state, user);
+    ix.accounts.last_mut().unwrap().pubkey = user;
+    assert_error(env.send_as_user(ix).await, ErrorCode::IncorrectOpenOrdersPayer);
+    let open_orders_rent = env.lamports(&state.stoploss_open_orders).await;
+    let signal_provider_before = env.lamports(&signal_provider).await;
+    env.send_as_user(client::close_order(env.program_id, &state, user)).await.unwrap();
+    assert!(!env.account_exists(&order).await);
+    assert!(!env.account_exists(&state.stoploss_open_orders).await);
+    assert_eq!(env.lamports(&signal_provider).await - signal_provider_before, open_orders_rent);
+}
+
+#[tokio::test]
+async fn new_order_can_create_open_orders() {
+    let mut env = env().await;
+    let order = env
+        .try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).should_create_open_orders(true))
+        .await
+        .unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!(state.stoploss_open_orders, anchor_stoploss::open_orders_address(&order, &env.program_id).0);
+    assert!(env.account_exists(&state.stoploss_open_orders).await);
+    assert_eq!(state.open_orders_payer, env.user.pubkey());
+
+    env.execute(&order, ONE, false).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Filled);
+}
+
+#[tokio::test]
+async fn one_shot_buy_partially_fills_and_cancels() {
+    let mut env = env().await;
+    // 10 base for up to 100 quote at no more than 6.045 only reaches the 7.8 base resting at 6.041
+    let order = env.new_order(Side::Bid, |b| b.prices(6045, 6030).quantity(10 * ONE, 100 * ONE)).await;
+    let coin_before = env.token_balance(&env.user_coin.clone()).await;
+    let pc_before = env.token_balance(&env.user_pc.clone()).await;
+
+    env.execute(&order, 10 * ONE, false).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Cancelled);
+    assert_eq!(state.coin_cum_qty, 78 * 100_000);
+    assert_eq!(state.last_price, Price { value: 6_041_000_000 });
+    assert_eq!(state.pc_leaves_qty, 0);
+    assert_eq!(env.token_balance(&env.user_coin.clone()).await - coin_before, state.coin_cum_qty);
+    // whatever was not spent comes back
+    assert_eq!(env.token_balance(&env.user_pc.clone()).await - pc_before, 100 * ONE - state.pc_cum_qty - state.fees_paid);
+}
+
+#[tokio::test]
+async fn buy_children_are_sized_in_base() {
+    let mut env = env().await;
+    let order = env.new_order(Side::Bid, |b| b.prices(6045, 6030).quantity(ONE, 100 * ONE)).await;
+    assert_error(env.execute(&order, 2 * ONE, true).await, ErrorCode::ExecuteQtyExceedsLeaves);
+    assert_error(env.execute(&order, 50_000, true).await, ErrorCode::ExecuteQtyBelowLotSize);
+
+    // half a base, not half a base's worth of quote
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert_eq!(state.coin_cum_qty, ONE / 2);
+    assert_eq!(state.coin_leaves_qty, ONE / 2);
+    assert_eq!(state.last_price, Price { value: 6_041_000_000 });
+
+    // reaching max_coin_qty fills the order and refunds the quote left over
+    let pc_before = env.token_balance(&env.user_pc.clone()).await;
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_cum_qty, ONE);
+    assert_eq!(env.token_balance(&env.user_pc.clone()).await - pc_before, 100 * ONE - state.pc_cum_qty - state.fees_paid);
+}
+
+#[tokio::test]
+async fn buys_need_a_max_coin_qty_and_fill_when_amended_to_what_they_spent() {
+    let mut env = env().await;
+    assert_error(
+        env.try_new_order(Side::Bid, |b| b.prices(6045, 6030).quantity(0, 100 * ONE)).await.map(|_| ()),
+        ErrorCode::InvalidMaxCoinQty,
+    );
+
+    let order = env.new_order(Side::Bid, |b| b.prices(6045, 6030).quantity(ONE, 100 * ONE)).await;
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert_eq!(state.coin_leaves_qty, ONE / 2);
+
+    // nothing left to spend, even though the base cap has not been reached
+    let pc_before = env.token_balance(&env.user_pc.clone()).await;
+    let spent = state.pc_cum_qty + state.fees_paid;
+    env.send_as_user(client::amend_order(env.program_id, &state, 6045, spent, 6030)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_leaves_qty, 0);
+    assert_eq!(state.pc_leaves_qty, 0);
+    assert_eq!(env.token_balance(&env.user_pc.clone()).await - pc_before, 100 * ONE - spent);
+    assert_error(env.execute(&order, ONE / 2, true).await, ErrorCode::AttemptingToExecuteFilledOrder);
+
+    env.send_as_user(client::close_order(env.program_id, &state, env.user.pubkey())).await.unwrap();
+    assert!(!env.account_exists(&order).await);
+}
+
+#[tokio::test]
+async fn reuse_unfilled_runs_as_several_children() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert_eq!(state.coin_leaves_qty, ONE / 2);
+
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::ExecuteQtyExceedsLeaves);
+    // half has already been sold
+    assert_error(
+        env.send_as_user(client::amend_order(env.program_id, &state, 5990, ONE / 4, 6010)).await,
+        ErrorCode::AlreadyFilledMoreThanRequestedAmendSize,
+    );
+
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_cum_qty, ONE);
+    assert_eq!(state.child_order_count, 2);
+}
+
+#[tokio::test]
+async fn post_only_child_rests_until_cancelled() {
+    let mut env = env().await;
+    let order = sell_resting(&mut env).await;
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!(state.resting_qty, ONE);
+    assert_eq!(state.coin_leaves_qty, ONE);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, 0);
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::ChildOrderResting);
+    assert_error(env.send_as_user(client::cancel_order(env.program_id, &state)).await, ErrorCode::ChildOrderResting);
+
+    let stranger = Keypair::new();
+    assert_error(
+        env.process(&[client::cancel_child_order(env.program_id, env.market, &state, stranger.pubkey())], &[&stranger]).await,
+        ErrorCode::IncorrectChildOrderAuthority,
+    );
+
+    // nothing traded, so the whole child goes back into the vault for the next one
+    let user = env.user.pubkey();
+    env.send_as_user(client::cancel_child_order(env.program_id, env.market, &state, user)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!((state.resting_qty, state.resting_client_order_id), (0, 0));
+    assert_eq!(state.coin_cum_qty, 0);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+    assert_error(env.send_as_user(client::cancel_child_order(env.program_id, env.market, &state, user)).await, ErrorCode::NoRestingChildOrder);
+
+    env.send_as_user(client::cancel_order(env.program_id, &state)).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Cancelled);
+}
+
+#[tokio::test]
+async fn execute_checks_trigger_and_limits() {
+    let mut env = env().await;
+    let order = env.new_order(Side::Ask, |b| b.prices(5800, 5900).quantity(ONE, 0)).await;
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::TriggerPriceNotReached);
+
+    let order = sell(&mut env).await;
+    assert_error(env.execute(&order, 0, false).await, ErrorCode::ExecuteQtyExceedsLeaves);
+    assert_error(env.execute(&order, 2 * ONE, false).await, ErrorCode::ExecuteQtyExceedsLeaves);
+
+    let state = env.order(&order).await;
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, env.signal_provider.pubkey())
+        .execute_qty(ONE)
+        .execute_limit(5980)
+        .keeper_bounty_account(state.client_paying_account)
+        .instruction();
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::AttemptingToExecuteOutsideParentLimit);
+
+    let stranger = Keypair::new();
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, stranger.pubkey())
+        .execute_qty(ONE)
+        .keeper_bounty_account(state.client_paying_account)
+        .instruction();
+    assert_error(env.process(&[ix], &[&stranger]).await, ErrorCode::IncorrectSignalProviderAccount);
+}
+
+#[tokio::test]
+async fn triggered_orders_work_their_leaves_after_the_price_recovers() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    env.execute(&order, ONE / 2, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert!(state.triggered);
+    assert_error(
+        env.send_as_user(client::amend_order(env.program_id, &state, 6010, ONE, 5980)).await,
+        ErrorCode::CannotAmendTriggeredTrigger,
+    );
+
+    // the best bid moves back above the trigger
+    env.seed_book(&[(6020, 100)], &[]).await;
+    let untriggered = sell(&mut env).await;
+    assert_error(env.execute(&untriggered, ONE, false).await, ErrorCode::TriggerPriceNotReached);
+
+    env.execute(&order, ONE / 2, false).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_cum_qty, ONE);
+    assert_eq!(state.last_price, Price { value: 6_020_000_000 });
+}
+
+#[tokio::test]
+async fn child_is_priced_at_execute_limit() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    let child = |env: &Env, state: &StoplossState, execute_limit: u64| {
+        ExecuteOrderBuilder::new(env.program_id, env.market, state, signal_provider.pubkey())
+            .execute_qty(ONE)
+            .execute_limit(execute_limit)
+            .reuse_unfilled(true)
+            .keeper_bounty_account(state.client_paying_account)
+            .instruction()
+    };
+
+    let state = env.order(&order).await;
+    let ix = child(&env, &state, 0);
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::AttemptingToExecuteOutsideParentLimit);
+
+    // inside the parent's band but above the best bid of 6.004, so nothing trades
+    let ix = child(&env, &state, 6010);
+    env.process(&[ix], &[&signal_provider]).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!(state.coin_cum_qty, 0);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+
+    // only the 6.004 level is at or above 6.0
+    let ix = child(&env, &state, 6000);
+    env.process(&[ix], &[&signal_provider]).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.last_price, Price { value: 6_004_000_000 });
+}
+
+#[tokio::test]
+async fn stop_market_sweeps_to_slippage_cap() {
+    let mut env = env().await;
+    let order = env
+        .new_order(Side::Ask, |b| b.prices(5900, 6010).quantity(10 * ONE, 0).order_mode(OrderMode::StopMarket))
+        .await;
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    let child = |env: &Env, state: &StoplossState, order_type: OrderType| {
+        ExecuteOrderBuilder::new(env.program_id, env.market, state, signal_provider.pubkey())
+            .reuse_unfilled(true)
+            .order_type(order_type)
+            .keeper_bounty_account(state.client_paying_account)
+            .instruction()
+    };
+
+    let state = env.order(&order).await;
+    let ix = child(&env, &state, OrderType::PostOnly);
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::RestingChildNotAllowed);
+
+    // 5 bps below the 6.01 trigger is 6.007, past the best bid of 6.004
+    env.update_config(instruction::SetMaxSlippage { max_slippage_bps: 5 }).await.unwrap();
+    let ix = child(&env, &state, OrderType::ImmediateOrCancel);
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::SlippageCapExceeded);
+
+    // 20 bps is 5.998, so only the 8.5 base bid at 6.004 trades
+    env.update_config(instruction::SetMaxSlippage { max_slippage_bps: 20 }).await.unwrap();
+    let ix = child(&env, &state, OrderType::ImmediateOrCancel);
+    env.process(&[ix], &[&signal_provider]).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert_eq!(state.coin_cum_qty, 8_500_000);
+    assert_eq!(state.last_price, Price { value: 6_004_000_000 });
+}
+
+#[tokio::test]
+async fn execute_rejects_substituted_accounts() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let cases = [
+        (0, ErrorCode::IncorrectMarketAccount),
+        (1, ErrorCode::IncorrectOpenOrdersAccount),
+        (2, ErrorCode::IncorrectRequestQueueAccount),
+        (3, ErrorCode::IncorrectEventQueueAccount),
+        (4, ErrorCode::IncorrectBidsAccount),
+        (5, ErrorCode::IncorrectAsksAccount),
+        (6, ErrorCode::IncorrectOrderPayerAccount),
+        (7, ErrorCode::IncorrectDexVaultAccount),
+        (8, ErrorCode::IncorrectDexVaultAccount),
+        (9, ErrorCode::IncorrectVaultSignerAccount),
+        (12, ErrorCode::IncorrectCoinMint),
+        (13, ErrorCode::IncorrectPcMint),
+        (14, ErrorCode::IncorrectStoplossBaseVault),
+        (15, ErrorCode::IncorrectStoplossQuoteVault),
+        (18, ErrorCode::IncorrectOpenOrdersAccount),
+        (19, ErrorCode::IncorrectAmendAccount),
+        (21, ErrorCode::IncorrectStoplossProgram),
+        (22, ErrorCode::IncorrectDexProgram),
+        (23, ErrorCode::IncorrectPdaAccount),
+        (24, ErrorCode::IncorrectTokenProgram),
+        (27, ErrorCode::IncorrectFeeVault),
+    ];
+    for &(index, expected) in cases.iter() {
+        let mut ix = env.execute_builder(&order, ONE, false).await;
+        ix.accounts[index].pubkey = Pubkey::new_unique();
+        let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+        assert_error(env.process(&[ix], &[&signal_provider]).await, expected);
+    }
+
+    // the wallets are real token accounts, just not the order's
+    let other_coin = env.create_token_account(&env.market.coin_mint.clone(), &Pubkey::new_unique()).await;
+    let other_pc = env.create_token_account(&env.market.pc_mint.clone(), &Pubkey::new_unique()).await;
+    for &(index, wallet, expected) in [(10, other_coin, ErrorCode::IncorrectClientCoinWallet), (11, other_pc, ErrorCode::IncorrectClientPcWallet)].iter() {
+        let mut ix = env.execute_builder(&order, ONE, false).await;
+        ix.accounts[index].pubkey = wallet;
+        let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+        assert_error(env.process(&[ix], &[&signal_provider]).await, expected);
+    }
+
+    // anchor rejects an unsigned execute before the handler runs
+    let mut ix = env.execute_builder(&order, ONE, false).await;
+    ix.accounts[16].is_signer = false;
+    assert_anchor_error(env.process(&[ix], &[]).await, anchor_lang::__private::ErrorCode::ConstraintSigner);
+
+    // nothing above touched the order
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::New);
+}
+
+// a sell with a post only child resting, as its limit is between the best bid and ask
+async fn sell_resting(env: &mut Env) -> Pubkey {
+    let order = env.new_order(Side::Ask, |b| b.prices(6030, 6010).quantity(ONE, 0)).await;
+    let state = env.order(&order).await;
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, signal_provider.pubkey())
+        .order_type(OrderType::PostOnly)
+        .keeper_bounty_account(state.client_paying_account)
+        .instruction();
+    env.process(&[ix], &[&signal_provider]).await.unwrap();
+    order
+}
+
+#[tokio::test]
+async fn settle_rejects_substituted_accounts() {
+    let mut env = env().await;
+    let order = sell_resting(&mut env).await;
+    let state = env.order(&order).await;
+    let cases = [
+        (0, ErrorCode::IncorrectMarketAccount),
+        (1, ErrorCode::IncorrectOpenOrdersAccount),
+        (2, ErrorCode::IncorrectRequestQueueAccount),
+        (3, ErrorCode::IncorrectEventQueueAccount),
+        (4, ErrorCode::IncorrectBidsAccount),
+        (5, ErrorCode::IncorrectAsksAccount),
+        (6, ErrorCode::IncorrectOrderPayerAccount),
+        (7, ErrorCode::IncorrectDexVaultAccount),
+        (8, ErrorCode::IncorrectDexVaultAccount),
+        (9, ErrorCode::IncorrectVaultSignerAccount),
+        (12, ErrorCode::IncorrectCoinMint),
+        (13, ErrorCode::IncorrectPcMint),
+        (14, ErrorCode::IncorrectStoplossBaseVault),
+        (15, ErrorCode::IncorrectStoplossQuoteVault),
+        (18, ErrorCode::IncorrectAmendAccount),
+        (19, ErrorCode::IncorrectDexProgram),
+        (20, ErrorCode::IncorrectPdaAccount),
+        (21, ErrorCode::IncorrectTokenProgram),
+        (23, ErrorCode::IncorrectFeeVault),
+    ];
+    let user = env.user.pubkey();
+    for &(index, expected) in cases.iter() {
+        let mut ix = client::settle_order(env.program_id, env.market, &state, user);
+        ix.accounts[index].pubkey = Pubkey::new_unique();
+        assert_error(env.send_as_user(ix).await, expected);
+    }
+
+    // the child is still resting
+    assert_eq!(env.order(&order).await.resting_qty, ONE);
+}
+
+#[tokio::test]
+async fn new_order_rejects_substituted_accounts() {
+    let mut env = env().await;
+    let cases = [
+        (14, ErrorCode::IncorrectOrderVaultAddress),
+        (15, ErrorCode::IncorrectOrderVaultAddress),
+        (20, ErrorCode::IncorrectStoplossProgram),
+        (22, ErrorCode::IncorrectPdaAccount),
+        (23, ErrorCode::IncorrectTokenProgram),
+    ];
+    for &(index, expected) in cases.iter() {
+        let result = env
+            .try_new_order_with_accounts(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0), |accounts| {
+                accounts[index].pubkey = Pubkey::new_unique()
+            })
+            .await;
+        assert_error(result.map(|_| ()), expected);
+    }
+
+    // each vault is derived from its mint, so the two cannot be swapped
+    let result = env
+        .try_new_order_with_accounts(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0), |accounts| accounts.swap(14, 15))
+        .await;
+    assert_error(result.map(|_| ()), ErrorCode::IncorrectOrderVaultAddress);
+}
+
+#[tokio::test]
+async fn only_the_program_config_is_accepted() {
+    // owned by the program and laid out like the config, but not at the config address
+    let (admin, forged) = (Keypair::new(), Pubkey::new_unique());
+    let config = StoplossConfig {
+        admin: admin.pubkey(),
+        signal_providers: vec![],
+        protocol_fee_bps: 0,
+        fee_recipient: admin.pubkey(),
+        max_slippage_bps: 0,
+        halted: false,
+        oracle_program: Pubkey::default(),
+    };
+    let mut env = Env::with_accounts(BIDS, ASKS, &[(forged, stoploss_account(&config, StoplossConfig::LEN))]).await;
+
+    let mut ix = client::initialise_config(env.program_id, admin.pubkey(), admin.pubkey(), 0, 0);
+    ix.accounts[1].pubkey = forged;
+    assert_error(env.process(&[ix], &[&admin]).await, ErrorCode::IncorrectConfigAccount);
+    let mut ix = client::update_config(env.program_id, admin.pubkey(), instruction::SetHalted { halted: true });
+    ix.accounts[0].pubkey = forged;
+    assert_error(env.process(&[ix], &[&admin]).await, ErrorCode::IncorrectConfigAccount);
+
+    let result = env
+        .try_new_order_with_accounts(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0), |accounts| accounts[26].pubkey = forged)
+        .await;
+    assert_error(result.map(|_| ()), ErrorCode::IncorrectConfigAccount);
+
+    let order = sell(&mut env).await;
+    let mut ix = env.execute_builder(&order, ONE, false).await;
+    ix.accounts[26].pubkey = forged;
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::IncorrectConfigAccount);
+
+    let order = sell_resting(&mut env).await;
+    let state = env.order(&order).await;
+    let mut ix = client::settle_order(env.program_id, env.market, &state, env.user.pubkey());
+    ix.accounts[22].pubkey = forged;
+    assert_error(env.send_as_user(ix).await, ErrorCode::IncorrectConfigAccount);
+}
+
+#[tokio::test]
+async fn cancel_and_amend() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let mut state = env.order(&order).await;
+
+    let stranger = Keypair::new();
+    let mut not_owner = state.clone();
+    not_owner.amend_authority = stranger.pubkey();
+    assert_error(
+        env.process(&[client::cancel_order(env.program_id, &not_owner)], &[&stranger]).await,
+        ErrorCode::IncorrectAmendAccount,
+    );
+    assert_error(
+        env.process(&[client::amend_order(env.program_id, &not_owner, 5990, ONE, 6000)], &[&stranger]).await,
+        ErrorCode::IncorrectAmendAccount,
+    );
+
+    let user = env.user.pubkey();
+    assert_error(env.send_as_user(client::close_order(env.program_id, &state, user)).await, ErrorCode::OrderNotTerminal);
+
+    // half the deposit comes back on amending down
+    let coin_before = env.token_balance(&env.user_coin.clone()).await;
+    env.send_as_user(client::amend_order(env.program_id, &state, 5980, ONE / 2, 6000)).await.unwrap();
+    state = env.order(&order).await;
+    assert_eq!((state.limit_price, state.trigger_price, state.coin_leaves_qty), (5980, 6000, ONE / 2));
+    assert_eq!(env.token_balance(&env.user_coin.clone()).await - coin_before, ONE / 2);
+
+    env.send_as_user(client::cancel_order(env.program_id, &state)).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Cancelled);
+    assert_eq!(env.token_balance(&env.user_coin.clone()).await, coin_before + ONE);
+    assert_error(env.send_as_user(client::cancel_order(env.program_id, &state)).await, ErrorCode::OrderAlreadyCancelled);
+    assert_error(env.execute(&order, ONE / 2, false).await, ErrorCode::AttemptingToExecuteCancelledOrder);
+}
+
+#[tokio::test]
+async fn cancel_and_amend_reject_substituted_accounts() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let state = env.order(&order).await;
+    // real vaults, just another order's
+    let other = sell(&mut env).await;
+    let other = env.order(&other).await;
+
+    let cases = [
+        (4, other.stoploss_base_vault, ErrorCode::IncorrectStoplossPayingVault),
+        (5, other.stoploss_quote_vault, ErrorCode::IncorrectStoplossReceivingVault),
+        (7, Pubkey::new_unique(), ErrorCode::IncorrectTokenProgram),
+    ];
+    for &(index, account, expected) in cases.iter() {
+        let mut ix = client::cancel_order(env.program_id, &state);
+        ix.accounts[index].pubkey = account;
+        assert_error(env.send_as_user(ix).await, expected);
+    }
+    let cases = [
+        (4, other.stoploss_base_vault, ErrorCode::IncorrectStoplossPayingVault),
+        (6, Pubkey::new_unique(), ErrorCode::IncorrectTokenProgram),
+    ];
+    for &(index, account, expected) in cases.iter() {
+        let mut ix = client::amend_order(env.program_id, &state, 5980, ONE / 2, 6000);
+        ix.accounts[index].pubkey = account;
+        assert_error(env.send_as_user(ix).await, expected);
+    }
+
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+    assert_eq!(env.token_balance(&other.stoploss_base_vault).await, ONE);
+}
+
+// `order` as it is in `env` once edited by `edit`, along with its wallets and vaults, for
+// loading into another env
+async fn forged_order(env: &mut Env, order: &Pubkey, edit: impl FnOnce(&mut StoplossState)) -> Vec<(Pubkey, Account)> {
+    let mut state = env.order(order).await;
+    edit(&mut state);
+    let len = env.ctx.banks_client.get_account(*order).await.unwrap().unwrap().data.len();
+    let mut accounts = vec![(*order, stoploss_account(&state, len))];
+    for address in [state.client_coin_wallet, state.client_pc_wallet, state.stoploss_base_vault, state.stoploss_quote_vault].iter() {
+        let account = env.ctx.banks_client.get_account(*address).await.unwrap().unwrap();
+        accounts.push((*address, account));
+    }
+    accounts
+}
+
+#[tokio::test]
+async fn terminal_orders_stay_terminal() {
+    // an order only expires with the clock and a filled order has closed its vaults, so both
+    // are forged from live orders
+    let owner = Keypair::new();
+    let mut env = env().await;
+    let expired = sell(&mut env).await;
+    let filled = sell(&mut env).await;
+    let mut accounts = forged_order(&mut env, &expired, |state| {
+        state.amend_authority = owner.pubkey();
+        state.ord_status = OrdStatus::Expired;
+        state.coin_leaves_qty = 0;
+    })
+    .await;
+    accounts.extend(
+        forged_order(&mut env, &filled, |state| {
+            state.amend_authority = owner.pubkey();
+            state.ord_status = OrdStatus::Filled;
+            state.coin_leaves_qty = 0;
+            state.coin_cum_qty = ONE;
+        })
+        .await,
+    );
+    let mut env = Env::with_accounts(BIDS, ASKS, &accounts).await;
+
+    let state = env.order(&expired).await;
+    assert_error(env.process(&[client::cancel_order(env.program_id, &state)], &[&owner]).await, ErrorCode::OrderAlreadyExpired);
+    assert_error(
+        env.process(&[client::amend_order(env.program_id, &state, 5980, ONE, 6010)], &[&owner]).await,
+        ErrorCode::OrderAlreadyExpired,
+    );
+    assert_error(env.process(&[client::expire_order(env.program_id, &state)], &[]).await, ErrorCode::OrderAlreadyExpired);
+    assert_error(env.execute(&expired, ONE, false).await, ErrorCode::AttemptingToExecuteExpiredOrder);
+
+    // the deposit is still in the vault, so closing would lose it
+    let state = env.order(&filled).await;
+    assert_error(
+        env.process(&[client::close_order(env.program_id, &state, owner.pubkey())], &[&owner]).await,
+        ErrorCode::OrderVaultNotEmpty,
+    );
+}
+
+#[tokio::test]
+async fn keeper_execution() {
+    let mut env = env().await;
+    assert_error(
+        env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).keeper(ONE)).await.map(|_| ()),
+        ErrorCode::KeeperBountyTooLarge,
+    );
+
+    let bounty = 1_000;
+    let order = env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).keeper(bounty)).await;
+    let keeper = Keypair::new();
+    let keeper_coin = env.create_token_account(&env.market.coin_mint.clone(), &keeper.pubkey()).await;
+    let state = env.order(&order).await;
+
+    let keeper_pc = env.create_token_account(&env.market.pc_mint.clone(), &keeper.pubkey()).await;
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, keeper.pubkey())
+        .keeper_bounty_account(keeper_pc)
+        .instruction();
+    assert_error(env.process(&[ix], &[&keeper]).await, ErrorCode::IncorrectKeeperBountyAccount);
+
+    // whatever the keeper asks for, the child is all of the leaves less the bounty as an IOC
+    // at the order's limit
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, keeper.pubkey())
+        .execute_qty(100_000)
+        .execute_limit(6000)
+        .order_type(OrderType::PostOnly)
+        .keeper_bounty_account(keeper_coin)
+        .instruction();
+    env.process(&[ix], &[&keeper]).await.unwrap();
+    assert_eq!(env.token_balance(&keeper_coin).await, bounty);
+    let state = env.order(&order).await;
+    assert!(state.keeper_bounty_paid);
+    assert_eq!(state.resting_qty, 0);
+    // only whole lots trade, and the rest stays on the order
+    assert_eq!(state.coin_cum_qty, 9 * 100_000);
+    assert_eq!(state.coin_leaves_qty, ONE - bounty - 9 * 100_000);
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+
+    // without keeper_allowed only the signal provider may execute
+    let order = sell(&mut env).await;
+    let state = env.order(&order).await;
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, keeper.pubkey())
+        .execute_qty(ONE)
+        .keeper_bounty_account(keeper_coin)
+        .instruction();
+    assert_error(env.process(&[ix], &[&keeper]).await, ErrorCode::IncorrectSignalProviderAccount);
+}
+
+#[tokio::test]
+async fn keeper_bounty_is_held_back_from_every_child() {
+    let mut env = env().await;
+    let bounty = 100_000;
+    let order = env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).keeper(bounty)).await;
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::ExecuteQtyExceedsLeaves);
+
+    // the signal provider works the order down to a lot above the bounty
+    env.execute(&order, ONE - bounty - 100_000, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::PartiallyFilled);
+    assert_eq!(state.coin_leaves_qty, bounty + 100_000);
+
+    // which still leaves a keeper the last lot and its bounty
+    let keeper = Keypair::new();
+    let keeper_coin = env.create_token_account(&env.market.coin_mint.clone(), &keeper.pubkey()).await;
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, keeper.pubkey())
+        .keeper_bounty_account(keeper_coin)
+        .instruction();
+    env.process(&[ix], &[&keeper]).await.unwrap();
+    assert_eq!(env.token_balance(&keeper_coin).await, bounty);
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    assert_eq!(state.coin_cum_qty, ONE - bounty);
+
+    // filled by the signal provider alone, the bounty goes back to the owner
+    let order = env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).keeper(bounty)).await;
+    let coin_before = env.token_balance(&env.user_coin.clone()).await;
+    env.execute(&order, ONE - bounty, false).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Filled);
+    assert_eq!(env.token_balance(&env.user_coin.clone()).await - coin_before, bounty);
+}
+
+#[tokio::test]
+async fn keeper_is_not_paid_without_a_fill() {
+    let mut env = env().await;
+    // triggered, but the best bid is below the limit
+    let order = env.new_order(Side::Ask, |b| b.prices(6030, 6010).quantity(ONE, 0).keeper(1_000)).await;
+    let keeper = Keypair::new();
+    let keeper_coin = env.create_token_account(&env.market.coin_mint.clone(), &keeper.pubkey()).await;
+    let state = env.order(&order).await;
+    let ix = ExecuteOrderBuilder::new(env.program_id, env.market, &state, keeper.pubkey())
+        .keeper_bounty_account(keeper_coin)
+        .instruction();
+    env.process(&[ix], &[&keeper]).await.unwrap();
+
+    assert_eq!(env.token_balance(&keeper_coin).await, 0);
+    let state = env.order(&order).await;
+    assert!(!state.keeper_bounty_paid);
+    assert!(!state.triggered);
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!(state.coin_leaves_qty, ONE);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+}
+
+#[tokio::test]
+async fn trailing_mark_follows_the_book() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let state = env.order(&order).await;
+    assert_error(
+        env.send_as_user(client::update_trailing_mark(env.program_id, &state)).await,
+        ErrorCode::NotATrailingOrder,
+    );
+
+    let order = env
+        .new_order(Side::Ask, |b| b.prices(5900, 0).quantity(ONE, 0).trailing(TriggerType::TrailingAbsolute, 50))
+        .await;
+    // the mark starts at the best bid, and the book has not moved since
+    let state = env.order(&order).await;
+    assert_eq!(state.trailing_mark, 6004);
+    env.send_as_user(client::update_trailing_mark(env.program_id, &state)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.trailing_mark, 6004);
+    assert_eq!(state.effective_trigger_price(), 5954);
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::TriggerPriceNotReached);
+
+    // the trigger comes from the mark, only the limit and size can be amended
+    assert_error(
+        env.send_as_user(client::amend_order(env.program_id, &state, 5900, ONE, 6010)).await,
+        ErrorCode::CannotAmendTrailingTrigger,
+    );
+    env.send_as_user(client::amend_order(env.program_id, &state, 5950, ONE, 0)).await.unwrap();
+    assert_eq!(env.order(&order).await.limit_price, 5950);
+
+    let result = env
+        .try_new_order(Side::Ask, |b| b.prices(5900, 0).quantity(ONE, 0).trailing(TriggerType::TrailingBps, 10_001))
+        .await;
+    assert_error(result.map(|_| ()), ErrorCode::InvalidBps);
+}
+
+#[tokio::test]
+async fn bracket_fill_cancels_sibling() {
+    let mut env = env().await;
+    // stop loss well below the book, take profit just under the best bid
+    let order = env.new_order(Side::Ask, |b| b.prices(5800, 5900).quantity(ONE, 0)).await;
+    let state = env.order(&order).await;
+
+    let leg = Keypair::new();
+    let space = 8 + std::mem::size_of::<anchor_stoploss::StoplossState>();
+    let create_leg = system_instruction::create_account(
+        &env.ctx.payer.pubkey(),
+        &leg.pubkey(),
+        Rent::default().minimum_balance(space),
+        space as u64,
+        &env.program_id,
+    );
+    let user = Keypair::from_bytes(&env.user.to_bytes()).unwrap();
+    let ix = client::new_bracket_leg(env.program_id, &state, leg.pubkey(), 5990, 2, 6000);
+    env.process(&[create_leg, ix], &[&user, &leg]).await.unwrap();
+
+    let state = env.order(&order).await;
+    assert_eq!(state.sibling, leg.pubkey());
+    let other = Keypair::new();
+    let ix = client::new_bracket_leg(env.program_id, &state, other.pubkey(), 5990, 3, 6000);
+    assert_error(env.process(&[ix], &[&user]).await, ErrorCode::AlreadyBracketed);
+    assert_error(
+        env.send_as_user(client::amend_order(env.program_id, &state, 5800, ONE / 2, 5900)).await,
+        ErrorCode::CannotAmendBracketQuantity,
+    );
+
+    // cancelling one leg cancels the other, which has to be passed along
+    let mut ix = client::cancel_order(env.program_id, &state);
+    ix.accounts.pop();
+    assert_error(env.send_as_user(ix).await, ErrorCode::MissingSiblingAccount);
+    let mut ix = client::cancel_order(env.program_id, &state);
+    ix.accounts.last_mut().unwrap().pubkey = Pubkey::new_unique();
+    assert_error(env.send_as_user(ix).await, ErrorCode::IncorrectSiblingAccount);
+
+    // the stop loss has not triggered but the take profit has
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::TriggerPriceNotReached);
+    env.execute(&leg.pubkey(), ONE, false).await.unwrap();
+    assert_eq!(env.order(&leg.pubkey()).await.ord_status, OrdStatus::Filled);
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Cancelled);
+}
+
+#[tokio::test]
+async fn bracket_legs_share_open_orders() {
+    let mut env = env().await;
+    let order = env.try_new_order(Side::Ask, |b| b.prices(5800, 5900).quantity(ONE, 0)).await.unwrap();
+    let state = env.order(&order).await;
+    let leg = Keypair::new();
+    let space = 8 + std::mem::size_of::<anchor_stoploss::StoplossState>();
+    let create_leg = system_instruction::create_account(
+        &env.ctx.payer.pubkey(),
+        &leg.pubkey(),
+        Rent::default().minimum_balance(space),
+        space as u64,
+        &env.program_id,
+    );
+    let user = Keypair::from_bytes(&env.user.to_bytes()).unwrap();
+    let ix = client::new_bracket_leg(env.program_id, &state, leg.pubkey(), 5990, 2, 6000);
+    env.process(&[create_leg, ix], &[&user, &leg]).await.unwrap();
+    assert_eq!(env.order(&leg.pubkey()).await.ord_status, OrdStatus::PendingInit);
+
+    // one initialise moves both legs
+    env.initialise_open_orders(&order).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::New);
+    assert_eq!(env.order(&leg.pubkey()).await.ord_status, OrdStatus::New);
+    assert_error(env.initialise_open_orders(&leg.pubkey()).await, ErrorCode::OpenOrdersAlreadyInitialised);
+
+    env.execute(&leg.pubkey(), ONE, false).await.unwrap();
+    let (state, leg_state) = (env.order(&order).await, env.order(&leg.pubkey()).await);
+    assert_eq!(state.ord_status, OrdStatus::Cancelled);
+    assert_eq!(leg_state.ord_status, OrdStatus::Filled);
+
+    // the first leg closed takes the shared open orders with it, the second finds it gone
+    let mut ix = client::close_order(env.program_id, &leg_state, env.user.pubkey());
+    ix.accounts.pop();
+    assert_error(env.send_as_user(ix).await, ErrorCode::MissingSiblingAccount);
+    env.send_as_user(client::close_order(env.program_id, &leg_state, env.user.pubkey())).await.unwrap();
+    assert!(!env.account_exists(&state.stoploss_open_orders).await);
+    env.send_as_user(client::close_order(env.program_id, &state, env.user.pubkey())).await.unwrap();
+    assert!(!env.account_exists(&order).await);
+    assert!(!env.account_exists(&leg.pubkey()).await);
+}
+
+#[tokio::test]
+async fn suspend_and_resume() {
+    let mut env = env().await;
+    let order = sell(&mut env).await;
+    let state = env.order(&order).await;
+
+    let stranger = Keypair::new();
+    let mut not_owner = state.clone();
+    not_owner.amend_authority = stranger.pubkey();
+    assert_error(
+        env.process(&[client::suspend_order(env.program_id, &not_owner)], &[&stranger]).await,
+        ErrorCode::IncorrectAmendAccount,
+    );
+    assert_error(env.send_as_user(client::resume_order(env.program_id, &state)).await, ErrorCode::OrderNotSuspended);
+
+    env.send_as_user(client::suspend_order(env.program_id, &state)).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Suspended);
+    assert_error(env.send_as_user(client::suspend_order(env.program_id, &state)).await, ErrorCode::OrderAlreadySuspended);
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::OrderSuspended);
+    // the deposit stays put while suspended
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+
+    env.send_as_user(client::resume_order(env.program_id, &state)).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::New);
+    env.execute(&order, ONE, false).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Filled);
+    assert_error(env.send_as_user(client::suspend_order(env.program_id, &state)).await, ErrorCode::OrderNotActive);
+}
+
+#[tokio::test]
+async fn suspend_waits_for_the_resting_child() {
+    let mut env = env().await;
+    let order = sell_resting(&mut env).await;
+    let state = env.order(&order).await;
+    let user = env.user.pubkey();
+    assert_error(env.send_as_user(client::suspend_order(env.program_id, &state)).await, ErrorCode::ChildOrderResting);
+
+    // settling books nothing and leaves the child working
+    env.send_as_user(client::settle_order(env.program_id, env.market, &state, user)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::New);
+    assert_eq!(state.resting_qty, ONE);
+
+    env.send_as_user(client::cancel_child_order(env.program_id, env.market, &state, user)).await.unwrap();
+    env.send_as_user(client::suspend_order(env.program_id, &state)).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Suspended);
+    assert_eq!(env.token_balance(&state.stoploss_base_vault).await, ONE);
+    assert_error(env.send_as_user(client::settle_order(env.program_id, env.market, &state, user)).await, ErrorCode::NoRestingChildOrder);
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Suspended);
+}
+
+// a sell like `sell` that triggers off `oracle`, accepting prices up to a minute old within
+// 50 bps confidence
+async fn sell_with_oracle(env: &mut Env, oracle: Pubkey) -> std::result::Result<Pubkey, TransportError> {
+    let order = env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).should_create_open_orders(true)).await?;
+    let ix = client::set_oracle(env.program_id, order, &env.market, env.user.pubkey(), oracle, 60, 50);
+    env.send_as_user(ix).await?;
+    Ok(order)
+}
+
+#[tokio::test]
+async fn oracle_orders_trigger_off_the_oracle() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let (above, below, stale, wide) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+    let (not_pyth, not_a_price, not_trading) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+    let mut not_trading_price = pyth_price_account(600_000_000, 10_000, -8, now);
+    not_trading_price.data[OraclePrice::AGG_STATUS_OFFSET..OraclePrice::AGG_STATUS_OFFSET + 4].copy_from_slice(&0u32.to_le_bytes());
+    let mut env = Env::with_accounts(
+        BIDS,
+        ASKS,
+        &[
+            (above, pyth_price_account(602_000_000, 10_000, -8, now)),
+            (below, pyth_price_account(600_000_000, 10_000, -8, now)),
+            (stale, pyth_price_account(600_000_000, 10_000, -8, now - 3_600)),
+            // 1% either side
+            (wide, pyth_price_account(600_000_000, 6_000_000, -8, now)),
+            // the right layout under the wrong owner, and the wrong layout under the right one
+            (not_pyth, Account { owner: Pubkey::new_unique(), ..pyth_price_account(600_000_000, 10_000, -8, now) }),
+            (not_a_price, Account { data: vec![0; 3312], ..pyth_price_account(600_000_000, 10_000, -8, now) }),
+            (not_trading, not_trading_price),
+        ],
+    )
+    .await;
+
+    // the best bid of 6.004 is through the 6.01 trigger but the oracle at 6.02 is not
+    let order = sell_with_oracle(&mut env, above).await.unwrap();
+    assert_eq!(env.order(&order).await.oracle, above);
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::TriggerPriceNotReached);
+
+    let order = sell_with_oracle(&mut env, stale).await.unwrap();
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::OraclePriceStale);
+
+    let order = sell_with_oracle(&mut env, wide).await.unwrap();
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::OracleConfidenceTooWide);
+
+    let order = sell_with_oracle(&mut env, not_trading).await.unwrap();
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::OraclePriceUnavailable);
+
+    let order = sell_with_oracle(&mut env, below).await.unwrap();
+    let mut ix = env.execute_builder(&order, ONE, false).await;
+    ix.accounts.last_mut().unwrap().pubkey = above;
+    let signal_provider = Keypair::from_bytes(&env.signal_provider.to_bytes()).unwrap();
+    assert_error(env.process(&[ix], &[&signal_provider]).await, ErrorCode::IncorrectOracleAccount);
+    env.execute(&order, ONE, false).await.unwrap();
+    assert_eq!(env.order(&order).await.ord_status, OrdStatus::Filled);
+
+    let user = env.user.pubkey();
+    let ix = client::set_oracle(env.program_id, order, &env.market, user, below, 60, 50);
+    assert_error(env.send_as_user(ix).await, ErrorCode::OracleLockedAfterExecution);
+    let market = env.market.market;
+    assert_error(sell_with_oracle(&mut env, market).await.map(|_| ()), ErrorCode::IncorrectOracleProgram);
+    assert_error(sell_with_oracle(&mut env, not_pyth).await.map(|_| ()), ErrorCode::IncorrectOracleProgram);
+    assert_error(sell_with_oracle(&mut env, not_a_price).await.map(|_| ()), ErrorCode::InvalidOracleAccount);
+
+    // every oracle order has a max age
+    let order = env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0)).await;
+    let ix = client::set_oracle(env.program_id, order, &env.market, user, below, 0, 50);
+    assert_error(env.send_as_user(ix).await, ErrorCode::InvalidOracleMaxAge);
+
+    // nothing passes until the admin names the oracle program
+    env.update_config(instruction::SetOracleProgram { oracle_program: Pubkey::default() }).await.unwrap();
+    let ix = client::set_oracle(env.program_id, order, &env.market, user, below, 60, 50);
+    assert_error(env.send_as_user(ix).await, ErrorCode::IncorrectOracleProgram);
+}
+
+#[tokio::test]
+async fn oracle_trailing_orders_trail_the_oracle() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let (oracle, stale) = (Pubkey::new_unique(), Pubkey::new_unique());
+    let mut env = Env::with_accounts(
+        BIDS,
+        ASKS,
+        &[
+            (oracle, pyth_price_account(602_000_000, 10_000, -8, now)),
+            (stale, pyth_price_account(602_000_000, 10_000, -8, now - 3_600)),
+        ],
+    )
+    .await;
+    let user = env.user.pubkey();
+
+    let order = env
+        .new_order(Side::Ask, |b| b.prices(5900, 0).quantity(ONE, 0).trailing(TriggerType::TrailingAbsolute, 50))
+        .await;
+    assert_eq!(env.order(&order).await.trailing_mark, 6004);
+    // the mark can only restart from a usable oracle price
+    let ix = client::set_oracle(env.program_id, order, &env.market, user, stale, 60, 50);
+    assert_error(env.send_as_user(ix).await, ErrorCode::OraclePriceStale);
+
+    // the mark restarts from the oracle at 6.02 rather than the 6.004 best bid
+    let ix = client::set_oracle(env.program_id, order, &env.market, user, oracle, 60, 50);
+    env.send_as_user(ix).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.trailing_mark, 6020);
+    assert_eq!(state.effective_trigger_price(), 5970);
+
+    // a best bid above the oracle does not move the mark
+    env.seed_book(&[(6030, 100)], &[]).await;
+    env.send_as_user(client::update_trailing_mark(env.program_id, &state)).await.unwrap();
+    assert_eq!(env.order(&order).await.trailing_mark, 6020);
+
+    let mut ix = client::update_trailing_mark(env.program_id, &state);
+    ix.accounts.last_mut().unwrap().pubkey = stale;
+    assert_error(env.send_as_user(ix).await, ErrorCode::IncorrectOracleAccount);
+}
+
+// a sell of `qty` base with its strategy set in the same transaction
+async fn sell_with_strategy(env: &mut Env, qty: u64, strategy: impl FnOnce(Pubkey) -> SetStrategyBuilder) -> std::result::Result<Pubkey, TransportError> {
+    let order = env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(qty, 0).should_create_open_orders(true)).await?;
+    env.send_as_user(strategy(order).instruction()).await?;
+    Ok(order)
+}
+
+#[tokio::test]
+async fn twap_children_follow_the_schedule() {
+    let mut env = env().await;
+    let (program_id, user) = (env.program_id, env.user.pubkey());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let twap = |start_ts: i64, slice_count: u16| {
+        move |order| {
+            SetStrategyBuilder::new(program_id, order, user, Strategy::Twap, start_ts, start_ts + 3_600)
+                .slice_count(slice_count)
+                .min_child_interval(600)
+        }
+    };
+    assert_error(sell_with_strategy(&mut env, 4 * ONE, twap(now, 0)).await.map(|_| ()), ErrorCode::InvalidStrategy);
+
+    // a keeper child would take the whole order in one go
+    let keeper_order = env.new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(4 * ONE, 0).keeper(1_000)).await;
+    assert_error(env.send_as_user(twap(now - 10, 4)(keeper_order).instruction()).await, ErrorCode::KeeperNotAllowedWithStrategy);
+
+    let order = sell_with_strategy(&mut env, 4 * ONE, twap(now + 3_600, 4)).await.unwrap();
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::StrategyNotStarted);
+
+    // a quarter of the order in each 15 minute slice, 10 minutes apart
+    let order = sell_with_strategy(&mut env, 4 * ONE, twap(now - 10, 4)).await.unwrap();
+    assert_error(env.execute(&order, 2 * ONE, true).await, ErrorCode::ExceedsStrategySchedule);
+    env.execute(&order, ONE, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.coin_cum_qty, ONE);
+    assert!(state.last_child_ts >= now - 10);
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::ChildOrderTooSoon);
+
+    let ix = SetStrategyBuilder::new(program_id, order, user, Strategy::None, 0, 0).instruction();
+    assert_error(env.send_as_user(ix).await, ErrorCode::StrategyLockedAfterExecution);
+}
+
+#[tokio::test]
+async fn vwap_children_are_capped_by_participation() {
+    let mut env = env().await;
+    let (program_id, user) = (env.program_id, env.user.pubkey());
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    // a vwap needs a window and a cap
+    for &(end_ts, max_participation_bps) in [(now - 10, 5_000), (now + 3_600, 0)].iter() {
+        let result = sell_with_strategy(&mut env, 10 * ONE, |order| {
+            SetStrategyBuilder::new(program_id, order, user, Strategy::Vwap, now - 10, end_ts).max_participation_bps(max_participation_bps)
+        })
+        .await;
+        assert_error(result.map(|_| ()), ErrorCode::InvalidStrategy);
+    }
+
+    let order = sell_with_strategy(&mut env, 10 * ONE, |order| {
+        SetStrategyBuilder::new(program_id, order, user, Strategy::Vwap, now - 10, now + 3_600).max_participation_bps(5_000)
+    })
+    .await
+    .unwrap();
+
+    // half of the 8.5 base bid at 6.004 is 42 lots
+    assert_error(env.execute(&order, 5 * ONE, true).await, ErrorCode::ExceedsMaxParticipation);
+    env.execute(&order, 4_200_000, true).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.coin_cum_qty, 4_200_000);
+
+    // the slices are measured against the deposit, only the prices can be amended
+    assert_error(
+        env.send_as_user(client::amend_order(env.program_id, &state, 5990, 9 * ONE, 6010)).await,
+        ErrorCode::CannotAmendStrategyQuantity,
+    );
+    env.send_as_user(client::amend_order(env.program_id, &state, 5980, 10 * ONE, 6010)).await.unwrap();
+    assert_eq!(env.order(&order).await.limit_price, 5980);
+
+    let order = sell_with_strategy(&mut env, 10 * ONE, |order| {
+        SetStrategyBuilder::new(program_id, order, user, Strategy::Vwap, now - 3_600, now - 10).max_participation_bps(5_000)
+    })
+    .await
+    .unwrap();
+    assert_error(env.execute(&order, ONE, true).await, ErrorCode::StrategyEnded);
+}
+
+#[tokio::test]
+async fn time_in_force() {
+    let mut env = env().await;
+    assert_error(
+        env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).time_in_force(TimeInForce::GoodTillDate, 1))
+            .await
+            .map(|_| ()),
+        ErrorCode::InvalidExpiry,
+    );
+
+    let order = env
+        .new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0).time_in_force(TimeInForce::Day, 0))
+        .await;
+    let state = env.order(&order).await;
+    assert_eq!(state.time_in_force, TimeInForce::Day);
+    assert_eq!(state.expiry_ts % 86_400, 0);
+    assert_error(env.send_as_user(client::expire_order(env.program_id, &state)).await, ErrorCode::OrderNotExpired);
+
+    // good till cancel never expires
+    let order = sell(&mut env).await;
+    let state = env.order(&order).await;
+    assert_eq!(state.expiry_ts, 0);
+    assert_error(env.send_as_user(client::expire_order(env.program_id, &state)).await, ErrorCode::OrderNotExpired);
+}
+
+#[tokio::test]
+async fn config_admin_and_halt() {
+    let mut env = env().await;
+    let config = env.config().await;
+    assert_eq!(config.admin, env.ctx.payer.pubkey());
+    assert_eq!(config.signal_providers, vec![env.signal_provider.pubkey()]);
+
+    // only the admin can change the config
+    let stranger = Keypair::new();
+    let ix = client::update_config(env.program_id, stranger.pubkey(), instruction::SetHalted { halted: true });
+    assert_error(env.process(&[ix], &[&stranger]).await, ErrorCode::IncorrectAdminAccount);
+    let signal_provider = env.signal_provider.pubkey();
+    assert_error(
+        env.update_config(instruction::AddSignalProvider { signal_provider }).await,
+        ErrorCode::SignalProviderAlreadyApproved,
+    );
+    assert_error(
+        env.update_config(instruction::SetProtocolFee {
+            protocol_fee_bps: 10_001,
+            fee_recipient: Pubkey::default(),
+        })
+        .await,
+        ErrorCode::InvalidBps,
+    );
+    env.update_config(instruction::SetMaxSlippage { max_slippage_bps: 50 }).await.unwrap();
+    assert_eq!(env.config().await.max_slippage_bps, 50);
+
+    let order = sell(&mut env).await;
+    env.update_config(instruction::SetHalted { halted: true }).await.unwrap();
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::ProgramHalted);
+    assert_error(
+        env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0)).await.map(|_| ()),
+        ErrorCode::ProgramHalted,
+    );
+    // owners can still get their funds back
+    let state = env.order(&order).await;
+    env.send_as_user(client::cancel_order(env.program_id, &state)).await.unwrap();
+
+    env.update_config(instruction::SetHalted { halted: false }).await.unwrap();
+    env.update_config(instruction::RemoveSignalProvider { signal_provider }).await.unwrap();
+    assert_error(
+        env.try_new_order(Side::Ask, |b| b.prices(5990, 6010).quantity(ONE, 0)).await.map(|_| ()),
+        ErrorCode::SignalProviderNotApproved,
+    );
+
+    // the allow-list has to fit in the config account
+    for _ in 0..StoplossConfig::MAX_SIGNAL_PROVIDERS {
+        env.update_config(instruction::AddSignalProvider { signal_provider: Pubkey::new_unique() }).await.unwrap();
+    }
+    assert_error(
+        env.update_config(instruction::AddSignalProvider { signal_provider: Pubkey::new_unique() }).await,
+        ErrorCode::TooManySignalProviders,
+    );
+}
+
+#[tokio::test]
+async fn protocol_fee_is_taken_from_proceeds() {
+    let mut env = env().await;
+    let admin = env.ctx.payer.pubkey();
+    let recipient = Keypair::new();
+    let pc_mint = env.market.pc_mint;
+    let ix = client::initialise_fee_vault(env.program_id, admin, pc_mint);
+    env.process(&[ix], &[]).await.unwrap();
+    env.update_config(instruction::SetProtocolFee {
+        protocol_fee_bps: 100,
+        fee_recipient: recipient.pubkey(),
+    })
+    .await
+    .unwrap();
+
+    let order = sell(&mut env).await;
+    let pc_before = env.token_balance(&env.user_pc.clone()).await;
+    env.execute(&order, ONE, false).await.unwrap();
+    let state = env.order(&order).await;
+    assert_eq!(state.ord_status, OrdStatus::Filled);
+    let net = state.pc_cum_qty - state.fees_paid;
+    assert_eq!(state.protocol_fees_paid, net / 100);
+    assert_eq!(env.token_balance(&env.user_pc.clone()).await - pc_before, net - state.protocol_fees_paid);
+    let fee_vault = anchor_stoploss::fee_vault_address(&pc_mint, &env.program_id).0;
+    assert_eq!(env.token_balance(&fee_vault).await, state.protocol_fees_paid);
+
+    // fees only go to the fee recipient
+    let other = env.create_token_account(&pc_mint, &Pubkey::new_unique()).await;
+    let ix = client::withdraw_fees(env.program_id, admin, pc_mint, other);
+    assert_error(env.process(&[ix], &[]).await, ErrorCode::IncorrectFeeRecipient);
+    let recipient_pc = env.create_token_account(&pc_mint, &recipient.pubkey()).await;
+    let stranger = Keypair::new();
+    let ix = client::withdraw_fees(env.program_id, stranger.pubkey(), pc_mint, recipient_pc);
+    assert_error(env.process(&[ix], &[&stranger]).await, ErrorCode::IncorrectAdminAccount);
+    let ix = client::withdraw_fees(env.program_id, admin, pc_mint, recipient_pc);
+    env.process(&[ix], &[]).await.unwrap();
+    assert_eq!(env.token_balance(&recipient_pc).await, state.protocol_fees_paid);
+    assert_eq!(env.token_balance(&fee_vault).await, 0);
+}
+
+#[tokio::test]
+async fn empty_book_has_no_price() {
+    let mut env = Env::new(&[], &[]).await;
+    let order = sell(&mut env).await;
+    assert_error(env.execute(&order, ONE, false).await, ErrorCode::MarketPriceUnavailable);
+}
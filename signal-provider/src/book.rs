@@ -0,0 +1,24 @@
+// Reads the Serum book off chain with the same code the program uses to check triggers,
+// so the daemon and execute_order always agree on the market price.
+
+use anchor_lang::prelude::*;
+use anchor_stoploss::StoplossState;
+
+/// The best price, in price lots, that a child order of `order` would trade against, ie
+/// the best bid for sells and the best ask for buys. None when that side of the book is
+/// empty or the accounts do not parse.
+pub fn best_opposite_price(order: &StoplossState, market: &[u8], bids: &[u8], asks: &[u8]) -> Option<u64> {
+    best_opposite_order(order, market, bids, asks).map(|(price, _)| price)
+}
+
+/// Like `best_opposite_price`, along with the size of the best order in base lots.
+pub fn best_opposite_order(order: &StoplossState, market: &[u8], bids: &[u8], asks: &[u8]) -> Option<(u64, u64)> {
+    let (mut market, mut bids, mut asks) = (market.to_vec(), bids.to_vec(), asks.to_vec());
+    let (mut market_lamports, mut bids_lamports, mut asks_lamports) = (0, 0, 0);
+    let market_info = AccountInfo::new(&order.market, false, true, &mut market_lamports, &mut market, &order.dex_program, false, 0);
+    let bids_info = AccountInfo::new(&order.bids, false, true, &mut bids_lamports, &mut bids, &order.dex_program, false, 0);
+    let asks_info = AccountInfo::new(&order.asks, false, true, &mut asks_lamports, &mut asks, &order.dex_program, false, 0);
+    anchor_stoploss::best_opposite_order(&market_info, &bids_info, &asks_info, order.side, &order.dex_program)
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,322 @@
+// The signal provider loop. Each tick loads every order this key is the signal provider for,
+// initialises the open orders of new orders, expires orders past their time in force, settles
+// resting child orders, and executes any order whose trigger has fired.
+
+use crate::book;
+use crate::rpc::{RpcError, StoplossRpc};
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{AccountDeserialize, Discriminator};
+use anchor_stoploss::client::{self, ExecuteOrderBuilder, MarketInfo};
+use anchor_stoploss::{is_triggered, open_orders_address, LotSizes, OpenOrdersBalances, OraclePrice, OrdStatus, OrderMode, Side, StoplossState, Strategy, TriggerType};
+use solana_sdk::signature::{Keypair, Signer};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct Config {
+    pub program_id: Pubkey,
+    /// Largest child order to send, in native base on both sides. Orders bigger than this run
+    /// as several children with `reuse_unfilled` set.
+    pub max_child_qty: Option<u64>,
+}
+
+/// What to send for a triggered order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChildOrder {
+    pub execute_qty: u64,
+    pub execute_limit: u64,
+    pub reuse_unfilled: bool,
+    // trailing orders move the mark in the same transaction so the program sees the same
+    // trigger the daemon did
+    pub update_trailing_mark: bool,
+}
+
+/// Decides whether `order` should execute given the best opposite price, and sizes the child.
+/// Orders with an oracle trail and trigger off `oracle_price` instead, in price lots, though
+/// the book still needs an opposite order to trade with. Orders that have already triggered
+/// carry on whatever the price.
+///
+/// Children are sized in base and rounded down to whole coin lots, as the DEX would leave the
+/// remainder in the vault and the order would never reach Filled. Buys are sized to what
+/// their quote leaves buy at the limit once the taker fee is paid, up to their coin cap.
+pub fn plan_child_order(order: &StoplossState, market_price: Option<u64>, oracle_price: Option<u64>, lots: &LotSizes, max_child_qty: Option<u64>) -> Option<ChildOrder> {
+    if order.ord_status != OrdStatus::New && order.ord_status != OrdStatus::PartiallyFilled {
+        return None;
+    }
+    let trigger_px = trigger_check_price(order, market_price, oracle_price)?;
+
+    let mut order = order.clone();
+    let update_trailing_mark = ratchets_trailing_mark(&order, trigger_px);
+    if update_trailing_mark {
+        order.trailing_mark = trigger_px;
+    }
+    // the program stops checking the trigger once the first child has gone out
+    if !order.triggered && !is_triggered(order.side, order.order_kind, trigger_px, order.effective_trigger_price()) {
+        return None;
+    }
+
+    // the keeper bounty is held back from every child
+    let reserved_bounty = order.reserved_bounty();
+    let leaves = match order.side {
+        Side::Ask => lots.round_coin(order.coin_leaves_qty.saturating_sub(reserved_bounty)),
+        Side::Bid => lots
+            .coin_qty_for(order.pc_leaves_qty.saturating_sub(reserved_bounty), order.limit_price)
+            .min(lots.round_coin(order.coin_leaves_qty)),
+    };
+    let execute_qty = lots.round_coin(max_child_qty.map_or(leaves, |max| max.min(leaves)));
+    if execute_qty == 0 {
+        return None;
+    }
+    Some(ChildOrder {
+        execute_qty,
+        // stop-market children are priced on chain at the slippage cap
+        execute_limit: match order.order_mode {
+            OrderMode::StopLimit => order.limit_price,
+            OrderMode::StopMarket => 0,
+        },
+        reuse_unfilled: execute_qty < leaves,
+        update_trailing_mark,
+    })
+}
+
+/// Whether `price`, in price lots, moves the trailing mark of `order`, ie is a new high for
+/// sells or a new low for buys. Always false for Fixed triggers.
+pub fn ratchets_trailing_mark(order: &StoplossState, price: u64) -> bool {
+    if order.trigger_type == TriggerType::Fixed {
+        return false;
+    }
+    match order.side {
+        Side::Ask => price > order.trailing_mark,
+        Side::Bid => price < order.trailing_mark,
+    }
+}
+
+// the price `order` trails and triggers off, as the program sees it. None when the book has no
+// opposite order, or the order has an oracle without a usable price.
+fn trigger_check_price(order: &StoplossState, market_price: Option<u64>, oracle_price: Option<u64>) -> Option<u64> {
+    let px = market_price?;
+    match order.oracle == Pubkey::default() {
+        true => Some(px),
+        false => oracle_price,
+    }
+}
+
+/// Trims `child` to what the order's strategy allows at `now`, see `set_strategy`, given the
+/// size of the best opposite order in base lots. None when the strategy does not allow a
+/// child yet, or any more.
+pub fn fit_to_strategy(order: &StoplossState, child: ChildOrder, now: i64, lots: &LotSizes, best_order_lots: u64) -> Option<ChildOrder> {
+    if order.strategy == Strategy::None {
+        return Some(child);
+    }
+    if order.check_strategy_timing(now).is_err() {
+        return None;
+    }
+    let left = order.strategy_allowance(now).saturating_sub(order.paying_used());
+    let mut max_qty = match order.side {
+        Side::Ask => left,
+        Side::Bid => lots.coin_qty_for(left, order.limit_price),
+    };
+    if order.strategy == Strategy::Vwap {
+        let max_lots = best_order_lots as u128 * order.max_participation_bps as u128 / 10_000;
+        max_qty = max_qty.min((max_lots * lots.coin_lot_size as u128).min(u64::MAX as u128) as u64);
+    }
+    let execute_qty = lots.round_coin(child.execute_qty.min(max_qty));
+    if execute_qty == 0 {
+        return None;
+    }
+    Some(ChildOrder {
+        execute_qty,
+        // the rest goes in later slices
+        reuse_unfilled: child.reuse_unfilled || execute_qty < child.execute_qty,
+        ..child
+    })
+}
+
+pub struct Daemon<R: StoplossRpc> {
+    rpc: R,
+    signal_provider: Keypair,
+    config: Config,
+}
+
+impl<R: StoplossRpc> Daemon<R> {
+    pub fn new(rpc: R, signal_provider: Keypair, config: Config) -> Self {
+        Daemon {
+            rpc,
+            signal_provider,
+            config,
+        }
+    }
+
+    pub fn rpc(&self) -> &R {
+        &self.rpc
+    }
+
+    /// Runs `tick` forever, logging rather than stopping on errors.
+    pub fn run(&self, poll_interval: Duration) {
+        loop {
+            match self.tick() {
+                Ok(sent) if sent > 0 => println!("sent {} transactions", sent),
+                Ok(_) => (),
+                Err(e) => eprintln!("tick failed: {}", e),
+            }
+            thread::sleep(poll_interval);
+        }
+    }
+
+    /// One pass over every order. Returns the number of transactions sent.
+    ///
+    /// A failure on one order is logged and does not stop the others.
+    pub fn tick(&self) -> std::result::Result<usize, RpcError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut sent = 0;
+        for order in self.load_orders()? {
+            match self.process(&order, now) {
+                Ok(true) => sent += 1,
+                Ok(false) => (),
+                Err(e) => eprintln!("order {} failed: {}", order.own_address, e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// The orders this key is the signal provider for.
+    pub fn load_orders(&self) -> std::result::Result<Vec<StoplossState>, RpcError> {
+        let accounts = self.rpc.get_program_accounts(&self.config.program_id, &StoplossState::discriminator())?;
+        let mut orders = vec![];
+        for (address, data) in accounts {
+            match StoplossState::try_deserialize(&mut &data[..]) {
+                Ok(order) if order.signal_provider == self.signal_provider.pubkey() => orders.push(order),
+                Ok(_) => (),
+                Err(e) => eprintln!("unable to read order {}: {}", address, e),
+            }
+        }
+        Ok(orders)
+    }
+
+    fn process(&self, order: &StoplossState, now: i64) -> std::result::Result<bool, RpcError> {
+        match order.ord_status {
+            // the order can't be expired or executed again until its resting child is off the book
+            OrdStatus::New | OrdStatus::PartiallyFilled | OrdStatus::Suspended if order.resting_qty > 0 => self.settle_resting_child(order, now),
+            // the program refuses to execute these, so refund them instead
+            OrdStatus::PendingInit | OrdStatus::New | OrdStatus::PartiallyFilled | OrdStatus::Suspended if order.is_expired(now) => self.expire(order).map(|_| true),
+            // a bracket leg added to an order moves to New along with that order
+            OrdStatus::PendingInit if order.sibling != Pubkey::default() && open_orders_address(&order.own_address, &self.config.program_id).0 != order.stoploss_open_orders => Ok(false),
+            OrdStatus::PendingInit => self.initialise_open_orders(order).map(|_| true),
+            OrdStatus::New | OrdStatus::PartiallyFilled => self.execute_if_triggered(order, now),
+            _ => Ok(false),
+        }
+    }
+
+    // the program creates the order's open orders account, the signal provider pays the rent
+    fn initialise_open_orders(&self, order: &StoplossState) -> std::result::Result<(), RpcError> {
+        let ix = client::initialise_open_orders(self.config.program_id, order);
+        self.rpc.send_transaction(&[ix], &self.signal_provider, &[])?;
+        Ok(())
+    }
+
+    fn expire(&self, order: &StoplossState) -> std::result::Result<(), RpcError> {
+        let ix = client::expire_order(self.config.program_id, order);
+        let signature = self.rpc.send_transaction(&[ix], &self.signal_provider, &[])?;
+        println!("expired order {} at {}: {}", order.own_address, order.expiry_ts, signature);
+        Ok(())
+    }
+
+    // cancels the resting child of an expired order so it can be expired next tick, otherwise
+    // settles it once the DEX has credited any fills to the open orders account
+    fn settle_resting_child(&self, order: &StoplossState, now: i64) -> std::result::Result<bool, RpcError> {
+        let market_data = self.rpc.get_account_data(&order.market)?;
+        let market = MarketInfo::from_account_data(order.market, order.dex_program, &market_data)?;
+        let ix = if order.is_expired(now) {
+            client::cancel_child_order(self.config.program_id, market, order, self.signal_provider.pubkey())
+        } else {
+            let balances = OpenOrdersBalances::from_data(&self.rpc.get_account_data(&order.stoploss_open_orders)?)?;
+            if balances.coin_free == 0 && balances.pc_free == 0 {
+                return Ok(false);
+            }
+            client::settle_order(self.config.program_id, market, order, self.signal_provider.pubkey())
+        };
+        let signature = self.rpc.send_transaction(&[ix], &self.signal_provider, &[])?;
+        println!("settled child {} of order {}: {}", order.resting_client_order_id, order.own_address, signature);
+        Ok(true)
+    }
+
+    fn execute_if_triggered(&self, order: &StoplossState, now: i64) -> std::result::Result<bool, RpcError> {
+        let market_data = self.rpc.get_account_data(&order.market)?;
+        let bids = self.rpc.get_account_data(&order.bids)?;
+        let asks = self.rpc.get_account_data(&order.asks)?;
+        let market = MarketInfo::from_account_data(order.market, order.dex_program, &market_data)?;
+        let lots = market.lot_sizes(&self.rpc.get_account_data(&order.coin_mint)?, &self.rpc.get_account_data(&order.pc_mint)?)?;
+
+        let best_order = book::best_opposite_order(order, &market_data, &bids, &asks);
+        let px = best_order.map(|(price, _)| price);
+        // a stale or unusable oracle price is skipped, the program would refuse it too
+        let oracle_px = match order.oracle == Pubkey::default() {
+            true => None,
+            false => OraclePrice::from_data(&self.rpc.get_account_data(&order.oracle)?)?
+                .checked_price_lots(&lots, now, order.oracle_max_age, order.oracle_max_confidence_bps)
+                .ok(),
+        };
+        let (instructions, child) = self.order_instructions(order, market, &lots, best_order, oracle_px, now);
+        if instructions.is_empty() {
+            return Ok(false);
+        }
+        let signature = self.rpc.send_transaction(&instructions, &self.signal_provider, &[])?;
+        match child {
+            Some(child) => println!(
+                "executed order {} qty {} at market price {:?}: {}",
+                order.own_address,
+                lots.coin_ui(child.execute_qty),
+                px.map(|px| lots.price_ui(px)),
+                signature
+            ),
+            None => println!("moved trailing mark of order {} to {:?}: {}", order.own_address, oracle_px.or(px).map(|px| lots.price_ui(px)), signature),
+        }
+        Ok(true)
+    }
+
+    /// What to send for a live order given the best opposite order, as (price lots, size in
+    /// base lots), and the oracle price if it has a usable one, along with the child order
+    /// being executed. Empty when there is nothing to do.
+    ///
+    /// A trailing order whose mark moves without triggering still gets `update_trailing_mark`
+    /// on its own, so the mark on chain keeps up with the book, or the oracle, between
+    /// executions.
+    pub fn order_instructions(
+        &self,
+        order: &StoplossState,
+        market: MarketInfo,
+        lots: &LotSizes,
+        best_order: Option<(u64, u64)>,
+        oracle_px: Option<u64>,
+        now: i64,
+    ) -> (Vec<Instruction>, Option<ChildOrder>) {
+        let px = best_order.map(|(price, _)| price);
+        let child = plan_child_order(order, px, oracle_px, lots, self.config.max_child_qty)
+            .and_then(|child| fit_to_strategy(order, child, now, lots, best_order.map_or(0, |(_, qty)| qty)));
+
+        let mut instructions: Vec<Instruction> = vec![];
+        let child = match child {
+            Some(child) => child,
+            None => {
+                if trigger_check_price(order, px, oracle_px).map_or(false, |px| ratchets_trailing_mark(order, px)) {
+                    instructions.push(client::update_trailing_mark(self.config.program_id, order));
+                }
+                return (instructions, None);
+            }
+        };
+        if child.update_trailing_mark {
+            instructions.push(client::update_trailing_mark(self.config.program_id, order));
+        }
+        // nothing is paid to the signal provider, so any token account for the paying mint
+        // will do for the keeper bounty account
+        instructions.push(
+            ExecuteOrderBuilder::new(self.config.program_id, market, order, self.signal_provider.pubkey())
+                .execute_qty(child.execute_qty)
+                .execute_limit(child.execute_limit)
+                .reuse_unfilled(child.reuse_unfilled)
+                .keeper_bounty_account(order.client_paying_account)
+                .instruction(),
+        );
+        (instructions, Some(child))
+    }
+}
@@ -0,0 +1,11 @@
+//! Reference signal provider for the stoploss program.
+//!
+//! Watches every order it is the signal provider for, initialises their open orders and
+//! calls `execute_order` once the trigger has fired on the Serum book.
+
+pub mod book;
+pub mod daemon;
+pub mod rpc;
+
+pub use daemon::{fit_to_strategy, plan_child_order, ratchets_trailing_mark, ChildOrder, Config, Daemon};
+pub use rpc::{RpcError, SolanaRpc, StoplossRpc};
@@ -0,0 +1,52 @@
+use anchor_lang::solana_program::pubkey::Pubkey;
+use solana_sdk::signature::read_keypair_file;
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+use stoploss_signal_provider::{Config, Daemon, SolanaRpc};
+
+const USAGE: &str = "usage: stoploss-signal-provider --program-id <pubkey> --keypair <file> \
+                     [--url <rpc url>] [--max-child-qty <native base qty>] [--interval-ms <ms>]";
+
+fn main() {
+    let mut url = "http://localhost:8899".to_string();
+    let mut keypair = None;
+    let mut program_id = None;
+    let mut max_child_qty = None;
+    let mut interval_ms = 1000;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| exit(&format!("missing value for {}", flag)));
+        match flag.as_str() {
+            "--url" => url = value.clone(),
+            "--keypair" => keypair = Some(value.clone()),
+            "--program-id" => program_id = Some(Pubkey::from_str(value).unwrap_or_else(|_| exit("invalid --program-id"))),
+            "--max-child-qty" => max_child_qty = Some(value.parse().unwrap_or_else(|_| exit("invalid --max-child-qty"))),
+            "--interval-ms" => interval_ms = value.parse().unwrap_or_else(|_| exit("invalid --interval-ms")),
+            _ => exit(&format!("unknown argument {}", flag)),
+        }
+    }
+
+    let program_id = program_id.unwrap_or_else(|| exit("--program-id is required"));
+    let keypair = keypair.unwrap_or_else(|| exit("--keypair is required"));
+    let signal_provider = read_keypair_file(&keypair).unwrap_or_else(|e| exit(&format!("unable to read {}: {}", keypair, e)));
+
+    println!("running signal provider for program {} against {}", program_id, url);
+    let daemon = Daemon::new(
+        SolanaRpc::new(url),
+        signal_provider,
+        Config {
+            program_id,
+            max_child_qty,
+        },
+    );
+    daemon.run(Duration::from_millis(interval_ms));
+}
+
+fn exit(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    process::exit(1)
+}
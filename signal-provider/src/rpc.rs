@@ -0,0 +1,68 @@
+// The RPC calls the signal provider needs. Keeping them behind a trait lets the daemon run
+// against a validator through `SolanaRpc`, or against an in-process bank in tests.
+
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::pubkey::Pubkey;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use solana_sdk::transaction::Transaction;
+
+pub type RpcError = Box<dyn std::error::Error>;
+
+pub trait StoplossRpc {
+    /// Every account owned by `program_id` whose data starts with `discriminator`.
+    fn get_program_accounts(&self, program_id: &Pubkey, discriminator: &[u8; 8]) -> Result<Vec<(Pubkey, Vec<u8>)>, RpcError>;
+
+    fn get_account_data(&self, address: &Pubkey) -> Result<Vec<u8>, RpcError>;
+
+    /// Signs with `payer` and `signers` and sends one transaction, waiting for confirmation.
+    fn send_transaction(&self, instructions: &[Instruction], payer: &Keypair, signers: &[&Keypair]) -> Result<Signature, RpcError>;
+}
+
+/// `StoplossRpc` over a JSON RPC node, eg `solana-test-validator`.
+pub struct SolanaRpc {
+    client: RpcClient,
+}
+
+impl SolanaRpc {
+    pub fn new(url: String) -> Self {
+        SolanaRpc {
+            client: RpcClient::new_with_commitment(url, CommitmentConfig::confirmed()),
+        }
+    }
+}
+
+impl StoplossRpc for SolanaRpc {
+    fn get_program_accounts(&self, program_id: &Pubkey, discriminator: &[u8; 8]) -> Result<Vec<(Pubkey, Vec<u8>)>, RpcError> {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(vec![RpcFilterType::Memcmp(Memcmp {
+                offset: 0,
+                bytes: MemcmpEncodedBytes::Binary(bs58::encode(discriminator).into_string()),
+                encoding: None,
+            })]),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                ..RpcAccountInfoConfig::default()
+            },
+            ..RpcProgramAccountsConfig::default()
+        };
+        let accounts = self.client.get_program_accounts_with_config(program_id, config)?;
+        Ok(accounts.into_iter().map(|(key, account)| (key, account.data)).collect())
+    }
+
+    fn get_account_data(&self, address: &Pubkey) -> Result<Vec<u8>, RpcError> {
+        Ok(self.client.get_account_data(address)?)
+    }
+
+    fn send_transaction(&self, instructions: &[Instruction], payer: &Keypair, signers: &[&Keypair]) -> Result<Signature, RpcError> {
+        let (blockhash, _) = self.client.get_recent_blockhash()?;
+        let mut all_signers = vec![payer];
+        all_signers.extend_from_slice(signers);
+        let tx = Transaction::new_signed_with_payer(instructions, Some(&payer.pubkey()), &all_signers, blockhash);
+        Ok(self.client.send_and_confirm_transaction(&tx)?)
+    }
+}
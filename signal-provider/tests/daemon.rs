@@ -0,0 +1,499 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::{AccountSerialize, InstructionData};
+use anchor_stoploss::client::MarketInfo;
+use anchor_stoploss::{instruction, LotSizes, OrdStatus, OrderKind, OrderMode, OrderType, Price, Side, StoplossState, Strategy, TimeInForce, TriggerType, OPEN_ORDERS_LEN};
+use solana_sdk::signature::{Keypair, Signature, Signer};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use stoploss_signal_provider::{fit_to_strategy, plan_child_order, ChildOrder, Config, Daemon, RpcError, StoplossRpc};
+
+const COIN_LOT_SIZE: u64 = 100_000;
+const LOTS: LotSizes = LotSizes {
+    coin_lot_size: COIN_LOT_SIZE,
+    pc_lot_size: 100,
+    coin_decimals: 6,
+    pc_decimals: 6,
+};
+
+// in-memory accounts standing in for a bank
+#[derive(Default)]
+struct MockRpc {
+    accounts: HashMap<Pubkey, (Pubkey, Vec<u8>)>,
+    sent: RefCell<Vec<Vec<Instruction>>>,
+}
+
+impl MockRpc {
+    fn add_order(&mut self, program_id: Pubkey, order: &StoplossState) {
+        let mut data = vec![];
+        order.try_serialize(&mut data).unwrap();
+        self.accounts.insert(order.own_address, (program_id, data));
+    }
+}
+
+impl StoplossRpc for MockRpc {
+    fn get_program_accounts(&self, program_id: &Pubkey, discriminator: &[u8; 8]) -> std::result::Result<Vec<(Pubkey, Vec<u8>)>, RpcError> {
+        Ok(self
+            .accounts
+            .iter()
+            .filter(|(_, (owner, data))| owner == program_id && data.starts_with(discriminator))
+            .map(|(key, (_, data))| (*key, data.clone()))
+            .collect())
+    }
+
+    fn get_account_data(&self, address: &Pubkey) -> std::result::Result<Vec<u8>, RpcError> {
+        self.accounts.get(address).map(|(_, data)| data.clone()).ok_or_else(|| "account not found".into())
+    }
+
+    fn send_transaction(&self, instructions: &[Instruction], _payer: &Keypair, _signers: &[&Keypair]) -> std::result::Result<Signature, RpcError> {
+        self.sent.borrow_mut().push(instructions.to_vec());
+        Ok(Signature::default())
+    }
+}
+
+fn order(side: Side, signal_provider: Pubkey, ord_status: OrdStatus) -> StoplossState {
+    StoplossState {
+        own_address: Pubkey::new_unique(),
+        market: Pubkey::new_unique(),
+        stoploss_open_orders: Pubkey::new_unique(),
+        request_queue: Pubkey::new_unique(),
+        event_queue: Pubkey::new_unique(),
+        bids: Pubkey::new_unique(),
+        asks: Pubkey::new_unique(),
+        payer: Pubkey::new_unique(),
+        client_coin_wallet: Pubkey::new_unique(),
+        client_pc_wallet: Pubkey::new_unique(),
+        stoploss_base_vault: Pubkey::new_unique(),
+        stoploss_quote_vault: Pubkey::new_unique(),
+        dex_program: Pubkey::new_unique(),
+        pda: Pubkey::new_unique(),
+        stoploss_program: Pubkey::new_unique(),
+        vault_signer: Pubkey::new_unique(),
+        token_program: Pubkey::new_unique(),
+        amend_authority: Pubkey::new_unique(),
+        max_coin_qty: 1_000_000,
+        max_pc_qty: 6_000_000,
+        trigger_price: 550,
+        trigger_type: TriggerType::Fixed,
+        trailing_offset: 0,
+        trailing_mark: 0,
+        triggered: false,
+        order_kind: OrderKind::StopLoss,
+        order_mode: OrderMode::StopLimit,
+        sibling: Pubkey::default(),
+        side,
+        limit_price: 500,
+        client_order_id: 1,
+        coin_leaves_qty: 1_000_000,
+        pc_leaves_qty: 6_000_000,
+        coin_cum_qty: 0,
+        pc_cum_qty: 0,
+        last_price: Price::default(),
+        avg_price: Price::default(),
+        ord_status,
+        signal_provider,
+        child_order_count: 0,
+        client_paying_account: Pubkey::new_unique(),
+        coin_mint: Pubkey::new_unique(),
+        pc_mint: Pubkey::new_unique(),
+        should_create_open_orders: false,
+        open_orders_payer: Pubkey::default(),
+        keeper_allowed: false,
+        keeper_bounty: 0,
+        keeper_bounty_paid: false,
+        fees_paid: 0,
+        protocol_fees_paid: 0,
+        time_in_force: TimeInForce::GoodTillCancel,
+        expiry_ts: 0,
+        resting_qty: 0,
+        resting_client_order_id: 0,
+        oracle: Pubkey::default(),
+        oracle_max_age: 0,
+        oracle_max_confidence_bps: 0,
+        strategy: Strategy::None,
+        strategy_start_ts: 0,
+        strategy_end_ts: 0,
+        slice_count: 0,
+        max_participation_bps: 0,
+        min_child_interval: 0,
+        last_child_ts: 0,
+    }
+}
+
+fn daemon(rpc: MockRpc, signal_provider: Keypair, program_id: Pubkey) -> Daemon<MockRpc> {
+    Daemon::new(
+        rpc,
+        signal_provider,
+        Config {
+            program_id,
+            max_child_qty: None,
+        },
+    )
+}
+
+#[test]
+fn loads_only_own_orders() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    let mine = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    rpc.add_order(program_id, &mine);
+    rpc.add_order(program_id, &order(Side::Ask, Pubkey::new_unique(), OrdStatus::New));
+    // right discriminator, wrong program
+    rpc.add_order(Pubkey::new_unique(), &order(Side::Ask, signal_provider.pubkey(), OrdStatus::New));
+    rpc.accounts.insert(Pubkey::new_unique(), (program_id, vec![0; 64]));
+
+    let orders = daemon(rpc, signal_provider, program_id).load_orders().unwrap();
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].own_address, mine.own_address);
+}
+
+#[test]
+fn initialises_open_orders() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    let pending = order(Side::Bid, signal_provider.pubkey(), OrdStatus::PendingInit);
+    rpc.add_order(program_id, &pending);
+    rpc.add_order(program_id, &order(Side::Bid, signal_provider.pubkey(), OrdStatus::Filled));
+    rpc.add_order(program_id, &order(Side::Bid, signal_provider.pubkey(), OrdStatus::Cancelled));
+
+    let d = daemon(rpc, signal_provider, program_id);
+    assert_eq!(d.tick().unwrap(), 1);
+
+    let sent = d.rpc().sent.borrow();
+    assert_eq!(sent.len(), 1);
+    let ix = &sent[0][0];
+    assert_eq!(ix.program_id, program_id);
+    assert_eq!(ix.data, instruction::InitialiseOpenOrders {}.data());
+    assert_eq!(ix.accounts[1].pubkey, pending.own_address);
+    assert_eq!(ix.accounts[2].pubkey, pending.stoploss_open_orders);
+}
+
+#[test]
+fn initialises_bracket_legs_together() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    let mut pending = order(Side::Ask, signal_provider.pubkey(), OrdStatus::PendingInit);
+    pending.stoploss_open_orders = anchor_stoploss::open_orders_address(&pending.own_address, &program_id).0;
+    let mut leg = pending.clone();
+    leg.own_address = Pubkey::new_unique();
+    leg.sibling = pending.own_address;
+    pending.sibling = leg.own_address;
+    rpc.add_order(program_id, &pending);
+    rpc.add_order(program_id, &leg);
+
+    // the leg trades through the order's open orders, so only the order is sent
+    let d = daemon(rpc, signal_provider, program_id);
+    assert_eq!(d.tick().unwrap(), 1);
+    let sent = d.rpc().sent.borrow();
+    assert_eq!(sent.len(), 1);
+    let ix = &sent[0][0];
+    assert_eq!(ix.accounts[1].pubkey, pending.own_address);
+    assert_eq!(ix.accounts.last().unwrap().pubkey, leg.own_address);
+}
+
+#[test]
+fn order_errors_do_not_stop_the_tick() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    // the market is not on the mock bank so this order fails to load its book
+    rpc.add_order(program_id, &order(Side::Ask, signal_provider.pubkey(), OrdStatus::New));
+    rpc.add_order(program_id, &order(Side::Ask, signal_provider.pubkey(), OrdStatus::PendingInit));
+
+    let d = daemon(rpc, signal_provider, program_id);
+    assert_eq!(d.tick().unwrap(), 1);
+}
+
+#[test]
+fn expires_stale_orders() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    let mut stale = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    stale.time_in_force = TimeInForce::GoodTillDate;
+    stale.expiry_ts = 1;
+    rpc.add_order(program_id, &stale);
+    let mut done = order(Side::Ask, signal_provider.pubkey(), OrdStatus::Filled);
+    done.time_in_force = TimeInForce::GoodTillDate;
+    done.expiry_ts = 1;
+    rpc.add_order(program_id, &done);
+
+    let d = daemon(rpc, signal_provider, program_id);
+    assert_eq!(d.tick().unwrap(), 1);
+
+    let sent = d.rpc().sent.borrow();
+    assert_eq!(sent[0][0].data, instruction::ExpireOrder {}.data());
+    assert_eq!(sent[0][0].accounts[0].pubkey, stale.own_address);
+}
+
+// an initialised serum open orders account with just the four balances set
+fn open_orders_data(coin_free: u64, coin_total: u64, pc_free: u64, pc_total: u64) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    // initialised | open orders
+    data.extend_from_slice(&5u64.to_le_bytes());
+    data.extend_from_slice(&[0u8; 64]);
+    for v in [coin_free, coin_total, pc_free, pc_total].iter() {
+        data.extend_from_slice(&v.to_le_bytes());
+    }
+    data.resize(OPEN_ORDERS_LEN - 7, 0);
+    data.extend_from_slice(b"padding");
+    data
+}
+
+// a serum market account with just enough set for MarketInfo to load it
+fn market_data(market: &Pubkey, dex_program: &Pubkey) -> Vec<u8> {
+    let mut words = [0u64; 47];
+    // initialised | market
+    words[0] = 3;
+    for (i, chunk) in market.to_bytes().chunks(8).enumerate() {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(chunk);
+        words[1 + i] = u64::from_le_bytes(b);
+    }
+    words[5] = (0..).find(|n: &u64| Pubkey::create_program_address(&[market.as_ref(), &n.to_le_bytes()], dex_program).is_ok()).unwrap();
+    words[43] = COIN_LOT_SIZE;
+    words[44] = 100;
+    let mut data = b"serum".to_vec();
+    for w in words.iter() {
+        data.extend_from_slice(&w.to_le_bytes());
+    }
+    data.extend_from_slice(b"padding");
+    data
+}
+
+#[test]
+fn settles_resting_children_with_fills() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut rpc = MockRpc::default();
+    let mut filled = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    filled.resting_qty = 500_000;
+    filled.resting_client_order_id = 7;
+    rpc.add_order(program_id, &filled);
+    rpc.accounts.insert(filled.stoploss_open_orders, (filled.dex_program, open_orders_data(0, 300_000, 1_000_000, 1_000_000)));
+    // nothing traded yet, and an expired order whose child has to come off the book
+    let mut waiting = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    waiting.resting_qty = 500_000;
+    rpc.add_order(program_id, &waiting);
+    rpc.accounts.insert(waiting.stoploss_open_orders, (waiting.dex_program, open_orders_data(0, 500_000, 0, 0)));
+    let mut expired = order(Side::Ask, signal_provider.pubkey(), OrdStatus::PartiallyFilled);
+    expired.resting_qty = 500_000;
+    expired.time_in_force = TimeInForce::GoodTillDate;
+    expired.expiry_ts = 1;
+    rpc.add_order(program_id, &expired);
+    for o in [&filled, &waiting, &expired].iter() {
+        rpc.accounts.insert(o.market, (o.dex_program, market_data(&o.market, &o.dex_program)));
+    }
+
+    let d = daemon(rpc, signal_provider, program_id);
+    assert_eq!(d.tick().unwrap(), 2);
+
+    let sent = d.rpc().sent.borrow();
+    let sent_for = |order: &StoplossState| sent.iter().find(|ixs| ixs[0].accounts.iter().any(|m| m.pubkey == order.own_address)).map(|ixs| ixs[0].data.clone());
+    assert_eq!(sent_for(&filled), Some(instruction::SettleOrder {}.data()));
+    assert_eq!(sent_for(&waiting), None);
+    assert_eq!(sent_for(&expired), Some(instruction::CancelChildOrder {}.data()));
+}
+
+#[test]
+fn plans_sell_once_bid_falls_to_trigger() {
+    let sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    assert_eq!(plan_child_order(&sl, Some(551), None, &LOTS, None), None);
+    assert_eq!(plan_child_order(&sl, None, None, &LOTS, None), None);
+    assert_eq!(
+        plan_child_order(&sl, Some(550), None, &LOTS, None),
+        Some(ChildOrder {
+            execute_qty: 1_000_000,
+            execute_limit: 500,
+            reuse_unfilled: false,
+            update_trailing_mark: false,
+        })
+    );
+}
+
+#[test]
+fn plans_buy_once_ask_rises_to_trigger() {
+    let mut sl = order(Side::Bid, Pubkey::new_unique(), OrdStatus::New);
+    assert_eq!(plan_child_order(&sl, Some(549), None, &LOTS, None), None);
+    // capped by max_coin_qty
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 1_000_000);
+
+    // 6 quote buys 120 lots at 500, less the one the taker fee eats into
+    sl.coin_leaves_qty = u64::MAX;
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 11_900_000);
+    assert!(!child.reuse_unfilled);
+}
+
+#[test]
+fn stop_market_children_leave_the_limit_to_the_program() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    sl.order_mode = OrderMode::StopMarket;
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_limit, 0);
+    assert_eq!(child.execute_qty, 1_000_000);
+}
+
+#[test]
+fn oracle_orders_trigger_off_the_oracle() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    sl.oracle = Pubkey::new_unique();
+    // the book is through the trigger but the oracle is not, or is unusable
+    assert_eq!(plan_child_order(&sl, Some(500), Some(551), &LOTS, None), None);
+    assert_eq!(plan_child_order(&sl, Some(500), None, &LOTS, None), None);
+    let child = plan_child_order(&sl, Some(600), Some(550), &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 1_000_000);
+}
+
+#[test]
+fn triggered_orders_keep_working_after_the_price_recovers() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::PartiallyFilled);
+    sl.coin_leaves_qty = 500_000;
+    assert_eq!(plan_child_order(&sl, Some(600), None, &LOTS, None), None);
+    sl.triggered = true;
+    let child = plan_child_order(&sl, Some(600), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 500_000);
+}
+
+#[test]
+fn twap_children_are_trimmed_to_the_schedule() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    sl.strategy = Strategy::Twap;
+    sl.strategy_start_ts = 1_000;
+    sl.strategy_end_ts = 2_000;
+    sl.slice_count = 4;
+    sl.min_child_interval = 100;
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    assert_eq!(fit_to_strategy(&sl, child, 999, &LOTS, 0), None);
+
+    // a quarter of the 1 base deposit, rounded down to whole lots
+    let first = fit_to_strategy(&sl, child, 1_000, &LOTS, 0).unwrap();
+    assert_eq!(first.execute_qty, 200_000);
+    assert!(first.reuse_unfilled);
+
+    sl.coin_leaves_qty -= 200_000;
+    sl.child_order_count = 1;
+    sl.last_child_ts = 1_000;
+    assert_eq!(fit_to_strategy(&sl, child, 1_099, &LOTS, 0), None);
+    assert_eq!(fit_to_strategy(&sl, child, 1_100, &LOTS, 0), None);
+    assert_eq!(fit_to_strategy(&sl, child, 1_250, &LOTS, 0).unwrap().execute_qty, 300_000);
+    assert_eq!(fit_to_strategy(&sl, child, 2_000, &LOTS, 0).unwrap().execute_qty, 800_000);
+}
+
+#[test]
+fn vwap_children_are_trimmed_to_participation() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    sl.strategy = Strategy::Vwap;
+    sl.strategy_end_ts = 1;
+    sl.max_participation_bps = 5_000;
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    // half of a 5 lot order
+    assert_eq!(fit_to_strategy(&sl, child, 0, &LOTS, 5).unwrap().execute_qty, 200_000);
+    assert_eq!(fit_to_strategy(&sl, child, 0, &LOTS, 1), None);
+    // nothing once the window has ended
+    assert_eq!(fit_to_strategy(&sl, child, 1, &LOTS, 5), None);
+}
+
+#[test]
+fn splits_large_orders_into_whole_lot_children() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::PartiallyFilled);
+    let child = plan_child_order(&sl, Some(500), None, &LOTS, Some(250_000)).unwrap();
+    assert_eq!(child.execute_qty, 200_000);
+    assert!(child.reuse_unfilled);
+
+    // less than a lot left, nothing the DEX can trade
+    sl.coin_leaves_qty = 50_000;
+    assert_eq!(plan_child_order(&sl, Some(500), None, &LOTS, None), None);
+}
+
+#[test]
+fn keeper_bounty_is_held_back_from_children() {
+    let mut sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::New);
+    sl.keeper_allowed = true;
+    sl.keeper_bounty = 1_000;
+    let child = plan_child_order(&sl, Some(500), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 900_000);
+    assert!(child.reuse_unfilled);
+
+    // once paid the whole of the leaves can go
+    sl.keeper_bounty_paid = true;
+    let child = plan_child_order(&sl, Some(500), None, &LOTS, None).unwrap();
+    assert_eq!(child.execute_qty, 1_000_000);
+    assert!(!child.reuse_unfilled);
+}
+
+// a market at the order's market address, for building instructions
+fn market_info(order: &StoplossState) -> MarketInfo {
+    MarketInfo::from_account_data(order.market, order.dex_program, &market_data(&order.market, &order.dex_program)).unwrap()
+}
+
+#[test]
+fn trailing_sell_moves_mark_before_checking_trigger() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut sl = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    sl.trigger_type = TriggerType::TrailingAbsolute;
+    sl.trailing_offset = 50;
+    sl.trailing_mark = 600;
+    let d = daemon(MockRpc::default(), signal_provider, program_id);
+
+    // a higher bid only moves the mark, which is sent on its own
+    assert_eq!(plan_child_order(&sl, Some(700), None, &LOTS, None), None);
+    let (sent, child) = d.order_instructions(&sl, market_info(&sl), &LOTS, Some((700, 1)), None, 0);
+    assert_eq!(child, None);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].data, instruction::UpdateTrailingMark {}.data());
+    assert_eq!(sent[0].accounts[0].pubkey, sl.own_address);
+
+    // nothing to send while the bid sits between the trigger and the mark
+    let (sent, _) = d.order_instructions(&sl, market_info(&sl), &LOTS, Some((600, 1)), None, 0);
+    assert!(sent.is_empty());
+
+    // bid fell to mark - offset
+    let child = plan_child_order(&sl, Some(550), None, &LOTS, None).unwrap();
+    assert!(!child.update_trailing_mark);
+    let (sent, _) = d.order_instructions(&sl, market_info(&sl), &LOTS, Some((550, 1)), None, 0);
+    assert_eq!(sent.len(), 1);
+    let execute = instruction::ExecuteOrder {
+        execute_qty: 1_000_000,
+        execute_limit: 500,
+        reuse_unfilled: false,
+        order_type: OrderType::ImmediateOrCancel,
+    };
+    assert_eq!(sent[0].data, execute.data());
+}
+
+#[test]
+fn oracle_trailing_sell_trails_the_oracle() {
+    let program_id = Pubkey::new_unique();
+    let signal_provider = Keypair::new();
+    let mut sl = order(Side::Ask, signal_provider.pubkey(), OrdStatus::New);
+    sl.trigger_type = TriggerType::TrailingAbsolute;
+    sl.trailing_offset = 50;
+    sl.trailing_mark = 600;
+    sl.oracle = Pubkey::new_unique();
+    let d = daemon(MockRpc::default(), signal_provider, program_id);
+
+    // a higher bid leaves the mark alone, a higher oracle price moves it
+    let (sent, _) = d.order_instructions(&sl, market_info(&sl), &LOTS, Some((700, 1)), Some(600), 0);
+    assert!(sent.is_empty());
+    let (sent, child) = d.order_instructions(&sl, market_info(&sl), &LOTS, Some((600, 1)), Some(700), 0);
+    assert_eq!(child, None);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].data, instruction::UpdateTrailingMark {}.data());
+
+    // the oracle falling to mark - offset triggers it, wherever the book is
+    let child = plan_child_order(&sl, Some(700), Some(550), &LOTS, None).unwrap();
+    assert!(!child.update_trailing_mark);
+    assert_eq!(plan_child_order(&sl, Some(550), Some(600), &LOTS, None), None);
+}
+
+#[test]
+fn finished_orders_are_not_planned() {
+    let sl = order(Side::Ask, Pubkey::new_unique(), OrdStatus::Filled);
+    assert_eq!(plan_child_order(&sl, Some(1), None, &LOTS, None), None);
+}
@@ -0,0 +1,290 @@
+const assert = require("assert");
+const anchor = require("@project-serum/anchor");
+const BN = anchor.BN;
+
+const serumCmn = require("@project-serum/common");
+const Market = require("@project-serum/serum").Market;
+const TOKEN_PROGRAM_ID = require("@solana/spl-token").TOKEN_PROGRAM_ID;
+const utils = require("./utils");
+
+describe("account-validation", () => {
+  // Configure the client to use the local cluster.
+  anchor.setProvider(anchor.Provider.env());
+
+  // Stoploss program client.
+  const program = anchor.workspace.AnchorStoploss;
+
+  // Accounts used to setup the orderbook.
+  let ORDERBOOK_ENV,
+    // Accounts used for buy transactions
+    BUY_NEW_ORDER_ACCOUNTS,
+    // Accounts used for sell transactions
+    SELL_NEW_ORDER_ACCOUNTS,
+    // the accounts used by the algo server when executing child orders
+    EXECUTE_BUY_ORDER_ACCOUNTS,
+    // the accounts used by the algo server when executing child orders
+    EXECUTE_SELL_ORDER_ACCOUNTS,
+    // Serum DEX vault PDA for market A/USDC.
+    marketAVaultSigner,
+    // owner of the stoploss vaults
+    stoplossPDA,
+    // the account that can execute orders. This will be a server side component
+    signalProvider,
+    market;
+
+
+
+  it("BOILERPLATE: Sets up a market with resting orders", async () => {
+    ORDERBOOK_ENV = await utils.setupTheMarket({
+      provider: program.provider,
+    });
+    signalProvider = await utils.setupSignalProvider(program.provider, program.provider.wallet.payer, program._programId);
+    await utils.airdrop(program._provider.connection, 100000000, signalProvider);
+    program.send
+    stoplossPDA = await utils.getStoplossVaultOwner(Buffer.from("stoploss"), program._programId);
+    const marketA = ORDERBOOK_ENV.marketA;
+    market = await Market.load(program.provider.connection, marketA.publicKey, {}, utils.DEX_PID);
+
+  });
+
+  it("BOILERPLATE: Sets up reusable accounts", async () => {
+    const marketA = ORDERBOOK_ENV.marketA;
+
+    const [vaultSignerA] = await utils.getDexVaultOwnerAndNonce(
+      marketA._decoded.ownAddress
+    );
+    marketAVaultSigner = vaultSignerA;
+
+    const { stoplossOpenOrders } =
+      await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, marketA._decoded.ownAddress, signalProvider
+        );    
+
+    
+
+    // buys and sells need different accounts. 
+    BUY_NEW_ORDER_ACCOUNTS = {
+      market: {
+        market: marketA._decoded.ownAddress,
+        requestQueue: marketA._decoded.requestQueue,
+        eventQueue: marketA._decoded.eventQueue,
+        bids: marketA._decoded.bids,
+        asks: marketA._decoded.asks,
+        coinVault: marketA._decoded.baseVault,
+        pcVault: marketA._decoded.quoteVault,
+        vaultSigner: marketAVaultSigner,
+        // User params.
+        openOrders: stoplossOpenOrders.publicKey,
+        orderPayerTokenAccount: ORDERBOOK_ENV.godUsdc,
+        coinWallet: ORDERBOOK_ENV.godA,
+        pcWallet: ORDERBOOK_ENV.godUsdc,
+        coinMint: ORDERBOOK_ENV.mintA,
+        pcMint: ORDERBOOK_ENV.mintusdc,
+
+      },
+      stoplossOpenOrders: stoplossOpenOrders.publicKey,
+      stoplossProgram: program._programId,
+      config: await utils.setupConfig(program, signalProvider),
+      // fees are taken in the mint the order receives, base for buys
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintA, program._programId),
+      // only checked for orders with an oracle, see set_oracle
+      oracle: anchor.web3.SystemProgram.programId,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      // receives the rent back when the order's vaults are closed
+      amendAuthority: program.provider.wallet.publicKey,
+      // only paid into when a keeper executes
+      keeperBountyAccount: ORDERBOOK_ENV.godUsdc,
+
+      // signal provider is the off chain component that will call execute
+      signalProvider: signalProvider.publicKey,
+
+      authority: program.provider.wallet.publicKey,
+      dexProgram: utils.DEX_PID,
+      pda: stoplossPDA,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+    };
+    SELL_NEW_ORDER_ACCOUNTS = {
+      ...BUY_NEW_ORDER_ACCOUNTS,
+      clientPayingAccount: ORDERBOOK_ENV.godA,
+      clientReceivingAccount: ORDERBOOK_ENV.godUsdc,
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintusdc, program._programId),
+      market: {
+        ...BUY_NEW_ORDER_ACCOUNTS.market,
+        orderPayerTokenAccount: ORDERBOOK_ENV.godA,
+      },
+    };
+    EXECUTE_BUY_ORDER_ACCOUNTS = {
+      ...BUY_NEW_ORDER_ACCOUNTS,
+      authority: signalProvider.publicKey,
+      market: {
+        ...BUY_NEW_ORDER_ACCOUNTS.market,
+      },
+    };
+    EXECUTE_SELL_ORDER_ACCOUNTS = {
+      ...SELL_NEW_ORDER_ACCOUNTS,
+      authority: signalProvider.publicKey,
+      market: {
+        ...SELL_NEW_ORDER_ACCOUNTS.market,
+        orderPayerTokenAccount: ORDERBOOK_ENV.godA,
+      },
+    };
+
+  });
+
+ 
+  async function newSell(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
+    const stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+      limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
+  }
+
+  async function newBuy(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
+    const stoplossStateAccount = await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
+      limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(BUY_NEW_ORDER_ACCOUNTS, [SELL_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
+  }
+
+  // Each case replaces one account on an otherwise valid execute and checks the
+  // program rejects it with the dedicated error.
+  const EXECUTE_SUBSTITUTIONS = [
+    ["DEX coin vault as the stoploss base vault", (a) => ({ ...a, stoplossBaseVault: a.market.coinVault }), "Stoploss base vault does not match the order"],
+    ["DEX pc vault as the stoploss quote vault", (a) => ({ ...a, stoplossQuoteVault: a.market.pcVault }), "Stoploss quote vault does not match the order"],
+    ["another order's base vault", (a) => ({ ...a, stoplossBaseVault: OTHER_ACCOUNTS.stoplossBaseVault }), "Stoploss base vault does not match the order"],
+    ["another order's quote vault", (a) => ({ ...a, stoplossQuoteVault: OTHER_ACCOUNTS.stoplossQuoteVault }), "Stoploss quote vault does not match the order"],
+    ["another open orders account", (a) => ({ ...a, stoplossOpenOrders: OTHER_ACCOUNTS.stoplossOpenOrders }), "Open orders account does not match the order"],
+    ["bids and asks swapped", (a) => ({ ...a, market: { ...a.market, bids: a.market.asks, asks: a.market.bids } }), "Bids account does not match the order"],
+    ["request queue as event queue", (a) => ({ ...a, market: { ...a.market, eventQueue: a.market.requestQueue } }), "Event queue account does not match the order"],
+    ["another client coin wallet", (a) => ({ ...a, market: { ...a.market, coinWallet: OTHER_ACCOUNTS.coinWallet } }), "Client coin wallet does not match the order"],
+    ["another client pc wallet", (a) => ({ ...a, market: { ...a.market, pcWallet: OTHER_ACCOUNTS.pcWallet } }), "Client pc wallet does not match the order"],
+    ["DEX vaults swapped", (a) => ({ ...a, market: { ...a.market, coinVault: a.market.pcVault, pcVault: a.market.coinVault } }), "DEX vault accounts do not match the market"],
+    ["a different vault signer", (a) => ({ ...a, market: { ...a.market, vaultSigner: stoplossPDA } }), "DEX vault signer does not match the order"],
+    ["a different pda", (a) => ({ ...a, pda: signalProvider.publicKey }), "Stoploss PDA does not match the order"],
+  ];
+
+  // accounts belonging to a second order and user
+  let OTHER_ACCOUNTS;
+
+  it("BOILERPLATE: Sets up a second order to steal accounts from", async () => {
+    const { stoplossOpenOrders } =
+      await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, ORDERBOOK_ENV.marketA._decoded.ownAddress, signalProvider, program._programId);
+    await newSell(6.004, new BN(7654321), 20, 1.1, new BN(Number.MAX_SAFE_INTEGER));
+    OTHER_ACCOUNTS = {
+      stoplossBaseVault: SELL_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+      stoplossQuoteVault: SELL_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+      stoplossOpenOrders: stoplossOpenOrders.publicKey,
+      coinWallet: ORDERBOOK_ENV.secondUser.tokens[ORDERBOOK_ENV.mintA.toString()],
+      pcWallet: ORDERBOOK_ENV.secondUser.tokens[ORDERBOOK_ENV.mintusdc.toString()],
+    };
+  });
+
+  for (const [name, substitute, errMsg] of EXECUTE_SUBSTITUTIONS) {
+    it("Reject executing a sell with " + name, async () => {
+      let stoplossStateAccount = await newSell(6.004, new BN(1234567), 20, 2.2, new BN(Number.MAX_SAFE_INTEGER));
+      const accounts = substitute({ ...EXECUTE_SELL_ORDER_ACCOUNTS, stoplossState: stoplossStateAccount.publicKey });
+
+      try {
+        await program.rpc.executeOrder(
+          new BN(2.2 * 10 ** 6),
+          market.priceNumberToLots(6.004),
+          false,
+          utils.OrderType.ImmediateOrCancel,
+          {
+            accounts,
+            signers: [signalProvider]
+          }
+        );
+        assert.ok(false);
+      } catch (err) {
+        assert.equal(err.toString(), errMsg);
+      }
+
+      let sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+      assert.ok(sls.coinLeavesQty.toNumber() === 2.2 * 10 ** 6);
+      assert.ok(sls.childOrderCount.toNumber() === 0);
+    });
+  }
+
+  it("Reject cancelling a buy into another user's wallet", async () => {
+    let stoplossStateAccount = await newBuy(6.041, new BN(12345), 6, 1.2 * 10 ** 6, 1.2 * 10 ** 6 * 6.041);
+
+    try {
+      await program.rpc.cancelOrder({
+        accounts: {
+          stoplossState: stoplossStateAccount.publicKey,
+          coinWallet: ORDERBOOK_ENV.godA,
+          pcWallet: OTHER_ACCOUNTS.pcWallet,
+          stoplossPayingVault: BUY_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+          stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+          authority: program.provider.wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          vaultOwner: stoplossPDA
+        },
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.toString(), "Client pc wallet does not match the order");
+    }
+  });
+
+  it("Reject cancelling a buy from another order's vault", async () => {
+    let stoplossStateAccount = await newBuy(6.041, new BN(12345), 6, 1.2 * 10 ** 6, 1.2 * 10 ** 6 * 6.041);
+
+    try {
+      await program.rpc.cancelOrder({
+        accounts: {
+          stoplossState: stoplossStateAccount.publicKey,
+          coinWallet: ORDERBOOK_ENV.godA,
+          pcWallet: ORDERBOOK_ENV.godUsdc,
+          stoplossPayingVault: OTHER_ACCOUNTS.stoplossQuoteVault,
+          stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+          authority: program.provider.wallet.publicKey,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          vaultOwner: stoplossPDA
+        },
+      });
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.toString(), "Stoploss paying vault does not match the order");
+    }
+  });
+
+  it("Reject amending a sell using the wrong side's vault", async () => {
+    const clientOrderId = new BN(1234567);
+    let stoplossStateAccount = await newSell(6.004, clientOrderId, 20, 2.2, new BN(Number.MAX_SAFE_INTEGER));
+
+    try {
+      await program.rpc.amendOrder(
+        market.priceNumberToLots(6.004),
+        clientOrderId,
+        new BN(1.1 * 10 ** 6),
+        market.priceNumberToLots(20),
+        {
+          accounts: {
+            stoplossState: stoplossStateAccount.publicKey,
+            coinWallet: ORDERBOOK_ENV.godA,
+            pcWallet: ORDERBOOK_ENV.godUsdc,
+            stoplossPayingVault: SELL_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+            authority: program.provider.wallet.publicKey,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            vaultOwner: stoplossPDA
+          },
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      assert.equal(err.toString(), "Stoploss paying vault does not match the order");
+    }
+
+    let sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+    assert.ok(sls.maxCoinQty.toNumber() === 2.2 * 10 ** 6);
+  });
+
+});
+
+// Side rust enum used for the program's RPC API.
+const Side = {
+  Bid: { bid: {} },
+  Ask: { ask: {} },
+};
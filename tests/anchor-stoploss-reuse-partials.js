@@ -58,7 +58,7 @@ describe("anchor-stoploss-reuse-partials", () => {
     );
     marketAVaultSigner = vaultSignerA;
 
-    const { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders } =
+    const { stoplossOpenOrders } =
       await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, marketA._decoded.ownAddress, signalProvider
         );    
 
@@ -84,10 +84,18 @@ describe("anchor-stoploss-reuse-partials", () => {
         pcMint: ORDERBOOK_ENV.mintusdc,
 
       },
-      stoplossBaseVault: stoplossBaseVault.publicKey,
-      stoplossQuoteVault: stoplossQuoteVault.publicKey,
       stoplossOpenOrders: stoplossOpenOrders.publicKey,
       stoplossProgram: program._programId,
+      config: await utils.setupConfig(program, signalProvider),
+      // fees are taken in the mint the order receives, base for buys
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintA, program._programId),
+      // only checked for orders with an oracle, see set_oracle
+      oracle: anchor.web3.SystemProgram.programId,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      // receives the rent back when the order's vaults are closed
+      amendAuthority: program.provider.wallet.publicKey,
+      // only paid into when a keeper executes
+      keeperBountyAccount: ORDERBOOK_ENV.godUsdc,
 
       // signal provider is the off chain component that will call execute
       signalProvider: signalProvider.publicKey,
@@ -102,6 +110,7 @@ describe("anchor-stoploss-reuse-partials", () => {
       ...BUY_NEW_ORDER_ACCOUNTS,
       clientPayingAccount: ORDERBOOK_ENV.godA,
       clientReceivingAccount: ORDERBOOK_ENV.godUsdc,
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintusdc, program._programId),
       market: {
         ...BUY_NEW_ORDER_ACCOUNTS.market,
         orderPayerTokenAccount: ORDERBOOK_ENV.godA,
@@ -127,13 +136,17 @@ describe("anchor-stoploss-reuse-partials", () => {
 
  
   async function newSell(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+    const stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
   async function newBuy(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
+    const stoplossStateAccount = await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(BUY_NEW_ORDER_ACCOUNTS, [SELL_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
 
@@ -248,9 +261,10 @@ describe("anchor-stoploss-reuse-partials", () => {
       [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault, BUY_NEW_ORDER_ACCOUNTS.stoplossQuoteVault],
       async () => {
         await program.rpc.executeOrder(
-          new BN(baseQuantity * limitPrice * 10 ** 6),
+          new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_BUY_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -271,6 +285,7 @@ describe("anchor-stoploss-reuse-partials", () => {
           new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_SELL_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -58,7 +58,7 @@ describe("anchor-stoploss", () => {
     );
     marketAVaultSigner = vaultSignerA;
 
-    const { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders } =
+    const { stoplossOpenOrders } =
       await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, marketA._decoded.ownAddress, signalProvider, program._programId);
 
 
@@ -82,10 +82,18 @@ describe("anchor-stoploss", () => {
         coinMint: ORDERBOOK_ENV.mintA,
         pcMint: ORDERBOOK_ENV.mintusdc,
       },
-      stoplossBaseVault: stoplossBaseVault.publicKey,
-      stoplossQuoteVault: stoplossQuoteVault.publicKey,
       stoplossOpenOrders: stoplossOpenOrders.publicKey,
       stoplossProgram: program._programId,
+      config: await utils.setupConfig(program, signalProvider),
+      // fees are taken in the mint the order receives, base for buys
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintA, program._programId),
+      // only checked for orders with an oracle, see set_oracle
+      oracle: anchor.web3.SystemProgram.programId,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      // receives the rent back when the order's vaults are closed
+      amendAuthority: program.provider.wallet.publicKey,
+      // only paid into when a keeper executes
+      keeperBountyAccount: ORDERBOOK_ENV.godUsdc,
 
       // signal provider is the off chain component that will call execute
       signalProvider: signalProvider.publicKey,
@@ -100,6 +108,7 @@ describe("anchor-stoploss", () => {
       ...BUY_NEW_ORDER_ACCOUNTS,
       clientPayingAccount: ORDERBOOK_ENV.godA,
       clientReceivingAccount: ORDERBOOK_ENV.godUsdc,
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintusdc, program._programId),
       market: {
         ...BUY_NEW_ORDER_ACCOUNTS.market,
         orderPayerTokenAccount: ORDERBOOK_ENV.godA,
@@ -124,13 +133,17 @@ describe("anchor-stoploss", () => {
   });
 
   async function newSell(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+    const stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
   async function newBuy(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
+    const stoplossStateAccount = await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(BUY_NEW_ORDER_ACCOUNTS, [SELL_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
 
@@ -174,16 +187,17 @@ describe("anchor-stoploss", () => {
     assert.ok(sls.maxCoinQty.toNumber() === maxCoinQty * 10 ** 6);
     assert.ok(sls.coinLeavesQty.toNumber() === 0);
     assert.ok(sls.coinCumQty.toNumber() === maxCoinQty * 10 ** 6);
-    // small hacks to make it 5 sf
-    assert.ok((sls.pcCumQty.toNumber() / 10 ** 6).toFixed(5) === (expectedFillAmount / 10 ** 6).toFixed(5));
+    // cum qty is the traded amount, the taker fee is recorded separately
+    assert.ok(sls.pcCumQty.toNumber() === Math.round(amountToFill * 10 ** 6));
+    assert.ok(sls.feesPaid.toNumber() === Math.ceil(amountToFill * TAKER_FEE * 10 ** 6));
     // selling so pc that you receive can be arbitrarily huge really
     //assert.ok(sls.pcLeavesQty.toNumber() === 0);
     assert.ok(sls.limitPrice.toNumber() === market.priceNumberToLots(limitPrice).toNumber());
     assert.ok(sls.triggerPrice.toNumber() === market.priceNumberToLots(triggerPrice).toNumber());
     assert.ok(sls.clientOrderId.toNumber() === clientId.toNumber());
 
-    assert.ok(sls.avgPrice.toNumber() === 599079);
-    assert.ok(sls.lastPrice.toNumber() === 599079);
+    assert.ok(sls.avgPrice.value.toNumber() === 6004000000);
+    assert.ok(sls.lastPrice.value.toNumber() === 6004000000);
     // set to cancelled status because re-use is false. Ie its an IOC. This one fully filled
     if (sls.ordStatus.filled) {
       // ok
@@ -213,7 +227,7 @@ describe("anchor-stoploss", () => {
     const maxCoinQty = buyAmount * 10 ** 6 // 6 dp
     const maxPcQty = maxCoinQty * limitPrice;
     const clientOrderId = new BN(12345);
-    const triggerPrice = 20;
+    const triggerPrice = 6; // best ask of 6.041 is above the trigger so the buy stop fires
     const usdcBefore = (await getUsdc()).amount;
 
 
@@ -244,11 +258,12 @@ describe("anchor-stoploss", () => {
     assert.ok(sls.maxCoinQty.toNumber() === maxCoinQty);
     // this value should be the usdc number. 
     assert.ok(sls.coinCumQty.toNumber() === 1.1 * 10 ** 6);
-    assert.ok(sls.pcCumQty.toNumber() === -parseFloat(expectedUsdChange.toFixed(5)) * 10 ** 6);
-    assert.ok(sls.avgPrice.toNumber() / 10 ** 5 === parseFloat(((1 + TAKER_FEE) * limitPrice).toFixed(5)));
-    assert.ok(sls.lastPrice.toNumber() === 605429);
-    // didnt fully fill, some left over
-    assert.ok(sls.pcLeavesQty.toNumber() === usdcChange * 10 ** 6);
+    assert.ok(sls.pcCumQty.toNumber() === Math.round(tokenAChange * limitPrice * 10 ** 6));
+    assert.ok(sls.feesPaid.toNumber() === Math.ceil(tokenAChange * limitPrice * TAKER_FEE * 10 ** 6));
+    assert.ok(sls.avgPrice.value.toNumber() === 6041000000);
+    assert.ok(sls.lastPrice.value.toNumber() === 6041000000);
+    // didnt fully fill, the left over is refunded and leaves is zero in the terminal state
+    assert.ok(sls.pcLeavesQty.toNumber() === 0);
     assert.ok(sls.limitPrice.toNumber() === market.priceNumberToLots(limitPrice).toNumber());
     assert.ok(sls.triggerPrice.toNumber() === market.priceNumberToLots(triggerPrice).toNumber());
     assert.ok(sls.clientOrderId.toNumber() === clientOrderId.toNumber());
@@ -286,6 +301,7 @@ describe("anchor-stoploss", () => {
       coinWallet: ORDERBOOK_ENV.godA,
       pcWallet: ORDERBOOK_ENV.godUsdc,
       stoplossPayingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossQuoteVault"],
+      stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossBaseVault"],
       authority: program.provider.wallet.publicKey,
       tokenProgram: TOKEN_PROGRAM_ID,
       vaultOwner: stoplossPDA
@@ -343,6 +359,7 @@ describe("anchor-stoploss", () => {
       coinWallet: ORDERBOOK_ENV.godA,
       pcWallet: ORDERBOOK_ENV.godUsdc,
       stoplossPayingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossBaseVault"],
+      stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossQuoteVault"],
       authority: program.provider.wallet.publicKey,
       tokenProgram: TOKEN_PROGRAM_ID,
       vaultOwner: stoplossPDA
@@ -382,6 +399,323 @@ describe("anchor-stoploss", () => {
 
 
 
+  it("Reject executing a sell before the market crosses the trigger", async () => {
+
+    const coinBefore = (await getA()).amount;
+
+    const maxCoinQty = 2.2;
+    const maxPcQty = new BN(Number.MAX_SAFE_INTEGER);
+    const limitPrice = 5.9;
+    const clientId = new BN(1234568);
+    const triggerPrice = 5.5; // best bid is 6.004, so the sell stop has not triggered
+
+    let stoplossStateAccount = await newSell(limitPrice, clientId, triggerPrice, maxCoinQty, maxPcQty);
+    EXECUTE_SELL_ORDER_ACCOUNTS["stoplossState"] = stoplossStateAccount.publicKey;
+
+    try {
+      await executeSell(maxCoinQty, limitPrice, market);
+      assert.ok(false);
+    } catch (err) {
+      const errMsg =
+        "The market has not crossed the trigger price. Rejecting Execute instruction";
+      assert.equal(err.toString(), errMsg);
+    }
+
+    let sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+    assert.ok(sls.coinLeavesQty.toNumber() === maxCoinQty * 10 ** 6);
+    assert.ok(sls.coinCumQty.toNumber() === 0);
+
+    // funds are still held by the stoploss
+    let totalCoinChange = ((await getA()).amount.toNumber() - coinBefore.toNumber()) / 10 ** 6;
+    assert.ok(totalCoinChange === -maxCoinQty);
+  });
+
+
+
+
+
+
+  it("Trailing sell stop ratchets its mark and only triggers off the derived price", async () => {
+
+    const maxCoinQty = 2.2;
+    const maxPcQty = new BN(Number.MAX_SAFE_INTEGER);
+    const limitPrice = 5.9;
+    const clientId = new BN(1234569);
+    const trailingOffsetBps = 100; // trigger 1% below the best bid seen
+
+    let stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+      limitPrice, clientId, 0, maxCoinQty, maxPcQty, signalProvider, utils.TriggerType.TrailingBps, trailingOffsetBps);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+
+    // the mark starts at the best bid
+    let sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+    assert.ok(sls.triggerType.hasOwnProperty("trailingBps"));
+    assert.ok(sls.trailingMark.toNumber() === market.priceNumberToLots(6.004).toNumber());
+
+    // anyone can ratchet the mark, the book has not moved so it is unchanged
+    await program.rpc.updateTrailingMark({
+      accounts: {
+        stoplossState: stoplossStateAccount.publicKey,
+        market: ORDERBOOK_ENV.marketA._decoded.ownAddress,
+        bids: ORDERBOOK_ENV.marketA._decoded.bids,
+        asks: ORDERBOOK_ENV.marketA._decoded.asks,
+        dexProgram: utils.DEX_PID,
+        coinMint: ORDERBOOK_ENV.mintA,
+        pcMint: ORDERBOOK_ENV.mintusdc,
+        // only checked for orders with an oracle, see set_oracle
+        oracle: anchor.web3.SystemProgram.programId,
+      },
+      signers: [signalProvider],
+    });
+    sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+    assert.ok(sls.trailingMark.toNumber() === market.priceNumberToLots(6.004).toNumber());
+
+    // best bid of 6.004 is above the derived trigger of ~5.944
+    EXECUTE_SELL_ORDER_ACCOUNTS["stoplossState"] = stoplossStateAccount.publicKey;
+    try {
+      await executeSell(maxCoinQty, limitPrice, market);
+      assert.ok(false);
+    } catch (err) {
+      const errMsg =
+        "The market has not crossed the trigger price. Rejecting Execute instruction";
+      assert.equal(err.toString(), errMsg);
+    }
+  });
+
+
+
+
+
+
+  it("Bracket: take-profit leg fills from the shared deposit and cancels the stoploss leg", async () => {
+
+    const coinBefore = (await getA()).amount;
+
+    const maxCoinQty = 2.2;
+    const maxPcQty = new BN(Number.MAX_SAFE_INTEGER);
+    const stopLimitPrice = 5.5;
+    const stopTriggerPrice = 5.6; // best bid is 6.004 so the stop is not triggered
+    const takeProfitLimitPrice = 6.004;
+    const takeProfitTriggerPrice = 6; // best bid is 6.004 so the take-profit is triggered
+
+    let stopLeg = await newSell(stopLimitPrice, new BN(1234570), stopTriggerPrice, maxCoinQty, maxPcQty);
+
+    let takeProfitLeg = anchor.web3.Keypair.generate();
+    await program.rpc.newBracketLeg(
+      market.priceNumberToLots(takeProfitLimitPrice),
+      new BN(1234571),
+      market.priceNumberToLots(takeProfitTriggerPrice),
+      {
+        accounts: {
+          stoplossState: stopLeg.publicKey,
+          bracketLeg: takeProfitLeg.publicKey,
+          authority: program.provider.wallet.publicKey,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+        instructions: [await program.account.stoplossState.createInstruction(takeProfitLeg)],
+        signers: [takeProfitLeg],
+      }
+    );
+
+    // no more funds were locked for the second leg
+    let totalCoinChange = ((await getA()).amount.toNumber() - coinBefore.toNumber()) / 10 ** 6;
+    assert.ok(totalCoinChange === -maxCoinQty);
+
+    let tp = await program.account.stoplossState.fetch(takeProfitLeg.publicKey);
+    assert.ok(tp.orderKind.hasOwnProperty("takeProfit"));
+    assert.ok(tp.sibling.equals(stopLeg.publicKey));
+    assert.ok(tp.stoplossBaseVault.equals(SELL_NEW_ORDER_ACCOUNTS.stoplossBaseVault));
+
+    EXECUTE_SELL_ORDER_ACCOUNTS["stoplossState"] = takeProfitLeg.publicKey;
+    await program.rpc.executeOrder(
+      new BN(maxCoinQty * 10 ** 6),
+      market.priceNumberToLots(takeProfitLimitPrice),
+      false,
+      utils.OrderType.ImmediateOrCancel,
+      {
+        accounts: EXECUTE_SELL_ORDER_ACCOUNTS,
+        remainingAccounts: [{ pubkey: stopLeg.publicKey, isWritable: true, isSigner: false }],
+        signers: [signalProvider]
+      }
+    );
+
+    tp = await program.account.stoplossState.fetch(takeProfitLeg.publicKey);
+    assert.ok(tp.ordStatus.hasOwnProperty("filled"));
+    let stop = await program.account.stoplossState.fetch(stopLeg.publicKey);
+    assert.ok(stop.ordStatus.hasOwnProperty("cancelled"));
+    assert.ok(stop.coinLeavesQty.toNumber() === 0);
+  });
+
+
+
+
+
+
+  it("Keeper executes an opt-in sell and is paid the bounty", async () => {
+
+    const maxCoinQty = 2.2;
+    const maxPcQty = new BN(Number.MAX_SAFE_INTEGER);
+    const limitPrice = 6.004;
+    const clientId = new BN(1234572);
+    const triggerPrice = 20;
+    const bounty = 0.01 * 10 ** 6; // paid in A, the paying mint for a sell
+
+    let stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+      limitPrice, clientId, triggerPrice, maxCoinQty, maxPcQty, signalProvider, utils.TriggerType.Fixed, 0, true, bounty);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+
+    const keeper = anchor.web3.Keypair.generate();
+    await utils.airdrop(program.provider.connection, 100000000, keeper);
+    const keeperAccount = ORDERBOOK_ENV.secondUser.tokens[ORDERBOOK_ENV.mintA.toString()];
+    const keeperBefore = await utils.getTokenBalance(program.provider, keeperAccount);
+
+    await program.rpc.executeOrder(
+      new BN((maxCoinQty - 0.01) * 10 ** 6),
+      market.priceNumberToLots(limitPrice),
+      false,
+      utils.OrderType.ImmediateOrCancel,
+      {
+        accounts: {
+          ...EXECUTE_SELL_ORDER_ACCOUNTS,
+          stoplossState: stoplossStateAccount.publicKey,
+          authority: keeper.publicKey,
+          keeperBountyAccount: keeperAccount,
+        },
+        signers: [keeper]
+      }
+    );
+
+    const keeperAfter = await utils.getTokenBalance(program.provider, keeperAccount);
+    assert.ok(keeperAfter.toNumber() - keeperBefore.toNumber() === bounty);
+
+    let sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
+    assert.ok(sls.keeperBountyPaid);
+    assert.ok(sls.coinCumQty.toNumber() > 0);
+  });
+
+  it("Keeper cannot execute an order that has not opted in", async () => {
+    let stoplossStateAccount = await newSell(6.004, new BN(1234573), 20, 2.2, new BN(Number.MAX_SAFE_INTEGER));
+    const keeper = anchor.web3.Keypair.generate();
+
+    try {
+      await program.rpc.executeOrder(
+        new BN(2.2 * 10 ** 6),
+        market.priceNumberToLots(6.004),
+        false,
+        utils.OrderType.ImmediateOrCancel,
+        {
+          accounts: {
+            ...EXECUTE_SELL_ORDER_ACCOUNTS,
+            stoplossState: stoplossStateAccount.publicKey,
+            authority: keeper.publicKey,
+          },
+          signers: [keeper]
+        }
+      );
+      assert.ok(false);
+    } catch (err) {
+      const errMsg =
+        "Incorrect Signal Provider account was provided. Should be algo server sending execute instructions.";
+      assert.equal(err.toString(), errMsg);
+    }
+  });
+
+
+
+
+
+
+  it("Close a cancelled order and reclaim its rent", async () => {
+    let stoplossStateAccount = await newSell(6.004, new BN(1234574), 20, 2.2, new BN(Number.MAX_SAFE_INTEGER));
+
+    const CLOSE_ACCOUNTS = {
+      stoplossState: stoplossStateAccount.publicKey,
+      authority: program.provider.wallet.publicKey,
+      recipient: signalProvider.publicKey,
+      stoplossBaseVault: SELL_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+      stoplossQuoteVault: SELL_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+      vaultOwner: stoplossPDA,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    // cannot close while the order is live
+    try {
+      await program.rpc.closeOrder({ accounts: CLOSE_ACCOUNTS });
+      assert.ok(false);
+    } catch (err) {
+      const errMsg =
+        "The order must be filled or cancelled with no leaves before it can be closed";
+      assert.equal(err.toString(), errMsg);
+    }
+
+    await program.rpc.cancelOrder({
+      accounts: {
+        stoplossState: stoplossStateAccount.publicKey,
+        coinWallet: ORDERBOOK_ENV.godA,
+        pcWallet: ORDERBOOK_ENV.godUsdc,
+        stoplossPayingVault: SELL_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+        stoplossReceivingVault: SELL_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+        authority: program.provider.wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        vaultOwner: stoplossPDA
+      },
+    });
+
+    const rent = await program.provider.connection.getBalance(stoplossStateAccount.publicKey);
+    const recipientBefore = await program.provider.connection.getBalance(signalProvider.publicKey);
+
+    await program.rpc.closeOrder({ accounts: CLOSE_ACCOUNTS });
+
+    const recipientAfter = await program.provider.connection.getBalance(signalProvider.publicKey);
+    assert.ok(recipientAfter - recipientBefore === rent);
+    assert.ok((await program.provider.connection.getAccountInfo(stoplossStateAccount.publicKey)) === null);
+  });
+
+
+
+
+
+
+  it("Emits created and cancelled events carrying the order fields", async () => {
+    let created = null, cancelled = null;
+    const createdListener = program.addEventListener("StoplossOrderCreated", (event) => { created = event; });
+    const cancelledListener = program.addEventListener("StoplossOrderCancelled", (event) => { cancelled = event; });
+
+    const clientOrderId = new BN(1234575);
+    let stoplossStateAccount = await newSell(6.004, clientOrderId, 20, 2.2, new BN(Number.MAX_SAFE_INTEGER));
+    await program.rpc.cancelOrder({
+      accounts: {
+        stoplossState: stoplossStateAccount.publicKey,
+        coinWallet: ORDERBOOK_ENV.godA,
+        pcWallet: ORDERBOOK_ENV.godUsdc,
+        stoplossPayingVault: SELL_NEW_ORDER_ACCOUNTS.stoplossBaseVault,
+        stoplossReceivingVault: SELL_NEW_ORDER_ACCOUNTS.stoplossQuoteVault,
+        authority: program.provider.wallet.publicKey,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        vaultOwner: stoplossPDA
+      },
+    });
+    await utils.sleep(1000);
+
+    await program.removeEventListener(createdListener);
+    await program.removeEventListener(cancelledListener);
+
+    assert.ok(created.ownAddress.equals(stoplossStateAccount.publicKey));
+    assert.ok(created.clientOrderId.eq(clientOrderId));
+    assert.ok(created.side.hasOwnProperty("ask"));
+    assert.ok(created.maxCoinQty.toNumber() === 2.2 * 10 ** 6);
+    assert.ok(created.triggerPrice.eq(market.priceNumberToLots(20)));
+
+    assert.ok(cancelled.ownAddress.equals(stoplossStateAccount.publicKey));
+    assert.ok(cancelled.refundedCoinQty.toNumber() === 2.2 * 10 ** 6);
+    assert.ok(cancelled.ordStatus.hasOwnProperty("cancelled"));
+  });
+
+
+
+
+
+
   it("Amend a sell order", async () => {
 
     const maxCoinQty = 2.2; // size we target, ie 13.2088
@@ -397,6 +731,7 @@ describe("anchor-stoploss", () => {
       coinWallet: ORDERBOOK_ENV.godA,
       pcWallet: ORDERBOOK_ENV.godUsdc,
       stoplossPayingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossBaseVault"],
+      stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossQuoteVault"],
       authority: program.provider.wallet.publicKey,
       tokenProgram: TOKEN_PROGRAM_ID,
       vaultOwner: stoplossPDA
@@ -431,6 +766,7 @@ describe("anchor-stoploss", () => {
       coinWallet: ORDERBOOK_ENV.godA,
       pcWallet: ORDERBOOK_ENV.godUsdc,
       stoplossPayingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossQuoteVault"],
+      stoplossReceivingVault: BUY_NEW_ORDER_ACCOUNTS["stoplossBaseVault"],
       authority: program.provider.wallet.publicKey,
       tokenProgram: TOKEN_PROGRAM_ID,
       vaultOwner: stoplossPDA
@@ -516,9 +852,10 @@ describe("anchor-stoploss", () => {
       [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault, BUY_NEW_ORDER_ACCOUNTS.stoplossQuoteVault],
       async () => {
         await program.rpc.executeOrder(
-          new BN(baseQuantity * limitPrice * 10 ** 6),
+          new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_BUY_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -539,6 +876,7 @@ describe("anchor-stoploss", () => {
           new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_SELL_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -575,18 +913,14 @@ const Side = {
 async function withBalanceChange(provider, addrs, fn) {
   const beforeBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    beforeBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    beforeBalances.push(await utils.getTokenBalance(provider, addrs[k]));
   }
 
   await fn();
 
   const afterBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    afterBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    afterBalances.push(await utils.getTokenBalance(provider, addrs[k]));
   }
 
   const deltas = [];
@@ -57,7 +57,7 @@ describe("multi-amends-partial-fills", () => {
     );
     marketAVaultSigner = vaultSignerA;
 
-    const { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders } =
+    const { stoplossOpenOrders } =
       await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, marketA._decoded.ownAddress, signalProvider
       );
 
@@ -83,10 +83,18 @@ describe("multi-amends-partial-fills", () => {
         pcMint: ORDERBOOK_ENV.mintusdc,
 
       },
-      stoplossBaseVault: stoplossBaseVault.publicKey,
-      stoplossQuoteVault: stoplossQuoteVault.publicKey,
       stoplossOpenOrders: stoplossOpenOrders.publicKey,
       stoplossProgram: program._programId,
+      config: await utils.setupConfig(program, signalProvider),
+      // fees are taken in the mint the order receives, base for buys
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintA, program._programId),
+      // only checked for orders with an oracle, see set_oracle
+      oracle: anchor.web3.SystemProgram.programId,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      // receives the rent back when the order's vaults are closed
+      amendAuthority: program.provider.wallet.publicKey,
+      // only paid into when a keeper executes
+      keeperBountyAccount: ORDERBOOK_ENV.godUsdc,
 
       // signal provider is the off chain component that will call execute
       signalProvider: signalProvider.publicKey,
@@ -101,6 +109,7 @@ describe("multi-amends-partial-fills", () => {
       ...BUY_NEW_ORDER_ACCOUNTS,
       clientPayingAccount: ORDERBOOK_ENV.godA,
       clientReceivingAccount: ORDERBOOK_ENV.godUsdc,
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintusdc, program._programId),
       market: {
         ...BUY_NEW_ORDER_ACCOUNTS.market,
         orderPayerTokenAccount: ORDERBOOK_ENV.godA,
@@ -126,13 +135,17 @@ describe("multi-amends-partial-fills", () => {
 
 
   async function newSell(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+    const stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
   async function newBuy(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
+    const stoplossStateAccount = await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(BUY_NEW_ORDER_ACCOUNTS, [SELL_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
 
@@ -436,9 +449,10 @@ describe("multi-amends-partial-fills", () => {
       [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault, BUY_NEW_ORDER_ACCOUNTS.stoplossQuoteVault],
       async () => {
         await program.rpc.executeOrder(
-          new BN(baseQuantity * limitPrice * 10 ** 6),
+          new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_BUY_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -459,6 +473,7 @@ describe("multi-amends-partial-fills", () => {
           new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_SELL_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -540,18 +555,14 @@ async function createOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, s
 async function withBalanceChange(provider, addrs, fn) {
   const beforeBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    beforeBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    beforeBalances.push(await utils.getTokenBalance(provider, addrs[k]));
   }
 
   await fn();
 
   const afterBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    afterBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    afterBalances.push(await utils.getTokenBalance(provider, addrs[k]));
   }
 
   const deltas = [];
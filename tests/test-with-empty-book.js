@@ -58,7 +58,7 @@ describe("test-with-empty-book", () => {
     );
     marketAVaultSigner = vaultSignerA;
 
-    const { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders } =
+    const { stoplossOpenOrders } =
       await utils.setupStoplossAccounts(program.provider, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, program.provider.wallet.payer, stoplossPDA, marketA._decoded.ownAddress, signalProvider
         );    
 
@@ -84,10 +84,18 @@ describe("test-with-empty-book", () => {
         pcMint: ORDERBOOK_ENV.mintusdc,
 
       },
-      stoplossBaseVault: stoplossBaseVault.publicKey,
-      stoplossQuoteVault: stoplossQuoteVault.publicKey,
       stoplossOpenOrders: stoplossOpenOrders.publicKey,
       stoplossProgram: program._programId,
+      config: await utils.setupConfig(program, signalProvider),
+      // fees are taken in the mint the order receives, base for buys
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintA, program._programId),
+      // only checked for orders with an oracle, see set_oracle
+      oracle: anchor.web3.SystemProgram.programId,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      // receives the rent back when the order's vaults are closed
+      amendAuthority: program.provider.wallet.publicKey,
+      // only paid into when a keeper executes
+      keeperBountyAccount: ORDERBOOK_ENV.godUsdc,
 
       // signal provider is the off chain component that will call execute
       signalProvider: signalProvider.publicKey,
@@ -102,6 +110,7 @@ describe("test-with-empty-book", () => {
       ...BUY_NEW_ORDER_ACCOUNTS,
       clientPayingAccount: ORDERBOOK_ENV.godA,
       clientReceivingAccount: ORDERBOOK_ENV.godUsdc,
+      feeVault: await utils.getFeeVault(ORDERBOOK_ENV.mintusdc, program._programId),
       market: {
         ...BUY_NEW_ORDER_ACCOUNTS.market,
         orderPayerTokenAccount: ORDERBOOK_ENV.godA,
@@ -127,24 +136,25 @@ describe("test-with-empty-book", () => {
 
  
   async function newSell(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
+    const stoplossStateAccount = await utils.createSellOrder(program, SELL_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Ask,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(SELL_NEW_ORDER_ACCOUNTS, [BUY_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
   async function newBuy(limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty) {
-    return await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
+    const stoplossStateAccount = await utils.createBuyOrder(program, BUY_NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, Side.Bid,
       limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider);
+    utils.useOrderVaults(BUY_NEW_ORDER_ACCOUNTS, [SELL_NEW_ORDER_ACCOUNTS, EXECUTE_BUY_ORDER_ACCOUNTS, EXECUTE_SELL_ORDER_ACCOUNTS]);
+    return stoplossStateAccount;
   }
 
 
 
-  it("Create and execute sell Stoploss order - opposite side is empty, reuse is FALSE", async () => {
+  it("Reject executing a sell Stoploss order - opposite side is empty, no price to trigger from", async () => {
     
     const maxCoinQty = 22.2; 
     const maxPcQty = new BN(Number.MAX_SAFE_INTEGER);
-    const bestBidPrice = 0; // best bid
-    const amountToFill = 0 * bestBidPrice;
-    const expectedFillAmount = 0;
     const limitPrice = 6.004;
     const clientId = new BN(1234567);
     const triggerPrice = 20;
@@ -154,31 +164,32 @@ describe("test-with-empty-book", () => {
     let stoplossStateAccount = await newSell(limitPrice, clientId, triggerPrice, maxCoinQty, maxPcQty);
 
 
-    // EXECUTE - this will fire against first level that has nothing in
+    // EXECUTE - there are no bids so the trigger cannot be checked
     EXECUTE_SELL_ORDER_ACCOUNTS["stoplossState"] = stoplossStateAccount.publicKey;
-    [tokenAChange, usdcChange, stoplossBaseChange, stoplossQuoteChange] = await executeSell(maxCoinQty, limitPrice, market, false);
+    try {
+      await executeSell(maxCoinQty, limitPrice, market, false);
+      assert.ok(false);
+    } catch (err) {
+      const errMsg =
+        "Unable to read a market price from the order book to check the trigger";
+      assert.equal(err.toString(), errMsg);
+    }
 
 
-    // should have matched and settled....
     let totalUsdcChange = ((await getUsdc()).amount.toNumber() - usdcBefore.toNumber()) / 10 ** 6;
     let totalTokenAChange = ((await getA()).amount.toNumber() - tokenABefore.toNumber()) / 10 ** 6;
 
+    assert.ok(totalTokenAChange === -maxCoinQty); // still held in the stoploss vault
+    assert.ok(totalUsdcChange === 0);
 
-    assert.ok(tokenAChange === 22.2);
-    assert.ok(totalTokenAChange === 0);
-    assert.ok(totalUsdcChange === 0); // client is credited
-    assert.ok(stoplossBaseChange === -22.2); // 8.5 is qty resting on the book
-    assert.ok(stoplossQuoteChange === 0); //sl vault transferred out to client immediately, so no change
 
-
-    // check the "state" account is modified correctly 
+    // check the "state" account is untouched
     sls = await program.account.stoplossState.fetch(stoplossStateAccount.publicKey);
     assert.ok(sls.maxCoinQty.toNumber() === maxCoinQty * 10 ** 6);
-    assert.ok(sls.coinLeavesQty.toNumber() === 0);
+    assert.ok(sls.coinLeavesQty.toNumber() === maxCoinQty * 10 ** 6);
     assert.ok(sls.coinCumQty.toNumber() === 0);
     assert.ok(sls.pcCumQty.toNumber() === 0);
-    assert.ok(sls.avgPrice.toNumber() === 0);
-    assert.ok(sls.lastPrice.toNumber() === 0);
+    assert.ok(sls.childOrderCount.toNumber() === 0);
 
 
     // should be NO fills too
@@ -198,9 +209,10 @@ describe("test-with-empty-book", () => {
       [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, BUY_NEW_ORDER_ACCOUNTS.stoplossBaseVault, BUY_NEW_ORDER_ACCOUNTS.stoplossQuoteVault],
       async () => {
         await program.rpc.executeOrder(
-          new BN(baseQuantity * limitPrice * 10 ** 6),
+          new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_BUY_ORDER_ACCOUNTS,
             signers: [signalProvider]
@@ -221,6 +233,7 @@ describe("test-with-empty-book", () => {
           new BN(baseQuantity * 10 ** 6),
           market.priceNumberToLots(limitPrice),
           reuseUnfilled,
+          utils.OrderType.ImmediateOrCancel,
           {
             accounts: EXECUTE_SELL_ORDER_ACCOUNTS,
             signers: [signalProvider]
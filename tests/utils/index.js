@@ -46,6 +46,27 @@ async function setupSignalProvider(provider, payerAccount, stoplossProgramId) {
   return signalProvider;
 }
 
+// The program config is a singleton shared by every test file. The first caller creates it with
+// the provider wallet as admin, then each signal provider is approved as it is set up.
+async function setupConfig(program, signalProvider) {
+  const [config] = await PublicKey.findProgramAddress([Buffer.from("config")], program.programId);
+  const admin = program.provider.wallet.publicKey;
+  if ((await program.provider.connection.getAccountInfo(config)) === null) {
+    await program.rpc.initialiseConfig(admin, 0, 0, {
+      accounts: {
+        admin,
+        config,
+        systemProgram: SystemProgram.programId,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+    });
+  }
+  await program.rpc.addSignalProvider(signalProvider.publicKey, {
+    accounts: { config, admin },
+  });
+  return config;
+}
+
 async function createSLTokenAccountTransaction(
   connection,
   wallet,
@@ -86,39 +107,10 @@ async function setupStoplossAccounts(provider, mintA, mintusdc, payerAccount, st
   // tx0.add(quotetx.ix);
   // await provider.send(tx0, [payerAccount]);
 
-  let stoplossBaseVault = anchor.web3.Keypair.generate();
-  let stoplossQuoteVault = anchor.web3.Keypair.generate();
+  // the base/quote vaults and the open orders are created per order by new_order, see
+  // getOrderVaults. This one is only ever used as a wrong open orders account.
   let stoplossOpenOrders = anchor.web3.Keypair.generate();
 
-  const tx1 = new Transaction();
-  tx1.add(
-    SystemProgram.createAccount({
-      fromPubkey: payerAccount.publicKey,
-      newAccountPubkey: stoplossBaseVault.publicKey,
-      lamports: await connection.getMinimumBalanceForRentExemption(165),
-      space: 165,
-      programId: TOKEN_PROGRAM_ID,
-    }),
-    SystemProgram.createAccount({
-      fromPubkey: payerAccount.publicKey,
-      newAccountPubkey: stoplossQuoteVault.publicKey,
-      lamports: await connection.getMinimumBalanceForRentExemption(165),
-      space: 165,
-      programId: TOKEN_PROGRAM_ID,
-    }),
-    TokenInstructions.initializeAccount({
-      account: stoplossBaseVault.publicKey,
-      mint: mintA,
-      owner: stoplossPDA,
-    }),
-    TokenInstructions.initializeAccount({
-      account: stoplossQuoteVault.publicKey,
-      mint: mintusdc,
-      owner: stoplossPDA,
-    }),
-
-  );
-
   // signal provider is the thing that actually executes the order,
   // so it is the thing that needs to have an openOrders account
   // just do at the point of sending the new order for the first time from the server?
@@ -134,13 +126,9 @@ async function setupStoplossAccounts(provider, mintA, mintusdc, payerAccount, st
     )
   );
 
-  await provider.send(tx1, [payerAccount, stoplossBaseVault, stoplossQuoteVault]);
-
   await provider.send(tx2, [stoplossOpenOrders, signalProvider]);
 
-
-  const stoplossQuoteOwner = (await serumCmn.getTokenAccount(provider, stoplossQuoteVault.publicKey));
-  return { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders };
+  return { stoplossOpenOrders };
 }
 
 
@@ -368,6 +356,10 @@ async function initOrderbook({ provider, bids, asks }) {
   };
 }
 
+function sleep(ms) {
+  return new Promise((resolve) => setTimeout(resolve, ms));
+}
+
 async function airdrop(
   connection,
   lamports = 1000000,
@@ -719,7 +711,67 @@ async function getStoplossVaultOwner(seed, stoplossProgramId) {
   return pda_arr[0];
 }
 
-async function createBuyOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, side, limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider) {
+// TriggerType rust enum used for the program's RPC API.
+const TriggerType = {
+  Fixed: { fixed: {} },
+  TrailingAbsolute: { trailingAbsolute: {} },
+  TrailingBps: { trailingBps: {} },
+};
+
+// TimeInForce rust enum used for the program's RPC API.
+const TimeInForce = {
+  GoodTillCancel: { goodTillCancel: {} },
+  GoodTillDate: { goodTillDate: {} },
+  Day: { day: {} },
+};
+
+// OrderMode rust enum used for the program's RPC API.
+const OrderMode = {
+  StopLimit: { stopLimit: {} },
+  StopMarket: { stopMarket: {} },
+};
+
+// OrderType rust enum used for execute_order's child orders.
+const OrderType = {
+  Limit: { limit: {} },
+  ImmediateOrCancel: { immediateOrCancel: {} },
+  PostOnly: { postOnly: {} },
+};
+
+// The vaults new_order creates for an order, derived from the order address and mint, and
+// the order's open orders account.
+async function getOrderVaults(stoplossState, mintA, mintusdc, stoplossProgramId) {
+  const [stoplossBaseVault] = await PublicKey.findProgramAddress([stoplossState.toBuffer(), mintA.toBuffer()], stoplossProgramId);
+  const [stoplossQuoteVault] = await PublicKey.findProgramAddress([stoplossState.toBuffer(), mintusdc.toBuffer()], stoplossProgramId);
+  const [stoplossOpenOrders] = await PublicKey.findProgramAddress([Buffer.from("open_orders"), stoplossState.toBuffer()], stoplossProgramId);
+  return { stoplossBaseVault, stoplossQuoteVault, stoplossOpenOrders };
+}
+
+// Records the accounts of a new order in NEW_ORDER_ACCOUNTS.
+async function useNewOrder(NEW_ORDER_ACCOUNTS, stoplossState, ORDERBOOK_ENV, stoplossProgramId) {
+  NEW_ORDER_ACCOUNTS["stoplossState"] = stoplossState;
+  Object.assign(NEW_ORDER_ACCOUNTS, await getOrderVaults(stoplossState, ORDERBOOK_ENV.mintA, ORDERBOOK_ENV.mintusdc, stoplossProgramId));
+  NEW_ORDER_ACCOUNTS.market = { ...NEW_ORDER_ACCOUNTS.market, openOrders: NEW_ORDER_ACCOUNTS.stoplossOpenOrders };
+}
+
+// The program's fee vault for a mint. Only needs to exist once a protocol fee is set.
+async function getFeeVault(mint, stoplossProgramId) {
+  const [feeVault] = await PublicKey.findProgramAddress([Buffer.from("fees"), mint.toBuffer()], stoplossProgramId);
+  return feeVault;
+}
+
+// Points every account set used by a test at the vaults and open orders of the most recently
+// created order.
+function useOrderVaults(NEW_ORDER_ACCOUNTS, accountSets) {
+  for (let accounts of accountSets) {
+    accounts.stoplossBaseVault = NEW_ORDER_ACCOUNTS.stoplossBaseVault;
+    accounts.stoplossQuoteVault = NEW_ORDER_ACCOUNTS.stoplossQuoteVault;
+    accounts.stoplossOpenOrders = NEW_ORDER_ACCOUNTS.stoplossOpenOrders;
+    accounts.market = { ...accounts.market, openOrders: NEW_ORDER_ACCOUNTS.stoplossOpenOrders };
+  }
+}
+
+async function createBuyOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, side, limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider, triggerType = TriggerType.Fixed, trailingOffset = 0, keeperAllowed = false, keeperBounty = 0, timeInForce = TimeInForce.GoodTillCancel, expiryTs = 0, orderMode = OrderMode.StopLimit) {
   let stoplossStateAccount = anchor.web3.Keypair.generate();
   const tx = new anchor.web3.Transaction();
   tx.add(
@@ -738,7 +790,7 @@ async function createBuyOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market
 
   maxPcQty = Math.floor(maxPcQty);
 
-  NEW_ORDER_ACCOUNTS["stoplossState"] = stoplossStateAccount.publicKey;
+  await useNewOrder(NEW_ORDER_ACCOUNTS, stoplossStateAccount.publicKey, ORDERBOOK_ENV, program.programId);
 
   let [tokenAChange, usdcChange, stoplossBaseChange, stoplossQuoteChange] = await withBalanceChange(
     program.provider,
@@ -752,7 +804,15 @@ async function createBuyOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market
         market.priceNumberToLots(triggerPrice),
         new BN(maxCoinQty),
         new BN(maxPcQty),
-        signalProvider.publicKey,
+        // should_create_open_orders, so the order can execute straight away
+        true,
+        triggerType,
+        new BN(trailingOffset),
+        keeperAllowed,
+        new BN(keeperBounty),
+        timeInForce,
+        new BN(expiryTs),
+        orderMode,
         {
           accounts: NEW_ORDER_ACCOUNTS
         }
@@ -780,7 +840,7 @@ async function createBuyOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market
 }
 
 
-async function createSellOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, side, limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider) {
+async function createSellOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, market, side, limitPrice, clientOrderId, triggerPrice, maxCoinQty, maxPcQty, signalProvider, triggerType = TriggerType.Fixed, trailingOffset = 0, keeperAllowed = false, keeperBounty = 0, timeInForce = TimeInForce.GoodTillCancel, expiryTs = 0, orderMode = OrderMode.StopLimit) {
   let stoplossStateAccount = anchor.web3.Keypair.generate();
   const tx = new anchor.web3.Transaction();
   tx.add(
@@ -798,7 +858,7 @@ async function createSellOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, marke
   await program.provider.send(tx, [stoplossStateAccount]);
 
 
-  NEW_ORDER_ACCOUNTS["stoplossState"] = stoplossStateAccount.publicKey;
+  await useNewOrder(NEW_ORDER_ACCOUNTS, stoplossStateAccount.publicKey, ORDERBOOK_ENV, program.programId);
   let [tokenAChange, usdcChange, stoplossBaseChange, stoplossQuoteChange] = await withBalanceChange(
     program.provider,
     [ORDERBOOK_ENV.godA, ORDERBOOK_ENV.godUsdc, NEW_ORDER_ACCOUNTS.stoplossBaseVault, NEW_ORDER_ACCOUNTS.stoplossQuoteVault],
@@ -811,7 +871,15 @@ async function createSellOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, marke
         market.priceNumberToLots(triggerPrice),
         new BN(maxCoinQty * 10 ** 6),
         new BN(maxPcQty),
-        signalProvider.publicKey,
+        // should_create_open_orders, so the order can execute straight away
+        true,
+        triggerType,
+        new BN(trailingOffset),
+        keeperAllowed,
+        new BN(keeperBounty),
+        timeInForce,
+        new BN(expiryTs),
+        orderMode,
         {
           accounts: NEW_ORDER_ACCOUNTS
         }
@@ -838,23 +906,28 @@ async function createSellOrder(program, NEW_ORDER_ACCOUNTS, ORDERBOOK_ENV, marke
   return stoplossStateAccount;
 }
 
+// Order vaults do not exist before new_order and are closed once the order is
+// done, so a missing account is treated as an empty one.
+async function getTokenBalance(provider, addr) {
+  if ((await provider.connection.getAccountInfo(addr)) === null) {
+    return new BN(0);
+  }
+  return (await serumCmn.getTokenAccount(provider, addr)).amount;
+}
+
 // Executes a closure. Returning the change in balances from before and after
 // its execution.
 async function withBalanceChange(provider, addrs, fn) {
   const beforeBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    beforeBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    beforeBalances.push(await getTokenBalance(provider, addrs[k]));
   }
 
   await fn();
 
   const afterBalances = [];
   for (let k = 0; k < addrs.length; k += 1) {
-    afterBalances.push(
-      (await serumCmn.getTokenAccount(provider, addrs[k])).amount
-    );
+    afterBalances.push(await getTokenBalance(provider, addrs[k]));
   }
 
   const deltas = [];
@@ -879,8 +952,18 @@ module.exports = {
   getStoplossVaultOwner,
   setupStoplossAccounts,
   setupSignalProvider,
+  setupConfig,
+  getFeeVault,
   createSellOrder,
   createBuyOrder,
   withBalanceChange,
+  getTokenBalance,
+  getOrderVaults,
+  useOrderVaults,
+  TriggerType,
+  TimeInForce,
+  OrderMode,
+  OrderType,
+  sleep,
   setupEmptyMarket
 };